@@ -0,0 +1,360 @@
+use anyhow::{Result, anyhow};
+
+
+/// This type represents everything a user can type into the client.
+/// File is for sending a file specified by its path.
+/// Image is for sending a .png image specified by its path.
+/// TextFile is for sending the contents of a UTF-8 text file specified by its path as text.
+/// Rename is for changing the username of the logged in user.
+/// Nickname is for changing the name under which other clients see the messages of the logged in user.
+/// History is for asking the server for the given number of the most recent chat messages.
+/// Markdown is for sending a text message that other clients render as markdown.
+/// Multiline is for composing a text message of several lines. The message ends with a line containing only '.'.
+/// Help is for printing all commands. Nothing is sent to the server.
+/// Clear is for clearing the terminal screen. Nothing is sent to the server.
+/// Reconnect is for closing the connection to the server and connecting and logging in again, without stopping the client.
+/// Quit is for stopping the client.
+/// Text is for plain text that is sent to other clients as it is.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    File(String),
+    Image(String),
+    TextFile(String),
+    Rename(String),
+    Nickname(String),
+    History(String),
+    Markdown(String),
+    Multiline,
+    Help,
+    Clear,
+    Reconnect,
+    Quit,
+    Text(String)
+}
+
+
+/// Description of a command that starts with '.'.
+/// The same list of commands is used for parsing user input and for printing help, so they always match.
+/// If the command takes an argument, its name is given, e.g. "<path>".
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub argument: Option<&'static str>,
+    pub description: &'static str,
+    build: fn(String) -> Command
+}
+
+
+/// All commands that start with '.'.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "file",
+        argument: Some("<path>"),
+        description: "Send a file to all other clients.",
+        build: Command::File
+    },
+    CommandSpec {
+        name: "image",
+        argument: Some("<path>"),
+        description: "Send a .png image to all other clients.",
+        build: Command::Image
+    },
+    CommandSpec {
+        name: "text",
+        argument: Some("<path>"),
+        description: "Send the contents of a UTF-8 text file as a text message.",
+        build: Command::TextFile
+    },
+    CommandSpec {
+        name: "rename",
+        argument: Some("<username>"),
+        description: "Change your username.",
+        build: Command::Rename
+    },
+    CommandSpec {
+        name: "nickname",
+        argument: Some("<nickname>"),
+        description: "Change the name under which other users see your messages.",
+        build: Command::Nickname
+    },
+    CommandSpec {
+        name: "history",
+        argument: Some("<count>"),
+        description: "Show the given number of the most recent chat messages.",
+        build: Command::History
+    },
+    CommandSpec {
+        name: "markdown",
+        argument: Some("<text>"),
+        description: "Send a text message with **bold**, *italic* and `code` markdown styles.",
+        build: Command::Markdown
+    },
+    CommandSpec {
+        name: "multiline",
+        argument: None,
+        description: "Compose a text message of several lines. End it with a line containing only '.'.",
+        build: |_| Command::Multiline
+    },
+    CommandSpec {
+        name: "help",
+        argument: None,
+        description: "Show this list of commands.",
+        build: |_| Command::Help
+    },
+    CommandSpec {
+        name: "clear",
+        argument: None,
+        description: "Clear the screen. Nothing is sent.",
+        build: |_| Command::Clear
+    },
+    CommandSpec {
+        name: "reconnect",
+        argument: None,
+        description: "Close the connection to the server, then connect and log in again. Unsent messages are kept.",
+        build: |_| Command::Reconnect
+    },
+    CommandSpec {
+        name: "quit",
+        argument: None,
+        description: "Stop the client.",
+        build: |_| Command::Quit
+    }
+];
+
+
+/// Get the text listing all commands with their descriptions, one command per line.
+pub fn help_text() -> String {
+    let usages: Vec<String> = COMMANDS.iter().map(
+        |spec| match spec.argument {
+            Some(argument) => format!(".{} {}", spec.name, argument),
+            None => format!(".{}", spec.name)
+        }
+    ).collect();
+    let width = usages.iter().map(|usage| usage.len()).max().unwrap_or_default();
+
+    let mut lines = vec!["Available commands:".to_string()];
+    for (usage, spec) in usages.iter().zip(COMMANDS) {
+        lines.push(format!("  {:width$}  {}", usage, spec.description, width = width));
+    }
+    lines.push("Any other input is sent as a text message.".to_string());
+    lines.join("\n")
+}
+
+
+/// Parse user input into a command.
+/// An input starting with '.' directly followed by a command name is treated as a command.
+/// The name is matched regardless of case, e.g. '.QUIT' and '.File' are commands too. The case of the argument is kept.
+/// Everything after the first space is the argument of the command.
+/// All other inputs are plain text messages.
+pub fn parse_command(user_input: &str) -> Result<Command> {
+    let command_str = match user_input.strip_prefix('.') {
+        Some(command_str) => command_str,
+        None => {
+            return Ok(Command::Text(user_input.to_string()));
+        }
+    };
+    let (name, argument) = match command_str.split_once(' ') {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (command_str, "")
+    };
+
+    // Inputs like "..." or ". hello" are not commands.
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Ok(Command::Text(user_input.to_string()));
+    }
+
+    let spec = COMMANDS.iter().find(|spec| spec.name.eq_ignore_ascii_case(name)).ok_or_else(|| anyhow!("Unknown command '.{}'. Type '.help' to see all commands.", name))?;
+    match spec.argument {
+        Some(_) => Ok((spec.build)(get_required_argument(spec.name, argument)?)),
+        None => {
+            check_no_argument(spec.name, argument)?;
+            Ok((spec.build)(String::new()))
+        }
+    }
+}
+
+
+/// Parse the argument of the '.history' command.
+/// It must be a positive whole number that fits into the history request.
+pub fn parse_history_count(argument: &str) -> Result<u32> {
+    argument.parse::<u32>().ok().filter(|count| *count > 0).ok_or_else(|| anyhow!("The number of messages must be a positive whole number of at most {}.", u32::MAX))
+}
+
+
+/// Check that a command which needs an argument got one and return it.
+fn get_required_argument(name: &str, argument: &str) -> Result<String> {
+    if argument.is_empty() {
+        return Err(anyhow!("The '.{}' command requires an argument.", name));
+    }
+    Ok(argument.to_string())
+}
+
+
+/// Check that a command which takes no argument did not get one.
+fn check_no_argument(name: &str, argument: &str) -> Result<()> {
+    if !argument.is_empty() {
+        return Err(anyhow!("The '.{}' command does not take any argument.", name));
+    }
+    Ok(())
+}
+
+
+/// Line that ends a multiline message.
+pub const MULTILINE_END: &str = ".";
+
+
+/// Text message of several lines that is being composed by the user.
+#[derive(Debug, Default)]
+pub struct MultilineMessage {
+    lines: Vec<String>
+}
+
+
+impl MultilineMessage {
+    /// Add a line to the message.
+    /// If the line is MULTILINE_END, the message is finished and its text is returned.
+    pub fn push_line(&mut self, line: &str) -> Option<String> {
+        if line.trim() == MULTILINE_END {
+            return Some(self.lines.join("\n"));
+        }
+        self.lines.push(line.to_string());
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_command() {
+        let command = parse_command(".file some dir/notes.txt").unwrap();
+        assert_eq!(command, Command::File("some dir/notes.txt".to_string()));
+    }
+
+    #[test]
+    fn test_parse_image_command() {
+        let command = parse_command(".image picture.png").unwrap();
+        assert_eq!(command, Command::Image("picture.png".to_string()));
+    }
+
+    #[test]
+    fn test_parse_text_command() {
+        let command = parse_command(".text long_message.txt").unwrap();
+        assert_eq!(command, Command::TextFile("long_message.txt".to_string()));
+    }
+
+    #[test]
+    fn test_parse_rename_command() {
+        let command = parse_command(".rename  bob").unwrap();
+        assert_eq!(command, Command::Rename("bob".to_string()));
+    }
+
+    #[test]
+    fn test_parse_nickname_command() {
+        let command = parse_command(".nickname Bobby Tables").unwrap();
+        assert_eq!(command, Command::Nickname("Bobby Tables".to_string()));
+    }
+
+    #[test]
+    fn test_parse_history_command() {
+        assert_eq!(parse_command(".history 20").unwrap(), Command::History("20".to_string()));
+        assert_eq!(parse_history_count("20").unwrap(), 20);
+        assert!(parse_history_count("0").is_err());
+        assert!(parse_history_count("-5").is_err());
+        assert!(parse_history_count("99999999999").is_err());
+        assert!(parse_history_count("ten").is_err());
+    }
+
+    #[test]
+    fn test_parse_markdown_command() {
+        assert_eq!(parse_command(".markdown **Hello** there.").unwrap(), Command::Markdown("**Hello** there.".to_string()));
+        assert!(parse_command(".markdown").is_err());
+    }
+
+    #[test]
+    fn test_parse_quit_command() {
+        let command = parse_command(".quit").unwrap();
+        assert_eq!(command, Command::Quit);
+    }
+
+    #[test]
+    fn test_parse_clear_command() {
+        assert_eq!(parse_command(".clear").unwrap(), Command::Clear);
+        assert!(parse_command(".clear all").is_err());
+    }
+
+    #[test]
+    fn test_parse_reconnect_command() {
+        assert_eq!(parse_command(".reconnect").unwrap(), Command::Reconnect);
+        assert!(parse_command(".reconnect now").is_err());
+    }
+
+    #[test]
+    fn test_parse_commands_regardless_of_case() {
+        assert_eq!(parse_command(".QUIT").unwrap(), Command::Quit);
+        assert_eq!(parse_command(".Quit").unwrap(), Command::Quit);
+        assert_eq!(parse_command(".FILE Notes.TXT").unwrap(), Command::File("Notes.TXT".to_string()));
+        assert_eq!(parse_command(".Rename Bob").unwrap(), Command::Rename("Bob".to_string()));
+        let error = parse_command(".FILE").unwrap_err();
+        assert_eq!(error.to_string(), "The '.file' command requires an argument.");
+    }
+
+    #[test]
+    fn test_parse_plain_text() {
+        assert_eq!(parse_command("Hello there.").unwrap(), Command::Text("Hello there.".to_string()));
+        assert_eq!(parse_command("...").unwrap(), Command::Text("...".to_string()));
+        assert_eq!(parse_command(". hello").unwrap(), Command::Text(". hello".to_string()));
+        assert_eq!(parse_command("").unwrap(), Command::Text("".to_string()));
+    }
+
+    #[test]
+    fn test_parse_command_without_required_argument() {
+        assert!(parse_command(".file").is_err());
+        assert!(parse_command(".image   ").is_err());
+        assert!(parse_command(".text").is_err());
+        assert!(parse_command(".rename").is_err());
+        assert!(parse_command(".history").is_err());
+    }
+
+    #[test]
+    fn test_parse_quit_command_with_argument() {
+        assert!(parse_command(".quit now").is_err());
+    }
+
+    #[test]
+    fn test_parse_multiline_command() {
+        assert_eq!(parse_command(".multiline").unwrap(), Command::Multiline);
+        assert!(parse_command(".multiline hello").is_err());
+    }
+
+    #[test]
+    fn test_compose_multiline_message() {
+        let mut message = MultilineMessage::default();
+        assert_eq!(message.push_line("First line,"), None);
+        assert_eq!(message.push_line("    indented second line."), None);
+        assert_eq!(message.push_line(""), None);
+        assert_eq!(message.push_line("."), Some("First line,\n    indented second line.\n".to_string()));
+    }
+
+    #[test]
+    fn test_help_lists_all_commands() {
+        assert_eq!(parse_command(".help").unwrap(), Command::Help);
+        let help = help_text();
+        for spec in COMMANDS {
+            assert!(help.contains(&format!(".{}", spec.name)));
+            assert!(help.contains(spec.description));
+            // Every listed command can also be parsed.
+            let input = match spec.argument {
+                Some(_) => format!(".{} argument", spec.name),
+                None => format!(".{}", spec.name)
+            };
+            assert!(parse_command(&input).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_command() {
+        assert!(parse_command(".dance").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::VecDeque;
+use anyhow::{Result, anyhow};
+
+
+/// The maximum number of incoming files that can wait for the user's decision at once.
+/// Files that arrive while the queue is full are discarded.
+pub const MAX_PENDING_FILES: usize = 10;
+
+
+/// What the client does with files and images sent by other users.
+/// Always saves them right away, Never discards them and Prompt asks the user about each of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptFiles {
+    Always,
+    Never,
+    Prompt
+}
+
+
+impl AcceptFiles {
+    /// Names of all policies as they are typed on the command line.
+    pub const NAMES: [&'static str; 3] = ["always", "never", "prompt"];
+
+    /// Get the policy with the name typed on the command line.
+    pub fn from_name(name: &str) -> Result<AcceptFiles> {
+        match name {
+            "always" => Ok(AcceptFiles::Always),
+            "never" => Ok(AcceptFiles::Never),
+            "prompt" => Ok(AcceptFiles::Prompt),
+            _ => Err(anyhow!("Unknown policy '{}' for incoming files.", name))
+        }
+    }
+}
+
+
+/// A file or an image sent by another user that has not been saved yet.
+/// Dir is the directory in which it is saved.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IncomingFile {
+    pub dir: &'static str,
+    pub name: String,
+    pub bytes: Vec<u8>
+}
+
+
+impl IncomingFile {
+    /// The question asking the user whether the file should be saved.
+    pub fn question(&self) -> String {
+        format!("Save {} ({} bytes) into directory '{}'? Type 'y' or 'n'.", self.name, self.bytes.len(), self.dir)
+    }
+}
+
+
+/// The answer of the user to the question whether a file should be saved.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    Invalid
+}
+
+
+/// Parse the answer of the user. Letter case and surrounding whitespace do not matter.
+pub fn parse_answer(input: &str) -> Answer {
+    match input.trim().to_lowercase().as_str() {
+        "y" | "yes" => Answer::Yes,
+        "n" | "no" => Answer::No,
+        _ => Answer::Invalid
+    }
+}
+
+
+/// Incoming files waiting for the user's decision, the oldest first.
+/// The user is always asked only about the oldest one.
+#[derive(Debug, Default)]
+pub struct PendingFiles {
+    files: VecDeque<IncomingFile>
+}
+
+
+impl PendingFiles {
+    /// Add a file at the end of the queue. If the queue is full, the file is given back.
+    pub fn push(&mut self, file: IncomingFile) -> Result<(), IncomingFile> {
+        if self.files.len() >= MAX_PENDING_FILES {
+            return Err(file);
+        }
+        self.files.push_back(file);
+        Ok(())
+    }
+
+    /// Get the file the user is asked about.
+    pub fn front(&self) -> Option<&IncomingFile> {
+        self.files.front()
+    }
+
+    /// Remove the file the user is asked about, after the user has answered.
+    pub fn pop_front(&mut self) -> Option<IncomingFile> {
+        self.files.pop_front()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> IncomingFile {
+        IncomingFile { dir: "files", name: name.to_string(), bytes: vec![1, 2, 3] }
+    }
+
+    #[test]
+    fn test_parse_accept_files_policy() {
+        assert_eq!(AcceptFiles::from_name("always").unwrap(), AcceptFiles::Always);
+        assert_eq!(AcceptFiles::from_name("never").unwrap(), AcceptFiles::Never);
+        assert_eq!(AcceptFiles::from_name("prompt").unwrap(), AcceptFiles::Prompt);
+        assert!(AcceptFiles::from_name("sometimes").is_err());
+        for name in AcceptFiles::NAMES {
+            assert!(AcceptFiles::from_name(name).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        assert_eq!(parse_answer("y"), Answer::Yes);
+        assert_eq!(parse_answer(" YES "), Answer::Yes);
+        assert_eq!(parse_answer("n"), Answer::No);
+        assert_eq!(parse_answer("No"), Answer::No);
+        assert_eq!(parse_answer(""), Answer::Invalid);
+        assert_eq!(parse_answer("maybe"), Answer::Invalid);
+    }
+
+    #[test]
+    fn test_pending_files_are_bounded_and_ordered() {
+        let mut pending_files = PendingFiles::default();
+        for i in 0..MAX_PENDING_FILES {
+            assert!(pending_files.push(file(&format!("{}.txt", i))).is_ok());
+        }
+        assert_eq!(pending_files.push(file("too_many.txt")), Err(file("too_many.txt")));
+
+        assert_eq!(pending_files.front(), Some(&file("0.txt")));
+        assert_eq!(pending_files.pop_front(), Some(file("0.txt")));
+        assert_eq!(pending_files.front(), Some(&file("1.txt")));
+        assert!(pending_files.push(file("last.txt")).is_ok());
+    }
+}
@@ -1,56 +1,250 @@
+mod commands;
+mod incoming_files;
+mod markdown;
+mod message_tags;
+mod outgoing_queue;
+mod send_retry;
+
 use tokio::fs::{self, File};
-use tokio::net::TcpStream;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::io::AsyncWriteExt;
-use std::path::Path;
+use tokio::net::{TcpStream, lookup_host};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::future::Future;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use clap::{Arg, Command};
-use chrono::Local;
-use tokio::time::{Duration, timeout};
-use log::{info, error};
+use tokio::task::JoinHandle;
+use clap::Arg;
+use chrono::{DateTime, Local};
+use crossterm::style::Stylize;
+use tokio::time::{Duration, Instant, timeout};
+use log::{debug, info, error};
 use anyhow::{Context, Result, anyhow};
+use rustyline::{DefaultEditor, ExternalPrinter};
+use rustyline::error::ReadlineError;
+
+use shared::{MAX_TEXT_LENGTH, MAX_FILE_LENGTH, AuthError, BytesSendReceiveError, RejectReason, Compression, Direction, FrameSigner, MessageKey, MessageType, ReadReceipts, Signing, TextFormat, receive_message, receive_signed_message_into, send_message, send_signed_message, enable_keepalive, describe_version, init_logger, LogFormat};
+
+use commands::{Command, MULTILINE_END, MultilineMessage, help_text, parse_command, parse_history_count};
+use incoming_files::{AcceptFiles, Answer, IncomingFile, PendingFiles, parse_answer};
+use markdown::render_markdown;
+use message_tags::MessageTags;
+use outgoing_queue::OutgoingQueue;
+use send_retry::{RetryPolicy, send_with_retry};
+
+
+/// Prompt shown in front of the user input, unless another one is set by the user.
+const DEFAULT_PROMPT: &str = "> ";
+
+/// Prompt shown in front of the user input while a multiline message is being composed.
+const MULTILINE_PROMPT: &str = ". ";
+
+/// Environment variable with the chat socket of the server, used if the '--chat-socket' flag is not given.
+const SERVER_ENV_VAR: &str = "HELLO_RUST_SERVER";
+
+/// Chat socket of the server used if neither the flag nor the environment variable is given.
+const DEFAULT_CHAT_SOCKET: &str = "127.0.0.1:11111";
+
+/// Number of seconds to wait for a connection to the server, unless another number is set by the user.
+const DEFAULT_CONNECT_TIMEOUT_SECS: &str = "5";
+
+/// The delay before the first attempt to connect again after a failed reconnect.
+const FIRST_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The delay between attempts to connect again doubles after each failure, but never exceeds this.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Printer used to print incoming messages above the input prompt.
+/// If it is None, the console is not a terminal and messages are simply printed to stdout.
+type MessagePrinter = Option<Box<dyn ExternalPrinter + Send>>;
+
+/// Reading half of the connection to server, either over TCP or over a Unix domain socket.
+type ServerReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Writing half of the connection to server, either over TCP or over a Unix domain socket.
+type ServerWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Where the chat server listens for connections.
+/// Tcp holds a host name or an IP address with a port, Unix holds a path to a Unix domain socket (available only on Unix).
+enum ServerAddress {
+    Tcp(String),
+    Unix(PathBuf)
+}
+
+
+/// Username and password of the logged in user, kept in memory for automatic reconnects.
+/// Debug is intentionally not derived, so that the password cannot end up in logs.
+struct Credentials {
+    username: String,
+    password: String
+}
+
+
+/// Options that change how the client behaves. They stay the same across reconnects.
+/// If idle timeout is set and no data arrive from server for that long, the client asks to be reconnected.
+/// If connecting to the server takes longer than connect timeout, the client gives up.
+/// If remember is set, the credentials of the first successful authentication are kept and used for logging in after reconnects.
+/// The compression is offered to the server and, if the server agrees, used for all messages after the authentication.
+/// If there is a message key, the client asks the server for signing and all messages after the authentication are signed with it.
+/// If read receipts are enabled, a read receipt is sent for every shown message that has an id.
+/// Accept files decides what happens with files and images sent by other users.
+/// If show timestamps is set, each line printed about a received message starts with the local time when it arrived.
+/// Offline queue capacity is the maximum number of messages kept while the client is disconnected.
+/// If message tags are set, each text sent gets the next tag, which the server puts around the id of the message.
+/// Prompt is shown in front of each line the user types, except in multiline messages.
+struct ClientOptions {
+    idle_timeout: Option<Duration>,
+    connect_timeout: Duration,
+    keepalive_interval: Duration,
+    remember: bool,
+    compression: Compression,
+    message_key: Option<MessageKey>,
+    read_receipts: ReadReceipts,
+    accept_files: AcceptFiles,
+    show_timestamps: bool,
+    offline_queue_capacity: usize,
+    message_tags: Option<MessageTags>,
+    prompt: String,
+    send_retry_policy: RetryPolicy
+}
+
+
+/// Writing half of the connection to server together with the signer of the frames sent through it.
+/// They are locked together, so that frames are signed in the same order in which they are sent.
+struct SignedWriter {
+    writer: ServerWriter,
+    signer: Option<FrameSigner>
+}
+
+
+/// Incoming files waiting for the user's decision. They are added by the receiver and answered in the input loop.
+type SharedPendingFiles = Arc<Mutex<PendingFiles>>;
 
-use shared::{MessageType, receive_message, send_message};
+
+/// The reason why the client stopped running.
+/// Quit means that the client should exit.
+/// Reconnect means that the connection to server seems to be dead and a new one should be created.
+/// AuthFailed means that the user was not authenticated and the client should exit with an error code.
+/// Rejected means that the server rejected the connection and the client should exit with an error code too.
+#[derive(Debug, PartialEq, Eq)]
+enum ClientExit {
+    Quit,
+    Reconnect,
+    AuthFailed(AuthError),
+    Rejected(RejectReason)
+}
 
 
 /// This is the main client function.
 /// Its main thread waits for a user input and sends it to server.
 /// Another spawned thread listens on a socket for incoming messages and prints them in console.
-async fn run_client(socket_address: &str) -> Result<()> {
-    
-    // Try to connect to server and get a stream object.
-    let stream = TcpStream::connect(socket_address).await.context("Failed to connect to a server.")?;
-    // Split stream into reader and writer.
-    let (mut reader, mut writer) = stream.into_split();
+/// Incoming messages are printed above the input prompt so that they do not garble the line the user is typing.
+/// If the connection breaks, the user is told right away, even while waiting at the input prompt.
+/// In the prompt mode for incoming files, the next 'y' or 'n' typed by the user is the answer to the question about the oldest waiting file.
+/// This way, the receiver never waits for the user and the user is never asked in the middle of typing another line.
+/// Messages typed while the client is disconnected wait in the outgoing queue and are sent right after the next authentication.
+async fn run_client(connection: (ServerReader, ServerWriter), options: &ClientOptions, credentials: &mut Option<Credentials>, outgoing_queue: &mut OutgoingQueue) -> Result<ClientExit> {
+    let idle_timeout = options.idle_timeout;
+    let accept_files = options.accept_files;
+    let show_timestamps = options.show_timestamps;
+    let (mut reader, mut writer) = connection;
     
     // Try to authenticate user. If not successful, exit.
-    let auth_successful = authenticate_user(&mut reader, &mut writer).await.context("Authentification failed.")?;
-    if !auth_successful {
-        return Ok(());
+    let message_key = options.message_key.clone();
+    let signing = if message_key.is_some() { Signing::Enabled } else { Signing::Disabled };
+    let compression = match authenticate_user(&mut reader, &mut writer, options.remember, options.compression, signing, options.read_receipts, credentials).await.context("Authentification failed.")? {
+        Ok(compression) => compression,
+        Err(client_exit) => return Ok(client_exit)
+    };
+
+    // Frames in each direction are signed with their own sequence numbers, which start at 0 after the authentication.
+    let mut send_signer = message_key.clone().map(|key| FrameSigner::new(key, Direction::ClientToServer));
+    let mut receive_signer = message_key.map(|key| FrameSigner::new(key, Direction::ServerToClient));
+
+    // Messages typed while disconnected are sent first, in the order they were typed.
+    if !outgoing_queue.is_empty() {
+        match outgoing_queue.flush(&mut writer, compression, send_signer.as_mut()).await {
+            Ok(sent) => println!("Sent {} messages typed while disconnected.", sent),
+            Err(e) => {
+                error!("Failed to send messages typed while disconnected: {}", e);
+                return Ok(ClientExit::Reconnect);
+            }
+        }
     }
+
+    // The writer is shared, so that the receiver can send read receipts.
+    let writer = Arc::new(Mutex::new(SignedWriter { writer, signer: send_signer }));
+    let writer_cloned = Arc::clone(&writer);
+
+    // Files waiting for the user's decision in the prompt mode.
+    let pending_files: SharedPendingFiles = Arc::new(Mutex::new(PendingFiles::default()));
+    let pending_files_cloned = Arc::clone(&pending_files);
     
+    // Line editor keeps the input prompt at the bottom of the console.
+    let mut editor = DefaultEditor::new().context("Failed to create line editor.")?;
+    let mut printer: MessagePrinter = match editor.create_external_printer() {
+        Ok(p) => Some(Box::new(p)),
+        Err(e) => {
+            info!("Console is not a terminal, incoming messages will be printed directly: {}", e);
+            None
+        }
+    };
+
     // A shared variable. If user types .quit, this variable is set to false.
     let continue_running: Arc<Mutex<bool>> = Arc::new(Mutex::new(true));
     let continue_running_cloned = Arc::clone(&continue_running);
     
     // This thread will handle data received through stream.
-    let handle = tokio::spawn(async move {
+    let mut handle = tokio::spawn(async move {
+        // Time when the last data were received from server.
+        let mut last_received = Instant::now();
+        // One buffer is reused for all messages from the server.
+        let mut receive_buffer = Vec::new();
         
         // In the loop, it regularly tries to read from stream.
         loop {
-            match timeout(Duration::from_secs(3), receive_message(&mut reader)).await {
+            match timeout(Duration::from_secs(3), receive_signed_message_into(&mut reader, &mut receive_buffer, compression, receive_signer.as_mut())).await {
                 
                 // Data received and passed to the handler.
                 Ok(Ok(received_message)) => {
-                    if let Err(e) = handle_received_data_in_client(received_message).await {
+                    last_received = Instant::now();
+                    let (message_id, received_message) = match received_message {
+                        MessageType::Identified(id, message) => (Some(id), *message),
+                        message => (None, message)
+                    };
+                    let (author, received_message) = match received_message {
+                        MessageType::Authored(author, message) => (Some(author), *message),
+                        message => (None, message)
+                    };
+                    let (tag, received_message) = match received_message {
+                        MessageType::Tagged(tag, message) => (Some(tag), *message),
+                        message => (None, message)
+                    };
+                    let mut received_printer = ReceivedPrinter { printer: &mut printer, show_timestamps, author, tag };
+                    if let Err(e) = handle_received_data_in_client(received_message, &mut received_printer, accept_files, &pending_files_cloned).await {
                         error!("Cannot handle received data: {}", e);
                         continue;
                     };
+                    // The message was shown, so its author can be told it was read.
+                    if let Some(id) = message_id {
+                        let mut lock_writer = writer_cloned.lock().await;
+                        let SignedWriter { writer, signer } = &mut *lock_writer;
+                        if let Err(e) = send_signed_message(writer, &MessageType::ReadReceipt(id), compression, signer.as_mut()).await {
+                            error!("Failed to send read receipt: {}", e);
+                        }
+                    }
                 },
                 
-                // Error while reading.
+                // The connection broke, e.g. because the server stopped. The client connects again, so the outgoing queue and remembered credentials are kept.
+                Ok(Err(e)) if is_connection_lost(&e) => {
+                    print_line(&mut printer, format!("The connection to server was lost: {:#}. Press Enter to reconnect or type a message to send it after reconnecting.", e));
+                    return Ok(ClientExit::Reconnect);
+                }
+
+                // The server sent something invalid, e.g. a frame with a wrong signature. Connecting again would not help.
                 Ok(Err(e)) => {
+                    print_line(&mut printer, "The connection to server was lost. Press Enter to exit.".to_string());
                     return Err(anyhow!("Error while reading: {}", e));
                 }
                 
@@ -61,121 +255,617 @@ async fn run_client(socket_address: &str) -> Result<()> {
                     if !(*lock_continue_running) {
                         break;
                     }
+                    // Check how long the server has been silent.
+                    if let Some(idle_timeout) = idle_timeout {
+                        if last_received.elapsed() >= idle_timeout {
+                            print_line(&mut printer, format!(
+                                "No data received from server for {} seconds. The connection seems to be dead. Press Enter to reconnect or type a message to send it after reconnecting.",
+                                idle_timeout.as_secs()
+                            ));
+                            return Ok(ClientExit::Reconnect);
+                        }
+                    }
                 },
             };
         };
-        Ok(())
+        Ok(ClientExit::Quit)
     });
 
+    // Lines typed so far, if the user is composing a multiline message.
+    let mut multiline_message: Option<MultilineMessage> = None;
+
     // Loop for getting user input and sending data according to this input.
     loop {
-        // Get input.
-        let user_input = get_line_from_user().await.context("Failed to get user input.")?;
+        // Get input. Meanwhile, watch the receiver, which stops on its own only if the connection is idle or broken.
+        let prompt = if multiline_message.is_some() { MULTILINE_PROMPT } else { options.prompt.as_str() };
+        let mut pending_line = read_line_in_background(editor, prompt.to_string());
+        let (returned_editor, user_input) = tokio::select! {
+            line = &mut pending_line => line.context("Input task stopped without returning a line.")?,
+            receiver_result = &mut handle => {
+                // The receiver asked the user to press Enter. If the client reconnects, a message typed meanwhile is queued.
+                let receiver_result = receiver_result.map_err(|e| anyhow!("Error occured in spawned thread: {:?}", e))?;
+                let line = pending_line.await;
+                if let (Ok(ClientExit::Reconnect), Ok((_, Ok(line)))) = (&receiver_result, line) {
+                    return Ok(handle_line_while_disconnected(&line, credentials, outgoing_queue, options.message_tags.as_ref()).await);
+                }
+                return receiver_result;
+            }
+        };
+        editor = returned_editor;
+        let user_input = user_input.context("Failed to get user input.")?;
+
+        // While an incoming file waits for a decision, 'y' or 'n' is the answer.
+        if multiline_message.is_none() && answer_pending_file(&pending_files, &user_input).await {
+            continue;
+        }
+
+        // Find out what command the user typed.
+        // While a multiline message is being composed, every line except '.quit' is a part of the message.
+        let command = if let Some(message) = multiline_message.as_mut() {
+            if matches!(parse_command(user_input.trim()), Ok(Command::Quit)) {
+                Command::Quit
+            } else {
+                match message.push_line(&user_input) {
+                    Some(text) => {
+                        multiline_message = None;
+                        Command::Text(text)
+                    },
+                    None => continue
+                }
+            }
+        } else {
+            match parse_command(user_input.trim()) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("There was a problem processing user input: {}", e);
+                    continue;
+                }
+            }
+        };
+
+        // The .multiline command starts composing a message, which is sent when it is finished.
+        if command == Command::Multiline {
+            println!("Type the message. End it with a line containing only '{}'.", MULTILINE_END);
+            multiline_message = Some(MultilineMessage::default());
+            continue;
+        }
+
+        // The .help command only prints all commands. Nothing is sent to the server.
+        if command == Command::Help {
+            println!("{}", help_text());
+            continue;
+        }
 
-        // The .quit commands causes the client program to quit.
-        if user_input.trim() == ".quit" {
+        // The .clear command only clears the terminal screen. Nothing is sent to the server.
+        // The line editor clears it in the way of the terminal, so it works on Windows and Unix. Redirected output is left as it is.
+        if command == Command::Clear {
+            if let Err(e) = editor.clear_screen() {
+                error!("Failed to clear the screen: {}", e);
+            }
+            continue;
+        }
+
+        // The .reconnect command closes the connection and lets the client connect again.
+        // The receiver is stopped first. The outgoing queue and remembered credentials are kept by the caller.
+        if command == Command::Reconnect {
+            println!("Reconnecting...");
+            *continue_running.lock().await = false;
+            handle.abort();
+            let _ = handle.await;
+            return Ok(ClientExit::Reconnect);
+        }
+
+        // The .quit commands causes the client program to quit. Remembered credentials are not needed anymore.
+        if command == Command::Quit {
+            *credentials = None;
             let mut lock_continue_running = continue_running.lock().await;
             *lock_continue_running = false;
             break;
         }
 
-        // Based on user input, prepare a vector of bytes that should be sent.
-        let message = match prepare_message_based_on_user_input(user_input).await {
-            Ok(m) => m,
+        // Based on the command, prepare a message that should be sent.
+        let message = match prepare_message_based_on_command(command).await {
+            Ok(m) => tag_message(m, options.message_tags.as_ref()),
             Err(e) => {
                 error!("There was a problem processing user input: {}", e);
                 continue;
             }
         };
 
-        // Send bytes - direction server.
-        send_message(&mut writer, &message).await.context("Failed to send message.")?;
+        // Send bytes - direction server. Brief write errors are retried.
+        // If the connection is broken or the retries do not help, the message waits for the next connection.
+        let mut lock_writer = writer.lock().await;
+        let SignedWriter { writer, signer } = &mut *lock_writer;
+        if let Err(e) = send_with_retry(writer, &message, compression, signer.as_mut(), options.send_retry_policy).await {
+            error!("Failed to send message: {}", e);
+            handle.abort();
+            queue_message(outgoing_queue, message);
+            return Ok(ClientExit::Reconnect);
+        }
     };
     let _ = handle.await.map_err(|e| anyhow!("Error occured in spawned thread: {:?}", e))?;
-    Ok(())
+    Ok(ClientExit::Quit)
+}
+
+
+/// Connect to server and split the connection into a reader and a writer.
+/// If the server does not accept the connection within the connect timeout, an error is returned.
+/// TCP keepalive makes sure that a dead TCP connection is detected even if neither side sends anything.
+async fn connect_to_server(server_address: &ServerAddress, connect_timeout: Duration, keepalive_interval: Duration) -> Result<(ServerReader, ServerWriter)> {
+    match server_address {
+        ServerAddress::Tcp(address) => {
+            let stream = connect_with_timeout(connect_to_host(address), connect_timeout, address).await?;
+            if let Err(e) = enable_keepalive(&stream, keepalive_interval) {
+                error!("Failed to enable keepalive: {}", e);
+            }
+            let (reader, writer) = stream.into_split();
+            Ok((Box::new(reader), Box::new(writer)))
+        },
+        #[cfg(unix)]
+        ServerAddress::Unix(socket_path) => {
+            let stream = connect_with_timeout(tokio::net::UnixStream::connect(socket_path), connect_timeout, &socket_path.display().to_string()).await?;
+            let (reader, writer) = stream.into_split();
+            Ok((Box::new(reader), Box::new(writer)))
+        },
+        #[cfg(not(unix))]
+        ServerAddress::Unix(_) => Err(anyhow!("Unix domain sockets are not supported on this platform."))
+    }
+}
+
+
+/// Resolve the host name of the address and connect to the first of its socket addresses that accepts the connection.
+/// The name is resolved on each connect, so that a reconnect follows a server whose address has changed.
+async fn connect_to_host(address: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("'{}' was not resolved to any socket address.", address));
+    for socket_address in lookup_host(address).await? {
+        match TcpStream::connect(socket_address).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e
+        }
+    }
+    Err(last_error)
+}
+
+
+/// Connect to server again after the connection was lost.
+/// A failed attempt only means that the client is still offline, so it is tried again after a delay that doubles after each failure.
+/// Meanwhile, lines typed by the user are handled like at the prompt to reconnect: messages are put into the outgoing queue,
+/// an empty line or '.reconnect' tries again right away and '.quit' quits, in which case None is returned.
+/// If a line is being typed when the connection is made, it is finished first, because logging in may ask the user for credentials.
+async fn reconnect_to_server(server_address: &ServerAddress, options: &ClientOptions, credentials: &mut Option<Credentials>, outgoing_queue: &mut OutgoingQueue) -> Result<Option<(ServerReader, ServerWriter)>> {
+    let mut delay = FIRST_RECONNECT_DELAY;
+    let mut editor: Option<DefaultEditor> = None;
+    let mut pending_line: Option<JoinHandle<(DefaultEditor, Result<String>)>> = None;
+    loop {
+        let error = match connect_to_server(server_address, options.connect_timeout, options.keepalive_interval).await {
+            Ok(connection) => {
+                if let Some(pending_line) = pending_line {
+                    println!("Connected to server again. Press Enter to continue.");
+                    let (_, user_input) = pending_line.await.context("Failed to get user input.")?;
+                    let line = user_input.context("Failed to get user input.")?;
+                    if handle_line_while_disconnected(&line, credentials, outgoing_queue, options.message_tags.as_ref()).await == ClientExit::Quit {
+                        return Ok(None);
+                    }
+                }
+                return Ok(Some(connection));
+            },
+            Err(e) => e
+        };
+        println!("{}", format!("Still offline: {:#} Trying again in {} seconds. Messages typed now are sent after reconnecting, an empty line tries again right away.", error, delay.as_secs()).yellow());
+
+        let mut line_receiver = match pending_line.take() {
+            Some(receiver) => receiver,
+            None => {
+                let line_editor = match editor.take() {
+                    Some(line_editor) => line_editor,
+                    None => DefaultEditor::new().context("Failed to create line editor.")?
+                };
+                read_line_in_background(line_editor, options.prompt.clone())
+            }
+        };
+        let received = tokio::select! {
+            _ = tokio::time::sleep(delay) => None,
+            received = &mut line_receiver => Some(received)
+        };
+        match received {
+            // Nothing was typed in time, so the line keeps being read during the next attempt.
+            None => {
+                pending_line = Some(line_receiver);
+                delay = next_reconnect_delay(delay);
+            },
+            Some(received) => {
+                let (line_editor, user_input) = received.context("Failed to get user input.")?;
+                editor = Some(line_editor);
+                let line = user_input.context("Failed to get user input.")?;
+                if handle_line_while_disconnected(&line, credentials, outgoing_queue, options.message_tags.as_ref()).await == ClientExit::Quit {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+
+/// The delay before the next attempt to connect again is twice the last one, up to MAX_RECONNECT_DELAY.
+fn next_reconnect_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_RECONNECT_DELAY)
+}
+
+
+/// Wait until a connection to the address is made, but at most for the connect timeout.
+/// A timeout means that the server is unreachable, while a refused connection means that nothing listens at the address,
+/// so both get their own error message.
+async fn connect_with_timeout<S>(connect: impl Future<Output = io::Result<S>>, connect_timeout: Duration, address: &str) -> Result<S> {
+    match timeout(connect_timeout, connect).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => Err(anyhow!("Connection to {} was refused. Check that the server is running and listens at this address.", address)),
+        Ok(Err(e)) => Err(e).with_context(|| format!("Failed to connect to {}.", address)),
+        Err(_) => Err(anyhow!("Connecting to {} timed out after {} seconds. The server may be unreachable.", address, connect_timeout.as_secs_f64()))
+    }
 }
 
 
 /// Register or login user. In both cases, a name and a password are required.
-async fn authenticate_user(reader: &mut OwnedReadHalf, writer: &mut OwnedWriteHalf) -> Result<bool> {
-    // Find out if user wants to register or login.
-    println!("Do you want to register or login? (R/L)");
-    let action = get_line_from_user().await.context("Failed to get user action.")?;
-    if action != "R" && action != "L" {
-        println!("Invalid input! You must type either 'R' or 'L'!");
-        return Ok(false)
-    }
-    // Get username and password.
-    println!("Username:");
-    let username = get_line_from_user().await.context("Failed to get username.")?;
-    println!("Password:");
-    let password = get_line_from_user().await.context("Failed to get password.")?;
+/// If there are remembered credentials, the user is logged in with them without being asked.
+/// If remember is set, the credentials are remembered after a successful authentication.
+/// The compression is offered to the server. If the user is authenticated, the compression agreed on by the server is returned.
+/// Signing is asked for as given. A server that authenticates the user, but does not agree on the same signing, is an error.
+/// The server is also told whether the client takes part in read receipts.
+/// If the user is not authenticated, the reason is returned. Invalid input and a timeout are reported like the same failures on the server side.
+/// If stdin is closed before all questions are answered, the client quits as if the user typed '.quit'.
+async fn authenticate_user(reader: &mut ServerReader, writer: &mut ServerWriter, remember: bool, compression: Compression, signing: Signing, read_receipts: ReadReceipts, credentials: &mut Option<Credentials>) -> Result<Result<Compression, ClientExit>> {
+    let (action, username, password) = match credentials.take() {
+        Some(remembered) => {
+            println!("Logging in again as {}.", remembered.username);
+            ("L".to_string(), remembered.username, remembered.password)
+        },
+        None => {
+            // Find out if user wants to register or login.
+            print_question("Register or login? (R/L)")?;
+            let Some(action) = get_line_from_user().await.context("Failed to get user action.")? else {
+                return Ok(Err(quit_on_closed_stdin()));
+            };
+            if action != "R" && action != "L" {
+                println!("Invalid input! You must type either 'R' or 'L'!");
+                return Ok(Err(ClientExit::AuthFailed(AuthError::InvalidRequest)))
+            }
+            // Get username and password.
+            print_question("Username:")?;
+            let Some(username) = get_line_from_user().await.context("Failed to get username.")? else {
+                return Ok(Err(quit_on_closed_stdin()));
+            };
+            print_question("Password:")?;
+            let Some(password) = get_password_from_user().await.context("Failed to get password.")? else {
+                return Ok(Err(quit_on_closed_stdin()));
+            };
+            (action, username, password)
+        }
+    };
 
     // Create and send authentication request message.
-    let request_message = MessageType::AuthRequest(action, username, password);
+    let request_message = MessageType::AuthRequest(action, username.clone(), password.clone(), compression, signing, read_receipts);
     send_message(writer, &request_message).await.context("Failed to send auth request.")?;
 
     // Wait for authentication response message.
     match timeout(Duration::from_secs(5), receive_message(reader)).await {
                 
         // Data received and passed to the handler.
-        Ok(Ok(MessageType::AuthResponse(auth_successful, message_from_server))) => {
-            if auth_successful {
-                println!("Authentication succesfull: {}", message_from_server);
-                return Ok(true)
-            } else {
-                println!("Authentication not succesfull: {}", message_from_server);
-                return Ok(false)
+        Ok(Ok(MessageType::AuthResponse(true, _, message_from_server, agreed_compression, agreed_signing, session_id))) => {
+            // Messages must not be sent unsigned if the user asked for signing, nor signed if the server cannot check them.
+            if agreed_signing != signing {
+                return Err(anyhow!("The server answered with signing {:?}, but {:?} was asked for.", agreed_signing, signing));
+            }
+            println!("Authentication succesfull: {}", message_from_server);
+            // The id is only logged, so that the session can be found in the logs of the server.
+            if let Some(session_id) = session_id {
+                info!("The server assigned session id {}.", session_id);
+            }
+            // After a registration, the user already exists, so reconnects always log in.
+            if remember {
+                *credentials = Some(Credentials { username, password });
+            }
+            Ok(Ok(agreed_compression))
+        },
+
+        // A server that does not send the reason is treated as if it had an error.
+        Ok(Ok(MessageType::AuthResponse(false, auth_error, message_from_server, _, _, _))) => {
+            println!("Authentication not succesfull: {}", message_from_server);
+            Ok(Err(ClientExit::AuthFailed(auth_error.unwrap_or(AuthError::ServerError))))
+        },
+
+        // The server closes the connection right after telling why it rejected it.
+        Ok(Ok(MessageType::ConnectionRejected(reason, banner))) => {
+            println!("Connection rejected: {}", describe_rejection(reason));
+            if let Some(banner) = banner {
+                println!("{}", banner);
             }
+            Ok(Err(ClientExit::Rejected(reason)))
         },
 
         // Incorrect MessageType. This should never happen.
         Ok(Ok(_)) => {
-            return Err(anyhow!("Incorrect message type received from server."));
+            Err(anyhow!("Incorrect message type received from server."))
         }
         
         // Error while reading.
         Ok(Err(e)) => {
-            return Err(anyhow!("Error while waiting for an authentication response: {}", e));
+            Err(anyhow!("Error while waiting for an authentication response: {}", e))
         }
         
         // Waiting for authentication response timeout.
         Err(_) => {
             println!("Authentication timeout. The server took too long to respond.");
-            return Ok(false);
+            Ok(Err(ClientExit::AuthFailed(AuthError::ServerError)))
         },
-    };
+    }
+}
+
+
+/// Check that the chat socket of the server is a host name or an IP address with a port, e.g. "localhost:11111", "127.0.0.1:11111" or "[::1]:11111".
+/// Only the form is checked here. The host name is resolved when connecting.
+/// Unlike shared::parse_socket_addr, which the server uses for the addresses it binds, host names are allowed, because a server may move to another IP address between reconnects.
+fn check_host_and_port(input: &str) -> Result<String> {
+    let invalid = || anyhow!("'{}' is not a valid server address. Use a host name or an IP address and a port, e.g. localhost:11111, 127.0.0.1:11111 or [::1]:11111.", input);
+    if input.parse::<SocketAddr>().is_ok() {
+        return Ok(input.to_string());
+    }
+    let (host, port) = input.rsplit_once(':').ok_or_else(invalid)?;
+    // A bare IPv6 address must be in brackets, otherwise its last part would be taken for the port.
+    if host.is_empty() || host.contains(':') || host.contains('[') || port.parse::<u16>().is_err() {
+        return Err(invalid());
+    }
+    Ok(input.to_string())
+}
+
+
+/// Choose the chat socket of the server. The flag takes precedence over the environment variable and both over the default.
+/// An empty environment variable is treated as if it was not set.
+fn choose_chat_socket<'a>(flag: Option<&'a str>, server_env: Option<&'a str>) -> &'a str {
+    flag.or(server_env.filter(|value| !value.is_empty())).unwrap_or(DEFAULT_CHAT_SOCKET)
+}
+
+
+/// Get the exit code of the client for a failed authentication.
+/// Code 1 is left for other errors, so scripts can tell why the client stopped.
+fn auth_exit_code(auth_error: AuthError) -> i32 {
+    match auth_error {
+        AuthError::WrongCredentials => 2,
+        AuthError::UsernameTaken => 3,
+        AuthError::UsernameTooLong => 4,
+        AuthError::WeakPassword => 5,
+        AuthError::TooManyAttempts => 6,
+        AuthError::InvalidRequest => 7,
+        AuthError::ServerError => 8,
+        AuthError::Banned => 9,
+        AuthError::AlreadyLoggedIn => 10,
+        AuthError::SigningMismatch => 11
+    }
+}
+
+
+/// Get the exit code of the client for a rejected connection.
+/// Reasons that are also authentication failures share the exit code with them.
+fn rejection_exit_code(reason: RejectReason) -> i32 {
+    match reason {
+        RejectReason::Banned => auth_exit_code(AuthError::Banned),
+        RejectReason::TooManySessions => auth_exit_code(AuthError::AlreadyLoggedIn),
+        RejectReason::VersionMismatch => 12,
+        RejectReason::ServerFull => 13
+    }
 }
 
 
-/// Get user input from stdin.
-async fn get_line_from_user() -> Result<String> {
+/// Describe the reason of a rejected connection for the user.
+fn describe_rejection(reason: RejectReason) -> &'static str {
+    match reason {
+        RejectReason::ServerFull => "the server is full, try again later.",
+        RejectReason::Banned => "the account is banned.",
+        RejectReason::TooManySessions => "the user is already logged in elsewhere.",
+        RejectReason::VersionMismatch => "the server speaks another version of the protocol."
+    }
+}
+
+
+/// Print a question for the user, who types the answer on the same line.
+/// The question does not end with a new line, so stdout has to be flushed to show it before the answer is read.
+fn print_question(question: &str) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    write!(stdout, "{} ", question).and_then(|_| stdout.flush()).context("Failed to print a question.")
+}
+
+
+/// Tell the user that stdin was closed and get the reason for stopping the client, which is the same as for '.quit'.
+fn quit_on_closed_stdin() -> ClientExit {
+    println!();
+    println!("Standard input was closed. Quitting.");
+    ClientExit::Quit
+}
+
+
+/// Get user input from stdin. If stdin is closed, None is returned.
+async fn get_line_from_user() -> Result<Option<String>> {
+    read_trimmed_line(&mut std::io::stdin().lock())
+}
+
+
+/// Read one line from the reader and trim it. If the reader is at its end, None is returned.
+/// An empty line is still a line, so it is returned as an empty string.
+fn read_trimmed_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
     let mut input_str = String::new();
-    std::io::stdin().read_line(&mut input_str).context("Failed to read from standard input.")?;
-    Ok(input_str.trim().to_string())
+    let read_bytes = reader.read_line(&mut input_str).context("Failed to read from standard input.")?;
+    if read_bytes == 0 {
+        return Ok(None);
+    }
+    Ok(Some(input_str.trim().to_string()))
+}
+
+
+/// Get a password from the terminal without showing it on the screen.
+/// If stdin is not a terminal (for example when it is piped), the password is read as any other line, so None is returned if stdin is closed.
+async fn get_password_from_user() -> Result<Option<String>> {
+    if !std::io::stdin().is_terminal() {
+        return get_line_from_user().await;
+    }
+    // Reading blocks until the user presses Enter, so it must not occupy a worker thread of the runtime.
+    let password = tokio::task::spawn_blocking(rpassword::read_password).await
+        .context("Password reading task stopped without returning a password.")?
+        .context("Failed to read password from the terminal.")?;
+    Ok(Some(password.trim().to_string()))
+}
+
+
+/// Read a line from the line editor in a blocking task, so that waiting for the user does not block the client.
+/// The editor is handed back together with the line.
+/// The client always waits for a started line before it exits, because a blocking task that still waits for the user would keep the runtime from shutting down.
+fn read_line_in_background(mut editor: DefaultEditor, prompt: String) -> JoinHandle<(DefaultEditor, Result<String>)> {
+    tokio::task::spawn_blocking(move || {
+        let user_input = get_line_from_editor(&mut editor, &prompt);
+        (editor, user_input)
+    })
+}
+
+
+/// Get user input from the line editor. The line is not trimmed, so that indentation in multiline messages is kept.
+/// Pressing Ctrl-C or Ctrl-D is the same as typing the '.quit' command.
+fn get_line_from_editor(editor: &mut DefaultEditor, prompt: &str) -> Result<String> {
+    match editor.readline(prompt) {
+        Ok(input_str) => {
+            if let Err(e) = editor.add_history_entry(input_str.as_str()) {
+                error!("Failed to add user input to history: {}", e);
+            }
+            Ok(input_str)
+        },
+        Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => Ok(".quit".to_string()),
+        Err(e) => Err(anyhow!("Failed to read from standard input: {}", e))
+    }
+}
+
+
+/// Print a line of text. If the console is a terminal, the line is printed above the input prompt.
+fn print_line(printer: &mut MessagePrinter, text: String) {
+    match printer {
+        Some(p) => {
+            if let Err(e) = p.print(text) {
+                error!("Failed to print above the input prompt: {}", e);
+            }
+        },
+        None => {
+            println!("{}", text);
+        }
+    }
+}
+
+
+/// Printer of lines about received messages. If show timestamps is set, each line starts with the local time.
+/// The author is the nickname of the user who wrote the message, if the server sent it.
+/// The tag is the one the author chose for the message, if it was tagged.
+struct ReceivedPrinter<'a> {
+    printer: &'a mut MessagePrinter,
+    show_timestamps: bool,
+    author: Option<String>,
+    tag: Option<i64>
+}
+
+
+impl ReceivedPrinter<'_> {
+    /// Print a line about a received message, see print_line.
+    fn print(&mut self, text: String) {
+        let text = if self.show_timestamps { prefix_with_time(text, Local::now()) } else { text };
+        print_line(self.printer, text);
+    }
+
+    /// Print a chat message prefixed with the nickname of its author, if it is known.
+    fn print_from_author(&mut self, text: String) {
+        let text = match &self.author {
+            Some(author) => format_authored(author, &text),
+            None => text
+        };
+        self.print(text);
+    }
+}
+
+
+/// Prefix a chat message with the nickname of its author.
+fn format_authored(author: &str, text: &str) -> String {
+    format!("{}: {}", author.bold(), text)
+}
+
+
+/// Prefix a line with the time in the "HH:MM:SS" format.
+fn prefix_with_time(text: String, time: DateTime<Local>) -> String {
+    format!("[{}] {}", time.format("%H:%M:%S"), text)
 }
 
 
 /// Function for handling received data.
 /// If the message is of type File, save the file to directory "file" and print a message.
 /// If the message is of type Image, save the .png image to directory "image" and print a message.
-/// If the message is of type Text, only print out the message.
-async fn handle_received_data_in_client(message: MessageType) -> Result<()> {
-    
+/// Files and images are saved only if the accept files policy allows it, see receive_file.
+/// If the message is of type Text, only print out the message, prefixed with the nickname of its author if the server sent it.
+/// If the message is of type FormattedText, print it rendered in its format. Unknown formats are printed as plain text.
+/// If the message is of type ServerError or ServerNotice, print it out in color so it cannot be mistaken for a chat message.
+async fn handle_received_data_in_client(message: MessageType, printer: &mut ReceivedPrinter<'_>, accept_files: AcceptFiles, pending_files: &SharedPendingFiles) -> Result<()> {
+    debug!("Received a message of kind {}.", message.kind_str());
+
     // The behaviour will be based on the message type.
     match message {
-        MessageType::File(name, bytes) => {
-            println!("Receiving {}...", &name);
-            save_file("files".to_string(), name, bytes).await.context("Failed to save file to directory 'files'.")?;
+        MessageType::File { name, mime, data } => {
+            match mime {
+                Some(mime) => printer.print(format!("Receiving {} ({})...", &name, mime)),
+                None => printer.print(format!("Receiving {}...", &name))
+            }
+            receive_file(IncomingFile { dir: "files", name, bytes: data }, printer, accept_files, pending_files).await?;
         },
         MessageType::Image(bytes) => {
-            println!("Receiving image ...");
+            printer.print("Receiving image ...".to_string());
             let now = Local::now().format("%Y_%m_%d_%H_%M_%S").to_string();
             let name = format!("{}.png", now);
-            save_file("images".to_string(), name, bytes).await.context("Failed to save '.png' image to directory 'images'.")?;
+            receive_file(IncomingFile { dir: "images", name, bytes }, printer, accept_files, pending_files).await?;
         },
         MessageType::Text(text) => {
-            println!("{}", text);
+            printer.print_from_author(text);
+        },
+        MessageType::FormattedText { text, format: TextFormat::Markdown } => {
+            printer.print_from_author(render_markdown(&text));
+        },
+        // Plain texts and texts in formats this client does not know are shown as they are.
+        MessageType::FormattedText { text, .. } => {
+            printer.print_from_author(text);
+        },
+        MessageType::ServerError(text) => {
+            printer.print(format!("[server error] {}", text).red().to_string());
+        },
+        MessageType::ServerNotice(text) => {
+            printer.print(format!("[server] {}", text).yellow().to_string());
+        },
+        MessageType::ConnectionRejected(reason, banner) => {
+            let text = match banner {
+                Some(banner) => format!("[rejected] Connection rejected: {} {}", describe_rejection(reason), banner),
+                None => format!("[rejected] Connection rejected: {}", describe_rejection(reason))
+            };
+            printer.print(text.red().to_string());
+        },
+        MessageType::MessageId(id) => {
+            let text = match printer.tag {
+                Some(tag) => format!("[sent] Your message with tag {} got id {}.", tag, id),
+                None => format!("[sent] Your message got id {}.", id)
+            };
+            printer.print(text.dark_grey().to_string());
+        },
+        MessageType::ReadBy(id, username) => {
+            printer.print(format!("[read] Your message {} was read by {}.", id, username).dark_grey().to_string());
+        },
+        MessageType::History(entries) => {
+            if entries.is_empty() {
+                printer.print("[history] No messages yet.".yellow().to_string());
+            }
+            for entry in entries {
+                let text = match entry.tag {
+                    Some(tag) => format!("[history] {} {} (tag {}): {}", entry.sent_at, entry.username, tag, entry.content),
+                    None => format!("[history] {} {}: {}", entry.sent_at, entry.username, entry.content)
+                };
+                printer.print(text.yellow().to_string());
+            }
         },
         // To all other message types, react will we not.
         _ => {}
@@ -185,78 +875,612 @@ async fn handle_received_data_in_client(message: MessageType) -> Result<()> {
 }
 
 
-/// Create a file and write bytes into it.
-async fn save_file(dir: String, name: String, bytes: Vec<u8>) -> Result<()> {
-    let mut file = File::create(format!("{}\\{}", dir, name)).await.context("Failed to create file.")?;
-    file.write(&bytes).await.context("Failed to write bytes into file.")?;
-    Ok(())
+/// Save, discard or queue an incoming file according to the accept files policy.
+/// In the prompt mode, the user is asked only if no other file waits for an answer. Otherwise the question is asked after the previous answer.
+async fn receive_file(file: IncomingFile, printer: &mut ReceivedPrinter<'_>, accept_files: AcceptFiles, pending_files: &SharedPendingFiles) -> Result<()> {
+    match accept_files {
+        AcceptFiles::Always => save_incoming_file(file).await,
+        AcceptFiles::Never => {
+            printer.print(format!("[file] Discarded {}. Incoming files are not accepted.", file.name).yellow().to_string());
+            Ok(())
+        },
+        AcceptFiles::Prompt => {
+            let mut lock = pending_files.lock().await;
+            let question = file.question();
+            let is_first = lock.front().is_none();
+            match lock.push(file) {
+                Ok(_) => {
+                    if is_first {
+                        printer.print(format!("[file] {}", question).yellow().to_string());
+                    }
+                },
+                Err(file) => {
+                    printer.print(format!("[file] Discarded {}. Too many files are waiting for an answer.", file.name).yellow().to_string());
+                }
+            }
+            Ok(())
+        }
+    }
 }
 
 
-/// Based on what user typed into stdin, create a MessageType object and serialize it.
-async fn prepare_message_based_on_user_input(user_input: String) -> Result<MessageType> {
-    let message: MessageType;
-    if user_input.starts_with(".file ") {
-        message = get_file_message(user_input).await.context("The '.file' command seems to be invalid.")?;
-    } else if user_input.starts_with(".image ") {
-        message = get_image_message(user_input).await.context("The '.image' command seems to be invalid.")?;
-    } else {
-        message = MessageType::Text(user_input);
+/// If an incoming file waits for a decision and the user input is 'y' or 'n', take it as the answer and save or discard the file.
+/// Then ask about the next waiting file, if there is one.
+/// Return false if no file waits or if the input is not an answer, so that the input is handled as usual.
+/// This way, a message the user was typing when the question came is not lost.
+async fn answer_pending_file(pending_files: &SharedPendingFiles, user_input: &str) -> bool {
+    let mut lock = pending_files.lock().await;
+    if lock.front().is_none() {
+        return false;
+    }
+    match parse_answer(user_input) {
+        Answer::Yes => {
+            if let Some(file) = lock.pop_front() {
+                let name = file.name.clone();
+                match save_incoming_file(file).await {
+                    Ok(_) => println!("Saved {}.", name),
+                    Err(e) => error!("{:#}", e)
+                }
+            }
+        },
+        Answer::No => {
+            if let Some(file) = lock.pop_front() {
+                println!("Discarded {}.", file.name);
+            }
+        },
+        Answer::Invalid => {
+            return false;
+        }
+    }
+    if let Some(file) = lock.front() {
+        println!("{}", format!("[file] {}", file.question()).yellow());
     }
+    true
+}
+
+
+/// Save an incoming file into its directory.
+async fn save_incoming_file(file: IncomingFile) -> Result<()> {
+    let dir = file.dir;
+    save_file(Path::new(dir), &file.name, &file.bytes).await.with_context(|| format!("Failed to save file to directory '{}'.", dir))
+}
+
+
+/// Create a file in the directory and write bytes into it.
+/// The name comes from the sender, so only its last component is used and the file cannot be created outside of the directory.
+async fn save_file(dir: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    let file_name = Path::new(name).file_name().with_context(|| format!("'{}' is not a valid file name.", name))?;
+    let mut file = File::create(dir.join(file_name)).await.context("Failed to create file.")?;
+    file.write_all(bytes).await.context("Failed to write bytes into file.")?;
+    Ok(())
+}
+
+
+/// Based on the command the user typed into stdin, create a MessageType object.
+async fn prepare_message_based_on_command(command: Command) -> Result<MessageType> {
+    let message = match command {
+        Command::File(path) => get_file_message(&path).await.context("The '.file' command seems to be invalid.")?,
+        Command::Image(path) => get_image_message(&path).await.context("The '.image' command seems to be invalid.")?,
+        Command::TextFile(path) => get_text_message(&path).await.context("The '.text' command seems to be invalid.")?,
+        Command::Rename(new_username) => MessageType::Rename(new_username),
+        Command::Nickname(nickname) => MessageType::SetNickname(nickname),
+        Command::History(count) => MessageType::HistoryRequest(parse_history_count(&count).context("The '.history' command seems to be invalid.")?),
+        Command::Text(text) => {
+            check_text_length(&text)?;
+            MessageType::Text(text)
+        },
+        Command::Markdown(text) => {
+            check_text_length(&text)?;
+            MessageType::FormattedText { text, format: TextFormat::Markdown }
+        },
+        Command::Multiline | Command::Help | Command::Clear | Command::Reconnect | Command::Quit => {
+            return Err(anyhow!("This command cannot be turned into a message."));
+        }
+    };
 
     Ok(message)
 }
 
 
+/// Tag a text with the next tag, if tagging is enabled, and tell the user the tag.
+fn tag_message(message: MessageType, message_tags: Option<&MessageTags>) -> MessageType {
+    let Some(message_tags) = message_tags else {
+        return message;
+    };
+    let message = message_tags.tag(message);
+    if let MessageType::Tagged(tag, _) = &message {
+        println!("{}", format!("[tagged] Your message has tag {}.", tag).dark_grey());
+    }
+    message
+}
+
+
+/// Handle a line typed by the user at the prompt to reconnect.
+/// An empty line and '.reconnect' only reconnect and '.quit' quits. Other lines are turned into messages, tagged if tagging is enabled, and queued.
+async fn handle_line_while_disconnected(line: &str, credentials: &mut Option<Credentials>, outgoing_queue: &mut OutgoingQueue, message_tags: Option<&MessageTags>) -> ClientExit {
+    let command = match parse_command(line.trim()) {
+        Ok(Command::Text(text)) if text.is_empty() => return ClientExit::Reconnect,
+        Ok(Command::Reconnect) => return ClientExit::Reconnect,
+        Ok(Command::Quit) => {
+            *credentials = None;
+            return ClientExit::Quit;
+        },
+        Ok(command) => command,
+        Err(e) => {
+            error!("There was a problem processing user input: {}", e);
+            return ClientExit::Reconnect;
+        }
+    };
+    match prepare_message_based_on_command(command).await {
+        Ok(message) => queue_message(outgoing_queue, tag_message(message, message_tags)),
+        Err(e) => error!("There was a problem processing user input: {}", e)
+    }
+    ClientExit::Reconnect
+}
+
+
+/// Check if receiving a message failed because the connection broke, rather than because the server sent an invalid message.
+fn is_connection_lost(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref(), Some(BytesSendReceiveError::ConnectionClosed | BytesSendReceiveError::TruncatedFrame | BytesSendReceiveError::ReceiveFailed(_)))
+}
+
+
+/// Put a message that cannot be sent now into the outgoing queue. If the queue is full, the message is dropped and the user is warned.
+fn queue_message(outgoing_queue: &mut OutgoingQueue, message: MessageType) {
+    match outgoing_queue.push(message) {
+        Ok(()) => println!("Not connected to server. The message will be sent after reconnecting ({} waiting).", outgoing_queue.len()),
+        Err(_) => println!("{}", "Not connected to server and the queue of unsent messages is full. The message was dropped.".yellow())
+    }
+}
+
+
+/// Check that a text typed by the user is not too long to be sent.
+fn check_text_length(text: &str) -> Result<()> {
+    if text.len() > MAX_TEXT_LENGTH {
+        return Err(anyhow!("The text has {} bytes, but text can have at most {} bytes.", text.len(), MAX_TEXT_LENGTH));
+    }
+    Ok(())
+}
+
+
+/// Check that an image or a file is not too long to be sent in one message.
+fn check_file_length(bytes: &[u8]) -> Result<()> {
+    if bytes.len() > MAX_FILE_LENGTH {
+        return Err(anyhow!("The file has {} bytes, but a file can have at most {} bytes.", bytes.len(), MAX_FILE_LENGTH));
+    }
+    Ok(())
+}
+
+
 /// If the user's command is of type ".file", create a MessageType object of type File.
-/// This type contains a file name and the whole contents of the file as bytes.
-async fn get_file_message(user_input: String) -> Result<MessageType> {
-    let path_str = user_input.strip_prefix(".file ").ok_or_else(|| anyhow!("Failed to strip the '.file' prefix."))?;
+/// This type contains a file name, its MIME type guessed from the extension and the whole contents of the file as bytes.
+async fn get_file_message(path_str: &str) -> Result<MessageType> {
     let bytes = fs::read(path_str).await.context("Failed to read file.")?;
+    check_file_length(&bytes)?;
     let file_name = Path::new(path_str).file_name().context("Failed to parse filename.")?;
     let file_name = file_name.to_string_lossy().into_owned();
-    
-    Ok(MessageType::File(file_name, bytes))
+    let mime = guess_mime_type(&file_name);
+
+    Ok(MessageType::File { name: file_name, mime, data: bytes })
+}
+
+
+/// Guess the MIME type of a file from the extension of its name. If the extension is missing or unknown, None is returned.
+fn guess_mime_type(file_name: &str) -> Option<String> {
+    mime_guess::from_path(file_name).first().map(|mime| mime.to_string())
 }
 
 
 /// If a user's command is of type ".image", create a MessageType object of type Image.
 /// This type contains only the contents of the file as bytes.
 /// The image files that this message type transports are only those of type ".png".
-async fn get_image_message(user_input: String) -> Result<MessageType> {
-    let path_str = user_input.strip_prefix(".image ").ok_or_else(|| anyhow!("Failed to strip the '.image' prefix."))?;
-
+async fn get_image_message(path_str: &str) -> Result<MessageType> {
     if "png" != Path::new(path_str).extension().ok_or_else(|| anyhow!("Cannot parse extention from filename."))? {
         return Err(anyhow!("The file's extention is not '.png'."));
     }
 
     let bytes = fs::read(path_str).await.context("Failed to read file.")?;
+    check_file_length(&bytes)?;
 
     Ok(MessageType::Image(bytes))
 }
 
 
+/// If a user's command is of type ".text", create a MessageType object of type Text.
+/// The text is the whole contents of the file, which must be valid UTF-8 and not longer than MAX_TEXT_LENGTH bytes.
+async fn get_text_message(path_str: &str) -> Result<MessageType> {
+    let bytes = fs::read(path_str).await.context("Failed to read file.")?;
+    if bytes.len() > MAX_TEXT_LENGTH {
+        return Err(anyhow!("The file has {} bytes, but text can have at most {} bytes.", bytes.len(), MAX_TEXT_LENGTH));
+    }
+    let text = String::from_utf8(bytes).context("The file is not a valid UTF-8 text file.")?;
+
+    Ok(MessageType::Text(text))
+}
+
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
-
-    let matches = Command::new("Client")
+    let matches = clap::Command::new("Client")
         .about("Runs client")
         .arg(
             Arg::new("chat-socket")
             .short('c')
             .long("chat-socket")
             .value_name("CHAT_SOCKET")
-            .required(true)
-            .help("Chat server socket to which the client should connect.")
+            .help("Chat server socket to which the client should connect. If not given, the HELLO_RUST_SERVER environment variable is used and then 127.0.0.1:11111.")
+        )
+        .arg(
+            Arg::new("unix-socket")
+            .short('u')
+            .long("unix-socket")
+            .value_name("UNIX_SOCKET")
+            .value_parser(clap::value_parser!(PathBuf))
+            .conflicts_with("chat-socket")
+            .help("Path of a Unix domain socket of a chat server on the same machine to which the client should connect instead of a chat socket.")
+        )
+        .arg(
+            Arg::new("idle-timeout")
+            .short('i')
+            .long("idle-timeout")
+            .value_name("IDLE_TIMEOUT")
+            .value_parser(clap::value_parser!(u64))
+            .help("Number of seconds without any data from server after which the client reconnects. If not set, idle connections are not detected.")
+        )
+        .arg(
+            Arg::new("connect-timeout")
+            .long("connect-timeout")
+            .value_name("CONNECT_TIMEOUT")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .default_value(DEFAULT_CONNECT_TIMEOUT_SECS)
+            .help("Number of seconds to wait for a connection to server before giving up.")
+        )
+        .arg(
+            Arg::new("keepalive-interval")
+            .short('k')
+            .long("keepalive-interval")
+            .value_name("KEEPALIVE_INTERVAL")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("60")
+            .help("Number of seconds after which an idle connection to server is checked by TCP keepalive probes.")
+        )
+        .arg(
+            Arg::new("remember")
+            .short('r')
+            .long("remember")
+            .action(clap::ArgAction::SetTrue)
+            .help("Keep username and password in memory and log in with them automatically after a reconnect.")
+        )
+        .arg(
+            Arg::new("no-compression")
+            .long("no-compression")
+            .action(clap::ArgAction::SetTrue)
+            .help("Do not offer the server to compress large messages, e.g. images and files.")
+        )
+        .arg(
+            Arg::new("message-key")
+            .long("message-key")
+            .value_name("MESSAGE_KEY")
+            .value_parser(clap::builder::NonEmptyStringValueParser::new())
+            .help("Secret shared with the server. If set, all messages after the authentication are signed with HMAC-SHA256 and messages from the server must be signed with it too.")
+        )
+        .arg(
+            Arg::new("read-receipts")
+            .long("read-receipts")
+            .action(clap::ArgAction::SetTrue)
+            .help("Send read receipts for shown messages and show who has read your messages.")
+        )
+        .arg(
+            Arg::new("accept-files")
+            .long("accept-files")
+            .value_name("ACCEPT_FILES")
+            .value_parser(AcceptFiles::NAMES)
+            .default_value("always")
+            .help("What to do with files and images sent by other users: save them ('always'), discard them ('never') or ask about each of them ('prompt').")
+        )
+        .arg(
+            Arg::new("show-timestamps")
+            .long("show-timestamps")
+            .action(clap::ArgAction::SetTrue)
+            .help("Start each line about a received message with the local time when it arrived.")
+        )
+        .arg(
+            Arg::new("offline-queue-capacity")
+            .long("offline-queue-capacity")
+            .value_name("OFFLINE_QUEUE_CAPACITY")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("20")
+            .help("Maximum number of messages typed while disconnected that are kept and sent after reconnecting. With 0, such messages are dropped.")
+        )
+        .arg(
+            Arg::new("tag-messages")
+            .long("tag-messages")
+            .action(clap::ArgAction::SetTrue)
+            .help("Tag each sent text with a number counting up from 1. With --read-receipts, the id the server assigns to the text is shown with the tag.")
+        )
+        .arg(
+            Arg::new("send-retries")
+            .long("send-retries")
+            .value_name("SEND_RETRIES")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("3")
+            .help("Number of times sending a message is retried after a brief write error, e.g. a timeout. The wait before each retry doubles, starting at 100 ms. With 0, the client reconnects right away.")
+        )
+        .arg(
+            Arg::new("prompt")
+            .long("prompt")
+            .value_name("PROMPT")
+            .default_value(DEFAULT_PROMPT)
+            .help("Text shown in front of each line the user types. Use an empty value to show no prompt.")
+        )
+        .arg(
+            Arg::new("log-format")
+            .long("log-format")
+            .value_name("LOG_FORMAT")
+            .value_parser(LogFormat::NAMES)
+            .default_value("text")
+            .help("Format of the logs: human-readable text ('text') or one JSON object per line ('json').")
+        )
+        .arg(
+            Arg::new("version")
+            .short('V')
+            .long("version")
+            .action(clap::ArgAction::SetTrue)
+            .help("Print the version of the client and of the protocol it speaks, then exit without connecting.")
         )
         .get_matches();
 
-    let socket_address = matches.get_one::<String>("chat-socket").ok_or_else(|| anyhow!("The value is required."))?;
+    init_logger(LogFormat::from_name(matches.get_one::<String>("log-format").ok_or_else(|| anyhow!("There is always a value."))?)?);
+
+    if matches.get_flag("version") {
+        println!("{}", describe_version("client", env!("CARGO_PKG_VERSION")));
+        return Ok(());
+    }
+
+    let server_address = match matches.get_one::<PathBuf>("unix-socket") {
+        Some(socket_path) => ServerAddress::Unix(socket_path.clone()),
+        None => {
+            let server_env = std::env::var(SERVER_ENV_VAR).ok();
+            ServerAddress::Tcp(check_host_and_port(choose_chat_socket(matches.get_one::<String>("chat-socket").map(String::as_str), server_env.as_deref()))?)
+        }
+    };
+    let idle_timeout = matches.get_one::<u64>("idle-timeout").map(|secs| Duration::from_secs(*secs));
+    let connect_timeout = Duration::from_secs(*matches.get_one::<u64>("connect-timeout").ok_or_else(|| anyhow!("There is always a value."))?);
+    let keepalive_interval = Duration::from_secs(*matches.get_one::<u64>("keepalive-interval").ok_or_else(|| anyhow!("There is always a value."))?);
+
+    let options = ClientOptions {
+        idle_timeout,
+        connect_timeout,
+        keepalive_interval,
+        remember: matches.get_flag("remember"),
+        compression: if matches.get_flag("no-compression") { Compression::Disabled } else { Compression::Enabled },
+        message_key: matches.get_one::<String>("message-key").map(|key| MessageKey::new(key.as_bytes())).transpose()?,
+        read_receipts: if matches.get_flag("read-receipts") { ReadReceipts::Enabled } else { ReadReceipts::Disabled },
+        accept_files: AcceptFiles::from_name(matches.get_one::<String>("accept-files").ok_or_else(|| anyhow!("There is always a value."))?)?,
+        show_timestamps: matches.get_flag("show-timestamps"),
+        offline_queue_capacity: *matches.get_one::<usize>("offline-queue-capacity").ok_or_else(|| anyhow!("There is always a value."))?,
+        message_tags: matches.get_flag("tag-messages").then(MessageTags::new),
+        prompt: matches.get_one::<String>("prompt").ok_or_else(|| anyhow!("There is always a value."))?.clone(),
+        send_retry_policy: RetryPolicy::new(*matches.get_one::<u32>("send-retries").ok_or_else(|| anyhow!("There is always a value."))?)
+    };
+    // Credentials are kept here between reconnects. They are set only if remember is set.
+    let mut credentials: Option<Credentials> = None;
+    // Messages typed while disconnected are kept here between reconnects.
+    let mut outgoing_queue = OutgoingQueue::new(options.offline_queue_capacity);
 
     info!("Starting client...");
-    run_client(socket_address).await.context("Client stopped running because of an error.")?;
+    let connection = connect_to_server(&server_address, options.connect_timeout, options.keepalive_interval).await.context("Failed to connect to a server.")?;
+    let mut client_exit = run_client(connection, &options, &mut credentials, &mut outgoing_queue).await.context("Client stopped running because of an error.")?;
+    while client_exit == ClientExit::Reconnect {
+        info!("Reconnecting client...");
+        let Some(connection) = reconnect_to_server(&server_address, &options, &mut credentials, &mut outgoing_queue).await? else {
+            client_exit = ClientExit::Quit;
+            break;
+        };
+        client_exit = run_client(connection, &options, &mut credentials, &mut outgoing_queue).await.context("Client stopped running because of an error.")?;
+    }
     info!("Exiting client!...");
+    match client_exit {
+        ClientExit::AuthFailed(auth_error) => std::process::exit(auth_exit_code(auth_error)),
+        ClientExit::Rejected(reason) => std::process::exit(rejection_exit_code(reason)),
+        _ => {}
+    }
 
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handling_line_while_disconnected() {
+        let mut credentials = Some(Credentials { username: "alice".to_string(), password: "password".to_string() });
+        let mut outgoing_queue = OutgoingQueue::new(10);
+
+        // Reconnecting keeps the credentials and does not queue anything.
+        assert_eq!(handle_line_while_disconnected("", &mut credentials, &mut outgoing_queue, None).await, ClientExit::Reconnect);
+        assert_eq!(handle_line_while_disconnected(".reconnect", &mut credentials, &mut outgoing_queue, None).await, ClientExit::Reconnect);
+        assert!(outgoing_queue.is_empty());
+        assert!(credentials.is_some());
+
+        // A text is queued and sent after reconnecting.
+        assert_eq!(handle_line_while_disconnected("Hello.", &mut credentials, &mut outgoing_queue, None).await, ClientExit::Reconnect);
+        assert_eq!(outgoing_queue.len(), 1);
+
+        // Quitting drops the credentials.
+        assert_eq!(handle_line_while_disconnected(".quit", &mut credentials, &mut outgoing_queue, None).await, ClientExit::Quit);
+        assert!(credentials.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_text_message() {
+        let path = std::env::temp_dir().join("hello_rust_test_get_text_message.txt");
+        let text = "First line of a longer text.\nSecond line with ünïcödé.\n";
+        fs::write(&path, text).await.unwrap();
+
+        let message = get_text_message(path.to_str().unwrap()).await.unwrap();
+        fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(message, MessageType::Text(text.to_string()));
+    }
+
+    #[test]
+    fn test_guess_mime_type() {
+        assert_eq!(guess_mime_type("notes.txt"), Some("text/plain".to_string()));
+        assert_eq!(guess_mime_type("photo.JPG"), Some("image/jpeg".to_string()));
+        assert_eq!(guess_mime_type("report.pdf"), Some("application/pdf".to_string()));
+        assert_eq!(guess_mime_type("archive.unknownextension"), None);
+        assert_eq!(guess_mime_type("Makefile"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_file_message() {
+        let path = std::env::temp_dir().join("hello_rust_test_get_file_message.json");
+        fs::write(&path, "{}").await.unwrap();
+
+        let message = get_file_message(path.to_str().unwrap()).await.unwrap();
+        fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(message, MessageType::File {
+            name: "hello_rust_test_get_file_message.json".to_string(),
+            mime: Some("application/json".to_string()),
+            data: b"{}".to_vec()
+        });
+    }
+
+    #[tokio::test]
+    async fn test_saving_files_only_into_directory() {
+        let dir = std::env::temp_dir().join("hello_rust_test_saving_files");
+        let _ = fs::remove_dir_all(&dir).await;
+        let files_dir = dir.join("files");
+        fs::create_dir_all(&files_dir).await.unwrap();
+
+        // A name with a path of the sender is saved by its last component only.
+        save_file(&files_dir, "../escaped.txt", b"Hello.").await.unwrap();
+        let saved = fs::read(files_dir.join("escaped.txt")).await;
+        let escaped = fs::try_exists(dir.join("escaped.txt")).await.unwrap();
+        // A name without any file name is refused.
+        let parent_result = save_file(&files_dir, "..", b"Hello.").await;
+        fs::remove_dir_all(&dir).await.unwrap();
+
+        assert_eq!(saved.unwrap(), b"Hello.");
+        assert!(!escaped);
+        assert!(parent_result.is_err());
+    }
+
+    #[test]
+    fn test_prefix_with_time() {
+        let time = Local.with_ymd_and_hms(2025, 3, 14, 9, 5, 7).unwrap();
+        assert_eq!(prefix_with_time("Hello.".to_string(), time), "[09:05:07] Hello.");
+    }
+
+    #[tokio::test]
+    async fn test_get_text_message_from_invalid_files() {
+        let not_utf8_path = std::env::temp_dir().join("hello_rust_test_not_utf8.txt");
+        fs::write(&not_utf8_path, [0xffu8, 0xfe, 0xfd]).await.unwrap();
+        let too_long_path = std::env::temp_dir().join("hello_rust_test_too_long.txt");
+        fs::write(&too_long_path, "a".repeat(MAX_TEXT_LENGTH + 1)).await.unwrap();
+
+        let not_utf8_result = get_text_message(not_utf8_path.to_str().unwrap()).await;
+        let too_long_result = get_text_message(too_long_path.to_str().unwrap()).await;
+        fs::remove_file(&not_utf8_path).await.unwrap();
+        fs::remove_file(&too_long_path).await.unwrap();
+
+        assert!(not_utf8_result.is_err());
+        assert!(too_long_result.is_err());
+    }
+
+    #[test]
+    fn test_reading_trimmed_lines_until_closed_stdin() {
+        let mut stdin = std::io::Cursor::new("  L \n\nalice");
+
+        assert_eq!(read_trimmed_line(&mut stdin).unwrap(), Some("L".to_string()));
+        assert_eq!(read_trimmed_line(&mut stdin).unwrap(), Some(String::new()));
+        assert_eq!(read_trimmed_line(&mut stdin).unwrap(), Some("alice".to_string()));
+        assert_eq!(read_trimmed_line(&mut stdin).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_timing_out_connect() {
+        let connect = std::future::pending::<io::Result<TcpStream>>();
+
+        let error = connect_with_timeout(connect, Duration::from_millis(10), "192.0.2.1:11111").await.unwrap_err();
+
+        assert!(error.to_string().starts_with("Connecting to 192.0.2.1:11111 timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_refused_connect() {
+        // Nothing listens at the port of a dropped listener.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+
+        let error = connect_with_timeout(TcpStream::connect(address), Duration::from_secs(5), &address.to_string()).await.unwrap_err();
+
+        assert!(error.to_string().starts_with(&format!("Connection to {} was refused.", address)));
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        assert_eq!(next_reconnect_delay(FIRST_RECONNECT_DELAY), Duration::from_secs(2));
+        assert_eq!(next_reconnect_delay(Duration::from_secs(16)), MAX_RECONNECT_DELAY);
+        assert_eq!(next_reconnect_delay(MAX_RECONNECT_DELAY), MAX_RECONNECT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_telling_lost_connection_from_invalid_message() {
+        // The server closed the connection, between frames or in the middle of one.
+        let error = receive_signed_message_into(&mut [].as_slice(), &mut Vec::new(), Compression::Disabled, None).await.unwrap_err();
+        assert!(is_connection_lost(&error));
+        let error = receive_signed_message_into(&mut [0, 0, 0, 5, 1].as_slice(), &mut Vec::new(), Compression::Disabled, None).await.unwrap_err();
+        assert!(is_connection_lost(&error));
+
+        // The server sent a frame that is not a message or whose signature does not match.
+        let error = receive_signed_message_into(&mut [0, 0, 0, 1, 0xff].as_slice(), &mut Vec::new(), Compression::Disabled, None).await.unwrap_err();
+        assert!(!is_connection_lost(&error));
+        let mut signer = FrameSigner::new(MessageKey::new(b"secret").unwrap(), Direction::ServerToClient);
+        let error = receive_signed_message_into(&mut [0, 0, 0, 1, 0xff].as_slice(), &mut Vec::new(), Compression::Disabled, Some(&mut signer)).await.unwrap_err();
+        assert!(!is_connection_lost(&error));
+    }
+
+    #[test]
+    fn test_checking_host_and_port() {
+        assert_eq!(check_host_and_port("localhost:11111").unwrap(), "localhost:11111");
+        assert_eq!(check_host_and_port("chat.example.com:11111").unwrap(), "chat.example.com:11111");
+        assert_eq!(check_host_and_port("127.0.0.1:11111").unwrap(), "127.0.0.1:11111");
+        assert_eq!(check_host_and_port("[::1]:11111").unwrap(), "[::1]:11111");
+        assert!(check_host_and_port("localhost").is_err());
+        assert!(check_host_and_port(":11111").is_err());
+        assert!(check_host_and_port("localhost:99999").is_err());
+        assert!(check_host_and_port("::1:11111").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connecting_to_host_name() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let stream = connect_to_host(&format!("localhost:{}", port)).await.unwrap();
+
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn test_choosing_chat_socket() {
+        assert_eq!(choose_chat_socket(Some("10.0.0.1:12345"), Some("10.0.0.2:12345")), "10.0.0.1:12345");
+        assert_eq!(choose_chat_socket(None, Some("10.0.0.2:12345")), "10.0.0.2:12345");
+        assert_eq!(choose_chat_socket(None, Some("")), DEFAULT_CHAT_SOCKET);
+        assert_eq!(choose_chat_socket(None, None), DEFAULT_CHAT_SOCKET);
+    }
+
+    #[test]
+    fn test_reading_line_from_closed_stdin() {
+        let mut stdin = std::io::empty();
+
+        assert_eq!(read_trimmed_line(&mut stdin).unwrap(), None);
+    }
+}
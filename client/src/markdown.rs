@@ -0,0 +1,77 @@
+use crossterm::style::Stylize;
+
+
+/// Render a markdown text for the terminal.
+/// Only inline styles are supported: **bold**, *italic* or _italic_ and `code`. Styles cannot be nested.
+/// A marker without its closing pair and a marker escaped by '\' are shown as they are.
+pub fn render_markdown(text: &str) -> String {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '\\' {
+            let escaped = rest[1..].chars().next().filter(|escaped| "*_`\\".contains(*escaped));
+            match escaped {
+                Some(escaped) => {
+                    rendered.push(escaped);
+                    rest = &rest[1 + escaped.len_utf8()..];
+                },
+                None => {
+                    rendered.push(c);
+                    rest = &rest[1..];
+                }
+            }
+            continue;
+        }
+        let styled = ["**", "`", "*", "_"].iter().find_map(|marker| styled_span(rest, marker));
+        match styled {
+            Some((span, len)) => {
+                rendered.push_str(&span);
+                rest = &rest[len..];
+            },
+            None => {
+                rendered.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    rendered
+}
+
+
+/// If the text starts with a span enclosed in the marker, return the styled span and the length of the text it takes.
+/// Empty spans are not styled.
+fn styled_span(text: &str, marker: &str) -> Option<(String, usize)> {
+    let inner = text.strip_prefix(marker)?;
+    let end = inner.find(marker).filter(|end| *end > 0)?;
+    let content = &inner[..end];
+    let span = match marker {
+        "**" => content.bold().to_string(),
+        "`" => content.dark_yellow().to_string(),
+        _ => content.italic().to_string()
+    };
+    Some((span, 2 * marker.len() + end))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_markdown_styles() {
+        assert_eq!(render_markdown("plain text"), "plain text");
+        assert_eq!(render_markdown("a **bold** word"), format!("a {} word", "bold".bold()));
+        assert_eq!(render_markdown("*one* _two_"), format!("{} {}", "one".italic(), "two".italic()));
+        assert_eq!(render_markdown("run `cargo *test*`"), format!("run {}", "cargo *test*".dark_yellow()));
+        assert_eq!(render_markdown("ünï*cödé*"), format!("ünï{}", "cödé".italic()));
+    }
+
+    #[test]
+    fn test_render_markdown_unclosed_and_escaped_markers() {
+        assert_eq!(render_markdown("2 * 3 = 6"), "2 * 3 = 6");
+        assert_eq!(render_markdown("**not closed"), "**not closed");
+        assert_eq!(render_markdown("empty ** and ``"), "empty ** and ``");
+        assert_eq!(render_markdown(r"\*literal\* and \\"), r"*literal* and \");
+        assert_eq!(render_markdown(r"C:\path"), r"C:\path");
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+use shared::MessageType;
+
+
+/// Source of tags for texts the user sends, so that the replies of the server can be matched with them.
+/// Tags go up from 1 and are not reset by reconnects, so each text sent by one run of the client has its own tag.
+#[derive(Debug)]
+pub struct MessageTags {
+    next: AtomicI64
+}
+
+
+impl MessageTags {
+    /// Create a source whose first tag is 1.
+    pub fn new() -> MessageTags {
+        MessageTags { next: AtomicI64::new(1) }
+    }
+
+    /// Wrap a text into a tagged message with the next tag. Other messages cannot be tagged and are returned as they are.
+    pub fn tag(&self, message: MessageType) -> MessageType {
+        match message {
+            MessageType::Text(_) | MessageType::FormattedText { .. } => {
+                let tag = self.next.fetch_add(1, Ordering::Relaxed);
+                MessageType::Tagged(tag, Box::new(message))
+            },
+            message => message
+        }
+    }
+}
+
+
+impl Default for MessageTags {
+    fn default() -> MessageTags {
+        MessageTags::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> MessageType {
+        MessageType::Text(text.to_string())
+    }
+
+    #[test]
+    fn test_tagging_only_texts() {
+        let tags = MessageTags::new();
+        assert_eq!(tags.tag(text("first")), MessageType::Tagged(1, Box::new(text("first"))));
+        assert_eq!(tags.tag(MessageType::HistoryRequest(5)), MessageType::HistoryRequest(5));
+        assert_eq!(tags.tag(text("second")), MessageType::Tagged(2, Box::new(text("second"))));
+    }
+}
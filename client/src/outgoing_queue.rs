@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use anyhow::Result;
+use tokio::io::AsyncWrite;
+use shared::{Compression, FrameSigner, MessageType, send_signed_message};
+
+
+/// Messages typed by the user while the client was disconnected, the oldest first.
+/// They are sent after the client reconnects. At most capacity messages are kept.
+#[derive(Debug)]
+pub struct OutgoingQueue {
+    messages: VecDeque<MessageType>,
+    capacity: usize
+}
+
+
+impl OutgoingQueue {
+    /// Create an empty queue for at most capacity messages. With capacity 0, no messages are kept.
+    pub fn new(capacity: usize) -> OutgoingQueue {
+        OutgoingQueue { messages: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    /// Add a message at the end of the queue. If the queue is full, the message is given back.
+    pub fn push(&mut self, message: MessageType) -> Result<(), MessageType> {
+        if self.messages.len() >= self.capacity {
+            return Err(message);
+        }
+        self.messages.push_back(message);
+        Ok(())
+    }
+
+    /// Get the number of waiting messages.
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Check if no messages are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Send all waiting messages in order, signed if there is a signer, and return how many were sent.
+    /// If sending fails, the message that failed and all later ones stay in the queue for the next reconnect.
+    pub async fn flush<W: AsyncWrite + Unpin>(&mut self, writer: &mut W, compression: Compression, mut signer: Option<&mut FrameSigner>) -> Result<usize> {
+        let mut sent = 0;
+        while let Some(message) = self.messages.front() {
+            send_signed_message(writer, message, compression, signer.as_deref_mut()).await?;
+            self.messages.pop_front();
+            sent += 1;
+        }
+        Ok(sent)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::receive_message;
+
+    fn text(text: &str) -> MessageType {
+        MessageType::Text(text.to_string())
+    }
+
+    #[test]
+    fn test_outgoing_queue_is_bounded() {
+        let mut queue = OutgoingQueue::new(2);
+        assert!(queue.push(text("first")).is_ok());
+        assert!(queue.push(text("second")).is_ok());
+        assert_eq!(queue.push(text("third")), Err(text("third")));
+        assert_eq!(queue.len(), 2);
+
+        let mut disabled_queue = OutgoingQueue::new(0);
+        assert_eq!(disabled_queue.push(text("first")), Err(text("first")));
+    }
+
+    #[tokio::test]
+    async fn test_flush_after_reconnect_keeps_order() {
+        let mut queue = OutgoingQueue::new(10);
+        for message in ["first", "second", "third"] {
+            queue.push(text(message)).unwrap();
+        }
+
+        // The connection breaks, so nothing is sent and the messages wait for the next one.
+        let (mut broken_writer, broken_reader) = tokio::io::duplex(1024);
+        drop(broken_reader);
+        assert!(queue.flush(&mut broken_writer, Compression::Disabled, None).await.is_err());
+        assert_eq!(queue.len(), 3);
+
+        // After reconnecting, the messages are sent in the order they were typed.
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        assert_eq!(queue.flush(&mut writer, Compression::Disabled, None).await.unwrap(), 3);
+        assert!(queue.is_empty());
+        for message in ["first", "second", "third"] {
+            assert_eq!(receive_message(&mut reader).await.unwrap(), text(message));
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::io;
+use anyhow::{Context, Result, anyhow};
+use log::info;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::{Duration, sleep};
+use shared::{Compression, FrameSigner, MessageType, LEN_PREFIX_BYTES, encode_signed_message, write_len_prefix};
+
+
+/// The time to wait before the first retry. It is doubled before each following one.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+
+/// How many times a failed write of a message is retried and how long to wait before the first retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration
+}
+
+
+impl RetryPolicy {
+    /// Create a policy with the given number of retries and the default backoff. With 0 retries, nothing is retried.
+    pub fn new(max_retries: u32) -> RetryPolicy {
+        RetryPolicy { max_retries, initial_backoff: INITIAL_BACKOFF }
+    }
+}
+
+
+/// Check if a write error may go away by itself, so that writing again makes sense.
+/// Errors of a closed connection, e.g. a broken pipe after the server stopped, are not retryable.
+fn is_retryable(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+
+/// Send a message like send_signed_message, but retry retryable write errors according to the policy.
+/// The frame is written from the place where the failed write stopped, so no part of it is sent twice.
+/// If the error is not retryable or there are no retries left, it is returned.
+pub async fn send_with_retry<W: AsyncWrite + Unpin>(writer: &mut W, message: &MessageType, compression: Compression, signer: Option<&mut FrameSigner>, policy: RetryPolicy) -> Result<()> {
+    let bytes = encode_signed_message(message, compression, signer)?;
+    let mut frame = Vec::with_capacity(LEN_PREFIX_BYTES + bytes.len());
+    write_len_prefix(&mut frame, bytes.len()).await.context("Failed to encode the length of the message.")?;
+    frame.extend_from_slice(&bytes);
+
+    let mut written = 0;
+    let mut retries = 0;
+    let mut backoff = policy.initial_backoff;
+    while written < frame.len() {
+        match writer.write(&frame[written..]).await {
+            Ok(0) => return Err(anyhow!("The connection does not accept any more bytes.")),
+            Ok(n) => written += n,
+            Err(e) if is_retryable(&e) && retries < policy.max_retries => {
+                retries += 1;
+                info!("Failed to send message, retry {} of {} in {:?}: {}", retries, policy.max_retries, backoff, e);
+                sleep(backoff).await;
+                backoff *= 2;
+            },
+            Err(e) => return Err(e).context("Failed when sending bytes.")
+        }
+    }
+    writer.flush().await.context("Failed when sending bytes.")?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::DuplexStream;
+    use shared::receive_message;
+
+    /// Writer over a duplex stream that follows a script of results of its writes.
+    /// A write with None in the script writes at most 3 bytes, so that failures also happen in the middle of a frame.
+    /// A write with an error kind fails with it. After the script is over, all writes succeed.
+    struct FlakyWriter {
+        inner: DuplexStream,
+        script: VecDeque<Option<io::ErrorKind>>
+    }
+
+    impl AsyncWrite for FlakyWriter {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let len = match self.script.pop_front() {
+                Some(Some(kind)) => return Poll::Ready(Err(io::Error::from(kind))),
+                Some(None) => buf.len().min(3),
+                None => buf.len()
+            };
+            Pin::new(&mut self.inner).poll_write(cx, &buf[..len])
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    fn flaky_connection(script: Vec<Option<io::ErrorKind>>) -> (FlakyWriter, DuplexStream) {
+        let (inner, reader) = tokio::io::duplex(1024);
+        (FlakyWriter { inner, script: script.into() }, reader)
+    }
+
+    fn quick_policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy { max_retries, initial_backoff: Duration::from_millis(1) }
+    }
+
+    fn hello() -> MessageType {
+        MessageType::Text("Hello.".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_retrying_transient_write_errors() {
+        // Two writes time out in the middle of the frame.
+        let (mut writer, mut reader) = flaky_connection(vec![None, Some(io::ErrorKind::TimedOut), None, Some(io::ErrorKind::Interrupted)]);
+
+        send_with_retry(&mut writer, &hello(), Compression::Disabled, None, quick_policy(2)).await.unwrap();
+
+        // The message arrives whole, so no part of it was written twice.
+        assert_eq!(receive_message(&mut reader).await.unwrap(), hello());
+    }
+
+    #[tokio::test]
+    async fn test_giving_up_after_retries() {
+        let (mut writer, _reader) = flaky_connection(vec![None, Some(io::ErrorKind::TimedOut), None, Some(io::ErrorKind::TimedOut)]);
+
+        assert!(send_with_retry(&mut writer, &hello(), Compression::Disabled, None, quick_policy(1)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_not_retrying_closed_connection() {
+        let (mut writer, _reader) = flaky_connection(vec![Some(io::ErrorKind::BrokenPipe)]);
+
+        let error = send_with_retry(&mut writer, &hello(), Compression::Disabled, None, quick_policy(5)).await.unwrap_err();
+
+        let io_error = error.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
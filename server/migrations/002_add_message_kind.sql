@@ -0,0 +1,5 @@
+ALTER TABLE messages ADD COLUMN kind TEXT NOT NULL DEFAULT 'text' CHECK (kind IN ('text', 'image', 'file'));
+
+-- Messages saved before this migration only have the human-readable content, so derive their kind from it.
+UPDATE messages SET kind = 'image' WHERE content = 'SENT IMAGE';
+UPDATE messages SET kind = 'file' WHERE content LIKE 'FILE SENT: %';
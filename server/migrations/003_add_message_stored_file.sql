@@ -0,0 +1,2 @@
+-- Path to a copy of an image or a file on disk. It is NULL for text messages and for files that were not stored.
+ALTER TABLE messages ADD COLUMN stored_file TEXT;
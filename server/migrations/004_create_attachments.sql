@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS attachments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL,
+    filename TEXT NOT NULL,
+    path TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    FOREIGN KEY (message_id) REFERENCES messages(id) ON DELETE CASCADE
+);
+
+-- Files stored before this migration are moved into the new table. The original file name is taken from the message content.
+INSERT INTO attachments (message_id, filename, path, mime)
+SELECT
+    id,
+    CASE kind WHEN 'image' THEN 'image.png' ELSE substr(content, length('FILE SENT: ') + 1) END,
+    stored_file,
+    CASE kind WHEN 'image' THEN 'image/png' ELSE 'application/octet-stream' END
+FROM messages
+WHERE stored_file IS NOT NULL;
+
+ALTER TABLE messages DROP COLUMN stored_file;
@@ -0,0 +1,45 @@
+-- SQLite cannot add a CHECK constraint to an existing table, so the tables are recreated with the constraints.
+-- Foreign keys stay enforced during migrations, so the new tables reference each other from the start
+-- and the old tables are dropped children first. This way no rows are deleted by ON DELETE CASCADE.
+
+-- Usernames longer than 64 characters must be renamed before this migration, otherwise it fails.
+CREATE TABLE users_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE CONSTRAINT username_length CHECK (length(username) <= 64),
+    password_hash TEXT NOT NULL
+);
+INSERT INTO users_new (id, username, password_hash)
+SELECT id, username, password_hash FROM users;
+
+-- Messages longer than 4096 characters must be shortened or deleted before this migration, otherwise it fails.
+-- They are not shortened here, because that would silently change messages in the history.
+CREATE TABLE messages_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    content TEXT NOT NULL CONSTRAINT content_length CHECK (length(content) <= 4096),
+    sent_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    kind TEXT NOT NULL DEFAULT 'text' CHECK (kind IN ('text', 'image', 'file')),
+    FOREIGN KEY (user_id) REFERENCES users_new(id) ON DELETE CASCADE
+);
+INSERT INTO messages_new (id, user_id, content, sent_at, kind)
+SELECT id, user_id, content, sent_at, kind FROM messages;
+
+CREATE TABLE attachments_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_id INTEGER NOT NULL,
+    filename TEXT NOT NULL,
+    path TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    FOREIGN KEY (message_id) REFERENCES messages_new(id) ON DELETE CASCADE
+);
+INSERT INTO attachments_new (id, message_id, filename, path, mime)
+SELECT id, message_id, filename, path, mime FROM attachments;
+
+DROP TABLE attachments;
+DROP TABLE messages;
+DROP TABLE users;
+
+-- Renaming a table also updates the foreign keys that reference it.
+ALTER TABLE users_new RENAME TO users;
+ALTER TABLE messages_new RENAME TO messages;
+ALTER TABLE attachments_new RENAME TO attachments;
@@ -0,0 +1,5 @@
+-- Usernames that are not allowed to log in or register. A ban without an expiry time is permanent.
+CREATE TABLE IF NOT EXISTS banned_users (
+    username TEXT PRIMARY KEY,
+    expires_at DATETIME
+);
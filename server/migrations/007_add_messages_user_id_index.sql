@@ -0,0 +1,3 @@
+-- Messages are looked up and deleted by their author, and deleting a user cascades to them.
+-- Without an index, each of these scans the whole table.
+CREATE INDEX IF NOT EXISTS idx_messages_user_id ON messages(user_id);
@@ -0,0 +1,3 @@
+-- The name under which the messages of a user are shown to other users. It is NULL until the user sets a nickname,
+-- which means that the username is shown.
+ALTER TABLE users ADD COLUMN display_name TEXT CONSTRAINT display_name_length CHECK (length(display_name) <= 32);
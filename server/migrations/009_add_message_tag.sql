@@ -0,0 +1,2 @@
+-- The tag a client chose for a message to find the replies of the server to it. It is NULL for messages sent without a tag.
+ALTER TABLE messages ADD COLUMN tag INTEGER;
@@ -0,0 +1,15 @@
+-- Bans are kept by user id instead of username, so that renaming a banned user does not lift the ban
+-- and a new user who takes the username of a deleted one is not banned.
+-- Bans of usernames that no user has anymore are dropped. A ban is removed together with its user.
+CREATE TABLE banned_users_new (
+    user_id INTEGER PRIMARY KEY,
+    expires_at DATETIME,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+INSERT INTO banned_users_new (user_id, expires_at)
+SELECT users.id, banned_users.expires_at
+FROM banned_users
+JOIN users ON users.username = banned_users.username;
+
+DROP TABLE banned_users;
+ALTER TABLE banned_users_new RENAME TO banned_users;
@@ -1,22 +1,431 @@
-use sqlx::{SqlitePool, sqlite::SqlitePoolOptions};
+use prometheus::{HistogramTimer, HistogramVec};
+use sqlx::{Sqlite, SqlitePool, Transaction, migrate::Migrator, sqlite::{SqliteConnectOptions, SqlitePoolOptions}};
 use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::SystemTime;
 
+use crate::clock::Clock;
 
-/// Create a connection pool and return it from the function.
+
+/// Migrations of the database schema built into the server. The version of the last one is the schema version the server expects.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+
+/// The maximum number of characters of a username. It is enforced by the database.
+pub const MAX_USERNAME_LENGTH: usize = 64;
+
+
+/// The maximum number of characters of a display name. It is enforced by the database.
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 32;
+
+
+/// The maximum number of characters of a message content. It is enforced by the database.
+pub const MAX_CONTENT_LENGTH: usize = 4096;
+
+
+/// The format of the times when messages were sent, the same as the one of sqlite CURRENT_TIMESTAMP.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+
+/// The number of free pages returned to the file system in one step of an incremental vacuum.
+pub const VACUUM_STEP_PAGES: i64 = 256;
+
+
+/// The value of the 'auto_vacuum' pragma of a database in the incremental auto-vacuum mode.
+const INCREMENTAL_AUTO_VACUUM: i64 = 2;
+
+
+/// The future run by with_transaction. It borrows the transaction until it is finished.
+pub type TransactionFuture<'c, T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send + 'c>>;
+
+
+/// Custom error for signalizing why a user could not be added.
+#[derive(Error, Debug)]
+pub enum AddUserError {
+    #[error("Username '{0}' is already taken.")]
+    NameTaken(String),
+    #[error("Username can have at most {MAX_USERNAME_LENGTH} characters.")]
+    NameTooLong,
+    #[error("Failed to add new user into database.")]
+    Failed(#[source] sqlx::Error)
+}
+
+
+/// Custom error for signalizing why a message could not be added.
+#[derive(Error, Debug)]
+pub enum AddMessageError {
+    #[error("Message content can have at most {MAX_CONTENT_LENGTH} characters.")]
+    ContentTooLong,
+    #[error("Failed to add message into database.")]
+    Failed(#[source] sqlx::Error)
+}
+
+
+/// Custom error for signalizing why a user could not be renamed.
+#[derive(Error, Debug)]
+pub enum RenameUserError {
+    #[error("Username '{0}' is already taken.")]
+    NameTaken(String),
+    #[error("Username can have at most {MAX_USERNAME_LENGTH} characters.")]
+    NameTooLong,
+    #[error("User with id {0} does not exist.")]
+    UserNotFound(i64),
+    #[error("Failed to rename user.")]
+    Failed(#[source] sqlx::Error)
+}
+
+
+/// Custom error for signalizing why the display name of a user could not be set.
+#[derive(Error, Debug)]
+pub enum SetDisplayNameError {
+    #[error("Display name can have at most {MAX_DISPLAY_NAME_LENGTH} characters.")]
+    NameTooLong,
+    #[error("Display name can contain only letters, digits, spaces, '_', '-' and '.', and it cannot start or end with a space.")]
+    InvalidCharacters,
+    #[error("The name {0} is already used by another user.")]
+    NameTaken(String),
+    #[error("User with id {0} does not exist.")]
+    UserNotFound(i64),
+    #[error("Failed to set display name.")]
+    Failed(#[source] sqlx::Error)
+}
+
+
+/// Custom error for signalizing why a backup could not be imported. Nothing is imported in that case.
+#[derive(Error, Debug)]
+pub enum ImportError {
+    #[error("{0}")]
+    Invalid(String),
+    #[error("{0} is already in the database.")]
+    Conflict(String),
+    #[error("User {id} ('{username}') cannot be replaced, because user {owner_id} in the database has the same username.")]
+    UsernameTaken { id: i64, username: String, owner_id: i64 },
+    #[error("Failed to import backup into database.")]
+    Failed(#[source] sqlx::Error)
+}
+
+
+/// An image or a file that was sent with a message and stored on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub id: i64,
+    pub message_id: i64,
+    pub filename: String,
+    pub path: String,
+    pub mime: String
+}
+
+
+/// A user as returned to clients of the http server. The display name is left out if the user has not set one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserDto {
+    pub id: i64,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>
+}
+
+
+/// A user with the time of the last message, as returned to clients of the http server.
+/// The time is in the "YYYY-MM-DD HH:MM:SS" format in UTC. It is None if the user has not sent any message yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserActivityDto {
+    pub id: i64,
+    pub username: String,
+    pub last_message_at: Option<String>
+}
+
+
+/// A message as returned to clients of the http server.
+/// The time when the message was sent is in the "YYYY-MM-DD HH:MM:SS" format in UTC.
+/// The tag the client chose for the message is left out if there is none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MessageDto {
+    pub id: i64,
+    pub content: String,
+    pub kind: MessageKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<i64>,
+    pub sent_at: String
+}
+
+
+/// A message of any user as written into an export of the database and read from a backup.
+/// The time when the message was sent is in the "YYYY-MM-DD HH:MM:SS" format in UTC.
+/// The display name of the author is written for readers of the export and left out if the author has none.
+/// It is not imported, the author gets the display name of the user in the backup.
+/// The tag the author's client gave the message is kept through an export and an import, and left out if there is none.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExportedMessage {
+    pub id: i64,
+    pub user_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub content: String,
+    pub kind: MessageKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<i64>,
+    pub sent_at: String
+}
+
+
+/// A page of messages of all users listed by a cursor, as returned by get_messages_page.
+/// next_cursor is the id of the last message of the page, so that the next page starts after it.
+/// It is None if there are no more messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MessagesPage {
+    pub messages: Vec<ExportedMessage>,
+    pub next_cursor: Option<i64>
+}
+
+
+/// A user as read from a backup. Exports do not contain password hashes, so the hash is optional.
+/// A user without a display name shows the username.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ImportedUser {
+    pub id: i64,
+    pub username: String,
+    pub password_hash: Option<String>,
+    pub display_name: Option<String>
+}
+
+
+/// Users and messages of a backup, in the format of an export of the database.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Backup {
+    pub users: Vec<ImportedUser>,
+    pub messages: Vec<ExportedMessage>
+}
+
+
+/// The order of listed messages by the time when they were sent. Messages sent in the same second are ordered by their ids.
+/// Asc lists the oldest message first and Desc the newest one first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageOrder {
+    Asc,
+    #[default]
+    Desc
+}
+
+
+/// What happens with a user or a message of a backup whose id (or username) is already in the database.
+/// Skip keeps the row in the database, Fail stops the whole import and Replace overwrites the row with the one from the backup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    Skip,
+    #[default]
+    Fail,
+    Replace
+}
+
+
+/// Numbers of users and messages written into the database by an import. Skipped ones are not counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    pub users_imported: u64,
+    pub messages_imported: u64
+}
+
+
+/// A message together with the name of its author, as replayed to chat clients.
+/// The time when the message was sent is in the "YYYY-MM-DD HH:MM:SS" format in UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentMessage {
+    pub username: String,
+    pub content: String,
+    pub tag: Option<i64>,
+    pub sent_at: String
+}
+
+
+/// A message that is not yet in the 'messages' table, e.g. one waiting to be saved in a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewMessage {
+    pub user_id: i64,
+    pub content: String,
+    pub kind: MessageKind,
+    pub tag: Option<i64>,
+    pub sent_at: SystemTime,
+    pub attachment: Option<NewAttachment>
+}
+
+
+/// An attachment of a new message. It gets the id of the message when both are saved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewAttachment {
+    pub filename: String,
+    pub path: String,
+    pub mime: String
+}
+
+
+/// This type represents the kind of content a message in the 'messages' table was created from.
+/// Text is for plain text messages.
+/// Image is for images (only "SENT IMAGE" is saved as content).
+/// File is for files (only the name of the file is saved as content).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum MessageKind {
+    Text,
+    Image,
+    File
+}
+
+
+impl MessageKind {
+    /// Get the value stored in the 'kind' column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Text => "text",
+            MessageKind::Image => "image",
+            MessageKind::File => "file"
+        }
+    }
+}
+
+
+/// Check if an error was caused by the CHECK constraint with specified name.
+fn is_check_violation(error: &sqlx::Error, constraint: &str) -> bool {
+    match error {
+        sqlx::Error::Database(db_error) => db_error.is_check_violation() && db_error.message().contains(constraint),
+        _ => false
+    }
+}
+
+
+/// Format time in the same way as sqlite CURRENT_TIMESTAMP, i.e. "YYYY-MM-DD HH:MM:SS" in UTC.
+fn format_timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format(TIMESTAMP_FORMAT).to_string()
+}
+
+
+/// Check if time can be stored as a timestamp. Timestamps are compared as strings, so the year must have four digits.
+/// Times after the year 9999 and times before 1970 are not storable.
+pub fn is_storable_timestamp(time: SystemTime) -> bool {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .ok()
+        .and_then(|since_epoch| i64::try_from(since_epoch.as_secs()).ok())
+        .and_then(|secs| DateTime::<Utc>::from_timestamp(secs, 0))
+        .is_some_and(|time| time.year() <= 9999)
+}
+
+
+/// Histogram of durations of database operations labeled by the operation.
+/// It is set when the metrics of the server are created. Until then, timing of operations does nothing.
+static OPERATION_DURATIONS: OnceLock<HistogramVec> = OnceLock::new();
+
+
+/// Make all database operations observe their durations in the histogram.
+/// The histogram can be set only once, so a second call returns an error.
+pub fn set_operation_durations(histogram: HistogramVec) -> Result<()> {
+    OPERATION_DURATIONS.set(histogram).map_err(|_| anyhow!("Durations of database operations are already observed."))
+}
+
+
+/// Start timing a database operation. The duration is observed when the timer is dropped,
+/// so it is observed for failed operations too. Without a histogram, None is returned.
+fn time_operation(operation: &str) -> Option<HistogramTimer> {
+    OPERATION_DURATIONS.get().map(|histogram| histogram.with_label_values(&[operation]).start_timer())
+}
+
+
+/// Create a connection pool to the database in a file and return it from the function.
 /// This pool is used by functions executing database queries.
-pub async fn create_connection_pool(database_url: &str) -> Result<SqlitePool> {
+/// A missing database file is created. Its directory is created only if create_missing_dir is set,
+/// otherwise a missing directory is reported as an error.
+/// Migrations that were not applied to the database yet are applied first.
+/// A database with a schema this server does not know, e.g. one migrated by a newer server, is refused, see check_schema_version.
+pub async fn create_connection_pool(db_file: &Path, create_missing_dir: bool) -> Result<SqlitePool> {
+    if let Some(dir) = db_file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if !dir.is_dir() {
+            if !create_missing_dir {
+                return Err(anyhow!("Directory '{}' of the database file does not exist.", dir.display()));
+            }
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create directory '{}' for the database file.", dir.display()))?;
+        }
+    }
+    let connect_options = SqliteConnectOptions::new()
+        .filename(db_file)
+        .create_if_missing(true);
     let pool = SqlitePoolOptions::new()
         .max_connections(7)
-        .connect(database_url)
+        .connect_with(connect_options)
+        .await
+        .with_context(|| format!("Failed to open database file '{}'.", db_file.display()))?;
+    check_schema_version(&pool).await?;
+    MIGRATOR
+        .run(&pool)
         .await
-        .context("Failed to create a pool.")?;
+        .context("Failed to apply database migrations.")?;
+    Ok(pool)
+}
+
+
+/// Check that the schema of the database can be used by this server before any migration is applied.
+/// The schema version is the version of the last migration applied to the database. It must not be newer than
+/// the last migration of the server and every applied migration must be known to the server. Otherwise the database
+/// was migrated by another version of the server and changing it could corrupt the data.
+/// A migration that failed before is reported too, because the schema is then only partly changed.
+/// A new database without any migration is always fine.
+pub async fn check_schema_version(pool: &SqlitePool) -> Result<()> {
+    let has_migrations: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')")
+        .fetch_one(pool)
+        .await
+        .context("Failed to check if the database has any migrations.")?;
+    if !has_migrations {
+        return Ok(());
+    }
+    let applied: Vec<(i64, bool)> = sqlx::query_as("SELECT version, success FROM _sqlx_migrations ORDER BY version")
+        .fetch_all(pool)
+        .await
+        .context("Failed to get migrations applied to the database.")?;
+    let expected_version = MIGRATOR.iter().map(|migration| migration.version).max().unwrap_or(0);
+
+    if let Some((version, _)) = applied.iter().find(|(_, success)| !success) {
+        return Err(anyhow!("Migration {} of the database failed before, so its schema is incomplete. Fix the database before starting the server.", version));
+    }
+    if let Some((version, _)) = applied.last().filter(|(version, _)| *version > expected_version) {
+        return Err(anyhow!("The database has schema version {}, but this server supports at most version {}. It was probably migrated by a newer server, so this server refuses to use it.", version, expected_version));
+    }
+    if let Some((version, _)) = applied.iter().find(|(version, _)| !MIGRATOR.iter().any(|migration| migration.version == *version)) {
+        return Err(anyhow!("The database has migration {}, which this server does not know, so its schema is not compatible with this server.", version));
+    }
+    Ok(())
+}
+
+
+/// Create a connection pool to an in-memory database with all tables created.
+/// It is meant for tests. The pool has only one connection, because each connection to
+/// an in-memory database would otherwise get its own empty database.
+#[cfg(feature = "test-util")]
+pub async fn create_in_memory_connection_pool() -> Result<SqlitePool> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .context("Failed to create an in-memory pool.")?;
+    MIGRATOR
+        .run(&pool)
+        .await
+        .context("Failed to create tables in an in-memory database.")?;
     Ok(pool)
 }
 
 
 /// Add a user entry into the 'users' table.
 /// A new entry can be created by inserting username and a hashed password into the users table.
-pub async fn add_user(pool: &SqlitePool, username: &str, password_hash: &str) -> Result<i64> {
+/// Violated constraints are reported as NameTaken or NameTooLong.
+pub async fn add_user(pool: &SqlitePool, username: &str, password_hash: &str) -> Result<i64, AddUserError> {
+    let _timer = time_operation("add_user");
     let rec = sqlx::query!(
         r#"
         INSERT INTO users (username, password_hash)
@@ -28,7 +437,13 @@ pub async fn add_user(pool: &SqlitePool, username: &str, password_hash: &str) ->
     )
     .fetch_one(pool)
     .await
-    .context("Failed to add new user into database.")?;
+    .map_err(|e| match e {
+        sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => {
+            AddUserError::NameTaken(username.to_string())
+        },
+        e if is_check_violation(&e, "username_length") => AddUserError::NameTooLong,
+        e => AddUserError::Failed(e)
+    })?;
     
     Ok(rec.id)
 }
@@ -37,6 +452,7 @@ pub async fn add_user(pool: &SqlitePool, username: &str, password_hash: &str) ->
 /// Get a user entry from the 'users' table.
 /// The two values we want to obtain in this manner are id and password hash of a user.
 pub async fn get_user(pool: &SqlitePool, username: &str) -> Result<(i64, String)> {
+    let _timer = time_operation("get_user");
     let rec = sqlx::query!(
         r#"
         SELECT id, password_hash
@@ -56,42 +472,438 @@ pub async fn get_user(pool: &SqlitePool, username: &str) -> Result<(i64, String)
 
 /// Add a message into the messages table.
 /// Each message is associated to its auther by using user id.
-pub async fn add_message(pool: &SqlitePool, user_id: &i64, contents: &str) -> Result<()> {
-    sqlx::query!(
+/// The time when the message was sent is taken from the clock.
+/// The id of the new message is returned. Too long content is reported as ContentTooLong.
+pub async fn add_message(pool: &SqlitePool, user_id: &i64, contents: &str, kind: MessageKind, clock: &impl Clock) -> Result<i64, AddMessageError> {
+    add_tagged_message(pool, user_id, contents, kind, None, clock).await
+}
+
+
+/// Add a message into the messages table like add_message, together with the tag the client chose for it, if any.
+pub async fn add_tagged_message(pool: &SqlitePool, user_id: &i64, contents: &str, kind: MessageKind, tag: Option<i64>, clock: &impl Clock) -> Result<i64, AddMessageError> {
+    let _timer = time_operation("add_message");
+    let kind = kind.as_str();
+    let sent_at = format_timestamp(clock.now());
+    let rec = sqlx::query!(
         r#"
-        INSERT INTO messages (user_id, content)
-        VALUES (?, ?)
+        INSERT INTO messages (user_id, content, kind, tag, sent_at)
+        VALUES (?, ?, ?, ?, ?)
+        RETURNING id AS "id!"
         "#,
         user_id,
-        contents
+        contents,
+        kind,
+        tag,
+        sent_at
     )
-    .execute(pool)
+    .fetch_one(pool)
     .await
-    .context("Failed to add message into database.")?;
+    .map_err(|e| match e {
+        e if is_check_violation(&e, "content_length") => AddMessageError::ContentTooLong,
+        e => AddMessageError::Failed(e)
+    })?;
     
+    Ok(rec.id)
+}
+
+
+/// Add several messages (and their attachments) into the database in one transaction.
+/// Either all of them are saved or none of them is.
+pub async fn add_messages_batch(pool: &SqlitePool, messages: &[NewMessage]) -> Result<()> {
+    let _timer = time_operation("add_messages_batch");
+    let mut transaction = pool.begin().await.context("Failed to begin a transaction.")?;
+    for message in messages {
+        let kind = message.kind.as_str();
+        let sent_at = format_timestamp(message.sent_at);
+        let rec = sqlx::query!(
+            r#"
+            INSERT INTO messages (user_id, content, kind, tag, sent_at)
+            VALUES (?, ?, ?, ?, ?)
+            RETURNING id
+            "#,
+            message.user_id,
+            message.content,
+            kind,
+            message.tag,
+            sent_at
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .context("Failed to add message into database.")?;
+
+        let message_id = rec.id.ok_or(anyhow!("The value of id not returned from database."))?;
+        if let Some(attachment) = &message.attachment {
+            sqlx::query!(
+                r#"
+                INSERT INTO attachments (message_id, filename, path, mime)
+                VALUES (?, ?, ?, ?)
+                "#,
+                message_id,
+                attachment.filename,
+                attachment.path,
+                attachment.mime
+            )
+            .execute(&mut *transaction)
+            .await
+            .context("Failed to add attachment into database.")?;
+        }
+    }
+    transaction.commit().await.context("Failed to commit a batch of messages.")?;
+
     Ok(())
 }
 
 
-pub async fn get_messages_by_user(pool: &SqlitePool, user_id: &i64) -> Result<Vec<String>> {
-    let rec= sqlx::query!(
+/// Add an attachment entry into the 'attachments' table.
+/// The filename is the original name of the file, the path leads to its copy on disk.
+pub async fn add_attachment(pool: &SqlitePool, message_id: &i64, filename: &str, path: &str, mime: &str) -> Result<i64> {
+    let _timer = time_operation("add_attachment");
+    let rec = sqlx::query!(
         r#"
-        SELECT content
+        INSERT INTO attachments (message_id, filename, path, mime)
+        VALUES (?, ?, ?, ?)
+        RETURNING id
+        "#,
+        message_id,
+        filename,
+        path,
+        mime
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to add attachment into database.")?;
+
+    let id = rec.id.ok_or(anyhow!("The value of id not returned from database."))?;
+    Ok(id)
+}
+
+
+/// Get an attachment entry with specified id from the 'attachments' table.
+/// None is returned if there is no such attachment.
+pub async fn get_attachment(pool: &SqlitePool, attachment_id: &i64) -> Result<Option<Attachment>> {
+    let _timer = time_operation("get_attachment");
+    let rec = sqlx::query_as!(
+        Attachment,
+        r#"
+        SELECT id, message_id, filename, path, mime
+        FROM attachments
+        WHERE id = ?
+        "#,
+        attachment_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get an attachment.")?;
+
+    Ok(rec)
+}
+
+
+/// Get all messages sent by a user with specified id, with their ids, kinds, tags and the times when they were sent.
+/// The messages are listed in the given order.
+pub async fn get_messages_by_user(pool: &SqlitePool, user_id: &i64, order: MessageOrder) -> Result<Vec<MessageDto>> {
+    let _timer = time_operation("get_messages_by_user");
+    let mut messages = sqlx::query_as!(
+        MessageDto,
+        r#"
+        SELECT id AS "id!", content, kind AS "kind: MessageKind", tag, sent_at AS "sent_at: String"
         FROM messages
         WHERE user_id = ?
+        ORDER BY sent_at DESC, id DESC
         "#,
         user_id
     )
     .fetch_all(pool)
     .await
     .context("Failed to get messages.")?;
+    if order == MessageOrder::Asc {
+        messages.reverse();
+    }
+    
+    Ok(messages)
+}
+
+
+/// Get at most the given number of the most recently saved messages of all users.
+/// The messages are ordered from the oldest to the newest.
+pub async fn get_recent_messages(pool: &SqlitePool, limit: u32) -> Result<Vec<RecentMessage>> {
+    let _timer = time_operation("get_recent_messages");
+    let mut messages = sqlx::query_as!(
+        RecentMessage,
+        r#"
+        SELECT users.username, messages.content, messages.tag, messages.sent_at AS "sent_at: String"
+        FROM messages
+        JOIN users ON users.id = messages.user_id
+        ORDER BY messages.id DESC
+        LIMIT ?
+        "#,
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to get recent messages.")?;
+    messages.reverse();
+
+    Ok(messages)
+}
+
+
+/// Get messages of one kind sent by a user with specified id, listed in the given order.
+pub async fn get_messages_by_kind(pool: &SqlitePool, user_id: &i64, kind: MessageKind, order: MessageOrder) -> Result<Vec<MessageDto>> {
+    let _timer = time_operation("get_messages_by_kind");
+    let kind = kind.as_str();
+    let mut messages = sqlx::query_as!(
+        MessageDto,
+        r#"
+        SELECT id AS "id!", content, kind AS "kind: MessageKind", tag, sent_at AS "sent_at: String"
+        FROM messages
+        WHERE user_id = ? AND kind = ?
+        ORDER BY sent_at DESC, id DESC
+        "#,
+        user_id,
+        kind
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to get messages by kind.")?;
+    if order == MessageOrder::Asc {
+        messages.reverse();
+    }
     
-    let messages: Vec<String> = rec.into_iter().map(|row| row.content).collect();
     Ok(messages)
 }
 
 
+/// Get at most limit messages of all users that come after the cursor in the given order, together with the cursor of the next page.
+/// In the ascending order, the messages have ids greater than the cursor and the oldest one is first.
+/// In the descending order, they have ids less than the cursor and the newest one is first.
+/// Without a cursor, the first page starts with the oldest or the newest message.
+/// Unlike pages given by an offset, a page is found by the index of ids, so it is fast however far into the messages it is.
+/// Going through all messages page by page keeps only one page in memory.
+pub async fn get_messages_page(pool: &SqlitePool, after_id: Option<i64>, limit: u32, order: MessageOrder) -> Result<MessagesPage> {
+    let _timer = time_operation("get_messages_page");
+    // One more message than asked for is fetched to find out if there is a next page.
+    let fetched = i64::from(limit) + 1;
+    let mut messages = match order {
+        MessageOrder::Asc => {
+            let after_id = after_id.unwrap_or(0);
+            sqlx::query_as!(
+                ExportedMessage,
+                r#"
+                SELECT messages.id AS "id!", user_id, users.display_name AS "display_name?", content, kind AS "kind: MessageKind", tag, sent_at AS "sent_at: String"
+                FROM messages
+                JOIN users ON users.id = messages.user_id
+                WHERE messages.id > ?
+                ORDER BY messages.id
+                LIMIT ?
+                "#,
+                after_id,
+                fetched
+            )
+            .fetch_all(pool)
+            .await
+        },
+        MessageOrder::Desc => {
+            let after_id = after_id.unwrap_or(i64::MAX);
+            sqlx::query_as!(
+                ExportedMessage,
+                r#"
+                SELECT messages.id AS "id!", user_id, users.display_name AS "display_name?", content, kind AS "kind: MessageKind", tag, sent_at AS "sent_at: String"
+                FROM messages
+                JOIN users ON users.id = messages.user_id
+                WHERE messages.id < ?
+                ORDER BY messages.id DESC
+                LIMIT ?
+                "#,
+                after_id,
+                fetched
+            )
+            .fetch_all(pool)
+            .await
+        }
+    }
+    .context("Failed to get a page of messages.")?;
+    let next_cursor = if messages.len() > limit as usize {
+        messages.truncate(limit as usize);
+        messages.last().map(|message| message.id)
+    } else {
+        None
+    };
+
+    Ok(MessagesPage { messages, next_cursor })
+}
+
+
+/// Import users and messages of a backup in one transaction, so either all of them are imported or none of them.
+/// Ids are kept, so messages stay with their authors. Password hashes are inserted as they are, without hashing them again.
+/// A user without a password hash gets an empty one and cannot log in until the password is reset.
+/// A user whose id or username is already in the database, and a message whose id is, are handled according to the mode.
+/// Replacing works by ids, so a user whose username belongs to another user in the database makes the whole import fail.
+pub async fn import_backup(pool: &SqlitePool, backup: &Backup, mode: ImportMode) -> Result<ImportSummary, ImportError> {
+    let _timer = time_operation("import_backup");
+    validate_backup(backup)?;
+    let mut transaction = pool.begin().await.map_err(ImportError::Failed)?;
+    let mut summary = ImportSummary { users_imported: 0, messages_imported: 0 };
+
+    for user in &backup.users {
+        let result = match mode {
+            ImportMode::Skip => sqlx::query!(
+                r#"
+                INSERT INTO users (id, username, password_hash, display_name)
+                VALUES (?, ?, COALESCE(?, ''), ?)
+                ON CONFLICT DO NOTHING
+                "#,
+                user.id,
+                user.username,
+                user.password_hash,
+                user.display_name
+            )
+            .execute(&mut *transaction)
+            .await,
+            ImportMode::Fail => sqlx::query!(
+                r#"
+                INSERT INTO users (id, username, password_hash, display_name)
+                VALUES (?, ?, COALESCE(?, ''), ?)
+                "#,
+                user.id,
+                user.username,
+                user.password_hash,
+                user.display_name
+            )
+            .execute(&mut *transaction)
+            .await,
+            // A user without a password hash in the backup keeps the one in the database.
+            ImportMode::Replace => {
+                let owner = sqlx::query!(
+                    r#"
+                    SELECT id AS "id!"
+                    FROM users
+                    WHERE username = ? AND id <> ?
+                    "#,
+                    user.username,
+                    user.id
+                )
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(ImportError::Failed)?;
+                if let Some(owner) = owner {
+                    return Err(ImportError::UsernameTaken { id: user.id, username: user.username.clone(), owner_id: owner.id });
+                }
+                sqlx::query!(
+                    r#"
+                    INSERT INTO users (id, username, password_hash, display_name)
+                    VALUES (?, ?, COALESCE(?, ''), ?)
+                    ON CONFLICT (id) DO UPDATE SET username = excluded.username, password_hash = COALESCE(?, password_hash), display_name = excluded.display_name
+                    "#,
+                    user.id,
+                    user.username,
+                    user.password_hash,
+                    user.display_name,
+                    user.password_hash
+                )
+                .execute(&mut *transaction)
+                .await
+            }
+        };
+        let result = result.map_err(|e| to_import_error(e, format!("User {} ('{}')", user.id, user.username)))?;
+        summary.users_imported += result.rows_affected();
+    }
+
+    for message in &backup.messages {
+        let kind = message.kind.as_str();
+        let result = match mode {
+            ImportMode::Skip => sqlx::query!(
+                r#"
+                INSERT INTO messages (id, user_id, content, kind, tag, sent_at)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT DO NOTHING
+                "#,
+                message.id,
+                message.user_id,
+                message.content,
+                kind,
+                message.tag,
+                message.sent_at
+            )
+            .execute(&mut *transaction)
+            .await,
+            ImportMode::Fail => sqlx::query!(
+                r#"
+                INSERT INTO messages (id, user_id, content, kind, tag, sent_at)
+                VALUES (?, ?, ?, ?, ?, ?)
+                "#,
+                message.id,
+                message.user_id,
+                message.content,
+                kind,
+                message.tag,
+                message.sent_at
+            )
+            .execute(&mut *transaction)
+            .await,
+            ImportMode::Replace => sqlx::query!(
+                r#"
+                INSERT INTO messages (id, user_id, content, kind, tag, sent_at)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT (id) DO UPDATE SET user_id = excluded.user_id, content = excluded.content, kind = excluded.kind, tag = excluded.tag, sent_at = excluded.sent_at
+                "#,
+                message.id,
+                message.user_id,
+                message.content,
+                kind,
+                message.tag,
+                message.sent_at
+            )
+            .execute(&mut *transaction)
+            .await
+        };
+        let result = result.map_err(|e| to_import_error(e, format!("Message {}", message.id)))?;
+        summary.messages_imported += result.rows_affected();
+    }
+    transaction.commit().await.map_err(ImportError::Failed)?;
+
+    Ok(summary)
+}
+
+
+/// Check the values of a backup that the database would refuse without saying clearly which one is wrong.
+fn validate_backup(backup: &Backup) -> Result<(), ImportError> {
+    for user in &backup.users {
+        if user.username.chars().count() > MAX_USERNAME_LENGTH {
+            return Err(ImportError::Invalid(format!("Username of user {} has more than {} characters.", user.id, MAX_USERNAME_LENGTH)));
+        }
+        if let Some(display_name) = &user.display_name {
+            if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH || !is_display_name_valid(display_name) {
+                return Err(ImportError::Invalid(format!("Display name of user {} is not valid.", user.id)));
+            }
+        }
+    }
+    for message in &backup.messages {
+        if message.content.chars().count() > MAX_CONTENT_LENGTH {
+            return Err(ImportError::Invalid(format!("Content of message {} has more than {} characters.", message.id, MAX_CONTENT_LENGTH)));
+        }
+        if NaiveDateTime::parse_from_str(&message.sent_at, TIMESTAMP_FORMAT).is_err() {
+            return Err(ImportError::Invalid(format!("Message {} was sent at '{}', which is not in the YYYY-MM-DD HH:MM:SS format.", message.id, message.sent_at)));
+        }
+    }
+    Ok(())
+}
+
+
+/// Turn an error of inserting a row of a backup into an import error. The row is described for the user.
+fn to_import_error(error: sqlx::Error, row: String) -> ImportError {
+    match error {
+        sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => ImportError::Conflict(row),
+        sqlx::Error::Database(ref db_error) if db_error.is_foreign_key_violation() => {
+            ImportError::Invalid(format!("{} belongs to a user that is neither in the backup nor in the database.", row))
+        },
+        e => ImportError::Failed(e)
+    }
+}
+
+
 pub async fn delete_messages_by_user(pool: &SqlitePool, user_id: &i64) -> Result<()> {
+    let _timer = time_operation("delete_messages_by_user");
     sqlx::query!(
         r#"
         DELETE FROM messages
@@ -107,26 +919,292 @@ pub async fn delete_messages_by_user(pool: &SqlitePool, user_id: &i64) -> Result
 }
 
 
+/// Run a closure with a new transaction. The transaction is committed if the closure succeeds and rolled back if it fails.
+/// Errors of beginning and committing the transaction are converted into the error type of the closure.
+/// The closure returns a boxed future, e.g. `|transaction| Box::pin(async move { ... })`, so that the future can borrow the transaction.
+pub async fn with_transaction<T, E, F>(pool: &SqlitePool, f: F) -> Result<T, E>
+where
+    F: for<'c> FnOnce(&'c mut Transaction<'static, Sqlite>) -> TransactionFuture<'c, T, E>,
+    E: From<sqlx::Error>
+{
+    let mut transaction = pool.begin().await?;
+    match f(&mut transaction).await {
+        Ok(value) => {
+            transaction.commit().await?;
+            Ok(value)
+        },
+        Err(e) => {
+            // A transaction that is not committed is rolled back even if this fails, at the latest when it is dropped.
+            let _ = transaction.rollback().await;
+            Err(e)
+        }
+    }
+}
+
+
+/// Delete a user with specified id together with all messages of the user.
+/// The messages are deleted explicitly, so that the result does not depend on foreign keys being enforced.
+/// Both deletions are done in one transaction, so a failure never leaves a user without some messages.
 pub async fn delete_user(pool: &SqlitePool, user_id: &i64) -> Result<()> {
+    let _timer = time_operation("delete_user");
+    let user_id = *user_id;
+    with_transaction(pool, |transaction| Box::pin(async move {
+        sqlx::query!(
+            r#"
+            DELETE FROM messages
+            WHERE user_id = ?
+            "#,
+            user_id
+        )
+        .execute(&mut **transaction)
+        .await
+        .context("Failed to delete messages of the user.")?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM users
+            WHERE id = ?
+            "#,
+            user_id
+        )
+        .execute(&mut **transaction)
+        .await
+        .context("Failed to delete.")?;
+
+        Ok(())
+    })).await
+}
+
+
+/// Change the username of a user with specified id.
+/// If the new username already belongs to another user, the UNIQUE constraint fails and NameTaken is returned.
+pub async fn rename_user(pool: &SqlitePool, user_id: &i64, new_username: &str) -> Result<(), RenameUserError> {
+    let _timer = time_operation("rename_user");
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET username = ?
+        WHERE id = ?
+        "#,
+        new_username,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => {
+            RenameUserError::NameTaken(new_username.to_string())
+        },
+        e if is_check_violation(&e, "username_length") => RenameUserError::NameTooLong,
+        e => RenameUserError::Failed(e)
+    })?;
+
+    if result.rows_affected() == 0 {
+        return Err(RenameUserError::UserNotFound(*user_id));
+    }
+    Ok(())
+}
+
+
+/// Check that a display name is not empty and that it has only letters, digits, spaces, '_', '-' and '.'.
+/// Leading and trailing spaces are not allowed, so that two display names cannot look the same.
+/// The length is checked by the database.
+pub fn is_display_name_valid(display_name: &str) -> bool {
+    !display_name.is_empty()
+        && display_name.trim() == display_name
+        && display_name.chars().all(|c| c.is_alphanumeric() || matches!(c, ' ' | '_' | '-' | '.'))
+}
+
+
+/// Set the name under which the messages of a user with specified id are shown to other users.
+/// So that nobody can pose as another user, a display name that is the username or the display name
+/// of another user, regardless of case, is refused. The username is still used for logging in.
+pub async fn set_display_name(pool: &SqlitePool, user_id: &i64, display_name: &str) -> Result<(), SetDisplayNameError> {
+    let _timer = time_operation("set_display_name");
+    if !is_display_name_valid(display_name) {
+        return Err(SetDisplayNameError::InvalidCharacters);
+    }
+    // The name is checked in the same statement that sets it, so that two users cannot take it at once.
+    let result = sqlx::query!(
+        r#"
+        UPDATE users
+        SET display_name = ?
+        WHERE id = ? AND NOT EXISTS (
+            SELECT 1
+            FROM users AS others
+            WHERE others.id <> ? AND (lower(others.username) = lower(?) OR lower(others.display_name) = lower(?))
+        )
+        "#,
+        display_name,
+        user_id,
+        user_id,
+        display_name,
+        display_name
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| match e {
+        e if is_check_violation(&e, "display_name_length") => SetDisplayNameError::NameTooLong,
+        e => SetDisplayNameError::Failed(e)
+    })?;
+
+    if result.rows_affected() == 0 {
+        let rec = sqlx::query!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM users
+                WHERE id = ?
+            ) AS "exists!: bool"
+            "#,
+            user_id
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(SetDisplayNameError::Failed)?;
+        if rec.exists {
+            return Err(SetDisplayNameError::NameTaken(display_name.to_string()));
+        }
+        return Err(SetDisplayNameError::UserNotFound(*user_id));
+    }
+    Ok(())
+}
+
+
+/// Get the name under which the messages of a user with specified id are shown to other users.
+/// It is the username, unless the user has set a display name.
+pub async fn get_display_name(pool: &SqlitePool, user_id: &i64) -> Result<String> {
+    let _timer = time_operation("get_display_name");
+    let rec = sqlx::query!(
+        r#"
+        SELECT COALESCE(display_name, username) AS "display_name!: String"
+        FROM users
+        WHERE id = ?
+        "#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to get the display name of a user from a database.")?;
+
+    Ok(rec.display_name)
+}
+
+
+/// Replace the password hash of a user with specified id.
+pub async fn update_password_hash(pool: &SqlitePool, user_id: &i64, password_hash: &str) -> Result<()> {
+    let _timer = time_operation("update_password_hash");
     sqlx::query!(
         r#"
-        DELETE FROM users
+        UPDATE users
+        SET password_hash = ?
         WHERE id = ?
         "#,
+        password_hash,
         user_id
     )
     .execute(pool)
     .await
-    .context("Failed to delete.")?;
-    
+    .context("Failed to update password hash.")?;
+
     Ok(())
 }
 
 
-pub async fn get_all_users(pool: &SqlitePool) -> Result<Vec<(i64, String)>> {
-    let rec= sqlx::query!(
+/// Get the username of a user with specified id. If there is no such user, None is returned.
+pub async fn get_username(pool: &SqlitePool, user_id: &i64) -> Result<Option<String>> {
+    let _timer = time_operation("get_username");
+    let rec = sqlx::query!(
         r#"
-        SELECT id, username
+        SELECT username
+        FROM users
+        WHERE id = ?
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to get username.")?;
+
+    Ok(rec.map(|rec| rec.username))
+}
+
+
+/// Ban a user from logging in.
+/// If expires_at is None, the ban is permanent. Banning a banned user replaces the ban.
+/// The ban is kept by the user id, so it stays when the user is renamed and it is removed together with the user.
+/// An expiry that is not a storable timestamp is refused.
+pub async fn add_ban(pool: &SqlitePool, user_id: &i64, expires_at: Option<SystemTime>) -> Result<()> {
+    let _timer = time_operation("add_ban");
+    if expires_at.is_some_and(|expires_at| !is_storable_timestamp(expires_at)) {
+        return Err(anyhow!("The ban would expire after the year 9999."));
+    }
+    let expires_at = expires_at.map(format_timestamp);
+    sqlx::query!(
+        r#"
+        INSERT INTO banned_users (user_id, expires_at)
+        VALUES (?, ?)
+        ON CONFLICT (user_id) DO UPDATE SET expires_at = excluded.expires_at
+        "#,
+        user_id,
+        expires_at
+    )
+    .execute(pool)
+    .await
+    .context("Failed to add a ban.")?;
+
+    Ok(())
+}
+
+
+/// Lift the ban of a user. Return false if the user was not banned.
+pub async fn remove_ban(pool: &SqlitePool, user_id: &i64) -> Result<bool> {
+    let _timer = time_operation("remove_ban");
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM banned_users
+        WHERE user_id = ?
+        "#,
+        user_id
+    )
+    .execute(pool)
+    .await
+    .context("Failed to remove a ban.")?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+
+/// Check if a user is banned at the time taken from the clock.
+/// Expired bans are kept in the table, but they are ignored.
+pub async fn is_banned(pool: &SqlitePool, user_id: &i64, clock: &impl Clock) -> Result<bool> {
+    let _timer = time_operation("is_banned");
+    let now = format_timestamp(clock.now());
+    let rec = sqlx::query!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM banned_users
+            WHERE user_id = ? AND (expires_at IS NULL OR expires_at > ?)
+        ) AS "banned!: bool"
+        "#,
+        user_id,
+        now
+    )
+    .fetch_one(pool)
+    .await
+    .context("Failed to check a ban.")?;
+
+    Ok(rec.banned)
+}
+
+
+pub async fn get_all_users(pool: &SqlitePool) -> Result<Vec<UserDto>> {
+    let _timer = time_operation("get_all_users");
+    let users = sqlx::query_as!(
+        UserDto,
+        r#"
+        SELECT id AS "id!", username, display_name
         FROM users
         "#
     )
@@ -134,11 +1212,69 @@ pub async fn get_all_users(pool: &SqlitePool) -> Result<Vec<(i64, String)>> {
     .await
     .context("Failed to get all users.")?;
     
-    let users: Vec<(i64, String)> = rec.into_iter().map(
-        |row| {
-            let id = row.id.ok_or(anyhow!("A user entry has id null."))?;
-            Ok((id, row.username))
-        }
-    ).collect::<Result<Vec<(i64, String)>>>().context("Something wrong with extracting data from users table.")?;
     Ok(users)
 }
+
+
+/// Get all users with the time when each of them sent the last message, ordered by id.
+pub async fn get_users_with_last_activity(pool: &SqlitePool) -> Result<Vec<UserActivityDto>> {
+    let _timer = time_operation("get_users_with_last_activity");
+    let users = sqlx::query_as!(
+        UserActivityDto,
+        r#"
+        SELECT users.id AS "id!", users.username AS "username!", MAX(messages.sent_at) AS "last_message_at?: String"
+        FROM users
+        LEFT JOIN messages ON messages.user_id = users.id
+        GROUP BY users.id
+        ORDER BY users.id
+        "#
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to get users with their last activity.")?;
+
+    Ok(users)
+}
+
+
+/// Return free pages of the database file to the file system, e.g. after many messages were deleted.
+/// The first vacuum switches the database to the incremental auto-vacuum mode, which needs one full VACUUM.
+/// Saving of messages has to wait until the full VACUUM is done.
+/// Later vacuums free the pages in steps of VACUUM_STEP_PAGES pages, so that messages can be saved between the steps.
+pub async fn vacuum(pool: &SqlitePool) -> Result<()> {
+    let _timer = time_operation("vacuum");
+    let mut connection = pool.acquire().await.context("Failed to get a connection for vacuum.")?;
+    let auto_vacuum: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+        .fetch_one(&mut *connection)
+        .await
+        .context("Failed to get the auto-vacuum mode.")?;
+    if auto_vacuum != INCREMENTAL_AUTO_VACUUM {
+        // The mode is changed only after the VACUUM that follows, which must run on the same connection.
+        sqlx::query("PRAGMA auto_vacuum = INCREMENTAL")
+            .execute(&mut *connection)
+            .await
+            .context("Failed to set the incremental auto-vacuum mode.")?;
+        sqlx::query("VACUUM")
+            .execute(&mut *connection)
+            .await
+            .context("Failed to vacuum the database.")?;
+        return Ok(());
+    }
+
+    let free_pages: i64 = sqlx::query_scalar("PRAGMA freelist_count")
+        .fetch_one(&mut *connection)
+        .await
+        .context("Failed to get the number of free pages.")?;
+    // The connection is returned to the pool after each step, so that other queries can use it.
+    drop(connection);
+    // Pages freed while the vacuum is running are left for the next one.
+    for _ in 0..(free_pages + VACUUM_STEP_PAGES - 1) / VACUUM_STEP_PAGES {
+        sqlx::query(&format!("PRAGMA incremental_vacuum({})", VACUUM_STEP_PAGES))
+            .execute(pool)
+            .await
+            .context("Failed to vacuum the database incrementally.")?;
+        tokio::task::yield_now().await;
+    }
+
+    Ok(())
+}
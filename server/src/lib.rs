@@ -3,13 +3,47 @@ pub mod db;
 pub mod password_hashing {
     use anyhow::{anyhow, Result};
     use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
-    use argon2::Argon2;
+    use argon2::{Algorithm, Argon2, Params, Version};
     use rand::rngs::OsRng;
 
+    /// Settings for hashing and verifying passwords.
+    /// The pepper is an application-wide secret. It is not stored in the hash, so a leaked
+    /// database alone is not enough to crack the passwords.
+    /// The params specify memory, time and parallelism cost of hashing. They are stored in the hash,
+    /// so passwords hashed with different params can still be verified.
+    #[derive(Clone, Debug, Default)]
+    pub struct PasswordHashingOptions {
+        pub pepper: Option<String>,
+        pub params: Params,
+    }
+
+    /// Create an argon2 context. If a pepper is given, it is used as the argon2 secret.
+    fn create_argon2(options: &PasswordHashingOptions) -> Result<Argon2<'_>> {
+        match &options.pepper {
+            Some(pepper) => Argon2::new_with_secret(
+                pepper.as_bytes(),
+                Algorithm::default(),
+                Version::default(),
+                options.params.clone(),
+            )
+            .map_err(|e| anyhow!("Failed to use password pepper: {}", e)),
+            None => Ok(Argon2::new(
+                Algorithm::default(),
+                Version::default(),
+                options.params.clone(),
+            )),
+        }
+    }
+
+    /// Check if a password is strong enough to be set. Only empty passwords are refused.
+    pub fn is_password_strong(password: &str) -> bool {
+        !password.is_empty()
+    }
+
     /// Hash password using argon2 and return the hash.
-    pub async fn hash_password(password: &String) -> Result<String> {
+    pub async fn hash_password(password: &str, options: &PasswordHashingOptions) -> Result<String> {
         let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
+        let argon2 = create_argon2(options)?;
         let password_hash = match argon2.hash_password(password.as_bytes(), &salt) {
             Ok(password_hash) => password_hash.to_string(),
             Err(e) => {
@@ -20,75 +54,932 @@ pub mod password_hashing {
     }
 
     /// Verify a password against some hashed password.
-    pub async fn verify_password(password: &String, password_hash: &String) -> Result<()> {
+    /// The pepper must be the same as the one used when the password was hashed.
+    pub async fn verify_password(
+        password: &str,
+        password_hash: &str,
+        options: &PasswordHashingOptions,
+    ) -> Result<()> {
         let parsed_hash = match PasswordHash::new(password_hash) {
             Ok(parsed_hash) => parsed_hash,
             Err(e) => {
                 return Err(anyhow!("Failed to parse hashed password: {}", e));
             }
         };
-        match Argon2::default().verify_password(password.as_bytes(), &parsed_hash) {
+        match create_argon2(options)?.verify_password(password.as_bytes(), &parsed_hash) {
             Ok(_) => Ok(()),
             Err(e) => {
-                return Err(anyhow!("Failed to verify password: {}", e));
+                Err(anyhow!("Failed to verify password: {}", e))
             }
         }
     }
 }
 
-pub mod http_server {
+pub mod auth {
     use anyhow::Result;
+    use log::{error, info};
+    use shared::AuthError;
+    use sqlx::SqlitePool;
+
+    use crate::db::{self, AddUserError};
+    use crate::password_hashing::{
+        hash_password, is_password_strong, verify_password, PasswordHashingOptions,
+    };
+
+    /// Register a user with a password and return the id of the new user.
+    /// An empty password, a taken username and a too long username are refused.
+    /// Failures that are not the fault of the user are reported as ServerError.
+    pub async fn register_user(
+        pool: &SqlitePool,
+        password_hashing: &PasswordHashingOptions,
+        username: &str,
+        password: &str,
+    ) -> Result<i64, AuthError> {
+        if !is_password_strong(password) {
+            info!("Failed to register user: the password is empty.");
+            return Err(AuthError::WeakPassword);
+        }
+        let password_hash = hash_password(password, password_hashing)
+            .await
+            .map_err(|e| {
+                error!("Failed to hash password: {}", e);
+                AuthError::ServerError
+            })?;
+        match db::add_user(pool, username, &password_hash).await {
+            Ok(user_id) => {
+                info!("Successful registration of a user.");
+                Ok(user_id)
+            }
+            Err(AddUserError::NameTooLong) => {
+                info!("Failed to register user: the username is too long.");
+                Err(AuthError::UsernameTooLong)
+            }
+            Err(e @ AddUserError::NameTaken(_)) => {
+                info!("Failed to register user: {}", e);
+                Err(AuthError::UsernameTaken)
+            }
+            Err(e) => {
+                error!("Failed to register user: {}", e);
+                Err(AuthError::ServerError)
+            }
+        }
+    }
+
+    /// Check the password of a user and return the id of the user.
+    /// An unknown username is reported the same way as a wrong password, i.e. as WrongCredentials.
+    pub async fn authenticate(
+        pool: &SqlitePool,
+        password_hashing: &PasswordHashingOptions,
+        username: &str,
+        password: &str,
+    ) -> Result<i64, AuthError> {
+        let (user_id, password_hash) = match db::get_user(pool, username).await {
+            Ok((user_id, password_hash)) => (user_id, password_hash),
+            Err(e) if matches!(e.downcast_ref(), Some(sqlx::Error::RowNotFound)) => {
+                info!("Login not successful: {}", e);
+                return Err(AuthError::WrongCredentials);
+            }
+            Err(e) => {
+                error!("Login not successful: {}", e);
+                return Err(AuthError::ServerError);
+            }
+        };
+        match verify_user_password(pool, password_hashing, &user_id, password, &password_hash).await
+        {
+            Ok(_) => {
+                info!("Login successful.");
+                Ok(user_id)
+            }
+            Err(e) => {
+                info!("Login not successful: {}", e);
+                Err(AuthError::WrongCredentials)
+            }
+        }
+    }
+
+    /// Verify the password of a user.
+    /// If a pepper is set, hashes created before the pepper was introduced are verified without it.
+    /// Such a hash is then replaced by a hash with the pepper, so users are migrated as they log in.
+    async fn verify_user_password(
+        pool: &SqlitePool,
+        password_hashing: &PasswordHashingOptions,
+        user_id: &i64,
+        password: &str,
+        password_hash: &str,
+    ) -> Result<()> {
+        let verify_result = verify_password(password, password_hash, password_hashing).await;
+        if verify_result.is_ok() || password_hashing.pepper.is_none() {
+            return verify_result;
+        }
+        verify_password(password, password_hash, &PasswordHashingOptions::default()).await?;
+
+        info!(
+            "Replacing password hash of user {} by a hash with pepper.",
+            user_id
+        );
+        let new_password_hash = match hash_password(password, password_hashing).await {
+            Ok(new_password_hash) => new_password_hash,
+            Err(e) => {
+                error!("Failed to hash password with pepper: {}", e);
+                return Ok(());
+            }
+        };
+        if let Err(e) = db::update_password_hash(pool, user_id, &new_password_hash).await {
+            error!("Failed to replace password hash: {}", e);
+        }
+        Ok(())
+    }
+}
+
+pub mod connections {
+    use anyhow::{anyhow, Context, Result};
+    use log::{error, info};
+    use prometheus::Gauge;
+    use serde::Serialize;
+    use shared::{
+        encode_message, encode_signed_message, send_bytes, Compression, Direction, FrameSigner,
+        MessageKey, MessageType, ReadReceipts,
+    };
+    use std::collections::{HashMap, VecDeque};
+    use std::fmt;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::io::AsyncWrite;
+    use tokio::sync::mpsc::{self, error::TrySendError};
+    use tokio::sync::{Mutex, Notify};
+    use tokio::time::timeout;
+
+    use crate::sessions::SessionId;
+
+    /// The maximum number of broadcast failures that are kept. Older failures are dropped.
+    pub const MAX_BROADCAST_FAILURES: usize = 100;
+
+    /// The default number of messages that can wait to be written to one client.
+    pub const DEFAULT_OUTGOING_QUEUE_CAPACITY: usize = 256;
+
+    /// The longest time spent telling a client that cannot keep up why it is disconnected.
+    const DISCONNECT_NOTICE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Address of a connected client.
+    /// TCP clients are identified by their socket address. Clients connected through a Unix domain
+    /// socket usually have no address, so the server numbers them instead.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum ClientAddress {
+        Tcp(SocketAddr),
+        Unix(u64),
+    }
+
+    impl fmt::Display for ClientAddress {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ClientAddress::Tcp(address) => write!(f, "{}", address),
+                ClientAddress::Unix(number) => write!(f, "unix#{}", number),
+            }
+        }
+    }
+
+    impl From<SocketAddr> for ClientAddress {
+        fn from(address: SocketAddr) -> ClientAddress {
+            ClientAddress::Tcp(address)
+        }
+    }
+
+    /// Writing half of a connection to one client, either over TCP or over a Unix domain socket.
+    pub type ClientWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+    /// Writer to one client together with the compression and the signing of frames agreed with it.
+    /// Frames are neither compressed nor signed until they are set after a successful authentication.
+    /// Clients that do not take part in read receipts get identified messages without their ids.
+    /// After a successful authentication, the writer also knows the user id and the session id of the client.
+    /// Frames are put in a bounded queue and written by a separate task, so that a slow client does not hold up
+    /// the others. A client whose queue is full cannot keep up and is disconnected.
+    pub struct FrameWriter {
+        frames: mpsc::Sender<Vec<u8>>,
+        overflow: Arc<Notify>,
+        handler_overflow: Arc<Notify>,
+        overflowed: bool,
+        compression: Compression,
+        signer: Option<FrameSigner>,
+        read_receipts: ReadReceipts,
+        session: Option<(i64, SessionId)>,
+    }
+
+    impl FrameWriter {
+        /// Create a writer that sends frames without compression, signatures and message ids.
+        /// At most capacity frames can wait in its queue. It must be called within a tokio runtime.
+        pub fn new(writer: ClientWriter, capacity: usize) -> FrameWriter {
+            let (frames, frames_receiver) = mpsc::channel(capacity);
+            let overflow = Arc::new(Notify::new());
+            tokio::spawn(write_frames(writer, frames_receiver, Arc::clone(&overflow)));
+            FrameWriter {
+                frames,
+                overflow,
+                handler_overflow: Arc::new(Notify::new()),
+                overflowed: false,
+                compression: Compression::Disabled,
+                signer: None,
+                read_receipts: ReadReceipts::Disabled,
+                session: None,
+            }
+        }
+
+        /// Change the compression of all following frames.
+        pub fn set_compression(&mut self, compression: Compression) {
+            self.compression = compression;
+        }
+
+        /// Change the key all following frames are signed with. Without a key, they are not signed.
+        /// The first signed frame has the sequence number 0, the following ones count up from it.
+        pub fn set_message_key(&mut self, message_key: Option<MessageKey>) {
+            self.signer = message_key.map(|key| FrameSigner::new(key, Direction::ServerToClient));
+        }
+
+        /// Change whether the following identified messages are sent with their ids.
+        pub fn set_read_receipts(&mut self, read_receipts: ReadReceipts) {
+            self.read_receipts = read_receipts;
+        }
+
+        /// Remember the user id and the session id of an authenticated client.
+        pub fn set_session(&mut self, user_id: i64, session_id: SessionId) {
+            self.session = Some((user_id, session_id));
+        }
+
+        /// Get the user id and the session id of the client, if it is authenticated.
+        pub fn session(&self) -> Option<&(i64, SessionId)> {
+            self.session.as_ref()
+        }
+
+        /// Queue a message with the compression and the signing agreed with the client.
+        /// If the queue is full, the client is disconnected and no more messages are accepted.
+        pub async fn send(&mut self, message: &MessageType) -> Result<()> {
+            if self.is_disconnected() {
+                return Err(anyhow!("The client is disconnected."));
+            }
+            let message = match message {
+                MessageType::Identified(_, message)
+                    if self.read_receipts == ReadReceipts::Disabled =>
+                {
+                    message
+                }
+                message => message,
+            };
+            let frame = encode_signed_message(message, self.compression, self.signer.as_mut())?;
+            match self.frames.try_send(frame) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.overflowed = true;
+                    self.overflow.notify_one();
+                    self.handler_overflow.notify_one();
+                    Err(anyhow!(
+                        "The outgoing queue is full, the client cannot keep up and is disconnected."
+                    ))
+                }
+                Err(TrySendError::Closed(_)) => Err(anyhow!("The client is disconnected.")),
+            }
+        }
+
+        /// Get the notification of the queue overflowing for the handler of the client, so that it can close the connection.
+        /// The writing task has its own, so that both of them are notified.
+        pub fn overflow(&self) -> Arc<Notify> {
+            Arc::clone(&self.handler_overflow)
+        }
+
+        /// Check if the client was disconnected, because its queue overflowed or writing to it failed.
+        pub fn is_disconnected(&self) -> bool {
+            self.overflowed || self.frames.is_closed()
+        }
+    }
+
+    /// Write queued frames to a client until the queue is closed or writing fails.
+    /// If the queue overflows, the writing stops and the client is told why, unless it does not read at all.
+    async fn write_frames(
+        mut writer: ClientWriter,
+        mut frames: mpsc::Receiver<Vec<u8>>,
+        overflow: Arc<Notify>,
+    ) {
+        loop {
+            let frame = tokio::select! {
+                _ = overflow.notified() => {
+                    send_disconnect_notice(&mut writer).await;
+                    return;
+                }
+                frame = frames.recv() => match frame {
+                    Some(frame) => frame,
+                    None => return,
+                },
+            };
+            let mut write = Box::pin(send_bytes(&mut writer, &frame));
+            let result = tokio::select! {
+                _ = overflow.notified() => None,
+                result = &mut write => Some(result),
+            };
+            match result {
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    error!("Failed when writing a frame to a client: {}", e);
+                    return;
+                }
+                None => {
+                    // The notice must not be mixed into a frame, so the frame being written is finished first.
+                    let finished = timeout(DISCONNECT_NOTICE_TIMEOUT, &mut write).await;
+                    drop(write);
+                    if let Ok(Ok(_)) = finished {
+                        send_disconnect_notice(&mut writer).await;
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Tell a client that cannot keep up that it is disconnected. The client may not read anything, so it is not waited for long.
+    async fn send_disconnect_notice(writer: &mut ClientWriter) {
+        let notice = MessageType::ServerError(
+            "You are disconnected, because messages for you were coming faster than you read them."
+                .to_string(),
+        );
+        // The frame is not compressed, because the compression may not be agreed on yet.
+        let frame = match encode_message(&notice, Compression::Disabled) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Failed to encode disconnect notice: {}", e);
+                return;
+            }
+        };
+        if timeout(DISCONNECT_NOTICE_TIMEOUT, send_bytes(writer, &frame))
+            .await
+            .is_err()
+        {
+            info!("Disconnect notice was not sent in time.");
+        }
+    }
+
+    /// Writer to one client that can be shared between async tasks.
+    pub type SharedWriteHalf = Arc<Mutex<FrameWriter>>;
+
+    /// Writers to all connected clients. The address of a client is key.
+    /// It is shared by the chat server and the http server.
+    pub type ClientWriters = Arc<Mutex<HashMap<ClientAddress, SharedWriteHalf>>>;
+
+    /// Record of a message that could not be sent to a client.
+    #[derive(Serialize, Clone, Debug)]
+    pub struct BroadcastFailure {
+        pub peer_address: String,
+        pub message_summary: String,
+        pub error: String,
+        pub failed_at: u64,
+    }
+
+    /// Most recent broadcast failures, oldest first. There are at most MAX_BROADCAST_FAILURES of them.
+    /// It is shared by the chat server and the http server.
+    pub type BroadcastFailures = Arc<Mutex<VecDeque<BroadcastFailure>>>;
+
+    /// Create an empty map of client writers.
+    pub fn create_client_writers() -> ClientWriters {
+        Arc::new(Mutex::new(HashMap::new()))
+    }
+
+    /// Wrap a writing half of a connection so that it can be put among client writers.
+    pub fn create_shared_writer<W: AsyncWrite + Send + Unpin + 'static>(
+        writer: W,
+    ) -> SharedWriteHalf {
+        Arc::new(Mutex::new(FrameWriter::new(
+            Box::new(writer),
+            DEFAULT_OUTGOING_QUEUE_CAPACITY,
+        )))
+    }
+
+    /// Create an empty buffer of broadcast failures.
+    pub fn create_broadcast_failures() -> BroadcastFailures {
+        Arc::new(Mutex::new(VecDeque::with_capacity(MAX_BROADCAST_FAILURES)))
+    }
+
+    /// Describe a message briefly, without its whole contents.
+    pub fn summarize_message(message: &MessageType) -> String {
+        match message {
+            MessageType::Text(text) => format!("Text ({} bytes)", text.len()),
+            MessageType::Image(bytes) => format!("Image ({} bytes)", bytes.len()),
+            MessageType::File { name, data, .. } => {
+                format!("File {} ({} bytes)", name, data.len())
+            }
+            MessageType::ServerError(_) => "ServerError".to_string(),
+            MessageType::ServerNotice(_) => "ServerNotice".to_string(),
+            MessageType::AuthRequest(_, _, _, _, _, _) => "AuthRequest".to_string(),
+            MessageType::AuthResponse(_, _, _, _, _, _) => "AuthResponse".to_string(),
+            MessageType::Rename(_) => "Rename".to_string(),
+            MessageType::HistoryRequest(count) => format!("HistoryRequest ({} messages)", count),
+            MessageType::History(entries) => format!("History ({} messages)", entries.len()),
+            MessageType::Identified(id, message) => {
+                format!("{} with id {}", summarize_message(message), id)
+            }
+            MessageType::MessageId(_) => "MessageId".to_string(),
+            MessageType::ReadReceipt(_) => "ReadReceipt".to_string(),
+            MessageType::ReadBy(_, _) => "ReadBy".to_string(),
+            MessageType::FormattedText { text, format } => {
+                format!("FormattedText {:?} ({} bytes)", format, text.len())
+            }
+            MessageType::SetNickname(_) => "SetNickname".to_string(),
+            MessageType::Authored(_, message) => summarize_message(message),
+            MessageType::Tagged(tag, message) => {
+                format!("{} with tag {}", summarize_message(message), tag)
+            }
+            MessageType::ConnectionRejected(reason, _) => {
+                format!("ConnectionRejected ({:?})", reason)
+            }
+        }
+    }
+
+    /// Save a broadcast failure. If the buffer is full, the oldest failure is dropped.
+    pub async fn record_broadcast_failure(
+        broadcast_failures: &BroadcastFailures,
+        failure: BroadcastFailure,
+    ) {
+        let mut lock = broadcast_failures.lock().await;
+        if lock.len() >= MAX_BROADCAST_FAILURES {
+            lock.pop_front();
+        }
+        lock.push_back(failure);
+    }
+
+    /// Send a message only to the client with specified address.
+    pub async fn send_message_to_client(
+        client_address: &ClientAddress,
+        client_writers: &ClientWriters,
+        message: &MessageType,
+    ) -> Result<()> {
+        let lock = client_writers.lock().await;
+        let shared_writer = lock
+            .get(client_address)
+            .ok_or_else(|| anyhow!("Address not found in HashMap."))?;
+        let mut lock_writer = shared_writer.lock().await;
+        lock_writer
+            .send(message)
+            .await
+            .context("Failed when sending bytes.")?;
+        Ok(())
+    }
+
+    /// Send a message to all connected clients except the one with excluded address.
+    /// Failed sends are recorded in broadcast failures. Clients that got disconnected are removed
+    /// and the number of active connections is decreased for each of them under the same lock.
+    /// Return the number of clients to which the message was sent successfully.
+    pub async fn broadcast_message(
+        client_writers: &ClientWriters,
+        broadcast_failures: &BroadcastFailures,
+        active_connections_gauge: &Gauge,
+        message: &MessageType,
+        excluded_address: Option<&ClientAddress>,
+    ) -> usize {
+        let mut clients_reached = 0;
+        let mut disconnected_addresses = Vec::new();
+        let mut lock = client_writers.lock().await;
+        for (address, shared_writer) in lock.iter() {
+            if Some(address) == excluded_address {
+                continue;
+            }
+            let mut lock_writer = shared_writer.lock().await;
+            match lock_writer.send(message).await {
+                Ok(_) => clients_reached += 1,
+                Err(e) => {
+                    error!("Failed when sending bytes to address {}: {}", address, e);
+                    let failed_at = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|duration| duration.as_secs())
+                        .unwrap_or_default();
+                    let failure = BroadcastFailure {
+                        peer_address: address.to_string(),
+                        message_summary: summarize_message(message),
+                        error: format!("{:#}", e),
+                        failed_at,
+                    };
+                    record_broadcast_failure(broadcast_failures, failure).await;
+                    if lock_writer.is_disconnected() {
+                        disconnected_addresses.push(*address);
+                    }
+                }
+            }
+        }
+        for address in disconnected_addresses {
+            info!("Removing disconnected client {}.", address);
+            if lock.remove(&address).is_some() {
+                active_connections_gauge.dec();
+            }
+        }
+        clients_reached
+    }
+}
+
+pub mod http_server {
+    use anyhow::{Context, Result};
     use axum::{
-        extract::Path,
-        http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode},
-        response::{IntoResponse, Json},
-        routing::{delete, get, get_service},
+        body::{Body, Bytes},
+        extract::{DefaultBodyLimit, Path, Query, Request, State},
+        http::{
+            header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+            HeaderMap, HeaderValue, StatusCode,
+        },
+        middleware::{self, Next},
+        response::{IntoResponse, Json, Response},
+        routing::{any, delete, get, post},
         Extension, Router,
     };
-    use log::error;
-    use prometheus::{Registry, Encoder, TextEncoder};
+    use log::{error, info};
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    use prometheus::{Encoder, Gauge, Registry, TextEncoder};
+    use serde::{Deserialize, Serialize};
+    use shared::{parse_socket_addr, AuthError, MessageType, PROTOCOL_VERSION};
     use sqlx::{Pool, Sqlite};
+    use std::fmt;
+    use std::io;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tokio::fs::File;
     use tokio::net::TcpListener;
-    use tower_http::services::fs::ServeFile;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_stream::StreamExt;
+    use tokio_util::io::ReaderStream;
+    use tower_http::services::ServeDir;
 
-    use crate::db;
+    use crate::auth::register_user;
+    use crate::connections::{
+        broadcast_message, create_broadcast_failures, create_client_writers, BroadcastFailure,
+        BroadcastFailures, ClientWriters,
+    };
+    use crate::db::{
+        self, Backup, ImportError, ImportMode, ImportSummary, MessageDto, MessageKind,
+        MessageOrder, MessagesPage, RenameUserError, UserActivityDto, UserDto,
+    };
+    use crate::export::write_export;
+    use crate::metrics::encode_metric_families;
+    use crate::password_hashing::{hash_password, is_password_strong, PasswordHashingOptions};
+    use crate::sessions::{ActiveSessions, DuplicateLoginPolicy};
+
+    /// Size of the buffer between the task writing an export and the body of the response.
+    const EXPORT_BUFFER_SIZE: usize = 64 * 1024;
+
+    /// Number of chunks of an export waiting to be sent in the body of the response.
+    const EXPORT_CHANNEL_CAPACITY: usize = 16;
+
+    /// The maximum size of a backup sent to be imported. Other requests have the default limit of axum.
+    const MAX_IMPORT_SIZE: usize = 256 * 1024 * 1024;
+
+    /// The number of messages of all users in one page if the request does not say how many.
+    pub const DEFAULT_MESSAGES_PAGE_SIZE: u32 = 100;
+
+    /// The maximum number of messages of all users in one page.
+    pub const MAX_MESSAGES_PAGE_SIZE: u32 = 1000;
+
+    /// Query parameters of a request for messages of a user.
+    #[derive(Deserialize)]
+    pub struct MessagesQuery {
+        pub kind: Option<MessageKind>,
+        pub order: Option<MessageOrder>,
+    }
+
+    /// Query parameters of a request for a page of messages of all users.
+    /// after is the cursor returned with the previous page. Without it, the first page is returned.
+    #[derive(Deserialize)]
+    pub struct MessagesPageQuery {
+        pub after: Option<i64>,
+        pub limit: Option<u32>,
+        pub order: Option<MessageOrder>,
+    }
+
+    /// Query parameters of a request to import a backup.
+    /// Without a mode, a user or a message that is already in the database makes the whole import fail.
+    #[derive(Deserialize)]
+    pub struct ImportQuery {
+        pub mode: Option<ImportMode>,
+    }
+
+    /// Query parameters of a request for collected metrics.
+    /// If prefix is given, only metrics whose name starts with it are returned.
+    #[derive(Deserialize)]
+    pub struct MetricsQuery {
+        pub prefix: Option<String>,
+    }
+
+    /// Body of a request for creating a user.
+    #[derive(Deserialize)]
+    pub struct CreateUserRequest {
+        pub username: String,
+        pub password: String,
+    }
+
+    /// Body of a request for renaming a user.
+    #[derive(Deserialize)]
+    pub struct RenameUserRequest {
+        pub username: String,
+    }
+
+    /// Body of a request for resetting the password of a user.
+    #[derive(Deserialize)]
+    pub struct ResetPasswordRequest {
+        pub new_password: String,
+    }
+
+    /// Body of a request for banning a user.
+    /// If duration_secs is not given, the ban is permanent.
+    #[derive(Deserialize)]
+    pub struct BanUserRequest {
+        pub duration_secs: Option<u64>,
+    }
+
+    /// Body of a request for sending an announcement to all connected clients.
+    #[derive(Deserialize)]
+    pub struct AnnounceRequest {
+        pub message: String,
+    }
+
+    /// Body of an error response to a request for an unknown API path.
+    #[derive(Serialize)]
+    pub struct ApiErrorResponse {
+        pub error: String,
+    }
+
+    /// Body of a response to an announcement request.
+    #[derive(Serialize)]
+    pub struct AnnounceResponse {
+        pub clients_reached: usize,
+    }
+
+    /// One session of a logged in user, as returned by the sessions endpoint.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct SessionResponse {
+        pub session_id: String,
+        pub user_id: i64,
+        pub address: String,
+    }
+
+    /// Information about the running server, as returned by the info endpoint.
+    /// The start time is in seconds since the Unix epoch.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct InfoResponse {
+        pub started_at: u64,
+        pub uptime_seconds: u64,
+        pub version: String,
+        pub protocol_version: u32,
+    }
+
+    /// The moment when the server started.
+    /// The uptime is measured from the instant, so that changes of the system clock do not affect it.
+    #[derive(Clone, Copy, Debug)]
+    pub struct ServerStart {
+        pub instant: Instant,
+        pub time: SystemTime,
+    }
+
+    impl ServerStart {
+        /// Record the current moment as the start of the server.
+        pub fn now() -> ServerStart {
+            ServerStart {
+                instant: Instant::now(),
+                time: SystemTime::now(),
+            }
+        }
+    }
+
+    /// Secret that requests changing accounts must send as "Authorization: Bearer <token>".
+    /// It is never printed, not even in debug output.
+    #[derive(Clone)]
+    pub struct AdminToken(String);
+
+    impl AdminToken {
+        /// Create a token from its text.
+        pub fn new(token: &str) -> AdminToken {
+            AdminToken(token.to_string())
+        }
+
+        /// Check if a token sent in a request is this one.
+        /// All bytes are compared, so that the token cannot be guessed byte by byte from the time of the answer.
+        fn matches(&self, token: &str) -> bool {
+            let expected = self.0.as_bytes();
+            let token = token.as_bytes();
+            expected.len() == token.len()
+                && expected
+                    .iter()
+                    .zip(token)
+                    .fold(0, |difference, (a, b)| difference | (a ^ b))
+                    == 0
+        }
+    }
+
+    impl fmt::Debug for AdminToken {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "AdminToken(<hidden>)")
+        }
+    }
+
+    /// State of the http server. Each part of it is available to the handlers as an Extension.
+    /// The writers, the broadcast failures and the sessions are shared with the chat server.
+    /// Without an admin token, the admin endpoints are not served at all.
+    #[derive(Clone)]
+    pub struct HttpState {
+        pub connection_pool: Pool<Sqlite>,
+        pub registry: Registry,
+        pub client_writers: ClientWriters,
+        pub broadcast_failures: BroadcastFailures,
+        pub active_connections_gauge: Gauge,
+        pub password_hashing: PasswordHashingOptions,
+        pub server_start: ServerStart,
+        pub admin_token: Option<AdminToken>,
+        pub sessions: ActiveSessions,
+    }
+
+    impl HttpState {
+        /// Create a state with the given database, an empty registry, no connected clients, no broadcast failures,
+        /// an unregistered active connections gauge, default password hashing, the server started now,
+        /// no admin token and no logged in users. Parts shared with the chat server are set afterwards.
+        pub fn new(connection_pool: Pool<Sqlite>) -> HttpState {
+            HttpState {
+                connection_pool,
+                registry: Registry::new(),
+                client_writers: create_client_writers(),
+                broadcast_failures: create_broadcast_failures(),
+                active_connections_gauge: Gauge::new(
+                    "active_connections_gauge",
+                    "A gauge for tracking the number of active connections to the server",
+                )
+                .expect("The name of the gauge is a valid metric name."),
+                password_hashing: PasswordHashingOptions::default(),
+                server_start: ServerStart::now(),
+                admin_token: None,
+                sessions: ActiveSessions::new(DuplicateLoginPolicy::Reject),
+            }
+        }
+    }
 
     /// Define routes and actions and run an http server.
+    /// The socket address can be either IPv4 (e.g. "0.0.0.0:80") or IPv6 (e.g. "[::]:80").
     pub async fn run_http_server(
         http_socket_address: &str,
-        connection_pool: Pool<Sqlite>,
-        static_dir: &str,
-        registry: Registry
+        static_dirs: &[&str],
+        state: HttpState,
+    ) -> Result<()> {
+        let listener = bind_http_listener(http_socket_address).await?;
+        serve_http(listener, static_dirs, state).await
+    }
+
+    /// Bind a listener for the http server, so that it can be served later.
+    pub async fn bind_http_listener(http_socket_address: &str) -> Result<TcpListener> {
+        let http_socket_address = parse_socket_addr(http_socket_address)?;
+        TcpListener::bind(http_socket_address)
+            .await
+            .with_context(|| format!("Failed to bind http server to {}.", http_socket_address))
+    }
+
+    /// Serve static files from the directories searched in order, so that a file in an earlier
+    /// directory overrides the file with the same path in a later one, e.g. a theme overrides the base page.
+    /// A request for a directory, e.g. "/", gets its index.html. Files that are in none of the directories are not found.
+    pub fn serve_static_dirs(static_dirs: &[&str]) -> Router {
+        static_dirs
+            .iter()
+            .rev()
+            .fold(Router::new(), |fallback, static_dir| {
+                Router::new().fallback_service(ServeDir::new(static_dir).fallback(fallback))
+            })
+    }
+
+    /// Define routes and actions and run an http server on an already bound listener.
+    pub async fn serve_http(
+        listener: TcpListener,
+        static_dirs: &[&str],
+        state: HttpState,
     ) -> Result<()> {
         let app = Router::new()
-            // Serve an html file to a client browser.
-            .route(
-                "/",
-                get_service(ServeFile::new(format!("{}/index.html", static_dir))),
-            )
-            // Get all messages sent by one specific user.
+            // Get all messages sent by one specific user (optionally only those of one kind).
             .route("/api/users/{id}/messages", get(get_messages))
             // Get all users from database.
             .route("/api/users", get(get_users))
-            // Remove a user from database (along with all messages sent by him).
-            .route("/api/users/{id}", delete(remove_user))
+            // Get all users with the time of their last message.
+            .route("/api/users/activity", get(get_users_activity))
+            // Download an image or a file stored by the chat server.
+            .route("/api/attachments/{id}", get(get_attachment))
+            // Get the start time, uptime and versions of the server, e.g. for monitoring.
+            .route("/api/info", get(get_info))
+            // Admin endpoints are served only to requests with the admin token.
+            .merge(admin_routes(state.admin_token))
+            // Respond to all other API paths with a JSON error, so that the admin page can handle it.
+            // Known API routes are matched first, because a catch-all route has the lowest priority.
+            .route("/api/{*path}", any(api_not_found))
             // Expose an endpoint for prometheus metrics.
             .route("/metrics", get(get(get_metrics)))
-            .layer(Extension(connection_pool))
-            .layer(Extension(registry));
+            // Serve the html page and other static files to a client browser.
+            // Routes are matched first, so static files cannot hide them.
+            .fallback_service(serve_static_dirs(static_dirs))
+            .layer(Extension(state.connection_pool))
+            .layer(Extension(state.registry))
+            .layer(Extension(state.client_writers))
+            .layer(Extension(state.broadcast_failures))
+            .layer(Extension(state.active_connections_gauge))
+            .layer(Extension(state.password_hashing))
+            .layer(Extension(state.server_start))
+            .layer(Extension(state.sessions));
 
-        let listener = TcpListener::bind(http_socket_address).await.unwrap();
-        axum::serve(listener, app).await.unwrap();
+        axum::serve(listener, app)
+            .await
+            .context("Http server stopped because of an error.")?;
 
         Ok(())
     }
 
+    /// Routes of the admin endpoints, i.e. all endpoints that change accounts or the server, or that reveal messages or connections of users.
+    /// Each request must have the admin token, otherwise it is refused with 401.
+    /// Without a token, there are no such routes, so anybody who can reach the http server cannot take over accounts.
+    fn admin_routes(admin_token: Option<AdminToken>) -> Router {
+        let Some(admin_token) = admin_token else {
+            info!("No admin token is set, admin endpoints are disabled.");
+            return Router::new();
+        };
+        Router::new()
+            // Create a user that can log in with the given password.
+            .route("/api/users", post(create_user))
+            // Remove a user from database (along with all messages sent by him).
+            // Change username of a user.
+            .route("/api/users/{id}", delete(remove_user).patch(rename_user))
+            // Ban a user from logging in and disconnect the user, or lift the ban.
+            .route("/api/users/{id}/ban", post(ban_user).delete(unban_user))
+            // Set a new password of a user without knowing the old one.
+            .route("/api/users/{id}/reset-password", post(reset_password))
+            // Download all users and messages as one JSON document, e.g. for a backup.
+            // It has every message of every user, so it is not public.
+            .route("/api/export", get(export_database))
+            // Restore users and messages from a backup in the format of the export.
+            // Users of a backup can have password hashes, so an import can change any account.
+            .route(
+                "/api/import",
+                post(import_database).layer(DefaultBodyLimit::max(MAX_IMPORT_SIZE)),
+            )
+            // Send an announcement to all connected clients.
+            // Anybody could otherwise show any text as a message of the server to all users.
+            .route("/api/announce", post(announce))
+            // Return free pages of the database file to the file system.
+            // A vacuum can keep the chat server from saving messages, so it is not public.
+            .route("/api/maintenance/vacuum", post(vacuum_database))
+            // Get the sessions of all logged in users.
+            // The sessions reveal the addresses of the clients, so they are not public.
+            .route("/api/sessions", get(get_sessions))
+            // Get the most recent messages that could not be sent to a client.
+            // The failures reveal the addresses of the clients and summaries of the messages.
+            .route("/api/broadcast-failures", get(get_broadcast_failures))
+            // Get a page of messages of all users, e.g. for a transcript.
+            // Like the export, it has every message of every user, so it is not public.
+            .route("/api/messages", get(get_all_messages))
+            .route_layer(middleware::from_fn_with_state(
+                admin_token,
+                require_admin_token,
+            ))
+    }
+
+    /// Let a request through only if it has the admin token, otherwise respond with 401.
+    async fn require_admin_token(
+        State(admin_token): State<AdminToken>,
+        request: Request,
+        next: Next,
+    ) -> Result<Response, StatusCode> {
+        let token = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if admin_token.matches(token) => Ok(next.run(request).await),
+            _ => {
+                info!(
+                    "Refused a request to {} without a valid admin token.",
+                    request.uri().path()
+                );
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+
+    /// Respond to a request for an unknown API path with 404 and a JSON body.
+    async fn api_not_found(Path(path): Path<String>) -> (StatusCode, Json<ApiErrorResponse>) {
+        info!("Request for an unknown API path /api/{}.", path);
+        (
+            StatusCode::NOT_FOUND,
+            Json(ApiErrorResponse {
+                error: format!("Unknown API path /api/{}.", path),
+            }),
+        )
+    }
+
     /// Get all messages sent by a user with specified id.
+    /// If a kind is specified in the query, only messages of that kind are returned.
+    /// The messages are ordered by the order in the query, the newest first by default.
     async fn get_messages(
         Path(id): Path<i64>,
+        Query(query): Query<MessagesQuery>,
         Extension(connection_pool): Extension<Pool<Sqlite>>,
-    ) -> Result<Json<Vec<String>>, StatusCode> {
-        match db::get_messages_by_user(&connection_pool, &id).await {
+    ) -> Result<Json<Vec<MessageDto>>, StatusCode> {
+        let order = query.order.unwrap_or_default();
+        let messages = match query.kind {
+            Some(kind) => db::get_messages_by_kind(&connection_pool, &id, kind, order).await,
+            None => db::get_messages_by_user(&connection_pool, &id, order).await,
+        };
+        match messages {
             Ok(messages) => Ok(Json(messages)),
             Err(e) => {
                 error!("Failed to get messages from database: {}", e);
@@ -97,10 +988,103 @@ pub mod http_server {
         }
     }
 
+    /// Get a page of messages of all users together with the cursor of the next page, see db::get_messages_page.
+    /// The messages are ordered by their ids, the oldest first unless the order in the query is descending.
+    /// Respond with 400 if the limit is 0 or greater than MAX_MESSAGES_PAGE_SIZE.
+    async fn get_all_messages(
+        Query(query): Query<MessagesPageQuery>,
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+    ) -> Result<Json<MessagesPage>, StatusCode> {
+        let limit = query.limit.unwrap_or(DEFAULT_MESSAGES_PAGE_SIZE);
+        if limit == 0 || limit > MAX_MESSAGES_PAGE_SIZE {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let order = query.order.unwrap_or(MessageOrder::Asc);
+        match db::get_messages_page(&connection_pool, query.after, limit, order).await {
+            Ok(page) => Ok(Json(page)),
+            Err(e) => {
+                error!("Failed to get a page of messages from database: {}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Export all users and messages as one JSON document, see write_export.
+    /// The document is sent while it is being written, so the whole of it is never in memory.
+    /// If the export fails on the way, the error is logged and passed to the body as the last chunk,
+    /// so the response is aborted instead of ending as a truncated 200 OK.
+    async fn export_database(
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+    ) -> impl IntoResponse {
+        let (sender, receiver) = mpsc::channel::<Result<Bytes, io::Error>>(EXPORT_CHANNEL_CAPACITY);
+        tokio::spawn(async move {
+            let (mut writer, reader) = tokio::io::duplex(EXPORT_BUFFER_SIZE);
+            let export = async move {
+                let result = write_export(&connection_pool, &mut writer).await;
+                // The reader ends only when the writer is dropped.
+                drop(writer);
+                result
+            };
+            let forward = async {
+                let mut chunks = ReaderStream::new(reader);
+                while let Some(chunk) = chunks.next().await {
+                    if sender.send(chunk).await.is_err() {
+                        // The client is gone, so the export fails on the next write.
+                        break;
+                    }
+                }
+            };
+            let (result, ()) = tokio::join!(export, forward);
+            if let Err(e) = result {
+                error!("Failed to export database: {}", e);
+                let _ = sender.send(Err(io::Error::other(e.to_string()))).await;
+            }
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        (
+            StatusCode::OK,
+            headers,
+            Body::from_stream(ReceiverStream::new(receiver)),
+        )
+    }
+
+    /// Import users and messages from a backup in the format of an export in one transaction.
+    /// Respond with 400 if the backup is not valid and with 409 if a user or a message is already in the database in the fail mode,
+    /// or if a user to be replaced has the username of another user in the database.
+    async fn import_database(
+        Query(query): Query<ImportQuery>,
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+        Json(backup): Json<Backup>,
+    ) -> Result<Json<ImportSummary>, StatusCode> {
+        let mode = query.mode.unwrap_or_default();
+        match db::import_backup(&connection_pool, &backup, mode).await {
+            Ok(summary) => {
+                info!(
+                    "Imported {} users and {} messages from a backup.",
+                    summary.users_imported, summary.messages_imported
+                );
+                Ok(Json(summary))
+            }
+            Err(ImportError::Invalid(reason)) => {
+                info!("Failed to import backup: {}", reason);
+                Err(StatusCode::BAD_REQUEST)
+            }
+            Err(e @ (ImportError::Conflict(_) | ImportError::UsernameTaken { .. })) => {
+                info!("Failed to import backup: {}", e);
+                Err(StatusCode::CONFLICT)
+            }
+            Err(e) => {
+                error!("Failed to import backup: {}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
     /// Get all users from database.
     async fn get_users(
         Extension(connection_pool): Extension<Pool<Sqlite>>,
-    ) -> Result<Json<Vec<(i64, String)>>, StatusCode> {
+    ) -> Result<Json<Vec<UserDto>>, StatusCode> {
         match db::get_all_users(&connection_pool).await {
             Ok(users) => Ok(Json(users)),
             Err(e) => {
@@ -110,6 +1094,50 @@ pub mod http_server {
         }
     }
 
+    /// Create a user the same way as a registration in the chat does and return it with 201.
+    /// Respond with 400 if the password is empty or the username is too long and with 409 if the username is taken.
+    async fn create_user(
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+        Extension(password_hashing): Extension<PasswordHashingOptions>,
+        Json(request): Json<CreateUserRequest>,
+    ) -> Result<(StatusCode, Json<UserDto>), StatusCode> {
+        match register_user(
+            &connection_pool,
+            &password_hashing,
+            &request.username,
+            &request.password,
+        )
+        .await
+        {
+            Ok(id) => Ok((
+                StatusCode::CREATED,
+                Json(UserDto {
+                    id,
+                    username: request.username,
+                    display_name: None,
+                }),
+            )),
+            Err(AuthError::WeakPassword | AuthError::UsernameTooLong) => {
+                Err(StatusCode::BAD_REQUEST)
+            }
+            Err(AuthError::UsernameTaken) => Err(StatusCode::CONFLICT),
+            Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    /// Get all users with the time of their last message, e.g. to find inactive accounts.
+    async fn get_users_activity(
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+    ) -> Result<Json<Vec<UserActivityDto>>, StatusCode> {
+        match db::get_users_with_last_activity(&connection_pool).await {
+            Ok(users) => Ok(Json(users)),
+            Err(e) => {
+                error!("Failed to get activity of users from database: {}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
     /// Remove a user from a database.
     async fn remove_user(
         Path(id): Path<i64>,
@@ -124,22 +1152,295 @@ pub mod http_server {
         }
     }
 
-    // Get collected prometheus metrics.
-    async fn get_metrics(
-        Extension(registry): Extension<Registry>
-    ) -> Result<impl IntoResponse, StatusCode> {
-        let mut buffer = vec![];
-        let encoder = TextEncoder::new();
-        let metric_families = registry.gather();
-        
-        if let Err(err) = encoder.encode(&metric_families, &mut buffer) {
-            error!("Failed to extract collected metrics into a buffer: {}", err);
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-
-        let mut headers = HeaderMap::new();
-        let header_value = match HeaderValue::from_str(encoder.format_type()) {
-            Ok(header_value) => header_value,
+    /// Change username of a user with specified id.
+    async fn rename_user(
+        Path(id): Path<i64>,
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+        Json(request): Json<RenameUserRequest>,
+    ) -> Result<(), StatusCode> {
+        match db::rename_user(&connection_pool, &id, &request.username).await {
+            Ok(_) => Ok(()),
+            Err(RenameUserError::NameTaken(name)) => {
+                info!("Failed to rename user {}: {} is already taken.", id, name);
+                Err(StatusCode::CONFLICT)
+            }
+            Err(RenameUserError::NameTooLong) => {
+                info!("Failed to rename user {}: the username is too long.", id);
+                Err(StatusCode::BAD_REQUEST)
+            }
+            Err(RenameUserError::UserNotFound(id)) => {
+                info!("Failed to rename user {}: user does not exist.", id);
+                Err(StatusCode::NOT_FOUND)
+            }
+            Err(e) => {
+                error!("Failed when renaming user in database: {}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Set a new password of a user, e.g. when the user forgot the old one.
+    /// Respond with 400 if the password is not strong enough and with 404 if the user does not exist.
+    async fn reset_password(
+        Path(id): Path<i64>,
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+        Extension(password_hashing): Extension<PasswordHashingOptions>,
+        Json(request): Json<ResetPasswordRequest>,
+    ) -> Result<(), StatusCode> {
+        let username = get_existing_username(&connection_pool, &id).await?;
+        if !is_password_strong(&request.new_password) {
+            info!(
+                "Failed to reset password of {}: the password is too weak.",
+                username
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let password_hash = hash_password(&request.new_password, &password_hashing)
+            .await
+            .map_err(|e| {
+                error!("Failed to hash password: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        match db::update_password_hash(&connection_pool, &id, &password_hash).await {
+            Ok(_) => {
+                info!("Password of {} was reset.", username);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Failed to reset password of {}: {}", username, e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Ban a user with specified id, either permanently or for some time.
+    /// A connected user is disconnected and cannot log in again until the ban expires.
+    /// Respond with 400 if the ban would end after the year 9999, because such an expiry cannot be stored.
+    async fn ban_user(
+        Path(id): Path<i64>,
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+        Extension(sessions): Extension<ActiveSessions>,
+        Json(request): Json<BanUserRequest>,
+    ) -> Result<(), StatusCode> {
+        let username = get_existing_username(&connection_pool, &id).await?;
+        let expires_at = match request.duration_secs {
+            Some(duration_secs) => Some(
+                SystemTime::now()
+                    .checked_add(Duration::from_secs(duration_secs))
+                    .filter(|expires_at| db::is_storable_timestamp(*expires_at))
+                    .ok_or(StatusCode::BAD_REQUEST)?,
+            ),
+            None => None,
+        };
+        if let Err(e) = db::add_ban(&connection_pool, &id, expires_at).await {
+            error!("Failed to ban user {}: {}", username, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        info!("User {} was banned.", username);
+        if sessions.kick(id).await {
+            info!("Banned user {} was disconnected.", username);
+        }
+        Ok(())
+    }
+
+    /// Lift the ban of a user with specified id.
+    async fn unban_user(
+        Path(id): Path<i64>,
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+    ) -> Result<(), StatusCode> {
+        let username = get_existing_username(&connection_pool, &id).await?;
+        match db::remove_ban(&connection_pool, &id).await {
+            Ok(true) => {
+                info!("Ban of user {} was lifted.", username);
+                Ok(())
+            }
+            Ok(false) => Err(StatusCode::NOT_FOUND),
+            Err(e) => {
+                error!("Failed to lift ban of user {}: {}", username, e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Get the username of a user with specified id. If there is no such user, respond with 404.
+    async fn get_existing_username(
+        connection_pool: &Pool<Sqlite>,
+        id: &i64,
+    ) -> Result<String, StatusCode> {
+        match db::get_username(connection_pool, id).await {
+            Ok(Some(username)) => Ok(username),
+            Ok(None) => Err(StatusCode::NOT_FOUND),
+            Err(e) => {
+                error!("Failed to get user {} from database: {}", id, e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Vacuum the database on demand.
+    async fn vacuum_database(
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+    ) -> Result<(), StatusCode> {
+        info!("Vacuuming the database on request...");
+        match db::vacuum(&connection_pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to vacuum the database: {}", e);
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    /// Send an announcement as a text message to all connected clients.
+    /// Respond with the number of clients that were reached.
+    async fn announce(
+        Extension(client_writers): Extension<ClientWriters>,
+        Extension(broadcast_failures): Extension<BroadcastFailures>,
+        Extension(active_connections_gauge): Extension<Gauge>,
+        Json(request): Json<AnnounceRequest>,
+    ) -> Result<Json<AnnounceResponse>, StatusCode> {
+        if request.message.trim().is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let message = MessageType::Text(request.message);
+        let clients_reached = broadcast_message(
+            &client_writers,
+            &broadcast_failures,
+            &active_connections_gauge,
+            &message,
+            None,
+        )
+        .await;
+        info!("Announcement sent to {} clients.", clients_reached);
+        Ok(Json(AnnounceResponse { clients_reached }))
+    }
+
+    /// Get the sessions of all connected clients that are logged in, ordered by user id.
+    /// Clients that are connected but not authenticated yet have no session.
+    async fn get_sessions(
+        Extension(client_writers): Extension<ClientWriters>,
+    ) -> Json<Vec<SessionResponse>> {
+        let lock = client_writers.lock().await;
+        let mut sessions = Vec::new();
+        for (address, writer) in lock.iter() {
+            if let Some((user_id, session_id)) = writer.lock().await.session() {
+                sessions.push(SessionResponse {
+                    session_id: session_id.to_string(),
+                    user_id: *user_id,
+                    address: address.to_string(),
+                });
+            }
+        }
+        sessions.sort_by_key(|session| session.user_id);
+        Json(sessions)
+    }
+
+    /// Get the start time and uptime of the server, the version of the server and the version of the protocol it speaks.
+    async fn get_info(Extension(server_start): Extension<ServerStart>) -> Json<InfoResponse> {
+        let started_at = server_start
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default();
+        Json(InfoResponse {
+            started_at,
+            uptime_seconds: server_start.instant.elapsed().as_secs(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+        })
+    }
+
+    /// Get the most recent broadcast failures, oldest first.
+    async fn get_broadcast_failures(
+        Extension(broadcast_failures): Extension<BroadcastFailures>,
+    ) -> Json<Vec<BroadcastFailure>> {
+        let lock = broadcast_failures.lock().await;
+        Json(lock.iter().cloned().collect())
+    }
+
+    /// Stream a stored attachment from disk.
+    /// The response has the MIME type of the attachment and its original file name.
+    async fn get_attachment(
+        Path(id): Path<i64>,
+        Extension(connection_pool): Extension<Pool<Sqlite>>,
+    ) -> Result<impl IntoResponse, StatusCode> {
+        let attachment = match db::get_attachment(&connection_pool, &id).await {
+            Ok(Some(attachment)) => attachment,
+            Ok(None) => return Err(StatusCode::NOT_FOUND),
+            Err(e) => {
+                error!("Failed to get attachment from database: {}", e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let file = match File::open(&attachment.path).await {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Failed to open attachment '{}': {}", attachment.path, e);
+                return Err(StatusCode::NOT_FOUND);
+            }
+        };
+
+        let mut headers = HeaderMap::new();
+        let content_type = HeaderValue::from_str(&attachment.mime)
+            .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+        headers.insert(CONTENT_TYPE, content_type);
+        let content_disposition =
+            match HeaderValue::from_str(&get_content_disposition(&attachment.filename)) {
+                Ok(header_value) => header_value,
+                Err(err) => {
+                    error!("Failed to create headers: {}", err);
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            };
+        headers.insert(CONTENT_DISPOSITION, content_disposition);
+        // Browsers must not guess a different type, e.g. html, from the contents of a file.
+        headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+
+        let body = Body::from_stream(ReaderStream::new(file));
+        Ok((StatusCode::OK, headers, body))
+    }
+
+    /// Create a value of the Content-Disposition header that makes a browser download a file.
+    /// The file name comes from a client, so it is escaped. Characters that are not allowed
+    /// in the plain file name are replaced, the exact name is kept in its encoded form.
+    fn get_content_disposition(filename: &str) -> String {
+        let plain_filename: String = filename
+            .chars()
+            .map(|c| {
+                if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let encoded_filename = utf8_percent_encode(filename, NON_ALPHANUMERIC);
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            plain_filename, encoded_filename
+        )
+    }
+
+    // Get collected prometheus metrics (optionally only those with names starting with a prefix).
+    // Metric families that cannot be encoded are logged and left out, the rest is still returned.
+    async fn get_metrics(
+        Query(query): Query<MetricsQuery>,
+        Extension(registry): Extension<Registry>
+    ) -> Result<impl IntoResponse, StatusCode> {
+        let encoder = TextEncoder::new();
+        let mut metric_families = registry.gather();
+        if let Some(prefix) = &query.prefix {
+            metric_families.retain(|family| family.name().starts_with(prefix.as_str()));
+        }
+        
+        let (buffer, failures) = encode_metric_families(&encoder, &metric_families);
+        for failure in failures {
+            error!("Metrics are returned without a family: {}", failure);
+        }
+
+        let mut headers = HeaderMap::new();
+        let header_value = match HeaderValue::from_str(encoder.format_type()) {
+            Ok(header_value) => header_value,
             Err(err) => {
                 error!("Failed to create headers: {}", err);
                 return Err(StatusCode::INTERNAL_SERVER_ERROR);
@@ -151,9 +1452,68 @@ pub mod http_server {
     }
 }
 
-pub mod metrics {
+pub mod export {
     use anyhow::{Context, Result};
-    use prometheus::{Counter, Gauge, Opts};
+    use sqlx::SqlitePool;
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+    use crate::db::{self, MessageOrder};
+
+    /// Number of messages read from the database at once during an export.
+    pub const EXPORT_PAGE_SIZE: u32 = 500;
+
+    /// Write all users and all messages in the database as one JSON document, e.g.
+    /// {"users":[{"id":1,"username":"alice"}],"messages":[{"id":1,"user_id":1,"content":"Hello.","kind":"text","sent_at":"2025-04-01 12:00:00"}]}
+    /// Password hashes are never written.
+    /// Messages are read and written page by page, so not all of them have to be in memory at once.
+    pub async fn write_export<W: AsyncWrite + Unpin>(
+        connection_pool: &SqlitePool,
+        writer: &mut W,
+    ) -> Result<()> {
+        let users = db::get_all_users(connection_pool).await?;
+        writer.write_all(b"{\"users\":").await?;
+        writer
+            .write_all(&serde_json::to_vec(&users).context("Failed to serialize users.")?)
+            .await?;
+        writer.write_all(b",\"messages\":[").await?;
+
+        let mut cursor = None;
+        let mut first = true;
+        loop {
+            let page =
+                db::get_messages_page(connection_pool, cursor, EXPORT_PAGE_SIZE, MessageOrder::Asc)
+                    .await?;
+            for message in &page.messages {
+                if !first {
+                    writer.write_all(b",").await?;
+                }
+                writer
+                    .write_all(
+                        &serde_json::to_vec(message).context("Failed to serialize a message.")?,
+                    )
+                    .await?;
+                first = false;
+            }
+            cursor = match page.next_cursor {
+                Some(next_cursor) => Some(next_cursor),
+                None => break,
+            };
+        }
+
+        writer.write_all(b"]}").await?;
+        writer.flush().await?;
+        Ok(())
+    }
+}
+
+pub mod metrics {
+    use anyhow::{anyhow, Context, Result};
+    use prometheus::core::Collector;
+    use prometheus::proto::MetricFamily;
+    use prometheus::{
+        Counter, CounterVec, Encoder, Gauge, HistogramOpts, HistogramVec, Opts, Registry,
+        TextEncoder,
+    };
 
     /// Create a metric that tracks the number of messages sent through the server by clients.
     pub async fn get_messages_counter() -> Result<Counter> {
@@ -176,4 +1536,1043 @@ pub mod metrics {
             .context("Failed to create active connections gauge metric.")?;
         Ok(active_connections_gauge)
     }
+
+    /// Create a metric that tracks registrations and logins.
+    /// It is labeled by the action ("register" or "login") and by the result ("success" or "failure").
+    pub async fn get_auth_attempts_counter() -> Result<CounterVec> {
+        let auth_attempts_counter_opts = Opts::new(
+            "auth_attempts_counter",
+            "A counter for tracking the number of registrations and logins by their result",
+        );
+        let auth_attempts_counter =
+            CounterVec::new(auth_attempts_counter_opts, &["action", "result"])
+                .context("Failed to create authentication attempts counter metric.")?;
+        Ok(auth_attempts_counter)
+    }
+
+    /// Create a metric that tracks how long database operations take.
+    /// It is labeled by the operation, which is the name of the function in the db module (e.g. "add_user").
+    pub async fn get_db_operation_durations_histogram() -> Result<HistogramVec> {
+        let db_operation_durations_opts = HistogramOpts::new(
+            "db_operation_duration_seconds",
+            "A histogram for tracking the durations of database operations",
+        );
+        let db_operation_durations = HistogramVec::new(db_operation_durations_opts, &["operation"])
+            .context("Failed to create database operation durations histogram metric.")?;
+        Ok(db_operation_durations)
+    }
+
+    /// Encode metric families in the text format of prometheus.
+    /// Each family is encoded on its own, so a family that cannot be encoded does not spoil the others.
+    /// Such a family is left out and an error naming it is returned along with the encoded families.
+    pub fn encode_metric_families(
+        encoder: &TextEncoder,
+        metric_families: &[MetricFamily],
+    ) -> (Vec<u8>, Vec<anyhow::Error>) {
+        let mut buffer = Vec::new();
+        let mut failures = Vec::new();
+        for family in metric_families {
+            let mut family_buffer = Vec::new();
+            match encoder.encode(std::slice::from_ref(family), &mut family_buffer) {
+                Ok(()) => buffer.extend_from_slice(&family_buffer),
+                Err(e) => failures.push(anyhow!(
+                    "Failed to encode metric '{}': {}",
+                    family.name(),
+                    e
+                )),
+            }
+        }
+        (buffer, failures)
+    }
+
+    /// Register a metric in a registry.
+    /// Registering the same metric twice is a mistake, so a clear error naming the metric is returned.
+    pub fn register_metric<M: Collector + Clone + 'static>(
+        registry: &Registry,
+        metric: &M,
+    ) -> Result<()> {
+        let name = metric
+            .desc()
+            .first()
+            .map(|desc| desc.fq_name.clone())
+            .unwrap_or_default();
+        match registry.register(Box::new(metric.clone())) {
+            Ok(_) => Ok(()),
+            Err(prometheus::Error::AlreadyReg) => {
+                Err(anyhow!("Metric '{}' is already registered.", name))
+            }
+            Err(e) => Err(anyhow!("Failed to register metric '{}': {}", name, e)),
+        }
+    }
+
+    /// Create a registry and register all metrics in it.
+    /// The metrics are registered only here, the chat server gets their clones.
+    /// Because clones share their values, the registry always sees the current values.
+    pub fn create_registry(
+        messages_counter: &Counter,
+        active_connections_gauge: &Gauge,
+        auth_attempts_counter: &CounterVec,
+        db_operation_durations: &HistogramVec,
+    ) -> Result<Registry> {
+        let registry = Registry::new();
+        register_metric(&registry, messages_counter)?;
+        register_metric(&registry, active_connections_gauge)?;
+        register_metric(&registry, auth_attempts_counter)?;
+        register_metric(&registry, db_operation_durations)?;
+        Ok(registry)
+    }
+}
+
+pub mod file_store {
+    use anyhow::{anyhow, Context, Result};
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+
+    /// Directory where images and files sent by clients are stored.
+    /// Files larger than the limit are not stored.
+    #[derive(Clone, Debug)]
+    pub struct FileStore {
+        directory: PathBuf,
+        max_file_size: usize,
+    }
+
+    impl FileStore {
+        /// Create a file store. The directory is created if it does not exist.
+        pub async fn new(directory: &str, max_file_size: usize) -> Result<FileStore> {
+            tokio::fs::create_dir_all(directory)
+                .await
+                .with_context(|| {
+                    format!("Failed to create file store directory '{}'.", directory)
+                })?;
+            Ok(FileStore {
+                directory: PathBuf::from(directory),
+                max_file_size,
+            })
+        }
+
+        /// Write bytes into a new file with a generated name and return its path.
+        /// The extension of the original file name is kept.
+        /// If there are more bytes than allowed, nothing is written and None is returned.
+        pub async fn store(&self, original_name: &str, bytes: &[u8]) -> Result<Option<PathBuf>> {
+            if bytes.len() > self.max_file_size {
+                return Ok(None);
+            }
+            let path = self.directory.join(generate_file_name(original_name));
+            tokio::fs::write(&path, bytes)
+                .await
+                .with_context(|| format!("Failed to write file '{}'.", path.display()))?;
+            Ok(Some(path))
+        }
+    }
+
+    /// Guess the MIME type of a file from the extension of its name.
+    /// Unknown files are treated as binary data.
+    pub fn guess_mime_type(filename: &str) -> String {
+        mime_guess::from_path(filename)
+            .first_or_octet_stream()
+            .to_string()
+    }
+
+    /// Extensions of files that clients are allowed to send, e.g. to keep executables out of the chat.
+    /// Extensions are compared without the leading dot and regardless of case.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct AllowedExtensions {
+        extensions: HashSet<String>,
+    }
+
+    impl AllowedExtensions {
+        /// Parse a comma-separated list of extensions, e.g. "txt,pdf,.PNG".
+        /// Spaces around the extensions and empty entries are ignored, but at least one extension must be given.
+        pub fn parse(list: &str) -> Result<AllowedExtensions> {
+            let extensions: HashSet<String> = list
+                .split(',')
+                .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+                .filter(|extension| !extension.is_empty())
+                .collect();
+            if extensions.is_empty() {
+                return Err(anyhow!(
+                    "The list of allowed file extensions '{}' does not contain any extension.",
+                    list
+                ));
+            }
+            Ok(AllowedExtensions { extensions })
+        }
+
+        /// Check if a file with the name can be sent. Files without an extension are not allowed.
+        pub fn allows(&self, file_name: &str) -> bool {
+            Path::new(file_name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| self.extensions.contains(&extension.to_lowercase()))
+        }
+    }
+
+    /// Generate a random file name with the same extension as the original file name.
+    /// The original name is not used otherwise, because it comes from a client.
+    fn generate_file_name(original_name: &str) -> String {
+        let random_name = format!("{:016x}", rand::random::<u64>());
+        let extension = Path::new(original_name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .filter(|extension| extension.chars().all(|c| c.is_ascii_alphanumeric()));
+        match extension {
+            Some(extension) => format!("{}.{}", random_name, extension),
+            None => random_name,
+        }
+    }
+}
+
+pub mod clock {
+    use std::time::SystemTime;
+
+    /// Source of the current time.
+    /// Code that depends on time takes a clock, so that tests can use a fake one and move time as they need.
+    pub trait Clock: Clone + Send + Sync + 'static {
+        fn now(&self) -> SystemTime;
+    }
+
+    /// Clock that returns the real current time. It has no state, so using it costs nothing.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        #[inline]
+        fn now(&self) -> SystemTime {
+            SystemTime::now()
+        }
+    }
+
+    /// Clock for tests. Time stands still until it is moved by advance.
+    /// Clones share the same time.
+    #[cfg(feature = "test-util")]
+    #[derive(Clone, Debug)]
+    pub struct FakeClock {
+        now: std::sync::Arc<std::sync::Mutex<SystemTime>>,
+    }
+
+    #[cfg(feature = "test-util")]
+    impl FakeClock {
+        /// Create a fake clock that shows the given time.
+        pub fn new(now: SystemTime) -> FakeClock {
+            FakeClock {
+                now: std::sync::Arc::new(std::sync::Mutex::new(now)),
+            }
+        }
+
+        /// Move the time forward.
+        pub fn advance(&self, duration: std::time::Duration) {
+            let mut now = self.now.lock().unwrap();
+            *now += duration;
+        }
+    }
+
+    #[cfg(feature = "test-util")]
+    impl Clock for FakeClock {
+        fn now(&self) -> SystemTime {
+            *self.now.lock().unwrap()
+        }
+    }
+}
+
+pub mod login_attempts {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use tokio::sync::Mutex;
+
+    use crate::clock::{Clock, SystemClock};
+
+    /// Rule for locking out a username after too many failed logins.
+    /// If there are max_failures failed logins within the window, further logins are refused
+    /// until the window that started with the first failure is over.
+    #[derive(Clone, Copy, Debug)]
+    pub struct LockoutPolicy {
+        pub max_failures: u32,
+        pub window: Duration,
+    }
+
+    /// Failed logins of one username in the current window.
+    #[derive(Clone, Copy, Debug)]
+    struct FailedLogins {
+        count: u32,
+        first_failed_at: SystemTime,
+    }
+
+    /// Failed logins of all usernames together with the lockout policy.
+    /// Clones share the same failed logins, so one instance can be used by all client handlers.
+    #[derive(Clone, Debug)]
+    pub struct LoginAttempts<C: Clock = SystemClock> {
+        failed_logins: Arc<Mutex<HashMap<String, FailedLogins>>>,
+        policy: LockoutPolicy,
+        clock: C,
+    }
+
+    impl LoginAttempts {
+        /// Create login attempts with no failed logins.
+        pub fn new(policy: LockoutPolicy) -> LoginAttempts {
+            LoginAttempts::with_clock(policy, SystemClock)
+        }
+    }
+
+    impl<C: Clock> LoginAttempts<C> {
+        /// Create login attempts with no failed logins that take the time from a specified clock.
+        pub fn with_clock(policy: LockoutPolicy, clock: C) -> LoginAttempts<C> {
+            LoginAttempts {
+                failed_logins: Arc::new(Mutex::new(HashMap::new())),
+                policy,
+                clock,
+            }
+        }
+
+        /// Check if logins with a username are refused at the moment.
+        pub async fn is_locked_out(&self, username: &str) -> bool {
+            let now = self.clock.now();
+            let lock = self.failed_logins.lock().await;
+            match lock.get(username) {
+                Some(failed_logins) => {
+                    self.is_in_window(failed_logins, now)
+                        && failed_logins.count >= self.policy.max_failures
+                }
+                None => false,
+            }
+        }
+
+        /// Count a failed login of a username. A new window is started if the previous one is over.
+        /// Entries whose window is over are dropped, so that the map does not grow indefinitely.
+        pub async fn record_failed_login(&self, username: &str) {
+            let now = self.clock.now();
+            let mut lock = self.failed_logins.lock().await;
+            lock.retain(|_, failed_logins| self.is_in_window(failed_logins, now));
+            lock.entry(username.to_string())
+                .or_insert(FailedLogins {
+                    count: 0,
+                    first_failed_at: now,
+                })
+                .count += 1;
+        }
+
+        /// Forget failed logins of a username, e.g. after a successful login.
+        pub async fn clear_failed_logins(&self, username: &str) {
+            let mut lock = self.failed_logins.lock().await;
+            lock.remove(username);
+        }
+
+        /// If the clock went backwards, the failure is treated as if it happened just now.
+        fn is_in_window(&self, failed_logins: &FailedLogins, now: SystemTime) -> bool {
+            now.duration_since(failed_logins.first_failed_at)
+                .unwrap_or_default()
+                < self.policy.window
+        }
+    }
+}
+
+pub mod message_writer {
+    use anyhow::{anyhow, Result};
+    use log::{error, warn};
+    use sqlx::SqlitePool;
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::mpsc::{self, Receiver, Sender};
+    use tokio::sync::{Notify, Semaphore};
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep_until, timeout, Instant};
+
+    use crate::clock::{Clock, SystemClock};
+    use crate::db::{self, MessageKind, NewAttachment, NewMessage};
+
+    /// Number of messages that can wait for the background task before writers have to wait too.
+    pub const MESSAGE_QUEUE_CAPACITY: usize = 1024;
+
+    /// Rule for saving messages in batches.
+    /// A batch is saved when it has max_batch_size messages or when max_delay has passed since
+    /// its first message was received, whichever comes first.
+    #[derive(Clone, Copy, Debug)]
+    pub struct BatchingPolicy {
+        pub max_batch_size: usize,
+        pub max_delay: Duration,
+    }
+
+    /// Handle for passing messages to a background task that saves them in batches.
+    /// Clones send to the same task. The task saves the remaining messages and ends
+    /// when all handles are dropped or when it is shut down.
+    /// Messages that are waiting for their batch are lost if the server stops without shutting the task down.
+    #[derive(Clone, Debug)]
+    pub struct MessageWriter<C: Clock = SystemClock> {
+        sender: Sender<NewMessage>,
+        shutdown: Arc<Notify>,
+        clock: C,
+    }
+
+    impl MessageWriter {
+        /// Spawn a background task that saves messages in batches and return a handle to it.
+        pub fn spawn(pool: SqlitePool, policy: BatchingPolicy) -> (MessageWriter, JoinHandle<()>) {
+            MessageWriter::spawn_with_clock(pool, policy, SystemClock)
+        }
+    }
+
+    impl<C: Clock> MessageWriter<C> {
+        /// Spawn a background task that saves messages in batches and return a handle to it.
+        /// The time when a message was sent is taken from the specified clock.
+        pub fn spawn_with_clock(
+            pool: SqlitePool,
+            policy: BatchingPolicy,
+            clock: C,
+        ) -> (MessageWriter<C>, JoinHandle<()>) {
+            let (sender, receiver) = mpsc::channel(MESSAGE_QUEUE_CAPACITY);
+            let shutdown = Arc::new(Notify::new());
+            let handle = tokio::spawn(write_batches(pool, policy, receiver, Arc::clone(&shutdown)));
+            (
+                MessageWriter {
+                    sender,
+                    shutdown,
+                    clock,
+                },
+                handle,
+            )
+        }
+
+        /// Make the background task save all queued messages right away, without waiting for full batches, and end.
+        /// Messages written after this are refused. Wait until the task has ended, at most for the timeout.
+        /// The handle must be the one returned together with this writer.
+        pub async fn shut_down(&self, handle: JoinHandle<()>, wait: Duration) -> Result<()> {
+            self.shutdown.notify_one();
+            match timeout(wait, handle).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(anyhow!("The background task saving messages failed: {}", e)),
+                Err(_) => Err(anyhow!(
+                    "The background task saving messages did not end in {} seconds.",
+                    wait.as_secs()
+                )),
+            }
+        }
+
+        /// Queue a message to be saved in the next batch. The tag is the one the client chose, if any.
+        pub async fn write(
+            &self,
+            user_id: i64,
+            content: String,
+            kind: MessageKind,
+            tag: Option<i64>,
+            attachment: Option<NewAttachment>,
+        ) -> Result<()> {
+            let message = NewMessage {
+                user_id,
+                content,
+                kind,
+                tag,
+                sent_at: self.clock.now(),
+                attachment,
+            };
+            self.sender
+                .send(message)
+                .await
+                .map_err(|_| anyhow!("The background task saving messages is not running."))
+        }
+    }
+
+    /// Collect received messages into batches and save them until the channel is closed.
+    /// On shutdown, the channel is closed by the receiver, so the messages already in it are saved without delay.
+    /// If a batch fails to be saved, its messages are saved one by one, see save_one_by_one.
+    async fn write_batches(
+        pool: SqlitePool,
+        policy: BatchingPolicy,
+        mut receiver: Receiver<NewMessage>,
+        shutdown: Arc<Notify>,
+    ) {
+        let max_batch_size = policy.max_batch_size.max(1);
+        let mut batch = Vec::with_capacity(max_batch_size);
+        let mut closed = false;
+        loop {
+            // Wait for the first message of each batch without any deadline.
+            let message = tokio::select! {
+                message = receiver.recv() => message,
+                _ = shutdown.notified(), if !closed => {
+                    receiver.close();
+                    closed = true;
+                    continue;
+                }
+            };
+            let Some(message) = message else {
+                break;
+            };
+            batch.push(message);
+            let deadline = Instant::now() + policy.max_delay;
+            while batch.len() < max_batch_size {
+                tokio::select! {
+                    message = receiver.recv() => match message {
+                        Some(message) => batch.push(message),
+                        None => break,
+                    },
+                    _ = shutdown.notified(), if !closed => {
+                        receiver.close();
+                        closed = true;
+                    }
+                    _ = sleep_until(deadline) => break,
+                }
+            }
+            if let Err(e) = db::add_messages_batch(&pool, &batch).await {
+                warn!(
+                    "Failed to save a batch of {} messages, saving them one by one: {}",
+                    batch.len(),
+                    e
+                );
+                save_one_by_one(&pool, &batch).await;
+            }
+            batch.clear();
+        }
+    }
+
+    /// Save each message of a batch that failed on its own, so that one bad message does not lose the others.
+    /// Only the messages that fail again are logged and dropped.
+    async fn save_one_by_one(pool: &SqlitePool, batch: &[NewMessage]) {
+        let mut dropped = 0;
+        for message in batch {
+            if let Err(e) = db::add_messages_batch(pool, std::slice::from_ref(message)).await {
+                error!(
+                    "Failed to save a message of user {}, it is dropped: {}",
+                    message.user_id, e
+                );
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            error!(
+                "Dropped {} of {} messages of a batch that could not be saved.",
+                dropped,
+                batch.len()
+            );
+        }
+    }
+
+    /// Limit of saves of messages running in the background, shared by all connections.
+    /// Clones share the same limit.
+    #[derive(Clone, Debug)]
+    pub struct SaveSlots {
+        semaphore: Arc<Semaphore>,
+    }
+
+    impl SaveSlots {
+        /// Create a limit of at most max_in_flight saves running at once. It must not be zero.
+        pub fn new(max_in_flight: usize) -> SaveSlots {
+            SaveSlots {
+                semaphore: Arc::new(Semaphore::new(max_in_flight)),
+            }
+        }
+
+        /// Get the number of saves that can still be started without waiting.
+        pub fn available(&self) -> usize {
+            self.semaphore.available_permits()
+        }
+    }
+
+    /// Saves of messages of one connection that run in the background, so that the messages
+    /// can be forwarded without waiting for the database.
+    /// Each save waits for the previous one, so messages of one user are saved in the order
+    /// in which they were received. A save that fails is only logged.
+    #[derive(Debug)]
+    pub struct OrderedSaves {
+        slots: SaveSlots,
+        last: Option<JoinHandle<()>>,
+    }
+
+    impl OrderedSaves {
+        /// Create an empty sequence of saves that takes its slots from the shared limit.
+        pub fn new(slots: SaveSlots) -> OrderedSaves {
+            OrderedSaves { slots, last: None }
+        }
+
+        /// Start a save in the background after the previous one of this sequence.
+        /// If all slots are taken, this waits until one of the running saves ends.
+        /// The slot is held until the save ends, including the time it waits for the previous one.
+        pub async fn spawn<F>(&mut self, save: F) -> Result<()>
+        where
+            F: Future<Output = Result<()>> + Send + 'static,
+        {
+            let permit = Arc::clone(&self.slots.semaphore)
+                .acquire_owned()
+                .await
+                .map_err(|_| anyhow!("The limit of saves running in the background is closed."))?;
+            let previous = self.last.take();
+            self.last = Some(tokio::spawn(async move {
+                if let Some(previous) = previous {
+                    // A panicked save does not stop the following ones.
+                    let _ = previous.await;
+                }
+                if let Err(e) = save.await {
+                    error!("Failed to save message in the background: {:#}", e);
+                }
+                drop(permit);
+            }));
+            Ok(())
+        }
+
+        /// Wait until all started saves of this sequence have ended.
+        pub async fn finish(&mut self) {
+            if let Some(last) = self.last.take() {
+                let _ = last.await;
+            }
+        }
+    }
+}
+
+pub mod maintenance {
+    use log::{error, info, warn};
+    use prometheus::Gauge;
+    use sqlx::SqlitePool;
+    use std::time::Duration;
+    use tokio::task::JoinHandle;
+    use tokio::time::{interval_at, Instant, MissedTickBehavior};
+
+    use crate::connections::ClientWriters;
+    use crate::db;
+
+    /// Spawn a background task that vacuums the database regularly.
+    /// The first vacuum runs one interval after the task is spawned. The interval must not be zero.
+    /// A failed vacuum is only logged and the next one is tried after another interval.
+    pub fn spawn_vacuum_task(pool: SqlitePool, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = interval_at(Instant::now() + interval, interval);
+            // A long vacuum does not make the next ones run right after it.
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                info!("Vacuuming the database...");
+                if let Err(e) = db::vacuum(&pool).await {
+                    error!("Failed to vacuum the database: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Set the active connections gauge to the number of connected clients and return the drift that was corrected.
+    /// The map of writers is locked only while its length is read and the gauge is set.
+    /// Clients are added to the map and counted, and removed from it and uncounted, under the same lock,
+    /// so a connecting or disconnecting client is never counted twice.
+    pub async fn reconcile_active_connections_gauge(
+        active_connections_gauge: &Gauge,
+        client_writers: &ClientWriters,
+    ) -> f64 {
+        let lock = client_writers.lock().await;
+        let connections = lock.len() as f64;
+        let drift = active_connections_gauge.get() - connections;
+        active_connections_gauge.set(connections);
+        drift
+    }
+
+    /// Spawn a background task that regularly corrects the active connections gauge, see reconcile_active_connections_gauge.
+    /// The gauge is changed right away when a client connects or disconnects, so this only fixes a change that was missed.
+    /// The first correction runs one interval after the task is spawned. The interval must not be zero.
+    pub fn spawn_gauge_reconciliation_task(
+        active_connections_gauge: Gauge,
+        client_writers: ClientWriters,
+        interval: Duration,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticks = interval_at(Instant::now() + interval, interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let drift =
+                    reconcile_active_connections_gauge(&active_connections_gauge, &client_writers)
+                        .await;
+                if drift != 0.0 {
+                    warn!(
+                        "The active connections gauge was off by {}. It was corrected.",
+                        drift
+                    );
+                }
+            }
+        })
+    }
+}
+
+pub mod sessions {
+    use anyhow::{anyhow, Result};
+    use log::info;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::Arc;
+    use tokio::sync::{Mutex, Notify};
+    use uuid::Uuid;
+
+    use crate::connections::ClientAddress;
+
+    /// Id of one session of a user, assigned by the server when the user logs in or registers.
+    /// Unlike the address of the client, it is unique, so the session can be found in the logs
+    /// even if another client connects from the same address later.
+    /// It is a random UUID of version 4, shown in the usual textual form.
+    #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    pub struct SessionId(Uuid);
+
+    impl SessionId {
+        /// Create a new random session id.
+        pub fn new() -> SessionId {
+            SessionId(Uuid::new_v4())
+        }
+    }
+
+    impl Default for SessionId {
+        fn default() -> SessionId {
+            SessionId::new()
+        }
+    }
+
+    impl fmt::Display for SessionId {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    /// What happens when a user logs in while another connection is logged in as the same user.
+    /// Reject refuses the new login. KickExisting disconnects the other connection and lets the new one in.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum DuplicateLoginPolicy {
+        Reject,
+        KickExisting,
+    }
+
+    impl DuplicateLoginPolicy {
+        /// Names of all policies as they are typed on the command line.
+        pub const NAMES: [&'static str; 2] = ["reject", "kick"];
+
+        /// Get the policy with the name typed on the command line.
+        pub fn from_name(name: &str) -> Result<DuplicateLoginPolicy> {
+            match name {
+                "reject" => Ok(DuplicateLoginPolicy::Reject),
+                "kick" => Ok(DuplicateLoginPolicy::KickExisting),
+                _ => Err(anyhow!("Unknown policy '{}' for duplicate logins.", name)),
+            }
+        }
+    }
+
+    /// The connection logged in as a user. Kicked is notified when the connection has to be closed.
+    #[derive(Debug)]
+    struct Session {
+        id: SessionId,
+        address: ClientAddress,
+        kicked: Arc<Notify>,
+    }
+
+    /// Connections of logged in users, at most one per user id, together with the duplicate login policy.
+    /// Clones share the same sessions, so one instance can be used by all client handlers.
+    #[derive(Clone, Debug)]
+    pub struct ActiveSessions {
+        sessions: Arc<Mutex<HashMap<i64, Session>>>,
+        policy: DuplicateLoginPolicy,
+    }
+
+    impl ActiveSessions {
+        /// Create active sessions with no logged in users.
+        pub fn new(policy: DuplicateLoginPolicy) -> ActiveSessions {
+            ActiveSessions {
+                sessions: Arc::new(Mutex::new(HashMap::new())),
+                policy,
+            }
+        }
+
+        /// Start a session with the given id of a user on a connection.
+        /// If the user is logged in elsewhere, the policy decides whether None is returned or the other session is kicked.
+        /// The returned notify is notified when this session is kicked in turn.
+        pub async fn start(
+            &self,
+            user_id: i64,
+            address: ClientAddress,
+            id: SessionId,
+        ) -> Option<Arc<Notify>> {
+            let mut lock = self.sessions.lock().await;
+            if let Some(existing) = lock.get(&user_id) {
+                match self.policy {
+                    DuplicateLoginPolicy::Reject => return None,
+                    DuplicateLoginPolicy::KickExisting => {
+                        info!("Session {} is kicked by session {}.", existing.id, id);
+                        existing.kicked.notify_one();
+                    }
+                }
+            }
+            let kicked = Arc::new(Notify::new());
+            lock.insert(
+                user_id,
+                Session {
+                    id,
+                    address,
+                    kicked: Arc::clone(&kicked),
+                },
+            );
+            Some(kicked)
+        }
+
+        /// Get the id of the session of a user, if the user is logged in.
+        pub async fn get_session_id(&self, user_id: i64) -> Option<SessionId> {
+            let lock = self.sessions.lock().await;
+            lock.get(&user_id).map(|session| session.id.clone())
+        }
+
+        /// Kick the session of a user, e.g. because the user was banned. Return false if the user is not logged in.
+        /// The session ends when its connection is closed.
+        pub async fn kick(&self, user_id: i64) -> bool {
+            let lock = self.sessions.lock().await;
+            match lock.get(&user_id) {
+                Some(session) => {
+                    info!("Session {} is kicked.", session.id);
+                    session.kicked.notify_one();
+                    true
+                }
+                None => false,
+            }
+        }
+
+        /// End the session on a connection, e.g. after it was closed. Sessions of the user on other connections stay.
+        pub async fn end(&self, address: ClientAddress) {
+            let mut lock = self.sessions.lock().await;
+            lock.retain(|_, session| session.address != address);
+        }
+    }
+}
+
+pub mod read_receipts {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    use crate::connections::ClientAddress;
+
+    /// The maximum number of messages whose authors are remembered. Read receipts for older messages are dropped.
+    pub const MAX_TRACKED_MESSAGES: usize = 1000;
+
+    /// Remembered messages, oldest first, with the addresses of their authors. The next message gets next_id.
+    #[derive(Debug)]
+    struct TrackedMessages {
+        next_id: i64,
+        authors: VecDeque<(i64, ClientAddress)>,
+    }
+
+    /// Authors of the recently sent messages that can get read receipts.
+    /// The ids are assigned by the server and are only valid while it is running.
+    /// Clones share the same messages, so one instance can be used by all client handlers.
+    #[derive(Clone, Debug)]
+    pub struct MessageAuthors {
+        messages: Arc<Mutex<TrackedMessages>>,
+    }
+
+    impl Default for MessageAuthors {
+        fn default() -> MessageAuthors {
+            MessageAuthors::new()
+        }
+    }
+
+    impl MessageAuthors {
+        /// Create message authors with no messages.
+        pub fn new() -> MessageAuthors {
+            MessageAuthors {
+                messages: Arc::new(Mutex::new(TrackedMessages {
+                    next_id: 1,
+                    authors: VecDeque::with_capacity(MAX_TRACKED_MESSAGES),
+                })),
+            }
+        }
+
+        /// Assign a new id to a message sent by a client and remember the client as its author.
+        /// If there are too many messages, the oldest one is forgotten.
+        pub async fn assign_id(&self, author: ClientAddress) -> i64 {
+            let mut lock = self.messages.lock().await;
+            let id = lock.next_id;
+            lock.next_id += 1;
+            if lock.authors.len() >= MAX_TRACKED_MESSAGES {
+                lock.authors.pop_front();
+            }
+            lock.authors.push_back((id, author));
+            id
+        }
+
+        /// Get the address of the author of the message with specified id, if it is still remembered.
+        pub async fn get_author(&self, id: i64) -> Option<ClientAddress> {
+            let lock = self.messages.lock().await;
+            // Ids are assigned in ascending order, so the messages are sorted by them.
+            lock.authors
+                .binary_search_by_key(&id, |(message_id, _)| *message_id)
+                .ok()
+                .map(|index| lock.authors[index].1)
+        }
+
+        /// Forget all messages of a client, e.g. after it disconnected.
+        /// Another client may get the same address later and must not get read receipts for them.
+        pub async fn forget_author(&self, author: ClientAddress) {
+            let mut lock = self.messages.lock().await;
+            lock.authors.retain(|(_, address)| *address != author);
+        }
+    }
+}
+
+pub mod word_filter {
+    use anyhow::{anyhow, Context, Result};
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    /// The character each character of a banned word is replaced with when it is masked.
+    /// It is not '*', so that masked words are not taken for markdown styles.
+    pub const MASK_CHARACTER: char = '#';
+
+    /// What happens with a text message that contains a banned word.
+    /// Mask replaces the banned words and lets the message through. Reject refuses the whole message.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum FilterAction {
+        Mask,
+        Reject,
+    }
+
+    impl FilterAction {
+        /// Names of all actions as they are typed on the command line.
+        pub const NAMES: [&'static str; 2] = ["mask", "reject"];
+
+        /// Get the action with the name typed on the command line.
+        pub fn from_name(name: &str) -> Result<FilterAction> {
+            match name {
+                "mask" => Ok(FilterAction::Mask),
+                "reject" => Ok(FilterAction::Reject),
+                _ => Err(anyhow!("Unknown filter action '{}'.", name)),
+            }
+        }
+    }
+
+    /// Banned words together with the action taken on messages that contain them.
+    /// A word is a maximal run of letters and digits. Words are matched regardless of case,
+    /// so a banned word does not match longer words that contain it.
+    #[derive(Clone, Debug)]
+    pub struct Filter {
+        words: HashSet<String>,
+        action: FilterAction,
+    }
+
+    impl Filter {
+        /// Create a filter of the given words. The words must contain only letters and digits.
+        pub fn new<'a>(
+            words: impl IntoIterator<Item = &'a str>,
+            action: FilterAction,
+        ) -> Result<Filter> {
+            let mut banned_words = HashSet::new();
+            for word in words {
+                if word.is_empty() || !word.chars().all(char::is_alphanumeric) {
+                    return Err(anyhow!(
+                        "Banned word '{}' can contain only letters and digits.",
+                        word
+                    ));
+                }
+                banned_words.insert(word.to_lowercase());
+            }
+            Ok(Filter {
+                words: banned_words,
+                action,
+            })
+        }
+
+        /// Read banned words from a UTF-8 text file with one word per line.
+        /// Empty lines and lines starting with '#' are skipped.
+        pub async fn from_file(path: &Path, action: FilterAction) -> Result<Filter> {
+            let text = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read word filter file {}.", path.display()))?;
+            let words = text
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'));
+            Filter::new(words, action)
+        }
+
+        /// Get the action taken on messages that contain a banned word.
+        pub fn action(&self) -> FilterAction {
+            self.action
+        }
+
+        /// Mask all banned words in a text. Each character of a banned word is replaced with MASK_CHARACTER.
+        /// The second value tells whether any banned word was found.
+        pub fn sanitize(&self, text: &str) -> (String, bool) {
+            let mut sanitized = String::with_capacity(text.len());
+            let mut found = false;
+            let mut word_start = None;
+            for (index, character) in text.char_indices() {
+                match (character.is_alphanumeric(), word_start) {
+                    (true, None) => word_start = Some(index),
+                    (false, Some(start)) => {
+                        found |= self.push_word(&mut sanitized, &text[start..index]);
+                        sanitized.push(character);
+                        word_start = None;
+                    }
+                    (false, None) => sanitized.push(character),
+                    (true, Some(_)) => {}
+                }
+            }
+            if let Some(start) = word_start {
+                found |= self.push_word(&mut sanitized, &text[start..]);
+            }
+            (sanitized, found)
+        }
+
+        /// Append a word to the sanitized text, masked if it is banned. Return whether it was banned.
+        fn push_word(&self, sanitized: &mut String, word: &str) -> bool {
+            if self.words.contains(&word.to_lowercase()) {
+                sanitized.extend(word.chars().map(|_| MASK_CHARACTER));
+                true
+            } else {
+                sanitized.push_str(word);
+                false
+            }
+        }
+    }
+}
+
+pub mod duplicate_texts {
+    use std::time::{Duration, SystemTime};
+
+    use shared::MessageType;
+
+    use crate::clock::{Clock, SystemClock};
+
+    /// The last text message that one connection forwarded, used to drop the same text sent again right after it,
+    /// e.g. because of a double Enter. A text is a duplicate if it is the same as the last forwarded text,
+    /// no other message was forwarded in between and it arrived less than the window after that text.
+    /// Dropped duplicates do not move the window, so a text repeated for a long time still gets through once per window.
+    #[derive(Debug)]
+    pub struct DuplicateTexts<C: Clock = SystemClock> {
+        window: Duration,
+        last_text: Option<(String, SystemTime)>,
+        clock: C,
+    }
+
+    impl DuplicateTexts {
+        /// Create duplicate detection for a new connection with the given window.
+        pub fn new(window: Duration) -> DuplicateTexts {
+            DuplicateTexts::with_clock(window, SystemClock)
+        }
+    }
+
+    impl<C: Clock> DuplicateTexts<C> {
+        /// Create duplicate detection for a new connection that takes the time from a specified clock.
+        pub fn with_clock(window: Duration, clock: C) -> DuplicateTexts<C> {
+            DuplicateTexts {
+                window,
+                last_text: None,
+                clock,
+            }
+        }
+
+        /// Check if a message is a duplicate of the last forwarded text. If it is not, it is remembered as forwarded.
+        /// Only texts can be duplicates. If the clock went backwards, the text is treated as if it was forwarded just now.
+        pub fn is_duplicate(&mut self, message: &MessageType) -> bool {
+            let MessageType::Text(text) = message else {
+                self.last_text = None;
+                return false;
+            };
+            let now = self.clock.now();
+            if let Some((last_text, forwarded_at)) = &self.last_text {
+                if last_text == text
+                    && now.duration_since(*forwarded_at).unwrap_or_default() < self.window
+                {
+                    return true;
+                }
+            }
+            self.last_text = Some((text.clone(), now));
+            false
+        }
+    }
 }
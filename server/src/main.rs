@@ -1,75 +1,329 @@
 use anyhow::{anyhow, Context, Result};
-use clap::{Arg, Command};
-use log::{error, info};
-use prometheus::{Counter, Gauge, Registry};
+use argon2::Params;
+use clap::builder::{NonEmptyStringValueParser, RangedU64ValueParser};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::{debug, error, info};
+use mime_guess::mime::Mime;
+use prometheus::{Counter, CounterVec, Gauge};
 use sqlx::SqlitePool;
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use std::time::Duration;
+use tokio::io::AsyncRead;
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::sleep;
 
-use server::db;
-use server::http_server::run_http_server;
-use server::metrics::{get_active_connections_gauge, get_messages_counter};
-use server::password_hashing::{hash_password, verify_password};
-use shared::{receive_message, send_message, MessageType};
+use server::auth::{authenticate, register_user};
+use server::clock::SystemClock;
+use server::connections::{
+    broadcast_message, create_broadcast_failures, create_client_writers, send_message_to_client,
+    BroadcastFailures, ClientAddress, ClientWriter, ClientWriters, FrameWriter,
+};
+use server::db::{self, MessageKind, NewAttachment, RenameUserError, SetDisplayNameError};
+use server::duplicate_texts::DuplicateTexts;
+use server::file_store::{guess_mime_type, AllowedExtensions, FileStore};
+use server::http_server::{bind_http_listener, serve_http, AdminToken, HttpState, ServerStart};
+use server::login_attempts::{LockoutPolicy, LoginAttempts};
+use server::maintenance::{spawn_gauge_reconciliation_task, spawn_vacuum_task};
+use server::message_writer::{BatchingPolicy, MessageWriter, OrderedSaves, SaveSlots};
+use server::metrics::{
+    create_registry, get_active_connections_gauge, get_auth_attempts_counter,
+    get_db_operation_durations_histogram, get_messages_counter,
+};
+use server::password_hashing::PasswordHashingOptions;
+use server::read_receipts::MessageAuthors;
+use server::sessions::{ActiveSessions, DuplicateLoginPolicy, SessionId};
+use server::word_filter::{Filter, FilterAction};
+use shared::{
+    describe_version, enable_keepalive, init_logger, parse_socket_addr, receive_message,
+    receive_signed_message_into, AuthError, BytesSendReceiveError, Compression, DecodeError,
+    Direction, FrameSigner, HistoryEntry, LogFormat, MessageKey, MessageType, ReadReceipts,
+    RejectReason, Signing, MAX_TEXT_LENGTH,
+};
 
-type SharedWriteHalf = Arc<Mutex<OwnedWriteHalf>>;
+/// Options that change how the chat server handles clients.
+#[derive(Clone)]
+struct ChatServerOptions {
+    /// Time after which an idle connection is checked by TCP keepalive probes.
+    keepalive_interval: Duration,
+    /// If set, clients are not allowed to send images and files.
+    text_only: bool,
+    /// If set, only files with these extensions can be sent. Otherwise files with any extension are allowed.
+    allowed_extensions: Option<AllowedExtensions>,
+    /// If set, images and files sent by clients are stored in it.
+    file_store: Option<FileStore>,
+    /// Failed logins shared by all connections. Usernames with too many of them are locked out.
+    login_attempts: LoginAttempts,
+    /// Settings for hashing and verifying passwords of users.
+    password_hashing: PasswordHashingOptions,
+    /// If set, messages are saved in batches by a background task instead of one by one.
+    message_writer: Option<MessageWriter>,
+    /// If set, messages are saved in the background, so that forwarding them does not wait for the database.
+    /// Saves of one connection keep their order and all connections share the limit of saves running at once.
+    save_slots: Option<SaveSlots>,
+    /// Authors of recent messages shared by all connections. Read receipts are relayed to them.
+    message_authors: MessageAuthors,
+    /// If set, the message of the day is sent to each client after a successful authentication.
+    motd: Option<Motd>,
+    /// Number of messages that can wait to be written to one client. Clients whose queue is full are disconnected.
+    outgoing_queue_capacity: usize,
+    /// Connections of logged in users shared by all connections. A user can be logged in only once.
+    sessions: ActiveSessions,
+    /// Prometheus metric counting registrations and logins by their result.
+    auth_attempts_counter: CounterVec,
+    /// Prometheus metric counting connected clients. It is changed under the lock of the map of their writers.
+    active_connections_gauge: Gauge,
+    /// If set, banned words in text messages are masked or the messages are rejected.
+    word_filter: Option<Arc<Filter>>,
+    /// If set, a text that is the same as the previous text of the connection and comes within this time after it is dropped.
+    duplicate_window: Option<Duration>,
+    /// If set, all frames after the authentication are signed with this key and clients must sign theirs too.
+    message_key: Option<MessageKey>,
+    /// If set, it is sent along with the reason to each client whose connection is rejected.
+    rejection_banner: Option<String>,
+}
+
+/// Message of the day, either given directly or read from a file each time it is sent.
+#[derive(Clone, Debug)]
+enum Motd {
+    Text(String),
+    File(PathBuf),
+}
+
+/// The capacity the receive buffer of a connection is shrunk to after a larger message.
+/// Text messages of any allowed length fit into it.
+const RECEIVE_BUFFER_KEPT_CAPACITY: usize = 2 * MAX_TEXT_LENGTH;
+
+/// Arguments whose values must never be logged.
+const SECRET_ARGS: &[&str] = &["password-pepper", "message-key", "admin-token"];
+
+/// The maximum number of messages sent to a client in reply to one history request.
+/// Clients asking for more get only this many of the most recent messages.
+const MAX_HISTORY_LENGTH: u32 = 100;
+
+/// The delay before accepting a connection is retried after the first transient error.
+const MIN_ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// The longest delay before accepting a connection is retried after repeated transient errors.
+const MAX_ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest time the server waits on shutdown until messages waiting for their batch are saved.
+const SHUTDOWN_SAVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reading half of a connection to one client, either over TCP or over a Unix domain socket.
+type ClientReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Number given to the next client that connects through a Unix domain socket.
+static NEXT_UNIX_CLIENT_NUMBER: AtomicU64 = AtomicU64::new(1);
+
+/// Listener for incoming client connections. Unix domain sockets are available only on Unix.
+enum ChatListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl ChatListener {
+    /// Wait for a new client connection and split it into a reader and a writer.
+    /// TCP keepalive is enabled on each TCP connection so that half-open connections are detected and dropped.
+    async fn accept(
+        &self,
+        keepalive_interval: Duration,
+    ) -> Result<(ClientAddress, ClientReader, ClientWriter)> {
+        match self {
+            ChatListener::Tcp(listener) => {
+                let (client_stream, client_address) = listener
+                    .accept()
+                    .await
+                    .context("Failed to accept a new connection from a client.")?;
+                if let Err(e) = enable_keepalive(&client_stream, keepalive_interval) {
+                    error!("Failed to enable keepalive for {}: {}", client_address, e);
+                }
+                let (client_reader, client_writer) = client_stream.into_split();
+                Ok((
+                    client_address.into(),
+                    Box::new(client_reader),
+                    Box::new(client_writer),
+                ))
+            }
+            #[cfg(unix)]
+            ChatListener::Unix(listener) => {
+                let (client_stream, _) = listener
+                    .accept()
+                    .await
+                    .context("Failed to accept a new connection from a client.")?;
+                let client_address =
+                    ClientAddress::Unix(NEXT_UNIX_CLIENT_NUMBER.fetch_add(1, Ordering::Relaxed));
+                let (client_reader, client_writer) = client_stream.into_split();
+                Ok((
+                    client_address,
+                    Box::new(client_reader),
+                    Box::new(client_writer),
+                ))
+            }
+        }
+    }
+}
+
+/// Accept a connection, retrying after transient errors, e.g. when file descriptors are exhausted for a moment.
+/// The delay before a retry starts at MIN_ACCEPT_RETRY_DELAY and doubles after each failure up to MAX_ACCEPT_RETRY_DELAY.
+/// Fatal errors are returned, because the listener cannot be used anymore.
+async fn retry_transient_accept_errors<T, F, Fut>(mut accept: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut retry_delay = MIN_ACCEPT_RETRY_DELAY;
+    loop {
+        match accept().await {
+            Ok(accepted) => return Ok(accepted),
+            Err(e) if is_transient_accept_error(&e) => {
+                error!("{:#}. Retrying in {} ms.", e, retry_delay.as_millis());
+                sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(MAX_ACCEPT_RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Check if accepting a connection failed only for a while, so that it is worth trying again.
+/// These are errors of the connection being accepted and lack of resources, which may be freed soon.
+fn is_transient_accept_error(error: &anyhow::Error) -> bool {
+    let Some(error) = error.downcast_ref::<io::Error>() else {
+        return false;
+    };
+    if matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::OutOfMemory
+    ) {
+        return true;
+    }
+    // Running out of file descriptors or buffers has no error kind of its own.
+    #[cfg(unix)]
+    if matches!(
+        error.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    ) {
+        return true;
+    }
+    false
+}
+
+/// Bind a listener for chat clients to a TCP socket address.
+/// The socket address can be either IPv4 (e.g. "0.0.0.0:11111") or IPv6 (e.g. "[::]:11111").
+async fn bind_tcp_listener(socket_address: &str) -> Result<ChatListener> {
+    let socket_address = parse_socket_addr(socket_address)?;
+    let listener = TcpListener::bind(socket_address)
+        .await
+        .with_context(|| format!("TcpListener failed to bind to {}.", socket_address))?;
+    Ok(ChatListener::Tcp(listener))
+}
+
+/// Bind a listener for chat clients to a Unix domain socket. Clients are served the same way as on TCP.
+/// The socket file is created at the path. It must not exist yet. It is removed when the returned guard is dropped.
+#[cfg(unix)]
+fn bind_unix_listener(socket_path: &std::path::Path) -> Result<(ChatListener, UnixSocketFile)> {
+    let listener = UnixListener::bind(socket_path).with_context(|| {
+        format!(
+            "UnixListener failed to bind to {}. If the file is left over from a previous run, remove it.",
+            socket_path.display()
+        )
+    })?;
+    Ok((
+        ChatListener::Unix(listener),
+        UnixSocketFile(socket_path.to_path_buf()),
+    ))
+}
+
+/// Unix domain socket file that is removed when this value is dropped.
+#[cfg(unix)]
+struct UnixSocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for UnixSocketFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Ok(_) => info!("Removed Unix domain socket file {}.", self.0.display()),
+            Err(e) => error!(
+                "Failed to remove Unix domain socket file {}: {}",
+                self.0.display(),
+                e
+            ),
+        }
+    }
+}
 
-/// This function runs server.
-/// It listens for connections from clients in a loop.
+/// Accept connections from clients in a loop.
 /// Each time a client connects, a new async task is spawned that handles that connection.
-async fn run_server(
-    socket_address: &str,
+/// Transient errors of accepting are logged and retried, only fatal ones stop the loop.
+async fn serve_clients(
+    listener: ChatListener,
     connection_pool: SqlitePool,
+    client_writers: ClientWriters,
+    broadcast_failures: BroadcastFailures,
     messages_counter: &Counter,
-    active_connections_gauge: &Gauge,
+    options: ChatServerOptions,
 ) -> Result<()> {
-    let listener = TcpListener::bind(socket_address)
-        .await
-        .context("TcpListener failed to bind to a socket address.")?;
-    let client_writers: Arc<Mutex<HashMap<SocketAddr, SharedWriteHalf>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-
     loop {
-        // Create a new stream for each incomming connection.
-        let (client_stream, client_address) = listener
-            .accept()
-            .await
-            .context("Failed to accept a new connection from a client.")?;
-        // Split each stream into a reader and a writer.
-        let (client_reader, client_writer) = client_stream.into_split();
+        // Create a reader and a writer for each incomming connection.
+        let (client_address, client_reader, client_writer) =
+            retry_transient_accept_errors(|| listener.accept(options.keepalive_interval)).await?;
 
-        // Add writer to respective hash maps. The socket address is key.
+        // Add writer to respective hash maps. The client address is key.
         {
             let mut lock = client_writers.lock().await;
-            lock.insert(client_address.clone(), Arc::new(Mutex::new(client_writer)));
+            lock.insert(
+                client_address,
+                Arc::new(Mutex::new(FrameWriter::new(
+                    client_writer,
+                    options.outgoing_queue_capacity,
+                ))),
+            );
             // Increament the number of active connections.
-            active_connections_gauge.inc();
+            options.active_connections_gauge.inc();
         }
 
         // Clone reader hash map.
         let client_writers_cloned = Arc::clone(&client_writers);
+        // Clone broadcast failures buffer.
+        let broadcast_failures_cloned = Arc::clone(&broadcast_failures);
         // Clone connection pool.
         let connection_pool_cloned = connection_pool.clone();
         // Clone messages counter prometheus metric.
         let messages_counter_cloned = messages_counter.clone();
         // Clone active connections gauge prometheus metric.
-        let active_connections_gauge_cloned = active_connections_gauge.clone();
+        let active_connections_gauge_cloned = options.active_connections_gauge.clone();
+        // Clone options.
+        let options_cloned = options.clone();
         // For each incomming connection, there is a separate async task.
         tokio::spawn(async move {
-            let client_address_for_removal = client_address.clone();
+            let client_address_for_removal = client_address;
             let client_writers_for_removal = Arc::clone(&client_writers_cloned);
+            let message_authors = options_cloned.message_authors.clone();
+            let sessions = options_cloned.sessions.clone();
 
             // Start client handler that receives and forwards messages.
             if let Err(e) = handle_client(
                 client_address,
                 client_reader,
                 client_writers_cloned,
+                broadcast_failures_cloned,
                 connection_pool_cloned,
-                messages_counter_cloned
+                messages_counter_cloned,
+                options_cloned,
             )
             .await
             {
@@ -80,9 +334,19 @@ async fn run_server(
             };
 
             // After a spawned tasks comes to an end, remove writer associated with the corresponding client.
-            remove_client_writer(client_address_for_removal, client_writers_for_removal).await;
-            // Decreament the number of active connections.
-            active_connections_gauge_cloned.dec();
+            // The number of active connections is decreased together with it.
+            remove_client_writer(
+                client_address_for_removal,
+                client_writers_for_removal,
+                &active_connections_gauge_cloned,
+            )
+            .await;
+            // Read receipts for messages of this client cannot be delivered anymore.
+            message_authors
+                .forget_author(client_address_for_removal)
+                .await;
+            // The user can log in again from elsewhere.
+            sessions.end(client_address_for_removal).await;
         });
     }
 }
@@ -91,88 +355,500 @@ async fn run_server(
 /// First, it handles user authentication.
 /// Then a loop follows where the server waits for incomming messages.
 /// If a message arrives, it is saved into a database and resent to all other clients.
+/// Images and files are rejected if the server runs in text only mode.
+/// When the connection ends, saves of its messages that run in the background are waited for.
 async fn handle_client(
-    client_address: SocketAddr,
-    mut client_reader: OwnedReadHalf,
-    client_writers: Arc<Mutex<HashMap<SocketAddr, SharedWriteHalf>>>,
+    client_address: ClientAddress,
+    mut client_reader: ClientReader,
+    client_writers: ClientWriters,
+    broadcast_failures: BroadcastFailures,
     connection_pool: SqlitePool,
-    messages_counter: Counter
+    messages_counter: Counter,
+    options: ChatServerOptions,
 ) -> Result<()> {
     // Try to authenticate user. If not successful, the connection will be dropped.
-    let (user_id, _username) = match authenticate_user(
-        &mut client_reader,
-        &client_address,
-        &client_writers,
-        &connection_pool,
-    )
-    .await
-    {
-        Some((id, name)) => (id, name),
-        None => {
-            return Ok(());
-        }
+    let (user_id, mut username, session_id, compression, read_receipts, kicked) =
+        match authenticate_user(
+            &mut client_reader,
+            &client_address,
+            &client_writers,
+            &connection_pool,
+            &options,
+        )
+        .await
+        {
+            Some((id, name, session_id, compression, read_receipts, kicked)) => {
+                (id, name, session_id, compression, read_receipts, kicked)
+            }
+            None => {
+                return Ok(());
+            }
+        };
+    // If messages for the client come faster than it reads them, the connection is closed.
+    let overflow = match client_writers.lock().await.get(&client_address) {
+        Some(writer) => writer.lock().await.overflow(),
+        None => return Ok(()),
     };
-    loop {
-        // Wait for data from a client.
-        let received_message = receive_message(&mut client_reader)
+    // Messages of this connection that are being saved in the background, if it is enabled.
+    let mut background_saves = options.save_slots.clone().map(OrderedSaves::new);
+    // The last text of this connection, if duplicate texts are dropped.
+    let mut duplicate_texts = options.duplicate_window.map(DuplicateTexts::new);
+    // Messages of the user are shown to other clients under this name.
+    let mut display_name = db::get_display_name(&connection_pool, &user_id)
+        .await
+        .context("Failed to get display name.")?;
+
+    // Greet the client with the message of the day, if there is one.
+    if let Some(motd) = &options.motd {
+        if let Some(text) = load_motd(motd).await {
+            send_message_to_client(
+                &client_address,
+                &client_writers,
+                &MessageType::ServerNotice(text),
+            )
             .await
-            .context("Failed when receiving a message.")?;
+            .context("Failed to send message of the day.")?;
+        }
+    }
+
+    // One buffer is reused for all messages from the client.
+    let mut receive_buffer = Vec::new();
+    // Frames from the client are checked in the order in which the client signed them.
+    let mut receive_signer = options
+        .message_key
+        .clone()
+        .map(|key| FrameSigner::new(key, Direction::ClientToServer));
+    // Every way of leaving the loop ends the connection, so the saves started in the background are waited for after it.
+    let result: Result<()> = async {
+        loop {
+            // Wait for data from a client. A client closing the connection between messages is a normal disconnect.
+            // If the user logs in elsewhere or is banned and this session is kicked, the connection is closed.
+            // So it is if the outgoing queue of the client overflows.
+            let received = tokio::select! {
+                received = receive_signed_message_into(
+                    &mut client_reader,
+                    &mut receive_buffer,
+                    compression,
+                    receive_signer.as_mut(),
+                ) => received,
+                _ = kicked.notified() => {
+                    // A session is kicked either by a login elsewhere or by a ban.
+                    let banned = db::is_banned(&connection_pool, &user_id, &SystemClock)
+                        .await
+                        .unwrap_or(false);
+                    let reason = if banned {
+                        "you were banned"
+                    } else {
+                        "you logged in elsewhere"
+                    };
+                    info!(
+                        "{} was kicked, because {}. Closing the connection of session {}.",
+                        username, reason, session_id
+                    );
+                    let message_from_server = MessageType::ServerError(format!(
+                        "You are disconnected, because {}.",
+                        reason
+                    ));
+                    send_message_to_client(&client_address, &client_writers, &message_from_server)
+                        .await
+                        .context("Failed to send disconnect notice.")?;
+                    return Ok(());
+                }
+                _ = overflow.notified() => {
+                    info!(
+                        "{} cannot keep up with incoming messages. Closing the connection of session {}.",
+                        username, session_id
+                    );
+                    return Ok(());
+                }
+            };
+            let received_message = match received {
+                Ok(message) => message,
+                Err(e) if is_connection_closed(&e) => {
+                    info!(
+                        "Client {} of session {} disconnected.",
+                        username, session_id
+                    );
+                    return Ok(());
+                }
+                Err(e) => return Err(e.context("Failed when receiving a message.")),
+            };
+            // Do not keep a lot of memory for each connection after a large image or file.
+            receive_buffer.shrink_to(RECEIVE_BUFFER_KEPT_CAPACITY);
 
-        // Increment the number of received messages.
-        messages_counter.inc();
+            // The tag of a tagged text is kept aside, the text is handled like any other and the tag is put back when forwarding.
+            let (tag, received_message) = match received_message {
+                MessageType::Tagged(tag, message) if is_text(&message) => (Some(tag), *message),
+                MessageType::Tagged(_, message) => {
+                    info!(
+                        "Rejected a tagged message of kind {} from {}.",
+                        message.kind_str(),
+                        username
+                    );
+                    let message_from_server =
+                        MessageType::ServerError("Only text messages can be tagged.".to_string());
+                    send_message_to_client(&client_address, &client_writers, &message_from_server)
+                        .await
+                        .context("Failed to send rejection response.")?;
+                    continue;
+                }
+                message => (None, message),
+            };
 
-        // Save received message in a database.
-        save_message_in_database(&connection_pool, &user_id, &received_message)
-            .await
-            .context("Failed to save message in a database.")?;
+            // A rename request is handled by the server itself. It is neither saved nor forwarded.
+            if let MessageType::Rename(new_username) = received_message {
+                let message_from_server =
+                    rename(&connection_pool, &user_id, &mut username, new_username).await;
+                send_message_to_client(&client_address, &client_writers, &message_from_server)
+                    .await
+                    .context("Failed to send rename response.")?;
+                // Without a nickname, the messages are shown under the new username.
+                display_name = db::get_display_name(&connection_pool, &user_id)
+                    .await
+                    .context("Failed to get display name.")?;
+                continue;
+            }
+
+            // A nickname request is handled by the server itself too.
+            if let MessageType::SetNickname(nickname) = received_message {
+                let message_from_server = set_nickname(
+                    &connection_pool,
+                    &user_id,
+                    &username,
+                    &mut display_name,
+                    nickname,
+                )
+                .await;
+                send_message_to_client(&client_address, &client_writers, &message_from_server)
+                    .await
+                    .context("Failed to send nickname response.")?;
+                continue;
+            }
+
+            // A history request is also handled by the server itself.
+            if let MessageType::HistoryRequest(count) = received_message {
+                let message_from_server = get_history(&connection_pool, count).await;
+                send_message_to_client(&client_address, &client_writers, &message_from_server)
+                    .await
+                    .context("Failed to send history response.")?;
+                continue;
+            }
+
+            // A read receipt is only relayed to the author of the message.
+            if let MessageType::ReadReceipt(id) = received_message {
+                relay_read_receipt(
+                    &options.message_authors,
+                    &client_writers,
+                    &client_address,
+                    id,
+                    &username,
+                )
+                .await;
+                continue;
+            }
+
+            // In text only mode, images and files are neither saved nor forwarded.
+            if !is_message_allowed(&received_message, options.text_only) {
+                info!("Rejected a message from {} in text only mode.", username);
+                let message_from_server = MessageType::ServerError(
+                    "This server accepts only text messages. Images and files are not allowed."
+                        .to_string(),
+                );
+                send_message_to_client(&client_address, &client_writers, &message_from_server)
+                    .await
+                    .context("Failed to send rejection response.")?;
+                continue;
+            }
+
+            // Files with extensions that are not allowed are neither saved nor forwarded.
+            if let (MessageType::File { name, .. }, Some(allowed_extensions)) =
+                (&received_message, &options.allowed_extensions)
+            {
+                if !allowed_extensions.allows(name) {
+                    info!(
+                        "Rejected file {} from {}: its extension is not allowed.",
+                        name, username
+                    );
+                    let message_from_server = MessageType::ServerError(format!(
+                        "The file {} was not sent. Files of this type are not allowed on this server.",
+                        name
+                    ));
+                    send_message_to_client(&client_address, &client_writers, &message_from_server)
+                        .await
+                        .context("Failed to send rejection response.")?;
+                    continue;
+                }
+            }
+
+            // Texts that are too long to be saved are neither saved nor forwarded.
+            if !is_message_length_allowed(&received_message) {
+                info!("Rejected a too long message from {}.", username);
+                let message_from_server = MessageType::ServerError(format!(
+                    "The message is too long. Text messages can have at most {} characters.",
+                    db::MAX_CONTENT_LENGTH
+                ));
+                send_message_to_client(&client_address, &client_writers, &message_from_server)
+                    .await
+                    .context("Failed to send rejection response.")?;
+                continue;
+            }
+
+            // Banned words are masked before the message is saved and forwarded, or the message is rejected.
+            let received_message = match &options.word_filter {
+                Some(word_filter) => match apply_word_filter(received_message, word_filter) {
+                    Some(message) => message,
+                    None => {
+                        info!("Rejected a message with a banned word from {}.", username);
+                        let message_from_server = MessageType::ServerError(
+                            "The message contains a word that is not allowed on this server."
+                                .to_string(),
+                        );
+                        send_message_to_client(&client_address, &client_writers, &message_from_server)
+                            .await
+                            .context("Failed to send rejection response.")?;
+                        continue;
+                    }
+                },
+                None => received_message,
+            };
+
+            // The same text sent again right after the previous one is neither saved nor forwarded.
+            if let Some(duplicate_texts) = &mut duplicate_texts {
+                if duplicate_texts.is_duplicate(&received_message) {
+                    info!("Dropped a duplicate text from {}.", username);
+                    continue;
+                }
+            }
+
+            // Other kinds of messages cannot be saved, so the client is disconnected, also when saves run in the background.
+            if !can_be_saved(&received_message) {
+                return Err(anyhow!(
+                    "Messages of kind {} cannot be saved in database.",
+                    received_message.kind_str()
+                ));
+            }
+
+            // Increment the number of received messages.
+            messages_counter.inc();
+
+            // Save received message in a database, either right away or in the background.
+            match &mut background_saves {
+                Some(background_saves) => {
+                    let connection_pool = connection_pool.clone();
+                    let message = received_message.clone();
+                    let file_store = options.file_store.clone();
+                    background_saves
+                        .spawn(async move {
+                            save_message_in_database(
+                                &connection_pool,
+                                &user_id,
+                                &message,
+                                tag,
+                                file_store.as_ref(),
+                                None,
+                            )
+                            .await
+                        })
+                        .await
+                        .context("Failed to start saving message in the background.")?;
+                }
+                None => save_message_in_database(
+                    &connection_pool,
+                    &user_id,
+                    &received_message,
+                    tag,
+                    options.file_store.as_ref(),
+                    options.message_writer.as_ref(),
+                )
+                .await
+                .context("Failed to save message in a database.")?,
+            }
+
+            // Other clients get the tag of the author together with the message.
+            let received_message = match tag {
+                Some(tag) => MessageType::Tagged(tag, Box::new(received_message)),
+                None => received_message,
+            };
+
+            // Other clients are told who wrote the message by the display name of the author.
+            let received_message =
+                MessageType::Authored(display_name.clone(), Box::new(received_message));
 
-        // Send received data to all clients except the one from which the data were received.
-        let lock = client_writers.lock().await;
-        for address in lock.keys() {
-            if *address != client_address {
-                let shared_writer = lock
-                    .get(address)
-                    .ok_or_else(|| anyhow!("Address not found in HashMap."))?;
-                let mut lock_writer = shared_writer.lock().await;
-                if let Err(e) = send_message(&mut *lock_writer, &received_message).await {
-                    error!("Failed when sending bytes to address {}: {}", *address, e);
+            // If the author takes part in read receipts, the message gets an id, so that other clients can confirm reading it.
+            let (message_id, received_message) = match read_receipts {
+                ReadReceipts::Enabled => {
+                    let id = options.message_authors.assign_id(client_address).await;
+                    (
+                        Some(id),
+                        MessageType::Identified(id, Box::new(received_message)),
+                    )
                 }
+                ReadReceipts::Disabled => (None, received_message),
+            };
+
+            // Send received data to all clients except the one from which the data were received.
+            broadcast_message(
+                &client_writers,
+                &broadcast_failures,
+                &options.active_connections_gauge,
+                &received_message,
+                Some(&client_address),
+            )
+            .await;
+
+            // The author is told the id, so that it knows which message the read receipts are for.
+            // For a tagged message, the id comes with the tag, so that the author can match them.
+            if let Some(id) = message_id {
+                let message_from_server = match tag {
+                    Some(tag) => MessageType::Tagged(tag, Box::new(MessageType::MessageId(id))),
+                    None => MessageType::MessageId(id),
+                };
+                send_message_to_client(&client_address, &client_writers, &message_from_server)
+                    .await
+                    .context("Failed to send message id.")?;
             }
         }
     }
+    .await;
+    if let Some(background_saves) = &mut background_saves {
+        background_saves.finish().await;
+    }
+    result
 }
 
 /// Go through the whole process of authentification, including communication with a database.
+/// If the client offers compression of frames, it is agreed on and used after a successful authentication.
+/// Signing of frames must be asked for exactly if the server has a message key, otherwise the client is refused.
+/// The choice of the client whether it takes part in read receipts is returned too.
+/// Each successful authentication starts a session with a new id, which is sent to the client and used in the logs.
 async fn authenticate_user(
-    reader: &mut OwnedReadHalf,
-    client_address: &SocketAddr,
-    client_writers: &Arc<Mutex<HashMap<SocketAddr, SharedWriteHalf>>>,
+    reader: &mut ClientReader,
+    client_address: &ClientAddress,
+    client_writers: &ClientWriters,
     connection_pool: &SqlitePool,
-) -> Option<(i64, String)> {
+    options: &ChatServerOptions,
+) -> Option<(
+    i64,
+    String,
+    SessionId,
+    Compression,
+    ReadReceipts,
+    Arc<Notify>,
+)> {
     // Wait for authentication request message.
-    let (action, username, password) = match receive_message(reader).await {
-        // Data received and passed to the handler.
-        Ok(MessageType::AuthRequest(action, username, password)) => {
-            info!("Received authentication request from {}.", &username);
-            (action, username, password)
-        }
+    let (action, username, password, compression, signing, read_receipts) =
+        match receive_message(reader).await {
+            // Data received and passed to the handler.
+            Ok(MessageType::AuthRequest(
+                action,
+                username,
+                password,
+                compression,
+                signing,
+                read_receipts,
+            )) => {
+                info!("Received authentication request from {}.", &username);
+                (
+                    action,
+                    username,
+                    password,
+                    compression,
+                    signing,
+                    read_receipts,
+                )
+            }
 
-        // Incorrect MessageType. This should never happen.
-        Ok(_) => {
-            error!("Incorrect message type received from server.");
-            return None;
-        }
+            // The client sent something else before authenticating. It is told so before the connection is closed.
+            Ok(_) => {
+                info!(
+                    "Client {} sent a message before authenticating.",
+                    client_address
+                );
+                send_auth_failure(
+                    client_address,
+                    client_writers,
+                    AuthError::InvalidRequest,
+                    "authentication required first",
+                )
+                .await;
+                return None;
+            }
 
-        // Error while reading.
-        Err(e) => {
-            error!("Error while waiting for an authentication request: {}", e);
-            return None;
-        }
+            // A frame that cannot be decoded most likely comes from a client with another version of the protocol.
+            Err(e) if e.is::<DecodeError>() => {
+                info!(
+                    "Client {} sent a frame that could not be decoded: {}",
+                    client_address, e
+                );
+                send_rejection(
+                    client_address,
+                    client_writers,
+                    RejectReason::VersionMismatch,
+                    options,
+                )
+                .await;
+                return None;
+            }
+
+            // Error while reading.
+            Err(e) => {
+                error!("Error while waiting for an authentication request: {}", e);
+                return None;
+            }
+        };
+
+    // A client that does not sign its messages exactly when the server does is refused before the credentials are checked.
+    let expected_signing = match options.message_key {
+        Some(_) => Signing::Enabled,
+        None => Signing::Disabled,
     };
+    if signing != expected_signing {
+        info!(
+            "Client {} asked for signing {:?}, but the server uses {:?}.",
+            client_address, signing, expected_signing
+        );
+        let message_from_server = match expected_signing {
+            Signing::Enabled => "the server requires signed messages",
+            Signing::Disabled => "the server does not sign messages",
+        };
+        send_auth_failure(
+            client_address,
+            client_writers,
+            AuthError::SigningMismatch,
+            message_from_server,
+        )
+        .await;
+        return None;
+    }
+
+    // Authenticate and return user id or the reason of failure, and message that should be sent to client.
+    let (auth_result, message_from_server) = handle_auth_request(
+        connection_pool,
+        &options.login_attempts,
+        &options.password_hashing,
+        &options.auth_attempts_counter,
+        &action,
+        &username,
+        &password,
+    )
+    .await;
 
-    // Authenticate and return success status, message that should be sent to client and user id.
-    let (user_id, message_from_server) =
-        handle_auth_request(connection_pool, &action, &username, &password).await;
+    // A user who is logged in elsewhere gets a session only if the other one can be kicked.
+    let session_id = SessionId::new();
+    let (auth_result, message_from_server) = match auth_result {
+        Ok(id) => match options
+            .sessions
+            .start(id, *client_address, session_id.clone())
+            .await
+        {
+            Some(kicked) => (Ok((id, kicked)), message_from_server),
+            None => (
+                Err(AuthError::AlreadyLoggedIn),
+                "already logged in elsewhere".to_string(),
+            ),
+        },
+        Err(auth_error) => (Err(auth_error), message_from_server),
+    };
 
     // Send authentication response message back to the user.
     let lock = client_writers.lock().await;
@@ -185,177 +861,665 @@ async fn authenticate_user(
     };
     let mut lock_writer = shared_writer.lock().await;
 
-    match user_id {
+    match auth_result {
         // If id was returned, that means that the user was authented.
-        Some(id) => {
-            info!("Authentication succeeded. Sending response back to user.");
-            let auth_response_message = MessageType::AuthResponse(true, message_from_server);
+        Ok((id, kicked)) => {
+            info!(
+                "Authentication of {} succeeded with session {}. Sending response back to user.",
+                username, session_id
+            );
+            let auth_response_message = MessageType::AuthResponse(
+                true,
+                None,
+                message_from_server,
+                compression,
+                signing,
+                Some(session_id.to_string()),
+            );
             // Send auth response confirming that the user was authenticated.
-            match send_message(&mut *lock_writer, &auth_response_message).await {
+            // The response itself is neither compressed nor signed, they apply to the following frames.
+            match lock_writer.send(&auth_response_message).await {
                 Ok(_) => {
-                    return Some((id, username));
+                    lock_writer.set_compression(compression);
+                    lock_writer.set_message_key(options.message_key.clone());
+                    lock_writer.set_read_receipts(read_receipts);
+                    lock_writer.set_session(id, session_id.clone());
+                    Some((id, username, session_id, compression, read_receipts, kicked))
                 }
                 Err(e) => {
                     error!("Error while sending authentication response: {}", e);
-                    return None;
+                    None
                 }
             }
         }
         // If no id was returned, the user was not authented.
-        None => {
+        // Banned users and users who are logged in elsewhere are not told about the credentials, their connection is rejected.
+        Err(auth_error) => {
             info!("Authentication did not succeed. Sending response back to user.");
-            let auth_response_message = MessageType::AuthResponse(false, message_from_server);
-            // Send auth response informing client that the user was not authenticated.
-            match send_message(&mut *lock_writer, &auth_response_message).await {
-                Ok(_) => {
-                    return None;
+            let auth_response_message = match rejection_reason(auth_error) {
+                Some(reason) => {
+                    MessageType::ConnectionRejected(reason, options.rejection_banner.clone())
                 }
+                None => MessageType::AuthResponse(
+                    false,
+                    Some(auth_error),
+                    message_from_server,
+                    Compression::Disabled,
+                    Signing::Disabled,
+                    None,
+                ),
+            };
+            // Send auth response informing client that the user was not authenticated.
+            match lock_writer.send(&auth_response_message).await {
+                Ok(_) => None,
                 Err(e) => {
                     error!("Error while sending authentication response: {}", e);
-                    return None;
+                    None
                 }
             }
         }
     }
 }
 
+/// Send an authentication response informing the client that it was not authenticated.
+async fn send_auth_failure(
+    client_address: &ClientAddress,
+    client_writers: &ClientWriters,
+    auth_error: AuthError,
+    message_from_server: &str,
+) {
+    let lock = client_writers.lock().await;
+    let Some(shared_writer) = lock.get(client_address) else {
+        error!("Address not found in HashMap.");
+        return;
+    };
+    let auth_response_message = MessageType::AuthResponse(
+        false,
+        Some(auth_error),
+        message_from_server.to_string(),
+        Compression::Disabled,
+        Signing::Disabled,
+        None,
+    );
+    let mut lock_writer = shared_writer.lock().await;
+    if let Err(e) = lock_writer.send(&auth_response_message).await {
+        error!("Error while sending authentication response: {}", e);
+    }
+}
+
+/// The reason of rejecting the connection for authentication failures that are not about the credentials.
+fn rejection_reason(auth_error: AuthError) -> Option<RejectReason> {
+    match auth_error {
+        AuthError::Banned => Some(RejectReason::Banned),
+        AuthError::AlreadyLoggedIn => Some(RejectReason::TooManySessions),
+        _ => None,
+    }
+}
+
+/// Tell the client why its connection is rejected. The banner of the server goes along with the reason.
+async fn send_rejection(
+    client_address: &ClientAddress,
+    client_writers: &ClientWriters,
+    reason: RejectReason,
+    options: &ChatServerOptions,
+) {
+    let lock = client_writers.lock().await;
+    let Some(shared_writer) = lock.get(client_address) else {
+        error!("Address not found in HashMap.");
+        return;
+    };
+    let rejection_message =
+        MessageType::ConnectionRejected(reason, options.rejection_banner.clone());
+    let mut lock_writer = shared_writer.lock().await;
+    if let Err(e) = lock_writer.send(&rejection_message).await {
+        error!("Error while sending connection rejection: {}", e);
+    }
+}
+
 /// Based on parameters, try to either register or authenticate user. Produce a response message for client.
+/// A banned user cannot log in. The ban is checked after the password, so that it is not told to anyone else.
+/// The result of each registration and login is counted in the authentication attempts metric.
 async fn handle_auth_request(
     connection_pool: &SqlitePool,
-    action: &String,
-    username: &String,
-    password: &String,
-) -> (Option<i64>, String) {
+    login_attempts: &LoginAttempts,
+    password_hashing: &PasswordHashingOptions,
+    auth_attempts_counter: &CounterVec,
+    action: &str,
+    username: &str,
+    password: &str,
+) -> (Result<i64, AuthError>, String) {
     if action == "R" {
-        return register(connection_pool, username, password).await;
+        let outcome = register(connection_pool, password_hashing, username, password).await;
+        count_auth_attempt(auth_attempts_counter, "register", &outcome.0);
+        outcome
     } else if action == "L" {
-        return login(connection_pool, username, password).await;
+        let outcome = login(
+            connection_pool,
+            login_attempts,
+            password_hashing,
+            username,
+            password,
+        )
+        .await;
+        if let Ok(user_id) = outcome.0 {
+            if let Some(refusal) = refuse_banned_user(connection_pool, &user_id, username).await {
+                return refusal;
+            }
+        }
+        count_auth_attempt(auth_attempts_counter, "login", &outcome.0);
+        outcome
     } else {
-        return (
-            None,
+        (
+            Err(AuthError::InvalidRequest),
             "Authentication failed because of incorrect action identifier. (Must be 'R or 'L'')"
                 .to_string(),
-        );
+        )
     }
 }
 
-/// Register a user.
-async fn register(
+/// Get the response to a login of a banned user, or None if the user is not banned.
+async fn refuse_banned_user(
     connection_pool: &SqlitePool,
-    username: &String,
-    password: &String,
-) -> (Option<i64>, String) {
-    let password_hash = match hash_password(password).await {
-        Ok(password_hash) => password_hash,
-        Err(e) => {
-            error!("Failed to hash password: {}", e);
-            return (
-                None,
-                "Registration not successful. Try a different password.".to_string(),
+    user_id: &i64,
+    username: &str,
+) -> Option<(Result<i64, AuthError>, String)> {
+    match db::is_banned(connection_pool, user_id, &SystemClock).await {
+        Ok(false) => None,
+        Ok(true) => {
+            info!(
+                "Authentication of {} refused: the account is banned.",
+                username
             );
-        }
-    };
-    match db::add_user(connection_pool, username, &password_hash).await {
-        Ok(user_id) => {
-            info!("Successful registration of a user.");
-            return (Some(user_id), "Registration successful.".to_string());
+            Some((Err(AuthError::Banned), "account banned".to_string()))
         }
         Err(e) => {
-            info!("Failed to register user: {}", e);
-            return (
-                None,
-                "Registration not successful. Try a different username.".to_string(),
-            );
+            error!("Failed to check if {} is banned: {}", username, e);
+            Some((
+                Err(AuthError::ServerError),
+                "Authentication not successful.".to_string(),
+            ))
         }
     }
 }
 
+/// Count a registration or a login in the authentication attempts metric, labeled by the action and its result.
+fn count_auth_attempt(
+    auth_attempts_counter: &CounterVec,
+    action: &str,
+    auth_result: &Result<i64, AuthError>,
+) {
+    let result = if auth_result.is_ok() {
+        "success"
+    } else {
+        "failure"
+    };
+    auth_attempts_counter
+        .with_label_values(&[action, result])
+        .inc();
+}
+
+/// Register a user. An empty password is refused.
+async fn register(
+    connection_pool: &SqlitePool,
+    password_hashing: &PasswordHashingOptions,
+    username: &str,
+    password: &str,
+) -> (Result<i64, AuthError>, String) {
+    let result = register_user(connection_pool, password_hashing, username, password).await;
+    let message_for_client = match &result {
+        Ok(_) => "Registration successful.".to_string(),
+        Err(AuthError::WeakPassword) => {
+            "Registration not successful. The password must not be empty.".to_string()
+        }
+        Err(AuthError::UsernameTooLong) => format!(
+            "Registration not successful. Username can have at most {} characters.",
+            db::MAX_USERNAME_LENGTH
+        ),
+        Err(AuthError::UsernameTaken) => {
+            "Registration not successful. Try a different username.".to_string()
+        }
+        Err(_) => "Registration not successful.".to_string(),
+    };
+    (result, message_for_client)
+}
+
 /// Log in a user.
-/// First it is checked if the user has an entry in database and user id and password hash are obtained.
-/// Then, the password provided by the user is verified against the password hash from the database.
+/// A username with too many failed logins is locked out for a while and its logins are refused right away.
+/// Otherwise the password is checked and a wrong one is recorded as a failed login.
 async fn login(
     connection_pool: &SqlitePool,
-    username: &String,
-    password: &String,
-) -> (Option<i64>, String) {
-    let (user_id, password_hash) = match db::get_user(connection_pool, username).await {
-        Ok((user_id, password_hash)) => (user_id, password_hash),
-        Err(e) => {
-            info!("Login not successful: {}", e);
-            return (None, "Login not successful.".to_string());
+    login_attempts: &LoginAttempts,
+    password_hashing: &PasswordHashingOptions,
+    username: &str,
+    password: &str,
+) -> (Result<i64, AuthError>, String) {
+    if login_attempts.is_locked_out(username).await {
+        info!("Login not successful: {} is locked out.", username);
+        return (
+            Err(AuthError::TooManyAttempts),
+            "too many attempts, try later".to_string(),
+        );
+    }
+    let result = authenticate(connection_pool, password_hashing, username, password).await;
+    let message_for_client = match &result {
+        Ok(_) => {
+            login_attempts.clear_failed_logins(username).await;
+            "Successfully logged in.".to_string()
         }
+        Err(AuthError::WrongCredentials) => {
+            login_attempts.record_failed_login(username).await;
+            "Login not successful. The username or the password seems to be incorrect.".to_string()
+        }
+        Err(_) => "Login not successful.".to_string(),
     };
-    match verify_password(password, &password_hash).await {
+    (result, message_for_client)
+}
+
+/// Rename an authenticated user. Produce a response message for client.
+/// If the rename succeeds, the username kept for the current session is changed too.
+async fn rename(
+    connection_pool: &SqlitePool,
+    user_id: &i64,
+    username: &mut String,
+    new_username: String,
+) -> MessageType {
+    match db::rename_user(connection_pool, user_id, &new_username).await {
         Ok(_) => {
-            info!("Login successful.");
-            return (Some(user_id), "Successfully logged in.".to_string());
+            info!("User {} renamed to {}.", username, new_username);
+            let message_from_server = format!("Username changed to {}.", new_username);
+            *username = new_username;
+            MessageType::ServerNotice(message_from_server)
         }
-        Err(e) => {
-            info!("Login not successful: {}", e);
-            return (
-                None,
-                "Login not successful. The password seems to be incorrect.".to_string(),
+        Err(RenameUserError::NameTaken(name)) => {
+            info!(
+                "Failed to rename user {}: {} is already taken.",
+                username, name
             );
+            MessageType::ServerError(format!(
+                "Rename not successful. Username {} is already taken.",
+                name
+            ))
+        }
+        Err(RenameUserError::NameTooLong) => {
+            info!(
+                "Failed to rename user {}: the username is too long.",
+                username
+            );
+            MessageType::ServerError(format!(
+                "Rename not successful. Username can have at most {} characters.",
+                db::MAX_USERNAME_LENGTH
+            ))
+        }
+        Err(e) => {
+            error!("Failed to rename user {}: {}", username, e);
+            MessageType::ServerError("Rename not successful.".to_string())
         }
     }
 }
 
-/// Take a message and save it into a database.
-/// Each message is associated with its author.
-async fn save_message_in_database(
+/// Set the nickname of an authenticated user. Produce a response message for client.
+/// If it succeeds, the display name kept for the current session is changed too.
+async fn set_nickname(
     connection_pool: &SqlitePool,
     user_id: &i64,
-    message: &MessageType,
-) -> Result<()> {
-    let contents = match message {
-        MessageType::Text(text) => text.clone(),
-        MessageType::Image(_) => "SENT IMAGE".to_string(),
-        MessageType::File(name, _) => format!("FILE SENT: {}", name),
-        _ => {
-            return Err(anyhow!("This message type cannot be saved in database."));
+    username: &str,
+    display_name: &mut String,
+    nickname: String,
+) -> MessageType {
+    match db::set_display_name(connection_pool, user_id, &nickname).await {
+        Ok(_) => {
+            info!("User {} changed nickname to {}.", username, nickname);
+            let message_from_server = format!("Nickname changed to {}.", nickname);
+            *display_name = nickname;
+            MessageType::ServerNotice(message_from_server)
+        }
+        Err(
+            e @ (SetDisplayNameError::NameTooLong
+            | SetDisplayNameError::InvalidCharacters
+            | SetDisplayNameError::NameTaken(_)),
+        ) => {
+            info!("Failed to change nickname of user {}: {}", username, e);
+            MessageType::ServerError(format!("Nickname not changed. {}", e))
         }
+        Err(e) => {
+            error!("Failed to change nickname of user {}: {}", username, e);
+            MessageType::ServerError("Nickname not changed.".to_string())
+        }
+    }
+}
+
+/// Get the text of the message of the day. A file is read again each time, so that it can be changed without a restart.
+/// Nothing is sent if the text is empty or if the file cannot be read.
+async fn load_motd(motd: &Motd) -> Option<String> {
+    let text = match motd {
+        Motd::Text(text) => text.clone(),
+        Motd::File(path) => match tokio::fs::read_to_string(path).await {
+            Ok(text) => text,
+            Err(e) => {
+                error!(
+                    "Failed to read message of the day from {}: {}",
+                    path.display(),
+                    e
+                );
+                return None;
+            }
+        },
     };
-    db::add_message(connection_pool, user_id, &contents)
-        .await
-        .context("Failed to save message in a database")?;
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    Some(text.to_string())
+}
 
-    Ok(())
+/// Tell the author of the message with specified id that a user has read it.
+/// Receipts for unknown or forgotten messages and for own messages are dropped.
+/// The author may have disconnected in the meantime, so a failed send is only logged.
+async fn relay_read_receipt(
+    message_authors: &MessageAuthors,
+    client_writers: &ClientWriters,
+    reader_address: &ClientAddress,
+    id: i64,
+    reader_username: &str,
+) {
+    let author_address = match message_authors.get_author(id).await {
+        Some(address) if address != *reader_address => address,
+        _ => return,
+    };
+    let message = MessageType::ReadBy(id, reader_username.to_string());
+    if let Err(e) = send_message_to_client(&author_address, client_writers, &message).await {
+        info!(
+            "Failed to relay a read receipt to {}: {}",
+            author_address, e
+        );
+    }
 }
 
-/// Remove an invalid writer from a HashMap.
-async fn remove_client_writer(
-    client_address: SocketAddr,
-    client_writers: Arc<Mutex<HashMap<SocketAddr, SharedWriteHalf>>>,
-) -> () {
-    let mut lock = client_writers.lock().await;
-    match lock.remove(&client_address) {
-        Some(_) => {
-            info!(
-                "Removing writer associated with socket {} from HashMap.",
-                &client_address
-            );
-        }
-        None => {
-            error!(
-                "Writer associated with socket {} not found in HashMap.",
-                &client_address
-            );
+/// Get the most recent chat messages for a client that asked for the given number of them.
+/// The number is capped at MAX_HISTORY_LENGTH. Asking for no messages is refused.
+async fn get_history(connection_pool: &SqlitePool, count: u32) -> MessageType {
+    if count == 0 {
+        return MessageType::ServerError(
+            "History not available. Ask for at least one message.".to_string(),
+        );
+    }
+    match db::get_recent_messages(connection_pool, count.min(MAX_HISTORY_LENGTH)).await {
+        Ok(messages) => MessageType::History(
+            messages
+                .into_iter()
+                .map(|message| HistoryEntry {
+                    username: message.username,
+                    content: message.content,
+                    tag: message.tag,
+                    sent_at: message.sent_at,
+                })
+                .collect(),
+        ),
+        Err(e) => {
+            error!("Failed to get chat history: {}", e);
+            MessageType::ServerError("History not available.".to_string())
         }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
+/// Describe the effective configuration as "name=value" pairs, in the order in which the arguments are defined.
+/// Values of secret arguments are never included. Arguments without a value are left out.
+fn describe_config(matches: &ArgMatches) -> String {
+    build_cli()
+        .get_arguments()
+        .filter_map(|arg| {
+            let name = arg.get_id().as_str();
+            let values = matches.try_get_raw(name).ok().flatten()?;
+            if SECRET_ARGS.contains(&name) {
+                return Some(format!("{}=<hidden>", name));
+            }
+            let values: Vec<String> = values
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect();
+            Some(format!("{}={}", name, values.join(",")))
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
 
-    // Process command line arguments.
-    let matches = Command::new("Server")
-        .about("Runs server")
-        .arg(
-            Arg::new("chat-socket")
-            .short('c')
-            .long("chat-socket")
+/// Check if a message can be accepted by the server.
+/// In text only mode, images and files are not allowed.
+fn is_message_allowed(message: &MessageType, text_only: bool) -> bool {
+    !(text_only && matches!(message, MessageType::Image(_) | MessageType::File { .. }))
+}
+
+/// Check if a message is of a kind that is saved in the database, see save_message_in_database.
+fn can_be_saved(message: &MessageType) -> bool {
+    matches!(
+        message,
+        MessageType::Text(_)
+            | MessageType::FormattedText { .. }
+            | MessageType::Image(_)
+            | MessageType::File { .. }
+    )
+}
+
+/// Check if a message is a text, either plain or formatted. Only texts can be tagged.
+fn is_text(message: &MessageType) -> bool {
+    matches!(
+        message,
+        MessageType::Text(_) | MessageType::FormattedText { .. }
+    )
+}
+
+/// Check that the chat and http socket addresses are valid and that both servers can bind to them.
+/// The same port cannot be used twice on one IP address, nor on any IP address if the other
+/// server binds to all addresses (e.g. "0.0.0.0:80").
+fn validate_socket_addresses(chat_socket_address: &str, http_socket_address: &str) -> Result<()> {
+    let chat_socket_address =
+        parse_socket_addr(chat_socket_address).context("The chat socket is not valid.")?;
+    let http_socket_address =
+        parse_socket_addr(http_socket_address).context("The HTTP socket is not valid.")?;
+    let same_ip = chat_socket_address.ip() == http_socket_address.ip()
+        || chat_socket_address.ip().is_unspecified()
+        || http_socket_address.ip().is_unspecified();
+    if same_ip && chat_socket_address.port() == http_socket_address.port() {
+        return Err(anyhow!(
+            "Chat socket {} and HTTP socket {} cannot use the same port.",
+            chat_socket_address,
+            http_socket_address
+        ));
+    }
+    Ok(())
+}
+
+/// Check if receiving a message failed only because the peer closed the connection between messages.
+fn is_connection_closed(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref(),
+        Some(BytesSendReceiveError::ConnectionClosed)
+    )
+}
+
+/// Check if the content saved for a message fits into the database.
+/// Only text messages, formatted or not, can be too long. Images and files are saved with a short description.
+fn is_message_length_allowed(message: &MessageType) -> bool {
+    match message {
+        MessageType::Text(text) | MessageType::FormattedText { text, .. } => {
+            text.chars().count() <= db::MAX_CONTENT_LENGTH
+        }
+        _ => true,
+    }
+}
+
+/// Apply the word filter to a text message, formatted or not. Other messages are returned as they are.
+/// None is returned if the text contains a banned word and the filter rejects such messages.
+fn apply_word_filter(message: MessageType, word_filter: &Filter) -> Option<MessageType> {
+    match message {
+        MessageType::Text(text) => filter_text(text, word_filter).map(MessageType::Text),
+        MessageType::FormattedText { text, format } => {
+            filter_text(text, word_filter).map(|text| MessageType::FormattedText { text, format })
+        }
+        message => Some(message),
+    }
+}
+
+/// Get the text that is let through by the word filter, masked if the filter says so.
+fn filter_text(text: String, word_filter: &Filter) -> Option<String> {
+    let (sanitized, found) = word_filter.sanitize(&text);
+    match (found, word_filter.action()) {
+        (false, _) => Some(text),
+        (true, FilterAction::Mask) => Some(sanitized),
+        (true, FilterAction::Reject) => None,
+    }
+}
+
+/// Take a message and save it into a database.
+/// Each message is associated with its author and saved with the tag the client chose for it, if any.
+/// If there is a message writer, the message is only queued and saved later in a batch.
+async fn save_message_in_database(
+    connection_pool: &SqlitePool,
+    user_id: &i64,
+    message: &MessageType,
+    tag: Option<i64>,
+    file_store: Option<&FileStore>,
+    message_writer: Option<&MessageWriter>,
+) -> Result<()> {
+    debug!("Saving a message of kind {}.", message.kind_str());
+    let (contents, kind, blob, mime) = match message {
+        // The format of a formatted text is not saved, so it is in the history as plain text.
+        MessageType::Text(text) | MessageType::FormattedText { text, .. } => {
+            (text.clone(), MessageKind::Text, None, None)
+        }
+        MessageType::Image(bytes) => (
+            "SENT IMAGE".to_string(),
+            MessageKind::Image,
+            Some(("image.png", bytes)),
+            None,
+        ),
+        MessageType::File { name, mime, data } => (
+            describe_sent_file(name),
+            MessageKind::File,
+            Some((name.as_str(), data)),
+            mime.as_deref(),
+        ),
+        _ => {
+            return Err(anyhow!(
+                "Messages of kind {} cannot be saved in database.",
+                message.kind_str()
+            ));
+        }
+    };
+    // Images and files are tracked as attachments of the message if they were stored.
+    let attachment = match (file_store, blob) {
+        (Some(file_store), Some((name, bytes))) => {
+            let path = store_file(file_store, name, bytes).await;
+            path.map(|path| NewAttachment {
+                filename: name.to_string(),
+                path,
+                mime: choose_mime_type(name, mime),
+            })
+        }
+        _ => None,
+    };
+
+    if let Some(message_writer) = message_writer {
+        return message_writer
+            .write(*user_id, contents, kind, tag, attachment)
+            .await
+            .context("Failed to queue message for saving in a database");
+    }
+
+    let message_id =
+        db::add_tagged_message(connection_pool, user_id, &contents, kind, tag, &SystemClock)
+            .await
+            .context("Failed to save message in a database")?;
+    if let Some(attachment) = attachment {
+        db::add_attachment(
+            connection_pool,
+            &message_id,
+            &attachment.filename,
+            &attachment.path,
+            &attachment.mime,
+        )
+        .await
+        .context("Failed to save attachment in a database")?;
+    }
+
+    Ok(())
+}
+
+/// Describe a sent file in the history. A long name is shortened, so that the description can be saved.
+fn describe_sent_file(name: &str) -> String {
+    const PREFIX: &str = "FILE SENT: ";
+    let name: String = name
+        .chars()
+        .take(db::MAX_CONTENT_LENGTH - PREFIX.len())
+        .collect();
+    format!("{}{}", PREFIX, name)
+}
+
+/// Choose the MIME type of a stored file. The type sent by the client is used if it is a valid MIME type,
+/// otherwise it is guessed from the extension of the file name.
+fn choose_mime_type(name: &str, sent_mime: Option<&str>) -> String {
+    sent_mime
+        .and_then(|mime| mime.parse::<Mime>().ok())
+        .map(|mime| mime.to_string())
+        .unwrap_or_else(|| guess_mime_type(name))
+}
+
+/// Store an image or a file on disk and return the path to it.
+/// A failure to store a file is only logged, so that the message is still saved and forwarded.
+async fn store_file(file_store: &FileStore, name: &str, bytes: &[u8]) -> Option<String> {
+    match file_store.store(name, bytes).await {
+        Ok(Some(path)) => Some(path.to_string_lossy().into_owned()),
+        Ok(None) => {
+            info!(
+                "File '{}' ({} bytes) is too large to be stored.",
+                name,
+                bytes.len()
+            );
+            None
+        }
+        Err(e) => {
+            error!("Failed to store file '{}': {}", name, e);
+            None
+        }
+    }
+}
+
+/// Remove an invalid writer from a HashMap and decrease the number of active connections.
+/// The gauge is decreased under the same lock as the writer is removed,
+/// so that it always matches the number of writers, e.g. when the gauge is reconciled.
+async fn remove_client_writer(
+    client_address: ClientAddress,
+    client_writers: ClientWriters,
+    active_connections_gauge: &Gauge,
+) -> () {
+    let mut lock = client_writers.lock().await;
+    match lock.remove(&client_address) {
+        Some(_) => {
+            active_connections_gauge.dec();
+            info!(
+                "Removing writer associated with socket {} from HashMap.",
+                &client_address
+            );
+        }
+        None => {
+            error!(
+                "Writer associated with socket {} not found in HashMap.",
+                &client_address
+            );
+        }
+    }
+}
+
+/// Define command line arguments of the server.
+/// Each option can also be set by an environment variable. A command line argument takes precedence over it.
+fn build_cli() -> Command {
+    Command::new("Server")
+        .about("Runs server")
+        .arg(
+            Arg::new("chat-socket")
+            .short('c')
+            .long("chat-socket")
             .value_name("CHAT_SOCKET")
+            .env("CHAT_SOCKET")
             .default_value("0.0.0.0:11111")
             .help("Socket on which the chat server should listen for incomming client connections.")
         )
@@ -364,26 +1528,322 @@ async fn main() -> Result<()> {
             .short('w')
             .long("http-socket")
             .value_name("HTTP_SOCKET")
+            .env("HTTP_SOCKET")
             .default_value("0.0.0.0:80")
             .help("HTTP socket through which chat server admin page can be accessed.")
         )
+        .arg(
+            Arg::new("unix-socket")
+            .short('u')
+            .long("unix-socket")
+            .value_name("UNIX_SOCKET")
+            .env("UNIX_SOCKET")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("Path of a Unix domain socket on which the chat server should also listen for client connections. The file is removed when the server stops.")
+        )
+        .arg(
+            Arg::new("no-chat")
+            .long("no-chat")
+            .action(ArgAction::SetTrue)
+            .env("NO_CHAT")
+            .conflicts_with_all(["no-http", "unix-socket"])
+            .help("Do not run the chat server, only the HTTP server.")
+        )
+        .arg(
+            Arg::new("no-http")
+            .long("no-http")
+            .action(ArgAction::SetTrue)
+            .env("NO_HTTP")
+            .help("Do not run the HTTP server, only the chat server.")
+        )
         .arg(
             Arg::new("db-file")
             .short('d')
             .long("db-file")
             .value_name("DB_FILE")
+            .env("DB_FILE")
             .default_value("server/chat_app_data.db")
-            .help("Path to a '.db' file containing chat server sqlite database.")
+            .help("Path to a '.db' file containing chat server sqlite database. A missing file is created.")
+        )
+        .arg(
+            Arg::new("create-db-dir")
+            .long("create-db-dir")
+            .action(ArgAction::SetTrue)
+            .env("CREATE_DB_DIR")
+            .help("Create the directory of the database file if it does not exist. Otherwise the server exits with an error.")
         )
         .arg(
             Arg::new("static-dir")
             .short('s')
             .long("static-dir")
             .value_name("STATIC_DIR")
+            .env("STATIC_DIR")
             .default_value("server/static")
-            .help("Directory containing 'index.html' file.")
+            .action(ArgAction::Append)
+            .help("Directory containing 'index.html' file. It can be repeated, then files are searched for in the directories in the given order.")
+        )
+        .arg(
+            Arg::new("keepalive-interval")
+            .short('k')
+            .long("keepalive-interval")
+            .value_name("KEEPALIVE_INTERVAL")
+            .env("KEEPALIVE_INTERVAL")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("60")
+            .help("Number of seconds after which an idle client connection is checked by TCP keepalive probes.")
+        )
+        .arg(
+            Arg::new("text-only")
+            .short('t')
+            .long("text-only")
+            .action(ArgAction::SetTrue)
+            .env("TEXT_ONLY")
+            .help("Accept only text messages. Images and files sent by clients are rejected.")
+        )
+        .arg(
+            Arg::new("allowed-extensions")
+            .long("allowed-extensions")
+            .value_name("ALLOWED_EXTENSIONS")
+            .env("ALLOWED_EXTENSIONS")
+            .help("Comma-separated list of extensions of files that clients can send, e.g. 'txt,pdf,png'. If not set, files with any extension are allowed.")
+        )
+        .arg(
+            Arg::new("file-store")
+            .short('f')
+            .long("file-store")
+            .value_name("FILE_STORE")
+            .env("FILE_STORE")
+            .help("Directory where images and files sent by clients are stored. If not set, they are not stored.")
+        )
+        .arg(
+            Arg::new("max-stored-file-size")
+            .short('m')
+            .long("max-stored-file-size")
+            .value_name("MAX_STORED_FILE_SIZE")
+            .env("MAX_STORED_FILE_SIZE")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("10485760")
+            .help("Maximum size in bytes of an image or a file that is stored. Larger ones are not stored.")
+        )
+        .arg(
+            Arg::new("password-pepper")
+            .long("password-pepper")
+            .value_name("PASSWORD_PEPPER")
+            .env("CHAT_PASSWORD_PEPPER")
+            .hide_env_values(true)
+            .value_parser(NonEmptyStringValueParser::new())
+            .help("Secret added to all password hashes. If not set, passwords are hashed without it.")
+        )
+        .arg(
+            Arg::new("argon2-memory")
+            .long("argon2-memory")
+            .value_name("ARGON2_MEMORY")
+            .env("ARGON2_MEMORY")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("19456")
+            .help("Memory in KiB used for hashing a password with Argon2.")
+        )
+        .arg(
+            Arg::new("argon2-iterations")
+            .long("argon2-iterations")
+            .value_name("ARGON2_ITERATIONS")
+            .env("ARGON2_ITERATIONS")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("2")
+            .help("Number of iterations when hashing a password with Argon2.")
+        )
+        .arg(
+            Arg::new("argon2-parallelism")
+            .long("argon2-parallelism")
+            .value_name("ARGON2_PARALLELISM")
+            .env("ARGON2_PARALLELISM")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("1")
+            .help("Number of lanes used for hashing a password with Argon2.")
+        )
+        .arg(
+            Arg::new("max-failed-logins")
+            .short('l')
+            .long("max-failed-logins")
+            .value_name("MAX_FAILED_LOGINS")
+            .env("MAX_FAILED_LOGINS")
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .default_value("5")
+            .help("Number of failed logins within the lockout window after which a username is locked out.")
+        )
+        .arg(
+            Arg::new("lockout-window")
+            .short('o')
+            .long("lockout-window")
+            .value_name("LOCKOUT_WINDOW")
+            .env("LOCKOUT_WINDOW")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("300")
+            .help("Number of seconds in which failed logins of a username are counted. A locked out username can log in again after the window is over.")
+        )
+        .arg(
+            Arg::new("batch-size")
+            .short('b')
+            .long("batch-size")
+            .value_name("BATCH_SIZE")
+            .env("BATCH_SIZE")
+            .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+            .help("Maximum number of messages saved in the database in one transaction. If not set, each message is saved on its own.")
+        )
+        .arg(
+            Arg::new("async-saves")
+            .long("async-saves")
+            .value_name("ASYNC_SAVES")
+            .env("ASYNC_SAVES")
+            .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+            .conflicts_with("batch-size")
+            .help("Maximum number of messages saved in the background at once. If set, messages are forwarded without waiting until they are saved.")
+        )
+        .arg(
+            Arg::new("batch-delay")
+            .long("batch-delay")
+            .value_name("BATCH_DELAY")
+            .env("BATCH_DELAY")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("100")
+            .help("Maximum number of milliseconds a message waits for its batch to be saved. Used only with --batch-size.")
+        )
+        .arg(
+            Arg::new("vacuum-interval")
+            .long("vacuum-interval")
+            .value_name("VACUUM_INTERVAL")
+            .env("VACUUM_INTERVAL")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("0")
+            .help("Number of seconds between vacuums of the database, which return free space to the file system. 0 disables them.")
+        )
+        .arg(
+            Arg::new("gauge-reconcile-interval")
+            .long("gauge-reconcile-interval")
+            .value_name("GAUGE_RECONCILE_INTERVAL")
+            .env("GAUGE_RECONCILE_INTERVAL")
+            .value_parser(clap::value_parser!(u64))
+            .default_value("60")
+            .help("Number of seconds between corrections of the active connections gauge to the number of connected clients. 0 disables them.")
         )
-        .get_matches();
+        .arg(
+            Arg::new("motd")
+            .long("motd")
+            .value_name("MOTD")
+            .env("MOTD")
+            .conflicts_with("motd-file")
+            .help("Message of the day sent to each client right after it is authenticated.")
+        )
+        .arg(
+            Arg::new("motd-file")
+            .long("motd-file")
+            .value_name("MOTD_FILE")
+            .env("MOTD_FILE")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("Path of a UTF-8 text file with the message of the day. It is read again for each client, so it can be changed while the server runs.")
+        )
+        .arg(
+            Arg::new("outgoing-queue-capacity")
+            .long("outgoing-queue-capacity")
+            .value_name("OUTGOING_QUEUE_CAPACITY")
+            .env("OUTGOING_QUEUE_CAPACITY")
+            .value_parser(RangedU64ValueParser::<usize>::new().range(1..))
+            .default_value("256")
+            .help("Maximum number of messages waiting to be written to one client. Clients that read so slowly that their queue fills up are disconnected.")
+        )
+        .arg(
+            Arg::new("duplicate-login")
+            .long("duplicate-login")
+            .value_name("DUPLICATE_LOGIN")
+            .env("DUPLICATE_LOGIN")
+            .value_parser(DuplicateLoginPolicy::NAMES)
+            .default_value("reject")
+            .help("What to do when a user logs in while already logged in elsewhere: refuse the new login ('reject') or disconnect the previous connection ('kick').")
+        )
+        .arg(
+            Arg::new("filter-file")
+            .long("filter-file")
+            .value_name("FILTER_FILE")
+            .env("FILTER_FILE")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("Path of a UTF-8 text file with banned words, one per line. Empty lines and lines starting with '#' are skipped. Text messages with these words are masked or rejected.")
+        )
+        .arg(
+            Arg::new("filter-action")
+            .long("filter-action")
+            .value_name("FILTER_ACTION")
+            .env("FILTER_ACTION")
+            .value_parser(FilterAction::NAMES)
+            .default_value("mask")
+            .help("What to do with text messages that contain a banned word: replace the word with '#' characters ('mask') or refuse the message ('reject').")
+        )
+        .arg(
+            Arg::new("duplicate-window")
+            .long("duplicate-window")
+            .value_name("DUPLICATE_WINDOW")
+            .env("DUPLICATE_WINDOW")
+            .value_parser(clap::value_parser!(u64).range(1..))
+            .help("Number of milliseconds within which a text that is the same as the previous text of the client is dropped, e.g. after a double Enter. If not set, no texts are dropped.")
+        )
+        .arg(
+            Arg::new("message-key")
+            .long("message-key")
+            .value_name("MESSAGE_KEY")
+            .env("MESSAGE_KEY")
+            .hide_env_values(true)
+            .value_parser(NonEmptyStringValueParser::new())
+            .help("Secret shared with the clients. If set, all messages after the authentication are signed with HMAC-SHA256 and only clients with the same key are accepted.")
+        )
+        .arg(
+            Arg::new("admin-token")
+            .long("admin-token")
+            .value_name("ADMIN_TOKEN")
+            .env("ADMIN_TOKEN")
+            .hide_env_values(true)
+            .value_parser(NonEmptyStringValueParser::new())
+            .help("Secret that requests to the admin endpoints of the http server, e.g. resetting a password, must send as 'Authorization: Bearer <ADMIN_TOKEN>'. If not set, the admin endpoints are disabled.")
+        )
+        .arg(
+            Arg::new("rejection-banner")
+            .long("rejection-banner")
+            .value_name("REJECTION_BANNER")
+            .env("REJECTION_BANNER")
+            .value_parser(NonEmptyStringValueParser::new())
+            .help("Text sent along with the reason to each client whose connection is rejected, e.g. whom to contact.")
+        )
+        .arg(
+            Arg::new("log-format")
+            .long("log-format")
+            .value_name("LOG_FORMAT")
+            .env("LOG_FORMAT")
+            .value_parser(LogFormat::NAMES)
+            .default_value("text")
+            .help("Format of the logs: human-readable text ('text') or one JSON object per line ('json') for log aggregators.")
+        )
+        .arg(
+            Arg::new("version")
+            .short('V')
+            .long("version")
+            .action(ArgAction::SetTrue)
+            .help("Print the version of the server and of the protocol it speaks, then exit without binding any socket.")
+        )
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Process command line arguments.
+    let matches = build_cli().get_matches();
+    init_logger(LogFormat::from_name(
+        matches
+            .get_one::<String>("log-format")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+    )?);
+    if matches.get_flag("version") {
+        println!("{}", describe_version("server", env!("CARGO_PKG_VERSION")));
+        return Ok(());
+    }
+    // The http server reports the uptime since this moment.
+    let server_start = ServerStart::now();
     let chat_socket_address = matches
         .get_one::<String>("chat-socket")
         .ok_or_else(|| anyhow!("There is always a value."))?
@@ -392,84 +1852,366 @@ async fn main() -> Result<()> {
         .get_one::<String>("http-socket")
         .ok_or_else(|| anyhow!("There is always a value."))?
         .clone();
+    // Both servers cannot be disabled, the arguments conflict.
+    let run_chat_server = !matches.get_flag("no-chat");
+    let run_http_server = !matches.get_flag("no-http");
+    if run_chat_server && run_http_server {
+        validate_socket_addresses(&chat_socket_address, &http_socket_address)?;
+    }
+    let unix_socket_path = matches.get_one::<PathBuf>("unix-socket").cloned();
+    #[cfg(not(unix))]
+    if unix_socket_path.is_some() {
+        return Err(anyhow!(
+            "Unix domain sockets are not supported on this platform."
+        ));
+    }
     let db_file = matches
         .get_one::<String>("db-file")
         .ok_or_else(|| anyhow!("There is always a value."))?
         .clone();
-    let static_dir = matches
-        .get_one::<String>("static-dir")
+    let static_dirs: Vec<String> = matches
+        .get_many::<String>("static-dir")
         .ok_or_else(|| anyhow!("There is always a value."))?
-        .clone();
+        .cloned()
+        .collect();
+    let keepalive_interval = Duration::from_secs(
+        *matches
+            .get_one::<u64>("keepalive-interval")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+    );
+    let max_stored_file_size = *matches
+        .get_one::<usize>("max-stored-file-size")
+        .ok_or_else(|| anyhow!("There is always a value."))?;
+    let file_store = match matches.get_one::<String>("file-store") {
+        Some(directory) => Some(
+            FileStore::new(directory, max_stored_file_size)
+                .await
+                .context("Failed to create file store.")?,
+        ),
+        None => None,
+    };
+    let lockout_policy = LockoutPolicy {
+        max_failures: *matches
+            .get_one::<u32>("max-failed-logins")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+        window: Duration::from_secs(
+            *matches
+                .get_one::<u64>("lockout-window")
+                .ok_or_else(|| anyhow!("There is always a value."))?,
+        ),
+    };
+    let argon2_params = Params::new(
+        *matches
+            .get_one::<u32>("argon2-memory")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+        *matches
+            .get_one::<u32>("argon2-iterations")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+        *matches
+            .get_one::<u32>("argon2-parallelism")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+        None,
+    )
+    .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))?;
+    let batch_delay = Duration::from_millis(
+        *matches
+            .get_one::<u64>("batch-delay")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+    );
+    let vacuum_interval = Duration::from_secs(
+        *matches
+            .get_one::<u64>("vacuum-interval")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+    );
+    let gauge_reconcile_interval = Duration::from_secs(
+        *matches
+            .get_one::<u64>("gauge-reconcile-interval")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+    );
+    let motd = match matches.get_one::<PathBuf>("motd-file") {
+        Some(path) => Some(Motd::File(path.clone())),
+        None => matches.get_one::<String>("motd").cloned().map(Motd::Text),
+    };
+    let word_filter = match matches.get_one::<PathBuf>("filter-file") {
+        Some(path) => {
+            let action = FilterAction::from_name(
+                matches
+                    .get_one::<String>("filter-action")
+                    .ok_or_else(|| anyhow!("There is always a value."))?,
+            )?;
+            let word_filter = Filter::from_file(path, action)
+                .await
+                .context("Failed to load word filter.")?;
+            Some(Arc::new(word_filter))
+        }
+        None => None,
+    };
+    let batching_policy = matches
+        .get_one::<usize>("batch-size")
+        .map(|max_batch_size| BatchingPolicy {
+            max_batch_size: *max_batch_size,
+            max_delay: batch_delay,
+        });
 
     // Create metrics and register them.
-    let registry = Registry::new();
     let messages_counter = get_messages_counter()
         .await
         .context("Messages counter metric could not be created.")?;
-    registry
-        .register(Box::new(messages_counter.clone()))
-        .context("Failed to register messages counter metric.")?;
     let active_connections_gauge = get_active_connections_gauge()
         .await
         .context("Active connections gauge metric could not be created.")?;
-    registry
-        .register(Box::new(active_connections_gauge.clone()))
-        .context("Failed to register active connections gauge metric.")?;
-
-    // Create a database connection pool.
-    let database_url = format!("sqlite://{}", db_file);
-    let connection_pool_http_server = db::create_connection_pool(&database_url)
+    let auth_attempts_counter = get_auth_attempts_counter()
+        .await
+        .context("Authentication attempts counter metric could not be created.")?;
+    let db_operation_durations = get_db_operation_durations_histogram()
         .await
-        .context("Failed to create connection pool.")?;
+        .context("Database operation durations histogram metric could not be created.")?;
+    let registry = create_registry(
+        &messages_counter,
+        &active_connections_gauge,
+        &auth_attempts_counter,
+        &db_operation_durations,
+    )
+    .context("Failed to create metrics registry.")?;
+    db::set_operation_durations(db_operation_durations)
+        .context("Failed to start timing database operations.")?;
+
+    // Create a database connection pool. Both servers need it, and at least one of them runs.
+    let connection_pool_http_server =
+        db::create_connection_pool(Path::new(&db_file), matches.get_flag("create-db-dir"))
+            .await
+            .context("Failed to create connection pool.")?;
     let connection_pool_chat_server = connection_pool_http_server.clone();
 
-    // Run http server.
-    let http_task = tokio::spawn(async move {
-        info!("Starting http server...");
-        if let Err(e) = run_http_server(
-            &http_socket_address,
-            connection_pool_http_server,
-            &static_dir,
-            registry
-        )
-        .await
-        {
-            error!("HTTP server failed: {}", e);
-        };
-        info!("Exiting http server...");
+    // Start vacuuming the database regularly if it is enabled.
+    if !vacuum_interval.is_zero() {
+        spawn_vacuum_task(connection_pool_chat_server.clone(), vacuum_interval);
+    }
+
+    // Start saving messages in batches if it is enabled.
+    // A handle to the writer is kept, so that the waiting messages can be saved on shutdown.
+    let (message_writer, message_writer_task) = batching_policy
+        .map(|policy| MessageWriter::spawn(connection_pool_chat_server.clone(), policy))
+        .unzip();
+    let message_writer_on_shutdown = message_writer.clone();
+    let allowed_extensions = matches
+        .get_one::<String>("allowed-extensions")
+        .map(|list| AllowedExtensions::parse(list))
+        .transpose()
+        .context("Invalid list of allowed file extensions.")?;
+    let options = ChatServerOptions {
+        keepalive_interval,
+        text_only: matches.get_flag("text-only"),
+        allowed_extensions,
+        file_store,
+        login_attempts: LoginAttempts::new(lockout_policy),
+        password_hashing: PasswordHashingOptions {
+            pepper: matches.get_one::<String>("password-pepper").cloned(),
+            params: argon2_params,
+        },
+        message_writer,
+        save_slots: matches
+            .get_one::<usize>("async-saves")
+            .copied()
+            .map(SaveSlots::new),
+        message_authors: MessageAuthors::new(),
+        motd,
+        outgoing_queue_capacity: *matches
+            .get_one::<usize>("outgoing-queue-capacity")
+            .ok_or_else(|| anyhow!("There is always a value."))?,
+        sessions: ActiveSessions::new(DuplicateLoginPolicy::from_name(
+            matches
+                .get_one::<String>("duplicate-login")
+                .ok_or_else(|| anyhow!("There is always a value."))?,
+        )?),
+        auth_attempts_counter,
+        active_connections_gauge: active_connections_gauge.clone(),
+        word_filter,
+        duplicate_window: matches
+            .get_one::<u64>("duplicate-window")
+            .map(|millis| Duration::from_millis(*millis)),
+        message_key: matches
+            .get_one::<String>("message-key")
+            .map(|key| MessageKey::new(key.as_bytes()))
+            .transpose()?,
+        rejection_banner: matches.get_one::<String>("rejection-banner").cloned(),
+    };
+
+    // Create a map of writers to connected clients. It is shared by both servers.
+    let client_writers_chat_server = create_client_writers();
+    let client_writers_http_server = Arc::clone(&client_writers_chat_server);
+
+    // Start correcting the active connections gauge regularly if it is enabled.
+    if !gauge_reconcile_interval.is_zero() {
+        spawn_gauge_reconciliation_task(
+            active_connections_gauge.clone(),
+            Arc::clone(&client_writers_chat_server),
+            gauge_reconcile_interval,
+        );
+    }
+
+    // Create a buffer of messages that could not be sent to clients. It is shared by both servers.
+    let broadcast_failures_chat_server = create_broadcast_failures();
+    let broadcast_failures_http_server = Arc::clone(&broadcast_failures_chat_server);
+
+    // Bind all listeners first, so that the server is reported ready only if all of them are bound.
+    // Listeners of servers that are disabled are not bound.
+    let http_listener = if run_http_server {
+        Some(bind_http_listener(&http_socket_address).await?)
+    } else {
+        None
+    };
+    let chat_listener = if run_chat_server {
+        Some(bind_tcp_listener(&chat_socket_address).await?)
+    } else {
+        None
+    };
+    #[cfg(unix)]
+    let unix_listener = match &unix_socket_path {
+        Some(socket_path) => Some(bind_unix_listener(socket_path)?),
+        None => None,
+    };
+    info!("Server ready. {}", describe_config(&matches));
+
+    // Run http server if it is enabled.
+    let http_state = HttpState {
+        connection_pool: connection_pool_http_server,
+        registry,
+        client_writers: client_writers_http_server,
+        broadcast_failures: broadcast_failures_http_server,
+        active_connections_gauge: active_connections_gauge.clone(),
+        password_hashing: options.password_hashing.clone(),
+        server_start,
+        admin_token: matches
+            .get_one::<String>("admin-token")
+            .map(|token| AdminToken::new(token)),
+        sessions: options.sessions.clone(),
+    };
+    let mut http_task = tokio::spawn(async move {
+        if let Some(http_listener) = http_listener {
+            info!("Starting http server...");
+            let static_dirs: Vec<&str> = static_dirs.iter().map(String::as_str).collect();
+            if let Err(e) = serve_http(http_listener, &static_dirs, http_state).await {
+                error!("HTTP server failed: {}", e);
+            };
+            info!("Exiting http server...");
+        }
     });
 
-    // Run chat server.
-    let chat_task = tokio::spawn(async move {
-        info!("Starting chat server...");
-        if let Err(e) = run_server(
-            &chat_socket_address,
-            connection_pool_chat_server,
-            &messages_counter,
-            &active_connections_gauge,
-        )
-        .await
-        {
-            error!("Chat server failed: {}", e);
-        };
-        info!("Exiting chat server...");
+    // Run chat server on a Unix domain socket if it is set. It shares everything with the chat server on TCP.
+    #[cfg(unix)]
+    let mut unix_task = {
+        let connection_pool = connection_pool_chat_server.clone();
+        let client_writers = Arc::clone(&client_writers_chat_server);
+        let broadcast_failures = Arc::clone(&broadcast_failures_chat_server);
+        let messages_counter = messages_counter.clone();
+        let options = options.clone();
+        tokio::spawn(async move {
+            // The socket file guard is kept by this task, so the file is removed when the task ends or is aborted.
+            if let Some((listener, socket_file)) = unix_listener {
+                info!("Starting chat server on {}...", socket_file.0.display());
+                if let Err(e) = serve_clients(
+                    listener,
+                    connection_pool,
+                    client_writers,
+                    broadcast_failures,
+                    &messages_counter,
+                    options,
+                )
+                .await
+                {
+                    error!("Chat server on Unix domain socket failed: {}", e);
+                };
+                info!("Exiting chat server on Unix domain socket...");
+            }
+        })
+    };
+    #[cfg(not(unix))]
+    let mut unix_task = tokio::spawn(async {});
+
+    // Run chat server if it is enabled.
+    let mut chat_task = tokio::spawn(async move {
+        if let Some(chat_listener) = chat_listener {
+            info!("Starting chat server...");
+            if let Err(e) = serve_clients(
+                chat_listener,
+                connection_pool_chat_server,
+                client_writers_chat_server,
+                broadcast_failures_chat_server,
+                &messages_counter,
+                options,
+            )
+            .await
+            {
+                error!("Chat server failed: {}", e);
+            };
+            info!("Exiting chat server...");
+        }
     });
 
-    tokio::try_join!(http_task, chat_task)?;
+    // Run until the servers stop or until the server is stopped by Ctrl+C.
+    let servers_result = tokio::select! {
+        result = async { tokio::try_join!(&mut http_task, &mut chat_task, &mut unix_task) } => {
+            result.map(|_| ())
+        }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Stopping server...");
+            // Aborting the task drops the Unix domain socket file guard, which removes the file.
+            unix_task.abort();
+            let _ = unix_task.await;
+            Ok(())
+        }
+    };
+
+    // Messages waiting for their batch are saved before the server exits, also if the servers failed.
+    if let (Some(message_writer), Some(message_writer_task)) =
+        (message_writer_on_shutdown, message_writer_task)
+    {
+        info!("Saving messages waiting for their batch...");
+        if let Err(e) = message_writer
+            .shut_down(message_writer_task, SHUTDOWN_SAVE_TIMEOUT)
+            .await
+        {
+            error!("Failed to save messages waiting for their batch: {}", e);
+        }
+    }
+    servers_result?;
 
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::SystemTime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpStream;
+    use tokio::time::{sleep, timeout};
+
+    use server::connections::{create_shared_writer, DEFAULT_OUTGOING_QUEUE_CAPACITY};
+    use server::http_server::{run_http_server, HttpState};
+    use server::password_hashing::{hash_password, verify_password};
+    use shared::{
+        decode_signed_message, encode_signed_message, receive_bytes, send_bytes, send_message,
+        TextFormat,
+    };
 
     use super::*;
 
+    /// Send a GET request for prometheus metrics and return the whole response.
+    async fn get_metrics_response(http_socket_address: &str) -> String {
+        let mut stream = TcpStream::connect(http_socket_address).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
     #[tokio::test]
     async fn test_remove_client_writer() {
-        let writers_to_clients: Arc<Mutex<HashMap<SocketAddr, SharedWriteHalf>>> =
-            Arc::new(Mutex::new(HashMap::new()));
+        let writers_to_clients = create_client_writers();
 
         let server_socket_address = "127.0.0.1:33333";
         let server_listener = TcpListener::bind(server_socket_address).await.unwrap();
@@ -479,15 +2221,1471 @@ mod tests {
 
         {
             let mut lock = writers_to_clients.lock().await;
-            lock.insert(server_socket_address, Arc::new(Mutex::new(writer)));
+            lock.insert(server_socket_address.into(), create_shared_writer(writer));
             assert_eq!(lock.len(), 1);
         }
+        let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+        active_connections_gauge.inc();
 
         let cloned_writers_to_clients = writers_to_clients.clone();
-        remove_client_writer(server_socket_address, cloned_writers_to_clients).await;
+        remove_client_writer(
+            server_socket_address.into(),
+            cloned_writers_to_clients.clone(),
+            &active_connections_gauge,
+        )
+        .await;
         {
             let lock = writers_to_clients.lock().await;
             assert_eq!(lock.len(), 0);
         }
+        assert_eq!(active_connections_gauge.get(), 0.0);
+        // A writer that is already gone does not decrease the gauge again.
+        remove_client_writer(
+            server_socket_address.into(),
+            cloned_writers_to_clients,
+            &active_connections_gauge,
+        )
+        .await;
+        assert_eq!(active_connections_gauge.get(), 0.0);
+    }
+    #[tokio::test]
+    async fn test_active_connections_gauge_in_metrics() {
+        let chat_socket_address = "127.0.0.1:33334";
+        let http_socket_address = "127.0.0.1:33335";
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let client_writers = create_client_writers();
+        let broadcast_failures = create_broadcast_failures();
+        let messages_counter = get_messages_counter().await.unwrap();
+        let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+        let auth_attempts_counter = get_auth_attempts_counter().await.unwrap();
+        let db_operation_durations = get_db_operation_durations_histogram().await.unwrap();
+        let registry = create_registry(
+            &messages_counter,
+            &active_connections_gauge,
+            &auth_attempts_counter,
+            &db_operation_durations,
+        )
+        .unwrap();
+
+        // Run both servers like main does.
+        let connection_pool_cloned = connection_pool.clone();
+        let client_writers_cloned = Arc::clone(&client_writers);
+        let broadcast_failures_cloned = Arc::clone(&broadcast_failures);
+        tokio::spawn(async move {
+            run_http_server(
+                http_socket_address,
+                &["static"],
+                HttpState {
+                    registry,
+                    client_writers: client_writers_cloned,
+                    broadcast_failures: broadcast_failures_cloned,
+                    ..HttpState::new(connection_pool_cloned)
+                },
+            )
+            .await
+        });
+        let options = ChatServerOptions {
+            auth_attempts_counter,
+            active_connections_gauge,
+            ..default_options().await
+        };
+        tokio::spawn(async move {
+            serve_clients(
+                bind_tcp_listener(chat_socket_address).await?,
+                connection_pool,
+                client_writers,
+                broadcast_failures,
+                &messages_counter,
+                options,
+            )
+            .await
+        });
+        sleep(Duration::from_millis(200)).await;
+        assert!(get_metrics_response(http_socket_address)
+            .await
+            .contains("active_connections_gauge 0"));
+
+        // Connect a client and wait until the chat server accepts it.
+        let _client_stream = TcpStream::connect(chat_socket_address).await.unwrap();
+        let mut response = String::new();
+        for _ in 0..20 {
+            response = get_metrics_response(http_socket_address).await;
+            if response.contains("active_connections_gauge 1") {
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(response.contains("active_connections_gauge 1"));
+    }
+    /// Run one test of this binary again in a child process with the given environment variables, and return whether it passed.
+    fn run_test_with_env(test_name: &str, vars: &[(&str, &str)]) -> bool {
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([test_name, "--exact", "--test-threads", "1"])
+            .env_remove("CHAT_SOCKET")
+            .envs(vars.iter().copied())
+            .output()
+            .unwrap();
+        output.status.success() && String::from_utf8_lossy(&output.stdout).contains("1 passed")
+    }
+    // Clap reads the environment of the process, which tests running in parallel share, so the checks run in child processes with their own environment.
+    #[test]
+    fn test_options_resolved_from_env_and_command_line() {
+        const TEST_NAME: &str = "tests::test_options_resolved_from_env_and_command_line";
+        match std::env::var("OPTIONS_TEST_CASE").as_deref() {
+            Ok("valid") => {
+                let from_env = build_cli().try_get_matches_from(["server"]).unwrap();
+                assert_eq!(
+                    from_env.get_one::<String>("http-socket").unwrap(),
+                    "127.0.0.1:8080"
+                );
+                assert_eq!(*from_env.get_one::<u32>("max-failed-logins").unwrap(), 3);
+                assert!(from_env.get_flag("text-only"));
+                // Options set neither on the command line nor in the environment keep their defaults.
+                assert_eq!(
+                    from_env.get_one::<String>("chat-socket").unwrap(),
+                    "0.0.0.0:11111"
+                );
+                let from_command_line = build_cli()
+                    .try_get_matches_from([
+                        "server",
+                        "--http-socket",
+                        "127.0.0.1:9090",
+                        "--max-failed-logins",
+                        "7",
+                    ])
+                    .unwrap();
+                assert_eq!(
+                    from_command_line.get_one::<String>("http-socket").unwrap(),
+                    "127.0.0.1:9090"
+                );
+                assert_eq!(
+                    *from_command_line
+                        .get_one::<u32>("max-failed-logins")
+                        .unwrap(),
+                    7
+                );
+            }
+            Ok("invalid") => {
+                assert!(build_cli().try_get_matches_from(["server"]).is_err());
+            }
+            _ => {
+                assert!(run_test_with_env(
+                    TEST_NAME,
+                    &[
+                        ("OPTIONS_TEST_CASE", "valid"),
+                        ("HTTP_SOCKET", "127.0.0.1:8080"),
+                        ("MAX_FAILED_LOGINS", "3"),
+                        ("TEXT_ONLY", "true"),
+                    ],
+                ));
+                assert!(run_test_with_env(
+                    TEST_NAME,
+                    &[("OPTIONS_TEST_CASE", "invalid"), ("MAX_FAILED_LOGINS", "0")],
+                ));
+            }
+        }
+    }
+    #[test]
+    fn test_describing_config_without_secrets() {
+        let matches = build_cli()
+            .try_get_matches_from([
+                "server",
+                "--chat-socket",
+                "127.0.0.1:12345",
+                "--password-pepper",
+                "very secret pepper",
+                "--admin-token",
+                "very secret token",
+            ])
+            .unwrap();
+
+        let config = describe_config(&matches);
+
+        assert!(config.contains("chat-socket=127.0.0.1:12345"));
+        assert!(config.contains("db-file="));
+        assert!(config.contains("password-pepper=<hidden>"));
+        assert!(!config.contains("very secret pepper"));
+        assert!(config.contains("admin-token=<hidden>"));
+        assert!(!config.contains("very secret token"));
+        // Options without a value are left out.
+        assert!(!config.contains("unix-socket"));
+    }
+    #[test]
+    fn test_disabling_servers() {
+        let chat_only = build_cli()
+            .try_get_matches_from(["server", "--no-http"])
+            .unwrap();
+        assert!(!chat_only.get_flag("no-chat"));
+        assert!(chat_only.get_flag("no-http"));
+        let http_only = build_cli()
+            .try_get_matches_from(["server", "--no-chat"])
+            .unwrap();
+        assert!(http_only.get_flag("no-chat"));
+        assert!(!http_only.get_flag("no-http"));
+
+        // At least one server must run, and the Unix domain socket is served only by the chat server.
+        assert!(build_cli()
+            .try_get_matches_from(["server", "--no-chat", "--no-http"])
+            .is_err());
+        assert!(build_cli()
+            .try_get_matches_from(["server", "--no-chat", "--unix-socket", "chat.sock"])
+            .is_err());
+    }
+    #[test]
+    fn test_validating_socket_addresses() {
+        assert!(validate_socket_addresses("0.0.0.0:11111", "0.0.0.0:80").is_ok());
+        assert!(validate_socket_addresses("127.0.0.1:8080", "127.0.0.2:8080").is_ok());
+        assert!(validate_socket_addresses("[::]:11111", "[::]:80").is_ok());
+
+        assert!(validate_socket_addresses("0.0.0.0:11111", "0.0.0.0:11111").is_err());
+        assert!(validate_socket_addresses("[::1]:8080", "[::1]:8080").is_err());
+        // A server bound to all addresses conflicts with any address on the same port.
+        assert!(validate_socket_addresses("0.0.0.0:8080", "127.0.0.1:8080").is_err());
+        // Both addresses must be socket addresses, not host names or IP addresses without a port.
+        assert!(validate_socket_addresses("localhost:8080", "0.0.0.0:80").is_err());
+        assert!(validate_socket_addresses("0.0.0.0:11111", "0.0.0.0").is_err());
+    }
+    #[test]
+    fn test_choosing_mime_type_of_stored_file() {
+        assert_eq!(
+            choose_mime_type("notes.txt", Some("text/markdown")),
+            "text/markdown"
+        );
+        // Without a type or with an invalid one, it is guessed from the extension.
+        assert_eq!(choose_mime_type("photo.png", None), "image/png");
+        assert_eq!(
+            choose_mime_type("photo.png", Some("not a type")),
+            "image/png"
+        );
+        assert_eq!(
+            choose_mime_type("data.unknownextension", None),
+            "application/octet-stream"
+        );
+    }
+    #[test]
+    fn test_image_rejected_in_text_only_mode() {
+        let image_message = MessageType::Image(vec![137, 80, 78, 71]);
+        let file_message = MessageType::File {
+            name: "notes.txt".to_string(),
+            mime: None,
+            data: vec![1, 2, 3],
+        };
+        let text_message = MessageType::Text("Hello.".to_string());
+
+        assert!(!is_message_allowed(&image_message, true));
+        assert!(!is_message_allowed(&file_message, true));
+        assert!(is_message_allowed(&text_message, true));
+        assert!(is_message_allowed(&image_message, false));
+        assert!(is_message_allowed(&file_message, false));
+    }
+    #[test]
+    fn test_applying_word_filter_to_text_messages() {
+        let masking_filter = Filter::new(["darn"], FilterAction::Mask).unwrap();
+        let rejecting_filter = Filter::new(["darn"], FilterAction::Reject).unwrap();
+        let text_message = MessageType::Text("Darn it.".to_string());
+        let markdown_message = MessageType::FormattedText {
+            text: "**darn**".to_string(),
+            format: TextFormat::Markdown,
+        };
+        let file_message = MessageType::File {
+            name: "darn.txt".to_string(),
+            mime: None,
+            data: vec![1, 2, 3],
+        };
+
+        assert_eq!(
+            apply_word_filter(text_message, &masking_filter),
+            Some(MessageType::Text("#### it.".to_string()))
+        );
+        assert_eq!(
+            apply_word_filter(markdown_message, &masking_filter),
+            Some(MessageType::FormattedText {
+                text: "**####**".to_string(),
+                format: TextFormat::Markdown,
+            })
+        );
+        assert_eq!(
+            apply_word_filter(MessageType::Text("Darn it.".to_string()), &rejecting_filter),
+            None
+        );
+        assert_eq!(
+            apply_word_filter(MessageType::Text("Fine.".to_string()), &rejecting_filter),
+            Some(MessageType::Text("Fine.".to_string()))
+        );
+        // Only texts are filtered.
+        assert_eq!(
+            apply_word_filter(file_message, &rejecting_filter),
+            Some(MessageType::File {
+                name: "darn.txt".to_string(),
+                mime: None,
+                data: vec![1, 2, 3],
+            })
+        );
+    }
+    #[tokio::test]
+    async fn test_login_refused_when_locked_out() {
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let password_hashing = PasswordHashingOptions::default();
+        let password_hash = hash_password("password", &password_hashing).await.unwrap();
+        db::add_user(&connection_pool, "alice", &password_hash)
+            .await
+            .unwrap();
+        let login_attempts = LoginAttempts::new(LockoutPolicy {
+            max_failures: 2,
+            window: Duration::from_secs(300),
+        });
+
+        for _ in 0..2 {
+            let (user_id, _) = login(
+                &connection_pool,
+                &login_attempts,
+                &password_hashing,
+                "alice",
+                "wrong",
+            )
+            .await;
+            assert_eq!(user_id, Err(AuthError::WrongCredentials));
+        }
+        // Even the correct password is refused now.
+        let (user_id, message_from_server) = login(
+            &connection_pool,
+            &login_attempts,
+            &password_hashing,
+            "alice",
+            "password",
+        )
+        .await;
+
+        assert_eq!(user_id, Err(AuthError::TooManyAttempts));
+        assert_eq!(message_from_server, "too many attempts, try later");
+    }
+    #[tokio::test]
+    async fn test_auth_errors_of_failed_registrations_and_logins() {
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let password_hashing = PasswordHashingOptions::default();
+        let login_attempts = LoginAttempts::new(LockoutPolicy {
+            max_failures: 5,
+            window: Duration::from_secs(300),
+        });
+        let (user_id, _) = register(&connection_pool, &password_hashing, "alice", "password").await;
+        assert!(user_id.is_ok());
+
+        let (taken, _) = register(&connection_pool, &password_hashing, "alice", "password").await;
+        let (empty_password, _) = register(&connection_pool, &password_hashing, "bob", "").await;
+        let (too_long, _) = register(
+            &connection_pool,
+            &password_hashing,
+            &"b".repeat(db::MAX_USERNAME_LENGTH + 1),
+            "password",
+        )
+        .await;
+        let (unknown_user, _) = login(
+            &connection_pool,
+            &login_attempts,
+            &password_hashing,
+            "carol",
+            "password",
+        )
+        .await;
+        let (invalid_action, _) = handle_auth_request(
+            &connection_pool,
+            &login_attempts,
+            &password_hashing,
+            &get_auth_attempts_counter().await.unwrap(),
+            "X",
+            "alice",
+            "password",
+        )
+        .await;
+
+        assert_eq!(taken, Err(AuthError::UsernameTaken));
+        assert_eq!(empty_password, Err(AuthError::WeakPassword));
+        assert_eq!(too_long, Err(AuthError::UsernameTooLong));
+        assert_eq!(unknown_user, Err(AuthError::WrongCredentials));
+        assert_eq!(invalid_action, Err(AuthError::InvalidRequest));
+    }
+    #[tokio::test]
+    async fn test_counting_auth_attempts() {
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let password_hashing = PasswordHashingOptions::default();
+        let login_attempts = LoginAttempts::new(LockoutPolicy {
+            max_failures: 5,
+            window: Duration::from_secs(300),
+        });
+        let auth_attempts_counter = get_auth_attempts_counter().await.unwrap();
+
+        for (action, password) in [("R", "password"), ("L", "wrong")] {
+            let (auth_result, _) = handle_auth_request(
+                &connection_pool,
+                &login_attempts,
+                &password_hashing,
+                &auth_attempts_counter,
+                action,
+                "alice",
+                password,
+            )
+            .await;
+            assert_eq!(auth_result.is_ok(), action == "R");
+        }
+
+        let count = |action: &str, result: &str| {
+            auth_attempts_counter
+                .with_label_values(&[action, result])
+                .get()
+        };
+        assert_eq!(count("register", "success"), 1.0);
+        assert_eq!(count("login", "failure"), 1.0);
+        assert_eq!(count("login", "success"), 0.0);
+    }
+    #[tokio::test]
+    async fn test_banned_user_refused() {
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let password_hashing = PasswordHashingOptions::default();
+        let login_attempts = LoginAttempts::new(LockoutPolicy {
+            max_failures: 5,
+            window: Duration::from_secs(300),
+        });
+        let mut user_ids = Vec::new();
+        for username in ["alice", "bob", "carol"] {
+            let (user_id, _) =
+                register(&connection_pool, &password_hashing, username, "password").await;
+            user_ids.push(user_id.unwrap());
+        }
+        db::add_ban(&connection_pool, &user_ids[0], None)
+            .await
+            .unwrap();
+        // An expired ban does not refuse anything.
+        db::add_ban(
+            &connection_pool,
+            &user_ids[2],
+            Some(SystemTime::now() - Duration::from_secs(60)),
+        )
+        .await
+        .unwrap();
+        // The ban stays when the user is renamed.
+        db::rename_user(&connection_pool, &user_ids[0], "alicia")
+            .await
+            .unwrap();
+
+        let auth_attempts_counter = get_auth_attempts_counter().await.unwrap();
+        let mut results = Vec::new();
+        for (username, password) in [
+            ("alicia", "password"),
+            ("alicia", "wrong"),
+            ("bob", "password"),
+            ("carol", "password"),
+        ] {
+            results.push(
+                handle_auth_request(
+                    &connection_pool,
+                    &login_attempts,
+                    &password_hashing,
+                    &auth_attempts_counter,
+                    "L",
+                    username,
+                    password,
+                )
+                .await,
+            );
+        }
+
+        assert_eq!(
+            results[0],
+            (Err(AuthError::Banned), "account banned".to_string())
+        );
+        // The ban is not told to someone who does not know the password.
+        assert_eq!(results[1].0, Err(AuthError::WrongCredentials));
+        assert!(results[2].0.is_ok());
+        assert!(results[3].0.is_ok());
+    }
+    #[tokio::test]
+    async fn test_history_capped_and_ordered() {
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let alice_id = db::add_user(&connection_pool, "alice", "hash")
+            .await
+            .unwrap();
+        let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+        for i in 0..(MAX_HISTORY_LENGTH + 5) {
+            let user_id = if i % 2 == 0 { alice_id } else { bob_id };
+            db::add_message(
+                &connection_pool,
+                &user_id,
+                &format!("Message {}.", i),
+                MessageKind::Text,
+                &SystemClock,
+            )
+            .await
+            .unwrap();
+        }
+
+        let short_history = get_history(&connection_pool, 2).await;
+        let long_history = get_history(&connection_pool, u32::MAX).await;
+        let empty_history = get_history(&connection_pool, 0).await;
+
+        match short_history {
+            MessageType::History(entries) => {
+                let contents: Vec<(&str, &str)> = entries
+                    .iter()
+                    .map(|entry| (entry.username.as_str(), entry.content.as_str()))
+                    .collect();
+                assert_eq!(
+                    contents,
+                    vec![("bob", "Message 103."), ("alice", "Message 104.")]
+                );
+            }
+            message => panic!("Unexpected reply: {:?}", message),
+        }
+        match long_history {
+            MessageType::History(entries) => {
+                assert_eq!(entries.len(), MAX_HISTORY_LENGTH as usize);
+                assert_eq!(entries[0].content, "Message 5.");
+            }
+            message => panic!("Unexpected reply: {:?}", message),
+        }
+        assert!(matches!(empty_history, MessageType::ServerError(_)));
+    }
+    #[tokio::test]
+    async fn test_history_with_tags() {
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let user_id = db::add_user(&connection_pool, "alice", "hash")
+            .await
+            .unwrap();
+        db::add_tagged_message(
+            &connection_pool,
+            &user_id,
+            "Tagged.",
+            MessageKind::Text,
+            Some(3),
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+        db::add_message(
+            &connection_pool,
+            &user_id,
+            "Not tagged.",
+            MessageKind::Text,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+
+        match get_history(&connection_pool, 2).await {
+            MessageType::History(entries) => {
+                let tags: Vec<Option<i64>> = entries.iter().map(|entry| entry.tag).collect();
+                assert_eq!(tags, vec![Some(3), None]);
+            }
+            message => panic!("Unexpected reply: {:?}", message),
+        }
+    }
+    #[tokio::test]
+    async fn test_loading_motd() {
+        let path = std::env::temp_dir().join("hello_rust_test_motd.txt");
+        let motd_file = Motd::File(path.clone());
+
+        tokio::fs::write(&path, "Welcome!\n").await.unwrap();
+        let first = load_motd(&motd_file).await;
+        // The file is read again, so a changed message is sent to the next client.
+        tokio::fs::write(&path, "Maintenance at 22:00.")
+            .await
+            .unwrap();
+        let second = load_motd(&motd_file).await;
+        tokio::fs::write(&path, "  \n").await.unwrap();
+        let empty = load_motd(&motd_file).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        let missing = load_motd(&motd_file).await;
+
+        assert_eq!(first, Some("Welcome!".to_string()));
+        assert_eq!(second, Some("Maintenance at 22:00.".to_string()));
+        assert_eq!(empty, None);
+        assert_eq!(missing, None);
+        assert_eq!(
+            load_motd(&Motd::Text("Hello.".to_string())).await,
+            Some("Hello.".to_string())
+        );
+        assert_eq!(load_motd(&Motd::Text(String::new())).await, None);
+    }
+    #[tokio::test]
+    async fn test_login_replaces_hash_without_pepper() {
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let password_hash = hash_password("password", &PasswordHashingOptions::default())
+            .await
+            .unwrap();
+        let user_id = db::add_user(&connection_pool, "alice", &password_hash)
+            .await
+            .unwrap();
+        let password_hashing = PasswordHashingOptions {
+            pepper: Some("pepper".to_string()),
+            ..PasswordHashingOptions::default()
+        };
+        let login_attempts = LoginAttempts::new(LockoutPolicy {
+            max_failures: 5,
+            window: Duration::from_secs(300),
+        });
+
+        let (logged_in_user_id, _) = login(
+            &connection_pool,
+            &login_attempts,
+            &password_hashing,
+            "alice",
+            "password",
+        )
+        .await;
+
+        assert_eq!(logged_in_user_id, Ok(user_id));
+        let (_, new_password_hash) = db::get_user(&connection_pool, "alice").await.unwrap();
+        assert_ne!(new_password_hash, password_hash);
+        assert!(
+            verify_password("password", &new_password_hash, &password_hashing)
+                .await
+                .is_ok()
+        );
+        assert!(verify_password(
+            "password",
+            &new_password_hash,
+            &PasswordHashingOptions::default()
+        )
+        .await
+        .is_err());
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serving_client_on_unix_socket() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_serving_client.sock");
+        let (server_task, _) = spawn_unix_server(&socket_path, default_options().await).await;
+
+        // A client connected through the Unix domain socket is authenticated like a TCP client.
+        let mut client_stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        let auth_request = MessageType::AuthRequest(
+            "R".to_string(),
+            "alice".to_string(),
+            "password".to_string(),
+            Compression::Enabled,
+            Signing::Disabled,
+            ReadReceipts::Disabled,
+        );
+        send_message(&mut client_stream, &auth_request)
+            .await
+            .unwrap();
+        let auth_response = receive_message(&mut client_stream).await.unwrap();
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(true, None, _, Compression::Enabled, _, _)
+        ));
+
+        // The socket file is removed when the server stops.
+        server_task.abort();
+        let _ = server_task.await;
+        assert!(!socket_path.exists());
+    }
+
+    #[cfg(unix)]
+    async fn register_on_unix_socket(
+        socket_path: &std::path::Path,
+        username: &str,
+        read_receipts: ReadReceipts,
+    ) -> tokio::net::UnixStream {
+        let (client_stream, auth_response) =
+            authenticate_on_unix_socket(socket_path, "R", username, read_receipts).await;
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(true, None, _, _, _, _)
+        ));
+        client_stream
+    }
+
+    #[cfg(unix)]
+    async fn authenticate_on_unix_socket(
+        socket_path: &std::path::Path,
+        action: &str,
+        username: &str,
+        read_receipts: ReadReceipts,
+    ) -> (tokio::net::UnixStream, MessageType) {
+        let mut client_stream = tokio::net::UnixStream::connect(socket_path).await.unwrap();
+        let auth_request = MessageType::AuthRequest(
+            action.to_string(),
+            username.to_string(),
+            "password".to_string(),
+            Compression::Disabled,
+            Signing::Disabled,
+            read_receipts,
+        );
+        send_message(&mut client_stream, &auth_request)
+            .await
+            .unwrap();
+        let auth_response = receive_message(&mut client_stream).await.unwrap();
+        (client_stream, auth_response)
+    }
+
+    /// Options of a chat server for tests. Nothing optional is enabled and a second login of a user is refused.
+    async fn default_options() -> ChatServerOptions {
+        ChatServerOptions {
+            keepalive_interval: Duration::from_secs(60),
+            text_only: false,
+            allowed_extensions: None,
+            file_store: None,
+            login_attempts: LoginAttempts::new(LockoutPolicy {
+                max_failures: 5,
+                window: Duration::from_secs(300),
+            }),
+            password_hashing: PasswordHashingOptions::default(),
+            message_writer: None,
+            save_slots: None,
+            message_authors: MessageAuthors::new(),
+            motd: None,
+            outgoing_queue_capacity: DEFAULT_OUTGOING_QUEUE_CAPACITY,
+            sessions: ActiveSessions::new(DuplicateLoginPolicy::Reject),
+            auth_attempts_counter: get_auth_attempts_counter().await.unwrap(),
+            active_connections_gauge: get_active_connections_gauge().await.unwrap(),
+            word_filter: None,
+            duplicate_window: None,
+            message_key: None,
+            rejection_banner: None,
+        }
+    }
+
+    /// Serve clients on a Unix domain socket with the given options and an empty in-memory database.
+    /// The database is returned with the task, so that tests can check what was saved.
+    #[cfg(unix)]
+    async fn spawn_unix_server(
+        socket_path: &std::path::Path,
+        options: ChatServerOptions,
+    ) -> (tokio::task::JoinHandle<Result<()>>, SqlitePool) {
+        let _ = std::fs::remove_file(socket_path);
+        let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+        let messages_counter = get_messages_counter().await.unwrap();
+        let socket_path = socket_path.to_path_buf();
+        let connection_pool_cloned = connection_pool.clone();
+        let server_task = tokio::spawn(async move {
+            let (listener, _socket_file) = bind_unix_listener(&socket_path)?;
+            serve_clients(
+                listener,
+                connection_pool_cloned,
+                create_client_writers(),
+                create_broadcast_failures(),
+                &messages_counter,
+                options,
+            )
+            .await
+        });
+        sleep(Duration::from_millis(200)).await;
+        (server_task, connection_pool)
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rejecting_files_with_disallowed_extensions() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_allowed_extensions.sock");
+        let (server_task, connection_pool) = spawn_unix_server(
+            &socket_path,
+            ChatServerOptions {
+                allowed_extensions: Some(AllowedExtensions::parse("txt,pdf").unwrap()),
+                ..default_options().await
+            },
+        )
+        .await;
+        let mut alice =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+        let mut bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Disabled).await;
+        let program = MessageType::File {
+            name: "setup.exe".to_string(),
+            mime: None,
+            data: vec![1, 2, 3],
+        };
+        let notes = MessageType::File {
+            name: "notes.TXT".to_string(),
+            mime: Some("text/plain".to_string()),
+            data: b"Notes.".to_vec(),
+        };
+
+        send_message(&mut alice, &program).await.unwrap();
+        let rejection = receive_message(&mut alice).await.unwrap();
+        send_message(&mut alice, &notes).await.unwrap();
+        let forwarded = receive_message(&mut bob).await.unwrap();
+        server_task.abort();
+
+        assert!(
+            matches!(rejection, MessageType::ServerError(text) if text.starts_with("The file setup.exe was not sent."))
+        );
+        // Only the allowed file reaches other clients and the database.
+        assert_eq!(
+            forwarded,
+            MessageType::Authored("alice".to_string(), Box::new(notes))
+        );
+        let (user_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+        let messages = db::get_messages_by_user(&connection_pool, &user_id, db::MessageOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "FILE SENT: notes.TXT");
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_saving_file_with_long_name() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_long_file_name.sock");
+        let (server_task, connection_pool) =
+            spawn_unix_server(&socket_path, default_options().await).await;
+        let mut alice =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+        let mut bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Disabled).await;
+        let name = format!("{}.txt", "a".repeat(db::MAX_CONTENT_LENGTH));
+        let file = MessageType::File {
+            name: name.clone(),
+            mime: None,
+            data: b"Notes.".to_vec(),
+        };
+        let text = MessageType::Text("Did you get it?".to_string());
+
+        send_message(&mut alice, &file).await.unwrap();
+        let forwarded_file = receive_message(&mut bob).await.unwrap();
+        // The connection stays open after the file is saved.
+        send_message(&mut alice, &text).await.unwrap();
+        let forwarded_text = receive_message(&mut bob).await.unwrap();
+        server_task.abort();
+
+        assert_eq!(
+            forwarded_file,
+            MessageType::Authored("alice".to_string(), Box::new(file))
+        );
+        assert_eq!(
+            forwarded_text,
+            MessageType::Authored("alice".to_string(), Box::new(text))
+        );
+        let (user_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+        let messages = db::get_messages_by_user(&connection_pool, &user_id, db::MessageOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content.chars().count(), db::MAX_CONTENT_LENGTH);
+        assert!(messages[0].content.starts_with("FILE SENT: aaa"));
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_disconnecting_unsavable_message_with_async_saves() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_unsavable_async.sock");
+        let (server_task, connection_pool) = spawn_unix_server(
+            &socket_path,
+            ChatServerOptions {
+                save_slots: Some(SaveSlots::new(4)),
+                ..default_options().await
+            },
+        )
+        .await;
+        let mut alice =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+        let mut bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Disabled).await;
+        let text = MessageType::Text("Hello.".to_string());
+
+        send_message(&mut alice, &text).await.unwrap();
+        send_message(
+            &mut alice,
+            &MessageType::ServerNotice("Not from the server.".to_string()),
+        )
+        .await
+        .unwrap();
+        // Only the text is forwarded, the connection is closed instead of forwarding the other message.
+        assert_eq!(
+            receive_message(&mut bob).await.unwrap(),
+            MessageType::Authored("alice".to_string(), Box::new(text))
+        );
+        assert!(receive_message(&mut alice).await.is_err());
+        assert!(
+            timeout(Duration::from_millis(200), receive_message(&mut bob))
+                .await
+                .is_err()
+        );
+        server_task.abort();
+
+        // The text saved in the background is in the database once the connection is closed.
+        let (user_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+        let messages = db::get_messages_by_user(&connection_pool, &user_id, db::MessageOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+    }
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_dropping_duplicate_texts() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_duplicate_texts.sock");
+        let _ = std::fs::remove_file(&socket_path);
+        let (server_task, connection_pool) = spawn_unix_server(
+            &socket_path,
+            ChatServerOptions {
+                duplicate_window: Some(Duration::from_secs(60)),
+                ..default_options().await
+            },
+        )
+        .await;
+        let mut alice =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+        let mut bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Disabled).await;
+
+        // The same text sent twice quickly is forwarded once. Another text is forwarded as usual.
+        for text in ["Hello.", "Hello.", "Bye."] {
+            send_message(&mut alice, &MessageType::Text(text.to_string()))
+                .await
+                .unwrap();
+        }
+        let first = receive_message(&mut bob).await.unwrap();
+        let second = receive_message(&mut bob).await.unwrap();
+        server_task.abort();
+
+        assert_eq!(
+            first,
+            MessageType::Authored(
+                "alice".to_string(),
+                Box::new(MessageType::Text("Hello.".to_string()))
+            )
+        );
+        assert_eq!(
+            second,
+            MessageType::Authored(
+                "alice".to_string(),
+                Box::new(MessageType::Text("Bye.".to_string()))
+            )
+        );
+        let (user_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+        let messages = db::get_messages_by_user(&connection_pool, &user_id, db::MessageOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_relaying_read_receipts() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_read_receipts.sock");
+        let _ = std::fs::remove_file(&socket_path);
+        let (server_task, _) = spawn_unix_server(&socket_path, default_options().await).await;
+        let mut alice = register_on_unix_socket(&socket_path, "alice", ReadReceipts::Enabled).await;
+        let mut bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Enabled).await;
+        let mut carol =
+            register_on_unix_socket(&socket_path, "carol", ReadReceipts::Disabled).await;
+
+        send_message(&mut alice, &MessageType::Text("Hello.".to_string()))
+            .await
+            .unwrap();
+        let id = match receive_message(&mut alice).await.unwrap() {
+            MessageType::MessageId(id) => id,
+            message => panic!("Unexpected message: {:?}", message),
+        };
+        // Only clients taking part in read receipts get the id.
+        assert_eq!(
+            receive_message(&mut bob).await.unwrap(),
+            MessageType::Identified(
+                id,
+                Box::new(MessageType::Authored(
+                    "alice".to_string(),
+                    Box::new(MessageType::Text("Hello.".to_string()))
+                ))
+            )
+        );
+        assert_eq!(
+            receive_message(&mut carol).await.unwrap(),
+            MessageType::Authored(
+                "alice".to_string(),
+                Box::new(MessageType::Text("Hello.".to_string()))
+            )
+        );
+        send_message(&mut bob, &MessageType::ReadReceipt(id))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            receive_message(&mut alice).await.unwrap(),
+            MessageType::ReadBy(id, "bob".to_string())
+        );
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_relaying_tagged_messages() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_tagged_messages.sock");
+        let (server_task, connection_pool) =
+            spawn_unix_server(&socket_path, default_options().await).await;
+        let mut alice = register_on_unix_socket(&socket_path, "alice", ReadReceipts::Enabled).await;
+        let mut bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Disabled).await;
+
+        // The tag comes back with the id of the message and is forwarded with the message.
+        let hello = MessageType::Text("Hello.".to_string());
+        send_message(
+            &mut alice,
+            &MessageType::Tagged(42, Box::new(hello.clone())),
+        )
+        .await
+        .unwrap();
+        let id = match receive_message(&mut alice).await.unwrap() {
+            MessageType::Tagged(42, message) => match *message {
+                MessageType::MessageId(id) => id,
+                message => panic!("Unexpected message: {:?}", message),
+            },
+            message => panic!("Unexpected message: {:?}", message),
+        };
+        assert!(id > 0);
+        assert_eq!(
+            receive_message(&mut bob).await.unwrap(),
+            MessageType::Authored(
+                "alice".to_string(),
+                Box::new(MessageType::Tagged(42, Box::new(hello)))
+            )
+        );
+
+        // Only texts can be tagged.
+        let image = MessageType::Image(vec![1, 2, 3]);
+        send_message(&mut alice, &MessageType::Tagged(43, Box::new(image)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            receive_message(&mut alice).await.unwrap(),
+            MessageType::ServerError(_)
+        ));
+        server_task.abort();
+
+        // The tag is saved with the message.
+        let (user_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+        let messages = db::get_messages_by_user(&connection_pool, &user_id, db::MessageOrder::Asc)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].tag, Some(42));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_broadcasting_under_nickname() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_nickname.sock");
+        let (server_task, _) = spawn_unix_server(&socket_path, default_options().await).await;
+        let mut alice =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+        let mut bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Disabled).await;
+
+        // An invalid nickname is refused and the username is still shown.
+        send_message(&mut alice, &MessageType::SetNickname("Al\nice".to_string()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            receive_message(&mut alice).await.unwrap(),
+            MessageType::ServerError(_)
+        ));
+        send_message(&mut alice, &MessageType::Text("Hello.".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_message(&mut bob).await.unwrap(),
+            MessageType::Authored(
+                "alice".to_string(),
+                Box::new(MessageType::Text("Hello.".to_string()))
+            )
+        );
+
+        send_message(
+            &mut alice,
+            &MessageType::SetNickname("Ally Cat".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            receive_message(&mut alice).await.unwrap(),
+            MessageType::ServerNotice("Nickname changed to Ally Cat.".to_string())
+        );
+        send_message(&mut alice, &MessageType::Text("Hi again.".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_message(&mut bob).await.unwrap(),
+            MessageType::Authored(
+                "Ally Cat".to_string(),
+                Box::new(MessageType::Text("Hi again.".to_string()))
+            )
+        );
+
+        // The nickname is kept for the next login, which still uses the username.
+        drop(alice);
+        sleep(Duration::from_millis(100)).await;
+        let (mut alice, auth_response) =
+            authenticate_on_unix_socket(&socket_path, "L", "alice", ReadReceipts::Disabled).await;
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(true, None, _, _, _, _)
+        ));
+        send_message(&mut alice, &MessageType::Text("Back.".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_message(&mut bob).await.unwrap(),
+            MessageType::Authored(
+                "Ally Cat".to_string(),
+                Box::new(MessageType::Text("Back.".to_string()))
+            )
+        );
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_assigning_session_ids() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_session_ids.sock");
+        let (server_task, _) = spawn_unix_server(&socket_path, default_options().await).await;
+
+        // Each session gets its own id, also after the user logs in again.
+        let mut session_ids = Vec::new();
+        for action in ["R", "L"] {
+            let (_, auth_response) =
+                authenticate_on_unix_socket(&socket_path, action, "alice", ReadReceipts::Disabled)
+                    .await;
+            match auth_response {
+                MessageType::AuthResponse(true, None, _, _, _, Some(session_id)) => {
+                    session_ids.push(session_id)
+                }
+                message => panic!("Unexpected message: {:?}", message),
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert_ne!(session_ids[0], session_ids[1]);
+
+        // A failed authentication has no session.
+        let (_, auth_response) =
+            authenticate_on_unix_socket(&socket_path, "L", "bob", ReadReceipts::Disabled).await;
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(false, Some(AuthError::WrongCredentials), _, _, _, None)
+        ));
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_sending_before_authenticating() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_unauthenticated.sock");
+        let (server_task, _) = spawn_unix_server(&socket_path, default_options().await).await;
+
+        // A message other than an authentication request is answered with a failed authentication.
+        let mut client_stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        send_message(&mut client_stream, &MessageType::Text("Hello.".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_message(&mut client_stream).await.unwrap(),
+            MessageType::AuthResponse(
+                false,
+                Some(AuthError::InvalidRequest),
+                "authentication required first".to_string(),
+                Compression::Disabled,
+                Signing::Disabled,
+                None
+            )
+        );
+
+        // The connection is closed afterwards.
+        assert!(receive_message(&mut client_stream).await.is_err());
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_signing_messages() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_signing.sock");
+        let _ = std::fs::remove_file(&socket_path);
+        let key = MessageKey::new(b"shared secret").unwrap();
+        let (server_task, _) = spawn_unix_server(
+            &socket_path,
+            ChatServerOptions {
+                message_key: Some(key.clone()),
+                ..default_options().await
+            },
+        )
+        .await;
+
+        // A client that does not ask for signing is refused.
+        let (_, auth_response) =
+            authenticate_on_unix_socket(&socket_path, "R", "mallory", ReadReceipts::Disabled).await;
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(false, Some(AuthError::SigningMismatch), _, _, _, None)
+        ));
+
+        // Clients that ask for signing are accepted and their signed messages are forwarded signed.
+        let mut clients = Vec::new();
+        for username in ["alice", "bob", "carol"] {
+            let mut client_stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+            let auth_request = MessageType::AuthRequest(
+                "R".to_string(),
+                username.to_string(),
+                "password".to_string(),
+                Compression::Disabled,
+                Signing::Enabled,
+                ReadReceipts::Disabled,
+            );
+            send_message(&mut client_stream, &auth_request)
+                .await
+                .unwrap();
+            assert!(matches!(
+                receive_message(&mut client_stream).await.unwrap(),
+                MessageType::AuthResponse(true, None, _, _, Signing::Enabled, _)
+            ));
+            clients.push(client_stream);
+        }
+        let [mut alice, mut bob, mut carol] = clients.try_into().unwrap();
+        let mut alice_signer = FrameSigner::new(key.clone(), Direction::ClientToServer);
+        let mut bob_receiver = FrameSigner::new(key.clone(), Direction::ServerToClient);
+        let frame = encode_signed_message(
+            &MessageType::Text("Hello.".to_string()),
+            Compression::Disabled,
+            Some(&mut alice_signer),
+        )
+        .unwrap();
+        send_bytes(&mut alice, &frame).await.unwrap();
+        let received_frame = receive_bytes(&mut bob).await.unwrap();
+        assert_eq!(
+            decode_signed_message(
+                &received_frame,
+                Compression::Disabled,
+                Some(&mut bob_receiver)
+            )
+            .unwrap(),
+            MessageType::Authored(
+                "alice".to_string(),
+                Box::new(MessageType::Text("Hello.".to_string()))
+            )
+        );
+        receive_bytes(&mut carol).await.unwrap();
+
+        // A tampered frame is refused and the connection of its sender is closed.
+        let mut tampered_frame = encode_signed_message(
+            &MessageType::Text("Hello.".to_string()),
+            Compression::Disabled,
+            Some(&mut FrameSigner::new(
+                key.clone(),
+                Direction::ClientToServer,
+            )),
+        )
+        .unwrap();
+        tampered_frame[2] ^= 1;
+        send_bytes(&mut carol, &tampered_frame).await.unwrap();
+        assert!(receive_message(&mut carol).await.is_err());
+        assert!(
+            timeout(Duration::from_millis(200), receive_message(&mut bob))
+                .await
+                .is_err()
+        );
+
+        // A replayed frame is out of sequence, so it is refused too.
+        send_bytes(&mut alice, &frame).await.unwrap();
+        assert!(receive_message(&mut alice).await.is_err());
+        // So is a frame of the server reflected back to it, because it was signed for the other direction.
+        send_bytes(&mut bob, &received_frame).await.unwrap();
+        assert!(receive_message(&mut bob).await.is_err());
+
+        server_task.abort();
+        let _ = std::fs::remove_file(&socket_path);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rejecting_undecodable_first_frame() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_reject_version.sock");
+        let (server_task, _) = spawn_unix_server(&socket_path, default_options().await).await;
+
+        // A frame that is not a message of this protocol version gets a rejection and the connection is closed.
+        let mut client_stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        send_bytes(&mut client_stream, &[0xff, 0x00, 0x01])
+            .await
+            .unwrap();
+        assert_eq!(
+            receive_message(&mut client_stream).await.unwrap(),
+            MessageType::ConnectionRejected(RejectReason::VersionMismatch, None)
+        );
+        assert!(receive_message(&mut client_stream).await.is_err());
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_rejecting_duplicate_login() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_reject_duplicate.sock");
+        let (server_task, _) = spawn_unix_server(&socket_path, default_options().await).await;
+        let mut first =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+
+        let (_, auth_response) =
+            authenticate_on_unix_socket(&socket_path, "L", "alice", ReadReceipts::Disabled).await;
+        assert_eq!(
+            auth_response,
+            MessageType::ConnectionRejected(RejectReason::TooManySessions, None)
+        );
+        // The first connection stays logged in.
+        send_message(&mut first, &MessageType::HistoryRequest(1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            receive_message(&mut first).await.unwrap(),
+            MessageType::History(_)
+        ));
+
+        // After the first connection is closed, the user can log in again.
+        drop(first);
+        sleep(Duration::from_millis(100)).await;
+        let (_, auth_response) =
+            authenticate_on_unix_socket(&socket_path, "L", "alice", ReadReceipts::Disabled).await;
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(true, None, _, _, _, _)
+        ));
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_kicking_existing_session_on_duplicate_login() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_kick_duplicate.sock");
+        let (server_task, _) = spawn_unix_server(
+            &socket_path,
+            ChatServerOptions {
+                sessions: ActiveSessions::new(DuplicateLoginPolicy::KickExisting),
+                ..default_options().await
+            },
+        )
+        .await;
+        let mut first =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+
+        let (_, auth_response) =
+            authenticate_on_unix_socket(&socket_path, "L", "alice", ReadReceipts::Disabled).await;
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(true, None, _, _, _, _)
+        ));
+        // The first connection is told why and closed.
+        assert_eq!(
+            receive_message(&mut first).await.unwrap(),
+            MessageType::ServerError(
+                "You are disconnected, because you logged in elsewhere.".to_string()
+            )
+        );
+        assert!(receive_message(&mut first).await.is_err());
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_closing_connection_of_slow_client() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_slow_client.sock");
+        let (server_task, _) = spawn_unix_server(
+            &socket_path,
+            ChatServerOptions {
+                outgoing_queue_capacity: 1,
+                ..default_options().await
+            },
+        )
+        .await;
+        let mut alice =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+        // Bob never reads, so his queue overflows when the socket buffer is full.
+        let _bob = register_on_unix_socket(&socket_path, "bob", ReadReceipts::Disabled).await;
+        let file = MessageType::File {
+            name: "large.bin".to_string(),
+            mime: None,
+            data: vec![0; 512 * 1024],
+        };
+        for _ in 0..8 {
+            send_message(&mut alice, &file).await.unwrap();
+        }
+        sleep(Duration::from_millis(300)).await;
+
+        // The connection of bob is closed and cleaned up, so his session has ended and he can log in again.
+        let (_, auth_response) =
+            authenticate_on_unix_socket(&socket_path, "L", "bob", ReadReceipts::Disabled).await;
+        assert!(matches!(
+            auth_response,
+            MessageType::AuthResponse(true, None, _, _, _, _)
+        ));
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_disconnecting_banned_user() {
+        let socket_path = std::env::temp_dir().join("hello_rust_test_banned_user.sock");
+        let sessions = ActiveSessions::new(DuplicateLoginPolicy::Reject);
+        let (server_task, connection_pool) = spawn_unix_server(
+            &socket_path,
+            ChatServerOptions {
+                sessions: sessions.clone(),
+                ..default_options().await
+            },
+        )
+        .await;
+        let mut alice =
+            register_on_unix_socket(&socket_path, "alice", ReadReceipts::Disabled).await;
+        let (alice_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+
+        // This is what the http server does when it bans a user.
+        db::add_ban(&connection_pool, &alice_id, None)
+            .await
+            .unwrap();
+        assert!(sessions.kick(alice_id).await);
+
+        assert_eq!(
+            receive_message(&mut alice).await.unwrap(),
+            MessageType::ServerError("You are disconnected, because you were banned.".to_string())
+        );
+        assert!(receive_message(&mut alice).await.is_err());
+
+        server_task.abort();
+        let _ = server_task.await;
+    }
+
+    #[tokio::test]
+    async fn test_retrying_transient_accept_errors() {
+        let mut attempts = 0;
+        let accepted = retry_transient_accept_errors(|| {
+            attempts += 1;
+            let result = match attempts {
+                1 => Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+                #[cfg(unix)]
+                2 => Err(io::Error::from_raw_os_error(libc::EMFILE)),
+                _ => Ok(attempts),
+            };
+            async move { result.context("Failed to accept a new connection from a client.") }
+        })
+        .await
+        .unwrap();
+
+        let expected_attempts = if cfg!(unix) { 3 } else { 2 };
+        assert_eq!(accepted, expected_attempts);
+        assert_eq!(attempts, expected_attempts);
+    }
+
+    #[tokio::test]
+    async fn test_returning_fatal_accept_error() {
+        let mut attempts = 0;
+        let result: Result<()> = retry_transient_accept_errors(|| {
+            attempts += 1;
+            async {
+                Err(anyhow::Error::from(io::Error::from(
+                    io::ErrorKind::InvalidInput,
+                )))
+            }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
     }
 }
@@ -1,11 +1,56 @@
-use server::password_hashing::{hash_password, verify_password};
+use argon2::Params;
+use prometheus::proto::MetricFamily;
+use prometheus::TextEncoder;
+use server::auth::{authenticate, register_user};
+use server::clock::{Clock, FakeClock, SystemClock};
+use server::connections::{
+    broadcast_message, create_broadcast_failures, create_client_writers, create_shared_writer,
+    record_broadcast_failure, BroadcastFailure, FrameWriter, MAX_BROADCAST_FAILURES,
+};
+use server::db::{
+    self, AddMessageError, AddUserError, Backup, ImportError, ImportMode, ImportSummary,
+    MessageDto, MessageKind, MessageOrder, NewAttachment, RenameUserError, SetDisplayNameError,
+    UserActivityDto,
+};
+use server::duplicate_texts::DuplicateTexts;
+use server::export::{write_export, EXPORT_PAGE_SIZE};
+use server::file_store::{AllowedExtensions, FileStore};
+use server::http_server::{run_http_server, AdminToken, HttpState, InfoResponse, ServerStart};
+use server::login_attempts::{LockoutPolicy, LoginAttempts};
+use server::maintenance::{reconcile_active_connections_gauge, spawn_gauge_reconciliation_task};
+use server::message_writer::{BatchingPolicy, MessageWriter, OrderedSaves, SaveSlots};
+use server::metrics::{
+    create_registry, encode_metric_families, get_active_connections_gauge,
+    get_auth_attempts_counter, get_db_operation_durations_histogram, get_messages_counter,
+    register_metric,
+};
+use server::password_hashing::{hash_password, verify_password, PasswordHashingOptions};
+use server::sessions::{ActiveSessions, DuplicateLoginPolicy, SessionId};
+use server::word_filter::{Filter, FilterAction};
+use shared::{receive_message, AuthError, MessageType, PROTOCOL_VERSION};
+use sqlx::Row;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
 
+/// The admin token of http servers in tests whose requests go to admin endpoints.
+const ADMIN_TOKEN: &str = "admin token";
 
 #[tokio::test]
 async fn test_hashing_and_verifying_same_strings() {
     let test_password = "Po1Po2Ca+tE3pE4tL".to_string();
-    let test_password_hash = hash_password(&test_password).await.unwrap();
-    let verify_result = verify_password(&test_password, &test_password_hash).await;
+    let test_password_hash = hash_password(&test_password, &PasswordHashingOptions::default())
+        .await
+        .unwrap();
+    let verify_result = verify_password(
+        &test_password,
+        &test_password_hash,
+        &PasswordHashingOptions::default(),
+    )
+    .await;
     assert!(verify_result.is_ok());
 }
 
@@ -13,7 +58,3036 @@ async fn test_hashing_and_verifying_same_strings() {
 async fn test_hashing_and_verifying_different_strings() {
     let test_password = "Po1Po2Ca+tE3pE4tL".to_string();
     let different_password = "aCoMpLeTeLyDiFfErEnTpAsSwOrD".to_string();
-    let test_password_hash = hash_password(&test_password).await.unwrap();
-    let verify_result = verify_password(&different_password, &test_password_hash).await;
+    let test_password_hash = hash_password(&test_password, &PasswordHashingOptions::default())
+        .await
+        .unwrap();
+    let verify_result = verify_password(
+        &different_password,
+        &test_password_hash,
+        &PasswordHashingOptions::default(),
+    )
+    .await;
     assert!(verify_result.is_err());
 }
+
+#[tokio::test]
+async fn test_hashing_and_verifying_with_pepper() {
+    let test_password = "Po1Po2Ca+tE3pE4tL".to_string();
+    let options = PasswordHashingOptions {
+        pepper: Some("pepper".to_string()),
+        ..PasswordHashingOptions::default()
+    };
+    let different_options = PasswordHashingOptions {
+        pepper: Some("different pepper".to_string()),
+        ..PasswordHashingOptions::default()
+    };
+    let test_password_hash = hash_password(&test_password, &options).await.unwrap();
+
+    assert!(
+        verify_password(&test_password, &test_password_hash, &options)
+            .await
+            .is_ok()
+    );
+    assert!(
+        verify_password(&test_password, &test_password_hash, &different_options)
+            .await
+            .is_err()
+    );
+    assert!(verify_password(
+        &test_password,
+        &test_password_hash,
+        &PasswordHashingOptions::default()
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_hashing_and_verifying_with_non_default_params() {
+    let test_password = "Po1Po2Ca+tE3pE4tL".to_string();
+    let options = PasswordHashingOptions {
+        params: Params::new(8 * 1024, 3, 2, None).unwrap(),
+        ..PasswordHashingOptions::default()
+    };
+    let test_password_hash = hash_password(&test_password, &options).await.unwrap();
+
+    assert!(test_password_hash.contains("m=8192,t=3,p=2"));
+    // The params are taken from the hash, so verification works with any params.
+    assert!(
+        verify_password(&test_password, &test_password_hash, &options)
+            .await
+            .is_ok()
+    );
+    assert!(verify_password(
+        &test_password,
+        &test_password_hash,
+        &PasswordHashingOptions::default()
+    )
+    .await
+    .is_ok());
+}
+
+#[tokio::test]
+async fn test_creating_missing_database_file_and_directory() {
+    let dir = std::env::temp_dir().join("hello_rust_test_missing_db_dir");
+    let _ = std::fs::remove_dir_all(&dir);
+    let db_file = dir.join("nested").join("chat.db");
+
+    let without_dir = db::create_connection_pool(&db_file, false).await;
+    assert!(!dir.exists());
+    let with_dir = db::create_connection_pool(&db_file, true).await;
+    let db_file_exists = db_file.is_file();
+    let user_added = match &with_dir {
+        Ok(connection_pool) => db::add_user(connection_pool, "alice", "hash").await.is_ok(),
+        Err(_) => false,
+    };
+    if let Ok(connection_pool) = with_dir {
+        connection_pool.close().await;
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let error = format!("{:#}", without_dir.unwrap_err());
+    assert!(error.contains("does not exist"), "{}", error);
+    assert!(db_file_exists);
+    // The new database has all tables.
+    assert!(user_added);
+}
+
+#[tokio::test]
+async fn test_refusing_database_with_newer_schema() {
+    let dir = std::env::temp_dir().join("hello_rust_test_newer_schema");
+    let _ = std::fs::remove_dir_all(&dir);
+    let db_file = dir.join("chat.db");
+    let connection_pool = db::create_connection_pool(&db_file, true).await.unwrap();
+    assert!(db::check_schema_version(&connection_pool).await.is_ok());
+    // Pretend that a newer server applied one more migration.
+    sqlx::query(
+        "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (9999, 'from the future', TRUE, x'00', 0)",
+    )
+    .execute(&connection_pool)
+    .await
+    .unwrap();
+    connection_pool.close().await;
+
+    let reopened = db::create_connection_pool(&db_file, false).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let error = reopened.unwrap_err().to_string();
+    assert!(
+        error.starts_with(
+            "The database has schema version 9999, but this server supports at most version"
+        ),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn test_refusing_database_with_failed_migration() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    sqlx::query("UPDATE _sqlx_migrations SET success = FALSE WHERE version = 2")
+        .execute(&connection_pool)
+        .await
+        .unwrap();
+
+    let error = db::check_schema_version(&connection_pool)
+        .await
+        .unwrap_err()
+        .to_string();
+
+    assert!(error.starts_with("Migration 2 of the database failed before"));
+}
+
+#[tokio::test]
+async fn test_adding_and_getting_user() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let (returned_user_id, password_hash) = db::get_user(&connection_pool, "alice").await.unwrap();
+
+    assert_eq!(returned_user_id, user_id);
+    assert_eq!(password_hash, "hash");
+    assert!(db::get_user(&connection_pool, "bob").await.is_err());
+}
+
+#[tokio::test]
+async fn test_adding_and_getting_messages() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+
+    db::add_message(
+        &connection_pool,
+        &alice_id,
+        "Hello, Bob.",
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    db::add_message(
+        &connection_pool,
+        &bob_id,
+        "Hello, Alice.",
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    db::add_message(
+        &connection_pool,
+        &alice_id,
+        "How are you?",
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    let alice_messages = db::get_messages_by_user(&connection_pool, &alice_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let bob_messages = db::get_messages_by_user(&connection_pool, &bob_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        get_contents(&alice_messages),
+        vec!["Hello, Bob.", "How are you?"]
+    );
+    assert_eq!(get_contents(&bob_messages), vec!["Hello, Alice."]);
+    assert_eq!(alice_messages[0].kind, MessageKind::Text);
+}
+
+/// Get contents of messages in the same order.
+fn get_contents(messages: &[MessageDto]) -> Vec<&str> {
+    messages
+        .iter()
+        .map(|message| message.content.as_str())
+        .collect()
+}
+
+#[tokio::test]
+async fn test_saving_message_tags() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+
+    // Tags are saved both right away and in batches, and messages without a tag have none.
+    db::add_tagged_message(
+        &connection_pool,
+        &user_id,
+        "Tagged.",
+        MessageKind::Text,
+        Some(7),
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        "Not tagged.",
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    let (message_writer, handle) = MessageWriter::spawn(
+        connection_pool.clone(),
+        BatchingPolicy {
+            max_batch_size: 10,
+            max_delay: Duration::from_secs(60),
+        },
+    );
+    message_writer
+        .write(
+            user_id,
+            "Tagged in a batch.".to_string(),
+            MessageKind::Text,
+            Some(i64::MAX),
+            None,
+        )
+        .await
+        .unwrap();
+    message_writer
+        .shut_down(handle, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    let messages = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let tags: Vec<Option<i64>> = messages.iter().map(|message| message.tag).collect();
+    assert_eq!(tags, vec![Some(7), None, Some(i64::MAX)]);
+
+    // The tag is left out of the JSON of a message without one.
+    let json = serde_json::to_value(&messages).unwrap();
+    assert_eq!(json[0]["tag"], 7);
+    assert!(json[1].get("tag").is_none());
+}
+
+#[tokio::test]
+async fn test_ordering_messages_of_user() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    // The first message is saved with a later time than the others, e.g. because it was imported.
+    // The last two are sent in the same second, so they are ordered by their ids.
+    let later_clock = FakeClock::new(UNIX_EPOCH + Duration::from_secs(1_704_164_700));
+    let earlier_clock = FakeClock::new(UNIX_EPOCH + Duration::from_secs(1_704_164_645));
+    for (content, kind, clock) in [
+        ("Latest.", MessageKind::Text, &later_clock),
+        ("First.", MessageKind::Text, &earlier_clock),
+        ("SENT IMAGE", MessageKind::Image, &earlier_clock),
+        ("Second.", MessageKind::Text, &earlier_clock),
+    ] {
+        db::add_message(&connection_pool, &user_id, content, kind, clock)
+            .await
+            .unwrap();
+    }
+
+    let ascending = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let descending = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Desc)
+        .await
+        .unwrap();
+    assert_eq!(
+        get_contents(&ascending),
+        vec!["First.", "SENT IMAGE", "Second.", "Latest."]
+    );
+    assert_eq!(
+        get_contents(&descending),
+        vec!["Latest.", "Second.", "SENT IMAGE", "First."]
+    );
+
+    let ascending_texts = db::get_messages_by_kind(
+        &connection_pool,
+        &user_id,
+        MessageKind::Text,
+        MessageOrder::Asc,
+    )
+    .await
+    .unwrap();
+    let descending_texts = db::get_messages_by_kind(
+        &connection_pool,
+        &user_id,
+        MessageKind::Text,
+        MessageOrder::Desc,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_contents(&ascending_texts),
+        vec!["First.", "Second.", "Latest."]
+    );
+    assert_eq!(
+        get_contents(&descending_texts),
+        vec!["Latest.", "Second.", "First."]
+    );
+}
+
+#[tokio::test]
+async fn test_getting_messages_by_kind() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        "Hello.",
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        "SENT IMAGE",
+        MessageKind::Image,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        "FILE SENT: notes.txt",
+        MessageKind::File,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+
+    let text_messages = db::get_messages_by_kind(
+        &connection_pool,
+        &user_id,
+        MessageKind::Text,
+        MessageOrder::Asc,
+    )
+    .await
+    .unwrap();
+    let image_messages = db::get_messages_by_kind(
+        &connection_pool,
+        &user_id,
+        MessageKind::Image,
+        MessageOrder::Asc,
+    )
+    .await
+    .unwrap();
+    let file_messages = db::get_messages_by_kind(
+        &connection_pool,
+        &user_id,
+        MessageKind::File,
+        MessageOrder::Asc,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(get_contents(&text_messages), vec!["Hello."]);
+    assert_eq!(get_contents(&image_messages), vec!["SENT IMAGE"]);
+    assert_eq!(get_contents(&file_messages), vec!["FILE SENT: notes.txt"]);
+    assert_eq!(image_messages[0].kind, MessageKind::Image);
+}
+
+#[tokio::test]
+async fn test_getting_messages_after_cursor() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+    for i in 0..5 {
+        let user_id = if i % 2 == 0 { alice_id } else { bob_id };
+        db::add_message(
+            &connection_pool,
+            &user_id,
+            &format!("Message {}", i),
+            MessageKind::Text,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+    }
+
+    // Pages follow each other by their cursors until the last one, which has no next cursor.
+    for (order, expected_pages) in [
+        (MessageOrder::Asc, [vec![1, 2], vec![3, 4], vec![5]]),
+        (MessageOrder::Desc, [vec![5, 4], vec![3, 2], vec![1]]),
+    ] {
+        let mut cursor = None;
+        for (index, expected_ids) in expected_pages.iter().enumerate() {
+            let page = db::get_messages_page(&connection_pool, cursor, 2, order)
+                .await
+                .unwrap();
+            let ids: Vec<i64> = page.messages.iter().map(|message| message.id).collect();
+            assert_eq!(&ids, expected_ids);
+            if index < expected_pages.len() - 1 {
+                assert_eq!(page.next_cursor, ids.last().copied());
+            } else {
+                assert_eq!(page.next_cursor, None);
+            }
+            cursor = page.next_cursor;
+        }
+    }
+
+    // A page that ends exactly with the last message has no next cursor either.
+    let page = db::get_messages_page(&connection_pool, Some(3), 2, MessageOrder::Asc)
+        .await
+        .unwrap();
+    assert_eq!(page.messages.len(), 2);
+    assert_eq!(page.messages[1].content, "Message 4");
+    assert_eq!(page.next_cursor, None);
+}
+
+#[tokio::test]
+async fn test_adding_too_long_username_and_content() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let longest_username = "a".repeat(db::MAX_USERNAME_LENGTH);
+    let too_long_username = "b".repeat(db::MAX_USERNAME_LENGTH + 1);
+
+    let user_id = db::add_user(&connection_pool, &longest_username, "hash")
+        .await
+        .unwrap();
+    assert!(matches!(
+        db::add_user(&connection_pool, &too_long_username, "hash").await,
+        Err(AddUserError::NameTooLong)
+    ));
+    assert!(matches!(
+        db::add_user(&connection_pool, &longest_username, "hash").await,
+        Err(AddUserError::NameTaken(_))
+    ));
+    assert!(matches!(
+        db::rename_user(&connection_pool, &user_id, &too_long_username).await,
+        Err(RenameUserError::NameTooLong)
+    ));
+
+    // The length is in characters, not in bytes.
+    let longest_content = "é".repeat(db::MAX_CONTENT_LENGTH);
+    let too_long_content = "a".repeat(db::MAX_CONTENT_LENGTH + 1);
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        &longest_content,
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        db::add_message(
+            &connection_pool,
+            &user_id,
+            &too_long_content,
+            MessageKind::Text,
+            &SystemClock,
+        )
+        .await,
+        Err(AddMessageError::ContentTooLong)
+    ));
+}
+
+#[tokio::test]
+async fn test_vacuuming_database() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let content = "a".repeat(db::MAX_CONTENT_LENGTH);
+
+    // The first vacuum is a full one, the second one is incremental.
+    for _ in 0..2 {
+        let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+        for _ in 0..100 {
+            db::add_message(
+                &connection_pool,
+                &bob_id,
+                &content,
+                MessageKind::Text,
+                &SystemClock,
+            )
+            .await
+            .unwrap();
+        }
+        db::delete_user(&connection_pool, &bob_id).await.unwrap();
+        assert!(get_free_pages(&connection_pool).await > 0);
+
+        db::vacuum(&connection_pool).await.unwrap();
+
+        assert_eq!(get_free_pages(&connection_pool).await, 0);
+    }
+    let auto_vacuum: i64 = sqlx::query_scalar("PRAGMA auto_vacuum")
+        .fetch_one(&connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(auto_vacuum, 2);
+    assert_eq!(
+        db::get_user(&connection_pool, "alice").await.unwrap().0,
+        alice_id
+    );
+}
+
+#[tokio::test]
+async fn test_deleting_user_with_messages() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+    for user_id in [alice_id, bob_id] {
+        db::add_message(
+            &connection_pool,
+            &user_id,
+            "Hello.",
+            MessageKind::Text,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+    }
+
+    db::delete_user(&connection_pool, &bob_id).await.unwrap();
+
+    assert!(db::get_user(&connection_pool, "bob").await.is_err());
+    let user_ids: Vec<i64> = sqlx::query_scalar("SELECT user_id FROM messages")
+        .fetch_all(&connection_pool)
+        .await
+        .unwrap();
+    assert_eq!(user_ids, vec![alice_id]);
+}
+
+#[tokio::test]
+async fn test_rolling_back_failed_transaction() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+
+    // The first insert succeeds, but the second one fails, so neither of them is kept.
+    let result: anyhow::Result<()> = db::with_transaction(&connection_pool, |transaction| {
+        Box::pin(async move {
+            sqlx::query("INSERT INTO users (username, password_hash) VALUES ('alice', 'hash')")
+                .execute(&mut **transaction)
+                .await?;
+            sqlx::query("INSERT INTO users (username, password_hash) VALUES ('alice', 'hash')")
+                .execute(&mut **transaction)
+                .await?;
+            Ok(())
+        })
+    })
+    .await;
+    assert!(result.is_err());
+    assert!(db::get_user(&connection_pool, "alice").await.is_err());
+
+    // A closure that fails on its own rolls back too, and its error is returned as it is.
+    let result: Result<(), sqlx::Error> = db::with_transaction(&connection_pool, |transaction| {
+        Box::pin(async move {
+            sqlx::query("INSERT INTO users (username, password_hash) VALUES ('bob', 'hash')")
+                .execute(&mut **transaction)
+                .await?;
+            Err(sqlx::Error::RowNotFound)
+        })
+    })
+    .await;
+    assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+    assert!(db::get_user(&connection_pool, "bob").await.is_err());
+
+    // A successful closure is committed and its value is returned.
+    let user_id: i64 = db::with_transaction(&connection_pool, |transaction| {
+        Box::pin(async move {
+            sqlx::query_scalar(
+                "INSERT INTO users (username, password_hash) VALUES ('carol', 'hash') RETURNING id",
+            )
+            .fetch_one(&mut **transaction)
+            .await
+        })
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        db::get_user(&connection_pool, "carol").await.unwrap().0,
+        user_id
+    );
+}
+
+async fn get_free_pages(connection_pool: &sqlx::SqlitePool) -> i64 {
+    sqlx::query_scalar("PRAGMA freelist_count")
+        .fetch_one(connection_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_messages_by_user_found_by_index() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    for username in ["alice", "bob", "carol"] {
+        let user_id = db::add_user(&connection_pool, username, "hash")
+            .await
+            .unwrap();
+        for i in 0..50 {
+            db::add_message(
+                &connection_pool,
+                &user_id,
+                &format!("Message {}.", i),
+                MessageKind::Text,
+                &SystemClock,
+            )
+            .await
+            .unwrap();
+        }
+    }
+    sqlx::query("ANALYZE")
+        .execute(&connection_pool)
+        .await
+        .unwrap();
+
+    // The same filters as in get_messages_by_user and delete_messages_by_user.
+    for query in [
+        "EXPLAIN QUERY PLAN SELECT id, content FROM messages WHERE user_id = 2",
+        "EXPLAIN QUERY PLAN DELETE FROM messages WHERE user_id = 2",
+    ] {
+        let details: Vec<String> = sqlx::query(query)
+            .fetch_all(&connection_pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| row.get("detail"))
+            .collect();
+        assert!(
+            details
+                .iter()
+                .any(|detail| detail.contains("INDEX idx_messages_user_id")),
+            "{:?}",
+            details
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_banning_user_permanently() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let clock = FakeClock::new(SystemTime::now());
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+
+    db::add_ban(&connection_pool, &alice_id, None)
+        .await
+        .unwrap();
+    clock.advance(Duration::from_secs(10 * 365 * 24 * 60 * 60));
+
+    assert!(db::is_banned(&connection_pool, &alice_id, &clock)
+        .await
+        .unwrap());
+    assert!(!db::is_banned(&connection_pool, &bob_id, &clock)
+        .await
+        .unwrap());
+    assert!(db::remove_ban(&connection_pool, &alice_id).await.unwrap());
+    assert!(!db::remove_ban(&connection_pool, &alice_id).await.unwrap());
+    assert!(!db::is_banned(&connection_pool, &alice_id, &clock)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn test_banning_user_temporarily() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let clock = FakeClock::new(SystemTime::now());
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+
+    db::add_ban(
+        &connection_pool,
+        &alice_id,
+        Some(clock.now() + Duration::from_secs(60)),
+    )
+    .await
+    .unwrap();
+    let banned_before_expiry = db::is_banned(&connection_pool, &alice_id, &clock)
+        .await
+        .unwrap();
+    clock.advance(Duration::from_secs(61));
+    let banned_after_expiry = db::is_banned(&connection_pool, &alice_id, &clock)
+        .await
+        .unwrap();
+
+    assert!(banned_before_expiry);
+    assert!(!banned_after_expiry);
+}
+
+#[tokio::test]
+async fn test_keeping_ban_of_renamed_user() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    db::add_ban(&connection_pool, &alice_id, None)
+        .await
+        .unwrap();
+
+    // The ban stays with the user, not with the username.
+    db::rename_user(&connection_pool, &alice_id, "alicia")
+        .await
+        .unwrap();
+    let new_alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    assert!(db::is_banned(&connection_pool, &alice_id, &SystemClock)
+        .await
+        .unwrap());
+    assert!(
+        !db::is_banned(&connection_pool, &new_alice_id, &SystemClock)
+            .await
+            .unwrap()
+    );
+
+    // The ban is removed together with the user.
+    db::delete_user(&connection_pool, &alice_id).await.unwrap();
+    let remaining_bans: i64 = sqlx::query("SELECT COUNT(*) FROM banned_users")
+        .fetch_one(&connection_pool)
+        .await
+        .unwrap()
+        .get(0);
+    assert_eq!(remaining_bans, 0);
+}
+
+#[tokio::test]
+async fn test_renaming_user() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+
+    db::rename_user(&connection_pool, &user_id, "bob")
+        .await
+        .unwrap();
+
+    let (renamed_user_id, _) = db::get_user(&connection_pool, "bob").await.unwrap();
+    assert_eq!(renamed_user_id, user_id);
+    assert!(db::get_user(&connection_pool, "alice").await.is_err());
+}
+
+#[tokio::test]
+async fn test_renaming_user_to_taken_name() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+
+    let rename_result = db::rename_user(&connection_pool, &user_id, "bob").await;
+
+    assert!(matches!(rename_result, Err(RenameUserError::NameTaken(name)) if name == "bob"));
+    let (alice_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+    assert_eq!(alice_id, user_id);
+}
+
+#[tokio::test]
+async fn test_setting_display_name() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+
+    // Without a display name, the username is used.
+    assert_eq!(
+        db::get_display_name(&connection_pool, &user_id)
+            .await
+            .unwrap(),
+        "alice"
+    );
+
+    // Nobody can pose as another user, neither by the username nor by the display name.
+    for taken in ["bob", "BOB"] {
+        assert!(matches!(
+            db::set_display_name(&connection_pool, &user_id, taken).await,
+            Err(SetDisplayNameError::NameTaken(_))
+        ));
+    }
+    db::set_display_name(&connection_pool, &bob_id, "Builder")
+        .await
+        .unwrap();
+    assert!(matches!(
+        db::set_display_name(&connection_pool, &user_id, "builder").await,
+        Err(SetDisplayNameError::NameTaken(_))
+    ));
+    // The own username can be the display name.
+    db::set_display_name(&connection_pool, &user_id, "Alice")
+        .await
+        .unwrap();
+    db::set_display_name(&connection_pool, &user_id, "Ally-Cat 2.0")
+        .await
+        .unwrap();
+    assert_eq!(
+        db::get_display_name(&connection_pool, &user_id)
+            .await
+            .unwrap(),
+        "Ally-Cat 2.0"
+    );
+    // The username is still used for logging in.
+    let (alice_id, _) = db::get_user(&connection_pool, "alice").await.unwrap();
+    assert_eq!(alice_id, user_id);
+
+    for invalid in ["", " alice", "alice ", "al\tice", "<alice>"] {
+        assert!(matches!(
+            db::set_display_name(&connection_pool, &user_id, invalid).await,
+            Err(SetDisplayNameError::InvalidCharacters)
+        ));
+    }
+    // The length is in characters, not in bytes.
+    let longest = "é".repeat(db::MAX_DISPLAY_NAME_LENGTH);
+    db::set_display_name(&connection_pool, &user_id, &longest)
+        .await
+        .unwrap();
+    let too_long = "a".repeat(db::MAX_DISPLAY_NAME_LENGTH + 1);
+    assert!(matches!(
+        db::set_display_name(&connection_pool, &user_id, &too_long).await,
+        Err(SetDisplayNameError::NameTooLong)
+    ));
+    assert!(matches!(
+        db::set_display_name(&connection_pool, &(user_id + 100), "nobody").await,
+        Err(SetDisplayNameError::UserNotFound(_))
+    ));
+}
+
+#[test]
+fn test_masking_banned_words() {
+    let filter = Filter::new(["darn", "heck"], FilterAction::Mask).unwrap();
+
+    assert_eq!(
+        filter.sanitize("Darn, what the HECK!"),
+        ("####, what the ####!".to_string(), true)
+    );
+    // Only whole words are matched, so longer words containing a banned word are kept.
+    assert_eq!(
+        filter.sanitize("darned heckler"),
+        ("darned heckler".to_string(), false)
+    );
+    // Words end at any character that is not a letter or a digit.
+    assert_eq!(
+        filter.sanitize("darn_heck-darn2 darn"),
+        ("####_####-darn2 ####".to_string(), true)
+    );
+    assert_eq!(filter.sanitize(""), (String::new(), false));
+    assert_eq!(filter.action(), FilterAction::Mask);
+}
+
+#[test]
+fn test_creating_word_filter() {
+    let filter = Filter::new(["Čert"], FilterAction::Reject).unwrap();
+    assert_eq!(
+        filter.sanitize("čert vem to"),
+        ("#### vem to".to_string(), true)
+    );
+    assert_eq!(filter.action(), FilterAction::Reject);
+
+    assert!(Filter::new(["two words"], FilterAction::Mask).is_err());
+    assert!(Filter::new([""], FilterAction::Mask).is_err());
+    assert_eq!(
+        FilterAction::from_name("reject").unwrap(),
+        FilterAction::Reject
+    );
+    assert!(FilterAction::from_name("drop").is_err());
+}
+
+#[tokio::test]
+async fn test_loading_word_filter_from_file() {
+    let path = std::env::temp_dir().join("hello_rust_test_word_filter.txt");
+    tokio::fs::write(&path, "# Banned words\n\ndarn\n  heck  \n")
+        .await
+        .unwrap();
+
+    let filter = Filter::from_file(&path, FilterAction::Mask).await.unwrap();
+    assert_eq!(
+        filter.sanitize("darn heck # ok"),
+        ("#### #### # ok".to_string(), true)
+    );
+
+    tokio::fs::remove_file(&path).await.unwrap();
+    assert!(Filter::from_file(&path, FilterAction::Mask).await.is_err());
+}
+
+#[tokio::test]
+async fn test_broadcasting_announcement() {
+    // Prepare a connection whose server side writer is in the registry like a connected client.
+    let server_socket_address = "127.0.0.1:44444";
+    let server_listener = TcpListener::bind(server_socket_address).await.unwrap();
+    let client_stream = TcpStream::connect(server_socket_address).await.unwrap();
+    let (server_stream, client_address) = server_listener.accept().await.unwrap();
+    let (_, writer) = server_stream.into_split();
+    let (mut client_reader, _) = client_stream.into_split();
+    let client_writers = create_client_writers();
+    let broadcast_failures = create_broadcast_failures();
+    client_writers
+        .lock()
+        .await
+        .insert(client_address.into(), create_shared_writer(writer));
+
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+
+    let announcement = MessageType::Text("Server restarts in 5 minutes.".to_string());
+    let clients_reached = broadcast_message(
+        &client_writers,
+        &broadcast_failures,
+        &active_connections_gauge,
+        &announcement,
+        None,
+    )
+    .await;
+    let clients_reached_with_exclusion = broadcast_message(
+        &client_writers,
+        &broadcast_failures,
+        &active_connections_gauge,
+        &announcement,
+        Some(&client_address.into()),
+    )
+    .await;
+
+    assert_eq!(clients_reached, 1);
+    assert_eq!(clients_reached_with_exclusion, 0);
+    assert_eq!(
+        receive_message(&mut client_reader).await.unwrap(),
+        announcement
+    );
+}
+
+#[tokio::test]
+async fn test_encoding_metrics_without_a_broken_family() {
+    let messages_counter = get_messages_counter().await.unwrap();
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+    let auth_attempts_counter = get_auth_attempts_counter().await.unwrap();
+    let db_operation_durations = get_db_operation_durations_histogram().await.unwrap();
+    let registry = create_registry(
+        &messages_counter,
+        &active_connections_gauge,
+        &auth_attempts_counter,
+        &db_operation_durations,
+    )
+    .unwrap();
+    messages_counter.inc();
+    // A family without any metric cannot be encoded.
+    let mut broken_family = MetricFamily::default();
+    broken_family.set_name("broken_metric".to_string());
+    let mut metric_families = registry.gather();
+    metric_families.insert(0, broken_family);
+
+    let (buffer, failures) = encode_metric_families(&TextEncoder::new(), &metric_families);
+
+    let encoded = String::from_utf8(buffer).unwrap();
+    assert!(encoded.contains("messages_counter 1"));
+    assert!(encoded.contains("active_connections_gauge 0"));
+    assert!(!encoded.contains("broken_metric"));
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0]
+        .to_string()
+        .starts_with("Failed to encode metric 'broken_metric':"));
+}
+
+#[tokio::test]
+async fn test_registering_metric_twice() {
+    let messages_counter = get_messages_counter().await.unwrap();
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+    let auth_attempts_counter = get_auth_attempts_counter().await.unwrap();
+    let db_operation_durations = get_db_operation_durations_histogram().await.unwrap();
+    let registry = create_registry(
+        &messages_counter,
+        &active_connections_gauge,
+        &auth_attempts_counter,
+        &db_operation_durations,
+    )
+    .unwrap();
+
+    let register_result = register_metric(&registry, &active_connections_gauge);
+
+    let error = register_result.unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Metric 'active_connections_gauge' is already registered."
+    );
+    // The metric registered first is still collected.
+    assert_eq!(registry.gather().len(), 2);
+}
+
+#[tokio::test]
+async fn test_overriding_static_files() {
+    let http_socket_address = "127.0.0.1:44458";
+    let base_dir = std::env::temp_dir().join("hello_rust_test_overriding_static_files_base");
+    let theme_dir = std::env::temp_dir().join("hello_rust_test_overriding_static_files_theme");
+    std::fs::create_dir_all(&base_dir).unwrap();
+    std::fs::create_dir_all(theme_dir.join("api")).unwrap();
+    std::fs::write(base_dir.join("index.html"), "base page").unwrap();
+    std::fs::write(base_dir.join("style.css"), "base style").unwrap();
+    std::fs::write(theme_dir.join("index.html"), "theme page").unwrap();
+    // A file with the path of an API route does not hide the route.
+    std::fs::write(theme_dir.join("api").join("users"), "not users").unwrap();
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let static_dirs = [
+        theme_dir.to_str().unwrap().to_string(),
+        base_dir.to_str().unwrap().to_string(),
+    ];
+    tokio::spawn(async move {
+        let static_dirs: Vec<&str> = static_dirs.iter().map(String::as_str).collect();
+        run_http_server(
+            http_socket_address,
+            &static_dirs,
+            HttpState::new(connection_pool),
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let index = String::from_utf8(get_http_response(http_socket_address, "/").await).unwrap();
+    let style =
+        String::from_utf8(get_http_response(http_socket_address, "/style.css").await).unwrap();
+    let missing = get_http_response(http_socket_address, "/missing.css").await;
+    let users =
+        String::from_utf8(get_http_response(http_socket_address, "/api/users").await).unwrap();
+    std::fs::remove_dir_all(&base_dir).unwrap();
+    std::fs::remove_dir_all(&theme_dir).unwrap();
+
+    assert!(index.starts_with("HTTP/1.1 200 OK"));
+    assert!(index.ends_with("theme page"));
+    assert!(style.ends_with("base style"));
+    assert!(missing.starts_with(b"HTTP/1.1 404 Not Found"));
+    assert!(users.ends_with("[]"));
+}
+
+#[tokio::test]
+async fn test_running_http_server_on_invalid_address() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+
+    let run_result = run_http_server(
+        "localhost:eighty",
+        &["static"],
+        HttpState::new(connection_pool),
+    )
+    .await;
+
+    let error = run_result.unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "'localhost:eighty' is not a valid socket address. Use an IP address and a port, e.g. 127.0.0.1:11111 or [::1]:11111."
+    );
+}
+
+#[tokio::test]
+async fn test_running_http_server_on_used_port() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let http_socket_address = "127.0.0.1:44445";
+    let _listener = TcpListener::bind(http_socket_address).await.unwrap();
+
+    let run_result = run_http_server(
+        http_socket_address,
+        &["static"],
+        HttpState::new(connection_pool),
+    )
+    .await;
+
+    let error = run_result.unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Failed to bind http server to 127.0.0.1:44445."
+    );
+}
+
+#[tokio::test]
+async fn test_recording_failed_broadcast() {
+    // Prepare a connection whose writer is already shut down, so sending to it fails.
+    let server_socket_address = "127.0.0.1:44446";
+    let server_listener = TcpListener::bind(server_socket_address).await.unwrap();
+    let _client_stream = TcpStream::connect(server_socket_address).await.unwrap();
+    let (server_stream, client_address) = server_listener.accept().await.unwrap();
+    let (_, mut writer) = server_stream.into_split();
+    writer.shutdown().await.unwrap();
+    let client_writers = create_client_writers();
+    let broadcast_failures = create_broadcast_failures();
+    client_writers
+        .lock()
+        .await
+        .insert(client_address.into(), create_shared_writer(writer));
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+    active_connections_gauge.inc();
+
+    // The first message is only queued. Writing it fails afterwards and the client is disconnected.
+    let message = MessageType::Text("Hello.".to_string());
+    broadcast_message(
+        &client_writers,
+        &broadcast_failures,
+        &active_connections_gauge,
+        &message,
+        None,
+    )
+    .await;
+    sleep(Duration::from_millis(100)).await;
+    let clients_reached = broadcast_message(
+        &client_writers,
+        &broadcast_failures,
+        &active_connections_gauge,
+        &message,
+        None,
+    )
+    .await;
+
+    assert_eq!(clients_reached, 0);
+    // The removed client is not counted as an active connection anymore.
+    assert!(client_writers.lock().await.is_empty());
+    assert_eq!(active_connections_gauge.get(), 0.0);
+    let lock = broadcast_failures.lock().await;
+    assert_eq!(lock.len(), 1);
+    assert_eq!(lock[0].peer_address, client_address.to_string());
+    assert_eq!(lock[0].message_summary, "Text (6 bytes)");
+}
+
+#[tokio::test]
+async fn test_dropping_stalled_client() {
+    // The stalled client never reads, so only a few bytes can be written to it.
+    let (stalled_writer, _stalled_reader) = duplex(64);
+    let (healthy_writer, mut healthy_reader) = duplex(64);
+    let stalled_address = "127.0.0.1:1".parse::<std::net::SocketAddr>().unwrap();
+    let healthy_address = "127.0.0.1:2".parse::<std::net::SocketAddr>().unwrap();
+    let client_writers = create_client_writers();
+    let broadcast_failures = create_broadcast_failures();
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+    {
+        let mut lock = client_writers.lock().await;
+        lock.insert(
+            stalled_address.into(),
+            Arc::new(Mutex::new(FrameWriter::new(Box::new(stalled_writer), 2))),
+        );
+        lock.insert(
+            healthy_address.into(),
+            Arc::new(Mutex::new(FrameWriter::new(Box::new(healthy_writer), 2))),
+        );
+    }
+    active_connections_gauge.set(2.0);
+
+    // The healthy client reads all messages, while the stalled one does not read at all.
+    let messages_count = 10;
+    let healthy_client = tokio::spawn(async move {
+        let mut messages = Vec::new();
+        for _ in 0..messages_count {
+            messages.push(receive_message(&mut healthy_reader).await.unwrap());
+        }
+        messages
+    });
+    let message = MessageType::Text("Hello.".repeat(100));
+    for _ in 0..messages_count {
+        timeout(
+            Duration::from_secs(1),
+            broadcast_message(
+                &client_writers,
+                &broadcast_failures,
+                &active_connections_gauge,
+                &message,
+                None,
+            ),
+        )
+        .await
+        .unwrap();
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    let messages = timeout(Duration::from_secs(1), healthy_client)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(messages.iter().all(|received| *received == message));
+    let lock = client_writers.lock().await;
+    assert_eq!(lock.len(), 1);
+    assert!(lock.contains_key(&healthy_address.into()));
+    assert_eq!(active_connections_gauge.get(), 1.0);
+    let lock = broadcast_failures.lock().await;
+    assert_eq!(lock.len(), 1);
+    assert_eq!(lock[0].peer_address, stalled_address.to_string());
+    assert!(lock[0].error.contains("outgoing queue is full"));
+}
+
+#[tokio::test]
+async fn test_broadcast_failures_are_bounded() {
+    let broadcast_failures = create_broadcast_failures();
+
+    for i in 0..MAX_BROADCAST_FAILURES + 5 {
+        let failure = BroadcastFailure {
+            peer_address: format!("127.0.0.1:{}", i),
+            message_summary: "Text (6 bytes)".to_string(),
+            error: "Failed when sending bytes.".to_string(),
+            failed_at: 0,
+        };
+        record_broadcast_failure(&broadcast_failures, failure).await;
+    }
+
+    let lock = broadcast_failures.lock().await;
+    assert_eq!(lock.len(), MAX_BROADCAST_FAILURES);
+    assert_eq!(lock[0].peer_address, "127.0.0.1:5");
+}
+
+#[tokio::test]
+async fn test_storing_file() {
+    let directory = std::env::temp_dir().join("hello_rust_test_storing_file");
+    let file_store = FileStore::new(directory.to_str().unwrap(), 10)
+        .await
+        .unwrap();
+
+    let path = file_store
+        .store("notes.txt", b"Hello.")
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(path.parent().unwrap(), directory);
+    assert_eq!(path.extension().unwrap(), "txt");
+    assert_ne!(path.file_name().unwrap(), "notes.txt");
+    assert_eq!(std::fs::read(&path).unwrap(), b"Hello.");
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_storing_too_large_file() {
+    let directory = std::env::temp_dir().join("hello_rust_test_storing_too_large_file");
+    let file_store = FileStore::new(directory.to_str().unwrap(), 10)
+        .await
+        .unwrap();
+
+    let path = file_store
+        .store("notes.txt", b"Hello, this is too long.")
+        .await
+        .unwrap();
+
+    assert!(path.is_none());
+    assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Send a GET request to an http server and return the whole response.
+async fn get_http_response(http_socket_address: &str, path: &str) -> Vec<u8> {
+    let mut stream = TcpStream::connect(http_socket_address).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    response
+}
+
+#[test]
+fn test_allowing_file_extensions() {
+    let allowed_extensions = AllowedExtensions::parse("txt, .PDF,,png").unwrap();
+
+    assert!(allowed_extensions.allows("notes.txt"));
+    assert!(allowed_extensions.allows("Report.pdf"));
+    assert!(allowed_extensions.allows("photo.PNG"));
+    assert!(allowed_extensions.allows("archive.tar.txt"));
+    assert!(!allowed_extensions.allows("setup.exe"));
+    assert!(!allowed_extensions.allows("notes.txt.exe"));
+    assert!(!allowed_extensions.allows("Makefile"));
+    assert!(!allowed_extensions.allows(".txt"));
+}
+
+#[test]
+fn test_parsing_empty_list_of_file_extensions() {
+    assert!(AllowedExtensions::parse("").is_err());
+    assert!(AllowedExtensions::parse(" , .").is_err());
+}
+
+#[tokio::test]
+async fn test_downloading_stored_attachment() {
+    let http_socket_address = "127.0.0.1:44447";
+    let directory = std::env::temp_dir().join("hello_rust_test_downloading_stored_attachment");
+    let file_store = FileStore::new(directory.to_str().unwrap(), 1024)
+        .await
+        .unwrap();
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+
+    // Store a file the same way the chat server does.
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let message_id = db::add_message(
+        &connection_pool,
+        &user_id,
+        "FILE SENT: my notes.txt",
+        MessageKind::File,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    let path = file_store
+        .store("my notes.txt", b"Hello.")
+        .await
+        .unwrap()
+        .unwrap();
+    let attachment_id = db::add_attachment(
+        &connection_pool,
+        &message_id,
+        "my notes.txt",
+        path.to_str().unwrap(),
+        "text/plain",
+    )
+    .await
+    .unwrap();
+
+    let connection_pool_cloned = connection_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState::new(connection_pool_cloned),
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = get_http_response(
+        http_socket_address,
+        &format!("/api/attachments/{}", attachment_id),
+    )
+    .await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("content-type: text/plain"));
+    assert!(response.contains(
+        "content-disposition: attachment; filename=\"my notes.txt\"; filename*=UTF-8''my%20notes%2Etxt"
+    ));
+    assert!(response.contains("Hello."));
+
+    let response = get_http_response(
+        http_socket_address,
+        &format!("/api/attachments/{}", attachment_id + 1),
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 404 Not Found"));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn test_getting_users_and_messages_as_json() {
+    let http_socket_address = "127.0.0.1:44449";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    // 2024-01-02 03:04:05 UTC
+    let clock = FakeClock::new(UNIX_EPOCH + Duration::from_secs(1_704_164_645));
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        "Hello.",
+        MessageKind::Text,
+        &clock,
+    )
+    .await
+    .unwrap();
+
+    let connection_pool_cloned = connection_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState::new(connection_pool_cloned),
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = get_http_response(http_socket_address, "/api/users").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with(r#"[{"id":1,"username":"alice"}]"#));
+
+    let response = get_http_response(http_socket_address, "/api/users/1/messages").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with(
+        r#"[{"id":1,"content":"Hello.","kind":"text","sent_at":"2024-01-02 03:04:05"}]"#
+    ));
+}
+
+#[tokio::test]
+async fn test_ordering_messages_in_http_response() {
+    let http_socket_address = "127.0.0.1:44455";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let clock = FakeClock::new(UNIX_EPOCH + Duration::from_secs(1_704_164_645));
+    for content in ["First.", "Second."] {
+        db::add_message(
+            &connection_pool,
+            &user_id,
+            content,
+            MessageKind::Text,
+            &clock,
+        )
+        .await
+        .unwrap();
+        clock.advance(Duration::from_secs(1));
+    }
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState::new(connection_pool),
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let first = r#"{"id":1,"content":"First.","kind":"text","sent_at":"2024-01-02 03:04:05"}"#;
+    let second = r#"{"id":2,"content":"Second.","kind":"text","sent_at":"2024-01-02 03:04:06"}"#;
+    // The newest message is first by default.
+    for path in ["/api/users/1/messages", "/api/users/1/messages?order=desc"] {
+        let response = get_http_response(http_socket_address, path).await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.ends_with(&format!("[{},{}]", second, first)));
+    }
+    let response = get_http_response(
+        http_socket_address,
+        "/api/users/1/messages?order=asc&kind=text",
+    )
+    .await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.ends_with(&format!("[{},{}]", first, second)));
+
+    let response =
+        get_http_response(http_socket_address, "/api/users/1/messages?order=random").await;
+    assert!(response.starts_with(b"HTTP/1.1 400 Bad Request"));
+}
+
+#[tokio::test]
+async fn test_unknown_api_path_returns_json_404() {
+    let http_socket_address = "127.0.0.1:44450";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState::new(connection_pool),
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = get_http_response(http_socket_address, "/api/unknown").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    assert!(response.contains("content-type: application/json"));
+    assert!(response.ends_with(r#"{"error":"Unknown API path /api/unknown."}"#));
+
+    // Known routes are still matched before the catch-all route.
+    let response = get_http_response(http_socket_address, "/api/users").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+}
+
+async fn post_json_http_request(http_socket_address: &str, path: &str, body: &str) -> Vec<u8> {
+    send_json_http_request(http_socket_address, "POST", path, "", body).await
+}
+
+/// Send a POST request with a JSON body and the admin token, as an administrator does.
+async fn post_admin_json_http_request(
+    http_socket_address: &str,
+    path: &str,
+    body: &str,
+) -> Vec<u8> {
+    let authorization = format!("Authorization: Bearer {}\r\n", ADMIN_TOKEN);
+    send_json_http_request(http_socket_address, "POST", path, &authorization, body).await
+}
+
+/// Send a GET request with the admin token and return the whole response.
+async fn get_admin_http_response(http_socket_address: &str, path: &str) -> Vec<u8> {
+    let authorization = format!("Authorization: Bearer {}\r\n", ADMIN_TOKEN);
+    send_json_http_request(http_socket_address, "GET", path, &authorization, "").await
+}
+
+/// Send a request with a JSON body and extra header lines, each ending with "\r\n", and return the whole response.
+async fn send_json_http_request(
+    http_socket_address: &str,
+    method: &str,
+    path: &str,
+    headers: &str,
+    body: &str,
+) -> Vec<u8> {
+    let mut stream = TcpStream::connect(http_socket_address).await.unwrap();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        headers,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_resetting_password() {
+    let http_socket_address = "127.0.0.1:44451";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let options = PasswordHashingOptions::default();
+    let old_password_hash = hash_password("old password", &options).await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", &old_password_hash)
+        .await
+        .unwrap();
+    let connection_pool_cloned = connection_pool.clone();
+    let options_cloned = options.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                password_hashing: options_cloned,
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let path = format!("/api/users/{}/reset-password", user_id);
+    let response = post_admin_json_http_request(
+        http_socket_address,
+        &path,
+        r#"{"new_password":"new password"}"#,
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    // Only the new password can be used for logging in.
+    let (_, password_hash) = db::get_user(&connection_pool, "alice").await.unwrap();
+    assert!(verify_password("new password", &password_hash, &options)
+        .await
+        .is_ok());
+    assert!(verify_password("old password", &password_hash, &options)
+        .await
+        .is_err());
+
+    let response =
+        post_admin_json_http_request(http_socket_address, &path, r#"{"new_password":""}"#).await;
+    assert!(response.starts_with(b"HTTP/1.1 400 Bad Request"));
+    let response = post_admin_json_http_request(
+        http_socket_address,
+        &format!("/api/users/{}/reset-password", user_id + 1),
+        r#"{"new_password":"new password"}"#,
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 404 Not Found"));
+}
+
+#[tokio::test]
+async fn test_refusing_account_changes_without_admin_token() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let path = format!("/api/users/{}/reset-password", user_id);
+    let body = r#"{"new_password":"new password"}"#;
+
+    // With a token, requests without it or with another one are refused.
+    let http_socket_address = "127.0.0.1:44465";
+    let connection_pool_cloned = connection_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+    let response = post_json_http_request(http_socket_address, &path, body).await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+    let response = send_json_http_request(
+        http_socket_address,
+        "POST",
+        &path,
+        "Authorization: Bearer admin tokem\r\n",
+        body,
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+    let response = send_json_http_request(
+        http_socket_address,
+        "PATCH",
+        &format!("/api/users/{}", user_id),
+        "",
+        r#"{"username":"admin"}"#,
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+    // Reading users does not need the token.
+    let response = get_http_response(http_socket_address, "/api/users").await;
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+
+    // Without a token, the endpoints are not served at all.
+    let http_socket_address = "127.0.0.1:44466";
+    let connection_pool_cloned = connection_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState::new(connection_pool_cloned),
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+    let response = post_admin_json_http_request(http_socket_address, &path, body).await;
+    assert!(response.starts_with(b"HTTP/1.1 404 Not Found"));
+    let response = post_admin_json_http_request(
+        http_socket_address,
+        "/api/users",
+        r#"{"username":"mallory","password":"password"}"#,
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 405 Method Not Allowed"));
+
+    // The user keeps the name and the password.
+    let (_, password_hash) = db::get_user(&connection_pool, "alice").await.unwrap();
+    assert_eq!(password_hash, "hash");
+}
+
+#[tokio::test]
+async fn test_announcing_only_with_admin_token() {
+    let http_socket_address = "127.0.0.1:44469";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+    let body = r#"{"message":"Server restarts in 5 minutes."}"#;
+
+    let response = post_json_http_request(http_socket_address, "/api/announce", body).await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+
+    let response = post_admin_json_http_request(http_socket_address, "/api/announce", body).await;
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    assert!(response.ends_with(br#"{"clients_reached":0}"#));
+}
+
+#[tokio::test]
+async fn test_vacuuming_only_with_admin_token() {
+    let http_socket_address = "127.0.0.1:44470";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = post_json_http_request(http_socket_address, "/api/maintenance/vacuum", "").await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+
+    let response =
+        post_admin_json_http_request(http_socket_address, "/api/maintenance/vacuum", "").await;
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+}
+
+#[tokio::test]
+async fn test_getting_broadcast_failures_only_with_admin_token() {
+    let http_socket_address = "127.0.0.1:44471";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = get_http_response(http_socket_address, "/api/broadcast-failures").await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+
+    let response = get_admin_http_response(http_socket_address, "/api/broadcast-failures").await;
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    assert!(response.ends_with(b"[]"));
+}
+
+#[tokio::test]
+async fn test_registering_users() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let options = PasswordHashingOptions::default();
+
+    let user_id = register_user(&connection_pool, &options, "alice", "password")
+        .await
+        .unwrap();
+
+    let (stored_id, password_hash) = db::get_user(&connection_pool, "alice").await.unwrap();
+    assert_eq!(stored_id, user_id);
+    assert!(verify_password("password", &password_hash, &options)
+        .await
+        .is_ok());
+    assert_eq!(
+        register_user(&connection_pool, &options, "alice", "other password").await,
+        Err(AuthError::UsernameTaken)
+    );
+    assert_eq!(
+        register_user(&connection_pool, &options, "bob", "").await,
+        Err(AuthError::WeakPassword)
+    );
+    let too_long_username = "a".repeat(db::MAX_USERNAME_LENGTH + 1);
+    assert_eq!(
+        register_user(&connection_pool, &options, &too_long_username, "password").await,
+        Err(AuthError::UsernameTooLong)
+    );
+}
+
+#[tokio::test]
+async fn test_authenticating_users() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let options = PasswordHashingOptions::default();
+    let user_id = register_user(&connection_pool, &options, "alice", "password")
+        .await
+        .unwrap();
+
+    assert_eq!(
+        authenticate(&connection_pool, &options, "alice", "password").await,
+        Ok(user_id)
+    );
+    assert_eq!(
+        authenticate(&connection_pool, &options, "alice", "wrong").await,
+        Err(AuthError::WrongCredentials)
+    );
+    // An unknown user cannot be told apart from a wrong password.
+    assert_eq!(
+        authenticate(&connection_pool, &options, "bob", "password").await,
+        Err(AuthError::WrongCredentials)
+    );
+}
+
+#[tokio::test]
+async fn test_creating_user_over_http() {
+    let http_socket_address = "127.0.0.1:44457";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let options = PasswordHashingOptions::default();
+    let connection_pool_cloned = connection_pool.clone();
+    let options_cloned = options.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                password_hashing: options_cloned,
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = post_admin_json_http_request(
+        http_socket_address,
+        "/api/users",
+        r#"{"username":"alice","password":"password"}"#,
+    )
+    .await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 201 Created"));
+    assert!(response.ends_with(r#"{"id":1,"username":"alice"}"#));
+    // The user can log in like one registered in the chat.
+    assert_eq!(
+        authenticate(&connection_pool, &options, "alice", "password").await,
+        Ok(1)
+    );
+
+    let response = post_admin_json_http_request(
+        http_socket_address,
+        "/api/users",
+        r#"{"username":"alice","password":"password"}"#,
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 409 Conflict"));
+    let response = post_admin_json_http_request(
+        http_socket_address,
+        "/api/users",
+        r#"{"username":"bob","password":""}"#,
+    )
+    .await;
+    assert!(response.starts_with(b"HTTP/1.1 400 Bad Request"));
+}
+
+#[tokio::test]
+async fn test_filtering_metrics_by_prefix() {
+    let http_socket_address = "127.0.0.1:44448";
+    let messages_counter = get_messages_counter().await.unwrap();
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+    let auth_attempts_counter = get_auth_attempts_counter().await.unwrap();
+    let db_operation_durations = get_db_operation_durations_histogram().await.unwrap();
+    let registry = create_registry(
+        &messages_counter,
+        &active_connections_gauge,
+        &auth_attempts_counter,
+        &db_operation_durations,
+    )
+    .unwrap();
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                registry,
+                ..HttpState::new(connection_pool)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = get_http_response(http_socket_address, "/metrics").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.contains("messages_counter"));
+    assert!(response.contains("active_connections_gauge"));
+
+    let response = get_http_response(http_socket_address, "/metrics?prefix=active_").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(!response.contains("messages_counter"));
+    assert!(response.contains("active_connections_gauge"));
+}
+
+#[tokio::test]
+async fn test_scraping_db_operation_durations() {
+    let http_socket_address = "127.0.0.1:44456";
+    let messages_counter = get_messages_counter().await.unwrap();
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+    let auth_attempts_counter = get_auth_attempts_counter().await.unwrap();
+    let db_operation_durations = get_db_operation_durations_histogram().await.unwrap();
+    let registry = create_registry(
+        &messages_counter,
+        &active_connections_gauge,
+        &auth_attempts_counter,
+        &db_operation_durations,
+    )
+    .unwrap();
+    // Other tests in this file share the histogram from now on, so counts are not checked.
+    db::set_operation_durations(db_operation_durations.clone()).unwrap();
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    assert!(db::get_user(&connection_pool, "bob").await.is_err());
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                registry,
+                ..HttpState::new(connection_pool)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = get_http_response(http_socket_address, "/metrics").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.contains("db_operation_duration_seconds_bucket{operation=\"add_user\""));
+    // Failed operations are timed too.
+    assert!(response.contains("db_operation_duration_seconds_count{operation=\"get_user\"}"));
+    assert!(db::set_operation_durations(db_operation_durations).is_err());
+}
+
+#[tokio::test]
+async fn test_locking_out_after_failed_logins() {
+    let clock = FakeClock::new(SystemTime::now());
+    let login_attempts = LoginAttempts::with_clock(
+        LockoutPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+        },
+        clock.clone(),
+    );
+
+    for _ in 0..2 {
+        login_attempts.record_failed_login("alice").await;
+        clock.advance(Duration::from_secs(1));
+    }
+    assert!(!login_attempts.is_locked_out("alice").await);
+    login_attempts.record_failed_login("alice").await;
+    clock.advance(Duration::from_secs(1));
+
+    assert!(login_attempts.is_locked_out("alice").await);
+    assert!(!login_attempts.is_locked_out("bob").await);
+}
+
+#[tokio::test]
+async fn test_recovering_from_lockout() {
+    let clock = FakeClock::new(SystemTime::now());
+    let login_attempts = LoginAttempts::with_clock(
+        LockoutPolicy {
+            max_failures: 2,
+            window: Duration::from_secs(60),
+        },
+        clock.clone(),
+    );
+    login_attempts.record_failed_login("alice").await;
+    login_attempts.record_failed_login("alice").await;
+    login_attempts.record_failed_login("bob").await;
+    login_attempts.record_failed_login("bob").await;
+    assert!(login_attempts.is_locked_out("alice").await);
+    assert!(login_attempts.is_locked_out("bob").await);
+
+    // Successful login forgets previous failures.
+    login_attempts.clear_failed_logins("bob").await;
+    assert!(!login_attempts.is_locked_out("bob").await);
+
+    // The lockout ends when the window is over.
+    clock.advance(Duration::from_secs(60));
+    assert!(!login_attempts.is_locked_out("alice").await);
+    // A new failure after the window starts counting from one again.
+    clock.advance(Duration::from_secs(1));
+    login_attempts.record_failed_login("alice").await;
+    assert!(!login_attempts.is_locked_out("alice").await);
+}
+
+#[tokio::test]
+async fn test_message_sent_at_taken_from_clock() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    // 2024-01-02 03:04:05 UTC
+    let clock = FakeClock::new(UNIX_EPOCH + Duration::from_secs(1_704_164_645));
+
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        "Hello.",
+        MessageKind::Text,
+        &clock,
+    )
+    .await
+    .unwrap();
+    clock.advance(Duration::from_secs(60));
+    db::add_message(
+        &connection_pool,
+        &user_id,
+        "Hello again.",
+        MessageKind::Text,
+        &clock,
+    )
+    .await
+    .unwrap();
+
+    let messages = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let sent_at: Vec<&str> = messages
+        .iter()
+        .map(|message| message.sent_at.as_str())
+        .collect();
+    assert_eq!(sent_at, vec!["2024-01-02 03:04:05", "2024-01-02 03:05:05"]);
+}
+
+#[tokio::test]
+async fn test_saving_messages_in_batches() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let (message_writer, handle) = MessageWriter::spawn(
+        connection_pool.clone(),
+        BatchingPolicy {
+            max_batch_size: 4,
+            max_delay: Duration::from_secs(60),
+        },
+    );
+
+    for i in 0..10 {
+        message_writer
+            .write(
+                user_id,
+                format!("Message {}.", i),
+                MessageKind::Text,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+    }
+    // The last batch is not full, it is saved when the writer is dropped.
+    drop(message_writer);
+    handle.await.unwrap();
+
+    let messages = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let expected: Vec<String> = (0..10).map(|i| format!("Message {}.", i)).collect();
+    assert_eq!(get_contents(&messages), expected);
+}
+
+#[tokio::test]
+async fn test_saving_rest_of_failed_batch() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let (message_writer, handle) = MessageWriter::spawn(
+        connection_pool.clone(),
+        BatchingPolicy {
+            max_batch_size: 3,
+            max_delay: Duration::from_secs(60),
+        },
+    );
+
+    // The second message cannot be saved, so the whole batch fails.
+    for content in [
+        "Before.".to_string(),
+        "a".repeat(db::MAX_CONTENT_LENGTH + 1),
+        "After.".to_string(),
+    ] {
+        message_writer
+            .write(user_id, content, MessageKind::Text, None, None)
+            .await
+            .unwrap();
+    }
+    message_writer
+        .shut_down(handle, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    // Only the bad message is dropped.
+    let messages = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    assert_eq!(get_contents(&messages), vec!["Before.", "After."]);
+}
+
+#[tokio::test]
+async fn test_saving_waiting_messages_on_shutdown() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let (message_writer, handle) = MessageWriter::spawn(
+        connection_pool.clone(),
+        BatchingPolicy {
+            max_batch_size: 100,
+            max_delay: Duration::from_secs(60),
+        },
+    );
+
+    for i in 0..5 {
+        message_writer
+            .write(
+                user_id,
+                format!("Message {}.", i),
+                MessageKind::Text,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+    }
+    // The batch is neither full nor due, but shutting down saves it right away, although a handle still exists.
+    let message_writer_cloned = message_writer.clone();
+    message_writer
+        .shut_down(handle, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    let messages = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let expected: Vec<String> = (0..5).map(|i| format!("Message {}.", i)).collect();
+    assert_eq!(get_contents(&messages), expected);
+    // Messages written after the shutdown are refused.
+    assert!(message_writer_cloned
+        .write(
+            user_id,
+            "Too late.".to_string(),
+            MessageKind::Text,
+            None,
+            None
+        )
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_saving_messages_in_order_in_background() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let mut saves = OrderedSaves::new(SaveSlots::new(3));
+
+    for i in 0..6u64 {
+        let connection_pool = connection_pool.clone();
+        saves
+            .spawn(async move {
+                // Earlier saves take longer, so they would end last if they did not wait for each other.
+                sleep(Duration::from_millis(60 - 10 * i)).await;
+                db::add_message(
+                    &connection_pool,
+                    &user_id,
+                    &format!("Message {}.", i),
+                    MessageKind::Text,
+                    &SystemClock,
+                )
+                .await?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+    }
+    saves.finish().await;
+
+    let messages = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let expected: Vec<String> = (0..6).map(|i| format!("Message {}.", i)).collect();
+    assert_eq!(get_contents(&messages), expected);
+}
+
+#[tokio::test]
+async fn test_limiting_saves_in_background() {
+    let save_slots = SaveSlots::new(1);
+    let mut first_saves = OrderedSaves::new(save_slots.clone());
+    let mut second_saves = OrderedSaves::new(save_slots.clone());
+    let (release, released) = tokio::sync::oneshot::channel::<()>();
+
+    first_saves
+        .spawn(async move {
+            let _ = released.await;
+            Ok(())
+        })
+        .await
+        .unwrap();
+    assert_eq!(save_slots.available(), 0);
+
+    // Another connection waits until the running save ends.
+    let waiting = timeout(
+        Duration::from_millis(100),
+        second_saves.spawn(async { Ok(()) }),
+    )
+    .await;
+    assert!(waiting.is_err());
+
+    release.send(()).unwrap();
+    first_saves.finish().await;
+    assert_eq!(save_slots.available(), 1);
+    second_saves.spawn(async { Ok(()) }).await.unwrap();
+    second_saves.finish().await;
+    assert_eq!(save_slots.available(), 1);
+}
+
+#[tokio::test]
+async fn test_saving_incomplete_batch_after_delay() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let (message_writer, _) = MessageWriter::spawn(
+        connection_pool.clone(),
+        BatchingPolicy {
+            max_batch_size: 100,
+            max_delay: Duration::from_millis(50),
+        },
+    );
+
+    message_writer
+        .write(user_id, "Hello.".to_string(), MessageKind::Text, None, None)
+        .await
+        .unwrap();
+    message_writer
+        .write(
+            user_id,
+            "FILE SENT: notes.txt".to_string(),
+            MessageKind::File,
+            None,
+            Some(NewAttachment {
+                filename: "notes.txt".to_string(),
+                path: "files/0123456789abcdef.txt".to_string(),
+                mime: "text/plain".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(500)).await;
+
+    let messages = db::get_messages_by_user(&connection_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    assert_eq!(
+        get_contents(&messages),
+        vec!["Hello.", "FILE SENT: notes.txt"]
+    );
+    let attachment = db::get_attachment(&connection_pool, &1)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(attachment.filename, "notes.txt");
+    assert_eq!(attachment.message_id, 2);
+}
+
+#[tokio::test]
+async fn test_exporting_database() {
+    let http_socket_address = "127.0.0.1:44452";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "alice-secret-hash")
+        .await
+        .unwrap();
+    db::add_user(&connection_pool, "bob", "bob-secret-hash")
+        .await
+        .unwrap();
+    // More messages than fit in one page, so that the export goes through several pages.
+    let message_count = EXPORT_PAGE_SIZE as usize + 2;
+    for i in 0..message_count {
+        db::add_message(
+            &connection_pool,
+            &alice_id,
+            &format!("Message {}", i),
+            MessageKind::Text,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+    }
+
+    let mut export = Vec::new();
+    write_export(&connection_pool, &mut export).await.unwrap();
+
+    let export_text = String::from_utf8(export.clone()).unwrap();
+    assert!(!export_text.contains("secret-hash"));
+    assert!(!export_text.contains("password"));
+    let document: serde_json::Value = serde_json::from_slice(&export).unwrap();
+    let usernames: Vec<&str> = document["users"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["username"].as_str().unwrap())
+        .collect();
+    assert_eq!(usernames, ["alice", "bob"]);
+    let messages = document["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), message_count);
+    assert_eq!(messages[0]["content"], "Message 0");
+    assert_eq!(messages[0]["user_id"], alice_id);
+    assert_eq!(messages[0]["kind"], "text");
+    assert_eq!(
+        messages[message_count - 1]["content"],
+        format!("Message {}", message_count - 1)
+    );
+
+    // The same document is downloaded through the http server.
+    let connection_pool_cloned = connection_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+    let response = get_http_response(http_socket_address, "/api/export").await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+    let response = get_admin_http_response(http_socket_address, "/api/export").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("content-type: application/json"));
+    assert!(response.contains(r#"{"users":[{"id":1,"username":"alice"}"#));
+    assert!(!response.contains("secret-hash"));
+    // The body is chunked and it ends with the last, empty chunk.
+    assert!(response.ends_with("0\r\n\r\n"));
+}
+
+#[tokio::test]
+async fn test_aborting_failed_export() {
+    let http_socket_address = "127.0.0.1:44467";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    db::add_user(&connection_pool, "alice", "alice-hash")
+        .await
+        .unwrap();
+    // Users are exported, but reading messages fails.
+    sqlx::query("ALTER TABLE messages RENAME TO hidden_messages")
+        .execute(&connection_pool)
+        .await
+        .unwrap();
+    let mut export = Vec::new();
+    assert!(write_export(&connection_pool, &mut export).await.is_err());
+
+    let connection_pool_cloned = connection_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+    let response = get_admin_http_response(http_socket_address, "/api/export").await;
+    let response = String::from_utf8(response).unwrap();
+    // The status is sent before the export fails, but the connection is closed without the last chunk,
+    // so the client knows that the document is not complete.
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains(r#"{"users":[{"id":1,"username":"alice"}"#));
+    assert!(!response.ends_with("0\r\n\r\n"));
+}
+
+#[tokio::test]
+async fn test_importing_exported_database() {
+    let http_socket_address = "127.0.0.1:44453";
+    let source_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&source_pool, "alice", "alice-hash")
+        .await
+        .unwrap();
+    let bob_id = db::add_user(&source_pool, "bob", "bob-hash").await.unwrap();
+    for (user_id, content, kind) in [
+        (alice_id, "Hello.", MessageKind::Text),
+        (bob_id, "SENT IMAGE", MessageKind::Image),
+        (alice_id, "FILE SENT: notes.txt", MessageKind::File),
+    ] {
+        db::add_message(&source_pool, &user_id, content, kind, &SystemClock)
+            .await
+            .unwrap();
+    }
+    db::set_display_name(&source_pool, &bob_id, "Bobby")
+        .await
+        .unwrap();
+    let mut export = Vec::new();
+    write_export(&source_pool, &mut export).await.unwrap();
+    // The display names are written with the users and with the messages.
+    let document: serde_json::Value = serde_json::from_slice(&export).unwrap();
+    assert_eq!(document["users"][1]["display_name"], "Bobby");
+    assert!(document["users"][0].get("display_name").is_none());
+    assert_eq!(document["messages"][1]["display_name"], "Bobby");
+    assert!(document["messages"][0].get("display_name").is_none());
+
+    // The export is imported into a fresh database through the http server.
+    let target_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let target_pool_cloned = target_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(target_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+    let export_text = String::from_utf8(export.clone()).unwrap();
+    // Only an administrator can import a backup, because it can overwrite accounts.
+    let response = post_json_http_request(http_socket_address, "/api/import", &export_text).await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+    let response =
+        post_admin_json_http_request(http_socket_address, "/api/import", &export_text).await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with(r#"{"users_imported":2,"messages_imported":3}"#));
+
+    // Both databases are exported the same.
+    let mut reexport = Vec::new();
+    write_export(&target_pool, &mut reexport).await.unwrap();
+    assert_eq!(reexport, export);
+    // Exports have no password hashes, so the users cannot log in until their passwords are reset.
+    let (_, password_hash) = db::get_user(&target_pool, "alice").await.unwrap();
+    assert_eq!(password_hash, "");
+    assert_eq!(
+        db::get_display_name(&target_pool, &bob_id).await.unwrap(),
+        "Bobby"
+    );
+
+    // Importing the same backup again fails by default, but rows can be skipped instead.
+    let response =
+        post_admin_json_http_request(http_socket_address, "/api/import", &export_text).await;
+    assert!(response.starts_with(b"HTTP/1.1 409 Conflict"));
+    let response =
+        post_admin_json_http_request(http_socket_address, "/api/import?mode=skip", &export_text)
+            .await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.ends_with(r#"{"users_imported":0,"messages_imported":0}"#));
+}
+
+#[tokio::test]
+async fn test_keeping_message_tags_through_export_and_import() {
+    let source_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let user_id = db::add_user(&source_pool, "alice", "hash").await.unwrap();
+    db::add_tagged_message(
+        &source_pool,
+        &user_id,
+        "Tagged.",
+        MessageKind::Text,
+        Some(3),
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    db::add_message(
+        &source_pool,
+        &user_id,
+        "Not tagged.",
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+
+    // The tag is exported only with the tagged message.
+    let mut export = Vec::new();
+    write_export(&source_pool, &mut export).await.unwrap();
+    let document: serde_json::Value = serde_json::from_slice(&export).unwrap();
+    assert_eq!(document["messages"][0]["tag"], 3);
+    assert!(document["messages"][1].get("tag").is_none());
+
+    // The imported message keeps its tag, also in the pages of all messages and in the history.
+    let target_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let backup: Backup = serde_json::from_slice(&export).unwrap();
+    db::import_backup(&target_pool, &backup, ImportMode::Fail)
+        .await
+        .unwrap();
+    let messages = db::get_messages_by_user(&target_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let tags: Vec<Option<i64>> = messages.iter().map(|message| message.tag).collect();
+    assert_eq!(tags, vec![Some(3), None]);
+    let page = db::get_messages_page(&target_pool, None, 10, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let tags: Vec<Option<i64>> = page.messages.iter().map(|message| message.tag).collect();
+    assert_eq!(tags, vec![Some(3), None]);
+    let recent = db::get_recent_messages(&target_pool, 10).await.unwrap();
+    let tags: Vec<Option<i64>> = recent.iter().map(|message| message.tag).collect();
+    assert_eq!(tags, vec![Some(3), None]);
+
+    // Replacing a message replaces its tag too.
+    let mut changed = backup.clone();
+    changed.messages[0].tag = Some(4);
+    changed.messages[1].tag = Some(5);
+    db::import_backup(&target_pool, &changed, ImportMode::Replace)
+        .await
+        .unwrap();
+    let messages = db::get_messages_by_user(&target_pool, &user_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    let tags: Vec<Option<i64>> = messages.iter().map(|message| message.tag).collect();
+    assert_eq!(tags, vec![Some(4), Some(5)]);
+}
+
+#[tokio::test]
+async fn test_importing_backup_in_modes() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "old-hash")
+        .await
+        .unwrap();
+    db::add_message(
+        &connection_pool,
+        &alice_id,
+        "Old message.",
+        MessageKind::Text,
+        &SystemClock,
+    )
+    .await
+    .unwrap();
+    let backup: Backup = serde_json::from_str(&format!(
+        r#"{{
+            "users": [
+                {{ "id": {alice_id}, "username": "alice", "password_hash": "new-hash" }},
+                {{ "id": {carol_id}, "username": "carol" }}
+            ],
+            "messages": [
+                {{ "id": 1, "user_id": {alice_id}, "content": "New message.", "kind": "text", "sent_at": "2024-01-02 03:04:05" }}
+            ]
+        }}"#,
+        alice_id = alice_id,
+        carol_id = alice_id + 1
+    ))
+    .unwrap();
+
+    // Nothing is imported if any row is already in the database.
+    let result = db::import_backup(&connection_pool, &backup, ImportMode::Fail).await;
+    assert!(matches!(result, Err(ImportError::Conflict(_))));
+    assert!(db::get_user(&connection_pool, "carol").await.is_err());
+
+    let summary = db::import_backup(&connection_pool, &backup, ImportMode::Skip)
+        .await
+        .unwrap();
+    assert_eq!(
+        summary,
+        ImportSummary {
+            users_imported: 1,
+            messages_imported: 0
+        }
+    );
+    let (_, password_hash) = db::get_user(&connection_pool, "alice").await.unwrap();
+    assert_eq!(password_hash, "old-hash");
+
+    // Replaced rows get the values from the backup. Password hashes are taken as they are.
+    let summary = db::import_backup(&connection_pool, &backup, ImportMode::Replace)
+        .await
+        .unwrap();
+    assert_eq!(
+        summary,
+        ImportSummary {
+            users_imported: 2,
+            messages_imported: 1
+        }
+    );
+    let (_, password_hash) = db::get_user(&connection_pool, "alice").await.unwrap();
+    assert_eq!(password_hash, "new-hash");
+    let messages = db::get_messages_by_user(&connection_pool, &alice_id, MessageOrder::Asc)
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content, "New message.");
+    assert_eq!(messages[0].sent_at, "2024-01-02 03:04:05");
+
+    // Replacing goes by ids, so a username that belongs to another user in the database is refused.
+    let taken_backup: Backup = serde_json::from_str(
+        r#"{ "users": [{ "id": 7, "username": "alice" }, { "id": 8, "username": "dave" }], "messages": [] }"#,
+    )
+    .unwrap();
+    let result = db::import_backup(&connection_pool, &taken_backup, ImportMode::Replace).await;
+    assert!(matches!(
+        result,
+        Err(ImportError::UsernameTaken { id: 7, owner_id, .. }) if owner_id == alice_id
+    ));
+    assert!(db::get_user(&connection_pool, "dave").await.is_err());
+
+    // A message of an unknown user is refused.
+    let invalid_backup: Backup = serde_json::from_str(
+        r#"{ "users": [], "messages": [{ "id": 5, "user_id": 99, "content": "Hi.", "kind": "text", "sent_at": "2024-01-02 03:04:05" }] }"#,
+    )
+    .unwrap();
+    let result = db::import_backup(&connection_pool, &invalid_backup, ImportMode::Fail).await;
+    assert!(matches!(result, Err(ImportError::Invalid(_))));
+}
+
+#[tokio::test]
+async fn test_getting_users_with_last_activity() {
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let bob_id = db::add_user(&connection_pool, "bob", "hash").await.unwrap();
+    for secs in [1_700_000_000, 1_700_000_060] {
+        let clock = FakeClock::new(UNIX_EPOCH + Duration::from_secs(secs));
+        db::add_message(
+            &connection_pool,
+            &alice_id,
+            "Hello.",
+            MessageKind::Text,
+            &clock,
+        )
+        .await
+        .unwrap();
+    }
+
+    let users = db::get_users_with_last_activity(&connection_pool)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        users,
+        vec![
+            UserActivityDto {
+                id: alice_id,
+                username: "alice".to_string(),
+                last_message_at: Some("2023-11-14 22:14:20".to_string()),
+            },
+            UserActivityDto {
+                id: bob_id,
+                username: "bob".to_string(),
+                last_message_at: None,
+            },
+        ]
+    );
+    // A user without messages is reported with null.
+    assert_eq!(
+        serde_json::to_string(&users[1]).unwrap(),
+        format!(
+            r#"{{"id":{},"username":"bob","last_message_at":null}}"#,
+            bob_id
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_reconciling_active_connections_gauge() {
+    let client_writers = create_client_writers();
+    for port in [50001, 50002] {
+        let client_address: std::net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (writer, _) = duplex(64);
+        client_writers
+            .lock()
+            .await
+            .insert(client_address.into(), create_shared_writer(writer));
+    }
+    let active_connections_gauge = get_active_connections_gauge().await.unwrap();
+
+    // Three decrements were missed, so the gauge is too high.
+    active_connections_gauge.set(5.0);
+    let drift =
+        reconcile_active_connections_gauge(&active_connections_gauge, &client_writers).await;
+    assert_eq!(drift, 3.0);
+    assert_eq!(active_connections_gauge.get(), 2.0);
+
+    // The background task corrects a later drift too.
+    active_connections_gauge.set(-1.0);
+    spawn_gauge_reconciliation_task(
+        active_connections_gauge.clone(),
+        Arc::clone(&client_writers),
+        Duration::from_millis(50),
+    );
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(active_connections_gauge.get(), 2.0);
+}
+
+#[test]
+fn test_creating_session_ids() {
+    let session_id = SessionId::new();
+    let text = session_id.to_string();
+
+    // A version 4 UUID of the RFC 4122 variant, e.g. "0b4f5d1e-7c2a-4e6b-9f3d-2a1c5e8b7d90".
+    assert_eq!(text.len(), 36);
+    let groups: Vec<&str> = text.split('-').collect();
+    assert_eq!(
+        groups.iter().map(|group| group.len()).collect::<Vec<_>>(),
+        vec![8, 4, 4, 4, 12]
+    );
+    assert!(groups
+        .concat()
+        .chars()
+        .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+    assert!(groups[2].starts_with('4'));
+    assert!(groups[3].starts_with(['8', '9', 'a', 'b']));
+    assert_ne!(SessionId::new(), session_id);
+}
+
+#[tokio::test]
+async fn test_keeping_session_ids_in_registry() {
+    let sessions = ActiveSessions::new(DuplicateLoginPolicy::KickExisting);
+    let first_address: std::net::SocketAddr = "127.0.0.1:50001".parse().unwrap();
+    let second_address: std::net::SocketAddr = "127.0.0.1:50002".parse().unwrap();
+    let first_id = SessionId::new();
+    let second_id = SessionId::new();
+
+    sessions
+        .start(1, first_address.into(), first_id.clone())
+        .await
+        .unwrap();
+    assert_eq!(sessions.get_session_id(1).await, Some(first_id));
+
+    // A reconnecting client gets a new session, which replaces the kicked one.
+    sessions
+        .start(1, second_address.into(), second_id.clone())
+        .await
+        .unwrap();
+    assert_eq!(sessions.get_session_id(1).await, Some(second_id));
+    sessions.end(second_address.into()).await;
+    assert_eq!(sessions.get_session_id(1).await, None);
+}
+
+#[tokio::test]
+async fn test_banning_user_through_http() {
+    let http_socket_address = "127.0.0.1:44468";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    let sessions = ActiveSessions::new(DuplicateLoginPolicy::Reject);
+    let client_address: std::net::SocketAddr = "127.0.0.1:50003".parse().unwrap();
+    let kicked = sessions
+        .start(alice_id, client_address.into(), SessionId::new())
+        .await
+        .unwrap();
+    let connection_pool_cloned = connection_pool.clone();
+    let sessions_cloned = sessions.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                sessions: sessions_cloned,
+                ..HttpState::new(connection_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+    let path = format!("/api/users/{}/ban", alice_id);
+
+    // A ban that would end after the year 9999 is refused, whether the time overflows or only its year is too long.
+    for duration_secs in [u64::MAX, 10_000_000_000_000, 300_000_000_000] {
+        let body = format!(r#"{{ "duration_secs": {} }}"#, duration_secs);
+        let response = post_admin_json_http_request(http_socket_address, &path, &body).await;
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request"));
+        assert!(!db::is_banned(&connection_pool, &alice_id, &SystemClock)
+            .await
+            .unwrap());
+    }
+
+    // The session of a banned user is kicked, so that the connection is closed.
+    let response = post_admin_json_http_request(http_socket_address, &path, "{}").await;
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    assert!(db::is_banned(&connection_pool, &alice_id, &SystemClock)
+        .await
+        .unwrap());
+    timeout(Duration::from_secs(1), kicked.notified())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_getting_sessions() {
+    let http_socket_address = "127.0.0.1:44454";
+    let client_writers = create_client_writers();
+    let session_id = SessionId::new();
+    for (port, session) in [(50001, Some((7, session_id.clone()))), (50002, None)] {
+        let client_address: std::net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (writer, _) = duplex(64);
+        let shared_writer = create_shared_writer(writer);
+        if let Some((user_id, session_id)) = session {
+            shared_writer.lock().await.set_session(user_id, session_id);
+        }
+        client_writers
+            .lock()
+            .await
+            .insert(client_address.into(), shared_writer);
+    }
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                client_writers,
+                ..HttpState::new(connection_pool)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    // The sessions reveal the addresses of the clients, so they need the admin token.
+    let response = get_http_response(http_socket_address, "/api/sessions").await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+
+    // The client that is not authenticated yet has no session.
+    let response = get_admin_http_response(http_socket_address, "/api/sessions").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with(&format!(
+        r#"[{{"session_id":"{}","user_id":7,"address":"127.0.0.1:50001"}}]"#,
+        session_id
+    )));
+}
+
+#[test]
+fn test_detecting_duplicate_texts() {
+    let clock = FakeClock::new(UNIX_EPOCH);
+    let mut duplicate_texts = DuplicateTexts::with_clock(Duration::from_secs(2), clock.clone());
+    let hello = MessageType::Text("Hello.".to_string());
+    let image = MessageType::Image(vec![1, 2, 3]);
+
+    assert!(!duplicate_texts.is_duplicate(&hello));
+    assert!(duplicate_texts.is_duplicate(&hello));
+    // A dropped duplicate does not move the window.
+    clock.advance(Duration::from_secs(1));
+    assert!(duplicate_texts.is_duplicate(&hello));
+    clock.advance(Duration::from_secs(1));
+    assert!(!duplicate_texts.is_duplicate(&hello));
+
+    // Texts are duplicates only if they are the same and consecutive.
+    assert!(!duplicate_texts.is_duplicate(&MessageType::Text("hello.".to_string())));
+    assert!(!duplicate_texts.is_duplicate(&hello));
+    assert!(!duplicate_texts.is_duplicate(&image));
+    assert!(!duplicate_texts.is_duplicate(&hello));
+    // Other messages are never duplicates.
+    assert!(!duplicate_texts.is_duplicate(&image));
+    assert!(!duplicate_texts.is_duplicate(&image));
+}
+
+#[tokio::test]
+async fn test_getting_pages_of_messages_over_http() {
+    let http_socket_address = "127.0.0.1:44463";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    let alice_id = db::add_user(&connection_pool, "alice", "hash")
+        .await
+        .unwrap();
+    for i in 0..3 {
+        db::add_message(
+            &connection_pool,
+            &alice_id,
+            &format!("Message {}", i),
+            MessageKind::Text,
+            &SystemClock,
+        )
+        .await
+        .unwrap();
+    }
+    let connection_pool_cloned = connection_pool.clone();
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                admin_token: Some(AdminToken::new(ADMIN_TOKEN)),
+                ..HttpState::new(connection_pool_cloned)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    // The messages of all users need the admin token.
+    let response = get_http_response(http_socket_address, "/api/messages").await;
+    assert!(response.starts_with(b"HTTP/1.1 401 Unauthorized"));
+
+    // Follow the cursors until there is no next page.
+    let mut contents = Vec::new();
+    let mut path = "/api/messages?limit=2".to_string();
+    loop {
+        let response = get_admin_http_response(http_socket_address, &path).await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let page: serde_json::Value = serde_json::from_str(body).unwrap();
+        for message in page["messages"].as_array().unwrap() {
+            contents.push(message["content"].as_str().unwrap().to_string());
+        }
+        match page["next_cursor"].as_i64() {
+            Some(cursor) => path = format!("/api/messages?limit=2&after={}", cursor),
+            None => break,
+        }
+    }
+    assert_eq!(contents, ["Message 0", "Message 1", "Message 2"]);
+
+    let response =
+        get_admin_http_response(http_socket_address, "/api/messages?order=desc&limit=1").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.ends_with(r#""next_cursor":3}"#));
+    assert!(response.contains(r#""content":"Message 2""#));
+
+    for path in ["/api/messages?limit=0", "/api/messages?limit=1001"] {
+        let response = get_admin_http_response(http_socket_address, path).await;
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request"));
+    }
+}
+
+/// Start the server binary with the given arguments and a fresh database file in the temporary directory.
+fn spawn_server_process(db_file_name: &str, args: &[&str]) -> tokio::process::Child {
+    let db_file = std::env::temp_dir().join(db_file_name);
+    let _ = std::fs::remove_file(&db_file);
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg("--db-file")
+        .arg(&db_file)
+        .args(args)
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap()
+}
+
+/// Check whether something listens on the address, giving a starting server a few seconds.
+async fn is_listening(socket_address: &str) -> bool {
+    for _ in 0..50 {
+        if TcpStream::connect(socket_address).await.is_ok() {
+            return true;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_starting_chat_server_only() {
+    let chat_socket_address = "127.0.0.1:44459";
+    let http_socket_address = "127.0.0.1:44460";
+    let mut server = spawn_server_process(
+        "hello_rust_test_chat_only.db",
+        &[
+            "--no-http",
+            "--chat-socket",
+            chat_socket_address,
+            "--http-socket",
+            http_socket_address,
+        ],
+    );
+
+    assert!(is_listening(chat_socket_address).await);
+    assert!(TcpStream::connect(http_socket_address).await.is_err());
+
+    server.kill().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_starting_http_server_only() {
+    let chat_socket_address = "127.0.0.1:44461";
+    let http_socket_address = "127.0.0.1:44462";
+    let mut server = spawn_server_process(
+        "hello_rust_test_http_only.db",
+        &[
+            "--no-chat",
+            "--chat-socket",
+            chat_socket_address,
+            "--http-socket",
+            http_socket_address,
+        ],
+    );
+
+    assert!(is_listening(http_socket_address).await);
+    let response = get_http_response(http_socket_address, "/metrics").await;
+    assert!(String::from_utf8(response)
+        .unwrap()
+        .starts_with("HTTP/1.1 200 OK"));
+    assert!(TcpStream::connect(chat_socket_address).await.is_err());
+
+    server.kill().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_refusing_to_disable_both_servers() {
+    let status = spawn_server_process("hello_rust_test_no_servers.db", &["--no-chat", "--no-http"])
+        .wait()
+        .await
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[tokio::test]
+async fn test_getting_server_info_over_http() {
+    let http_socket_address = "127.0.0.1:44464";
+    let connection_pool = db::create_in_memory_connection_pool().await.unwrap();
+    // The server started a minute ago.
+    let server_start = ServerStart {
+        instant: Instant::now() - Duration::from_secs(60),
+        time: SystemTime::now() - Duration::from_secs(60),
+    };
+    tokio::spawn(async move {
+        run_http_server(
+            http_socket_address,
+            &["static"],
+            HttpState {
+                server_start,
+                ..HttpState::new(connection_pool)
+            },
+        )
+        .await
+    });
+    sleep(Duration::from_millis(200)).await;
+
+    let response = get_http_response(http_socket_address, "/api/info").await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let (_, body) = response.split_once("\r\n\r\n").unwrap();
+    let info: InfoResponse = serde_json::from_str(body).unwrap();
+    let started_at = server_start
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(info.started_at, started_at);
+    assert!((60..120).contains(&info.uptime_seconds));
+}
@@ -1,75 +1,640 @@
 pub mod utils {
-    use std::io;
+    use std::borrow::Cow;
+    use std::fmt;
+    use std::io::{self, Read, Write};
+    use std::net::SocketAddr;
+    use std::time::Duration;
     use serde_derive::{Deserialize, Serialize};
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+    use tokio::net::TcpStream;
     use thiserror::Error;
-    use anyhow::{Context, Result};
+    use anyhow::{Context, Result, anyhow};
     use serde_cbor::{to_vec, from_slice};
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+    use socket2::{SockRef, TcpKeepalive};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+
+    /// The version of the protocol spoken by the client and the server.
+    /// It is increased whenever messages of a new version cannot be understood by the previous one.
+    pub const PROTOCOL_VERSION: u32 = 11;
+
+
+    /// The maximum number of bytes a text message may have.
+    pub const MAX_TEXT_LENGTH: usize = 64 * 1024;
+
+
+    /// The number of bytes of the length prefix that is sent in front of each message.
+    /// The length is sent as a big-endian unsigned integer.
+    pub const LEN_PREFIX_BYTES: usize = 4;
+
+
+    /// Encoded messages shorter than this number of bytes are never compressed, because it would not pay off.
+    pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+
+    /// The maximum number of bytes of an image or a file sent in one message.
+    pub const MAX_FILE_LENGTH: usize = 64 * 1024 * 1024;
+
+
+    /// The maximum number of bytes of a frame. Longer frames are neither sent nor received.
+    /// Each byte of a file takes at most two bytes when it is encoded, so there is room for the largest file together with its name, its MIME type and the signature.
+    pub const MAX_FRAME_LENGTH: usize = 2 * MAX_FILE_LENGTH + MAX_TEXT_LENGTH;
+
+
+    /// The maximum number of bytes a compressed frame may be decompressed into.
+    /// It is the same as the maximum length of a frame, so a small compressed frame cannot make the receiver allocate more than the largest frame it accepts.
+    pub const MAX_DECOMPRESSED_LENGTH: usize = MAX_FRAME_LENGTH;
+
+
+    /// The first byte of a frame with compression enabled, if the rest of the frame is not compressed.
+    pub const FRAME_UNCOMPRESSED: u8 = 0;
+
+
+    /// The first byte of a frame with compression enabled, if the rest of the frame is compressed by deflate.
+    pub const FRAME_COMPRESSED: u8 = 1;
+
+
+    /// The number of bytes of the HMAC-SHA256 signature at the end of a signed frame.
+    pub const SIGNATURE_BYTES: usize = 32;
+
+
+    /// Whether frames on a connection may be compressed. The client offers it in AuthRequest
+    /// and the server answers in AuthResponse if it was agreed on.
+    /// Disabled frames contain only the encoded message. This is also how the authentication messages are sent.
+    /// Enabled frames start with a flag byte telling if the rest of the frame is compressed.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum Compression {
+        #[default]
+        Disabled,
+        Enabled
+    }
+
+
+    /// Whether frames on a connection are signed. The client asks for it in AuthRequest if it has a message key
+    /// and the server answers in AuthResponse. The server refuses clients that ask for something else than it does,
+    /// so that a relay cannot turn signing off by changing the request.
+    /// Enabled frames end with an HMAC-SHA256 signature of the rest of the frame, see FrameSigner. The authentication messages are never signed.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum Signing {
+        #[default]
+        Disabled,
+        Enabled
+    }
+
+
+    /// Secret key shared by the client and the server for signing frames.
+    /// It is never printed, not even in debug output.
+    #[derive(Clone)]
+    pub struct MessageKey(Hmac<Sha256>);
+
+
+    impl MessageKey {
+        /// Create a key from its bytes. An empty key is refused.
+        pub fn new(key: &[u8]) -> Result<MessageKey> {
+            if key.is_empty() {
+                return Err(anyhow!("Message key must not be empty."));
+            }
+            let mac = Hmac::<Sha256>::new_from_slice(key).map_err(|e| anyhow!("Invalid message key: {}", e))?;
+            Ok(MessageKey(mac))
+        }
+    }
+
+
+    impl fmt::Debug for MessageKey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "MessageKey(<hidden>)")
+        }
+    }
+
+
+    /// The way in which frames go on a connection. It is signed with each frame,
+    /// so that a frame cannot be reflected back to the side that sent it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Direction {
+        ClientToServer,
+        ServerToClient
+    }
+
+
+    impl Direction {
+        /// The byte that stands for the direction in the input of a signature.
+        fn as_byte(self) -> u8 {
+            match self {
+                Direction::ClientToServer => 0,
+                Direction::ServerToClient => 1
+            }
+        }
+    }
+
+
+    /// Signs or checks the signed frames going one way on one connection.
+    /// A signature covers the direction, the sequence number of the frame and the frame itself.
+    /// The sequence number counts the signed frames from 0. It is not sent, because both sides count the frames.
+    /// So a frame that is replayed, dropped, reordered or reflected does not match its signature.
+    #[derive(Debug, Clone)]
+    pub struct FrameSigner {
+        key: MessageKey,
+        direction: Direction,
+        sequence: u64
+    }
+
+
+    impl FrameSigner {
+        /// Create a signer for the frames going in the direction. Its first frame has the sequence number 0.
+        pub fn new(key: MessageKey, direction: Direction) -> FrameSigner {
+            FrameSigner { key, direction, sequence: 0 }
+        }
+
+        /// Start the signature of the next frame with the direction and the sequence number.
+        fn mac(&self, frame: &[u8]) -> Hmac<Sha256> {
+            let mut mac = self.key.0.clone();
+            mac.update(&[self.direction.as_byte()]);
+            mac.update(&self.sequence.to_be_bytes());
+            mac.update(frame);
+            mac
+        }
+    }
+
+
+    /// Whether a client takes part in read receipts. The client chooses it in AuthRequest.
+    /// Enabled clients get messages of other enabled clients with ids assigned by the server and send read receipts for them.
+    /// They are also told the ids of their own messages and who read them.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum ReadReceipts {
+        #[default]
+        Disabled,
+        Enabled
+    }
     
     
     /// This type is used to wrap data sent to server and other clients.
     /// Text is for sending pure text.
     /// Image is for sending .png files.
-    /// File is for sending files with their names.
-    /// AuthRequest is for sending auth request from client to server.
-    /// AuthResponse is for sending auth reply from server to client.
-    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    /// File is for sending files with their names. The MIME type of the file is sent too if the sender could tell it, e.g. from the extension of the name.
+    /// AuthRequest is for sending auth request from client to server. It also offers compression of later frames,
+    /// asks for signing of them and tells whether the client takes part in read receipts.
+    /// AuthResponse is for sending auth reply from server to client. If the authentication failed, it carries the reason.
+    /// It also carries the compression and the signing used for later frames and, if the authentication succeeded, the id of the new session.
+    /// Rename is for asking server to change the username of an authenticated user.
+    /// ServerError is for informing a client that the server could not do what the client asked for.
+    /// ServerNotice is for other information from server to a client that is not a chat message.
+    /// HistoryRequest is for asking server for the given number of the most recent chat messages.
+    /// History is the reply to HistoryRequest. It carries the messages from the oldest to the newest.
+    /// Identified is for a message of another client together with the id the server assigned to it.
+    /// MessageId is for telling a client the id the server assigned to the message the client has just sent.
+    /// ReadReceipt is for telling server that the message with the id was shown to the user.
+    /// ReadBy is for telling the author of the message with the id which user has read it.
+    /// FormattedText is for text with a hint how it should be shown. Clients that cannot render the format show it as Text.
+    /// SetNickname is for asking server to change the name under which the messages of an authenticated user are shown.
+    /// Authored is for a chat message of another client together with the nickname of its author.
+    /// Tagged is for a text message together with a tag, an id chosen by its author, e.g. from a counter. The server saves
+    /// the tag with the message, keeps it in the message forwarded to other clients and puts it around the MessageId reply.
+    /// ConnectionRejected is for telling a client why the server closes its connection instead of serving it.
+    /// It carries the reason and the banner of the server, e.g. whom to contact, if the server has one.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
     pub enum MessageType {
         Text(String),
         Image(Vec<u8>),
-        File(String, Vec<u8>),
-        AuthRequest(String, String, String),
-        AuthResponse(bool, String)
+        File { name: String, mime: Option<String>, data: Vec<u8> },
+        AuthRequest(String, String, String, Compression, Signing, ReadReceipts),
+        AuthResponse(bool, Option<AuthError>, String, Compression, Signing, Option<String>),
+        Rename(String),
+        ServerError(String),
+        ServerNotice(String),
+        HistoryRequest(u32),
+        History(Vec<HistoryEntry>),
+        Identified(i64, Box<MessageType>),
+        MessageId(i64),
+        ReadReceipt(i64),
+        ReadBy(i64, String),
+        FormattedText { text: String, format: TextFormat },
+        SetNickname(String),
+        Authored(String, Box<MessageType>),
+        Tagged(i64, Box<MessageType>),
+        ConnectionRejected(RejectReason, Option<String>)
+    }
+
+
+    impl MessageType {
+        /// A short label of the type of the message, e.g. "text" or "auth_request".
+        /// It is meant for logs, which should say what kind of message it was without the possibly huge payload.
+        pub fn kind_str(&self) -> &'static str {
+            match self {
+                MessageType::Text(_) => "text",
+                MessageType::Image(_) => "image",
+                MessageType::File { .. } => "file",
+                MessageType::AuthRequest(..) => "auth_request",
+                MessageType::AuthResponse(..) => "auth_response",
+                MessageType::Rename(_) => "rename",
+                MessageType::ServerError(_) => "server_error",
+                MessageType::ServerNotice(_) => "server_notice",
+                MessageType::HistoryRequest(_) => "history_request",
+                MessageType::History(_) => "history",
+                MessageType::Identified(..) => "identified",
+                MessageType::MessageId(_) => "message_id",
+                MessageType::ReadReceipt(_) => "read_receipt",
+                MessageType::ReadBy(..) => "read_by",
+                MessageType::FormattedText { .. } => "formatted_text",
+                MessageType::SetNickname(_) => "set_nickname",
+                MessageType::Authored(..) => "authored",
+                MessageType::Tagged(..) => "tagged",
+                MessageType::ConnectionRejected(..) => "connection_rejected"
+            }
+        }
+    }
+
+
+    /// The format of a formatted text message.
+    /// Formats added by newer versions of the protocol are received as Unknown and shown as plain text.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum TextFormat {
+        Plain,
+        Markdown,
+        #[serde(other)]
+        Unknown
+    }
+
+
+    /// One chat message saved by the server, as sent to a client in the chat history.
+    /// The tag is the one the author's client gave the message, if it was tagged.
+    /// The time when the message was sent is in the "YYYY-MM-DD HH:MM:SS" format in UTC.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+    pub struct HistoryEntry {
+        pub username: String,
+        pub content: String,
+        pub tag: Option<i64>,
+        pub sent_at: String
+    }
+
+
+    /// The reason why the server did not authenticate a client.
+    /// It lets the client react to the failure without parsing the message from server.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AuthError {
+        WrongCredentials,
+        UsernameTaken,
+        UsernameTooLong,
+        WeakPassword,
+        TooManyAttempts,
+        Banned,
+        InvalidRequest,
+        ServerError,
+        AlreadyLoggedIn,
+        SigningMismatch
+    }
+
+
+    /// The reason why the server rejected a connection. The connection is closed right after the client is told.
+    /// ServerFull is for servers that cannot take any more connections.
+    /// Banned means that the user is banned. TooManySessions means that the user is logged in elsewhere and
+    /// the server does not allow another session. VersionMismatch means that the first frame of the client could not be
+    /// understood, which usually means that the client speaks another version of the protocol.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RejectReason {
+        ServerFull,
+        Banned,
+        TooManySessions,
+        VersionMismatch
     }
 
 
     /// Custom error for signalizing problem in functions for sending and receiving bytes.
+    /// ConnectionClosed means that the peer closed the connection between frames, which is a normal disconnect.
+    /// TruncatedFrame means that the connection was closed after a part of a frame was received.
     #[derive(Error, Debug)]
     pub enum BytesSendReceiveError {
         #[error("Sending bytes failed.")]
         SendFailed(#[source] io::Error),
         #[error("Receiving bytes failed.")]
-        ReceiveFailed(#[source] io::Error)
+        ReceiveFailed(#[source] io::Error),
+        #[error("The connection was closed by the peer.")]
+        ConnectionClosed,
+        #[error("The connection was closed in the middle of a frame.")]
+        TruncatedFrame,
+        #[error("The frame has {0} bytes, but a frame can have at most {max} bytes.", max = MAX_FRAME_LENGTH)]
+        FrameTooLong(usize)
+    }
+
+
+    /// Custom error for the bytes of a message that could not be turned into a message.
+    /// It tells how many bytes there were and at which of them decoding failed, so that corrupt frames can be diagnosed.
+    /// The bytes are those of the frame without the flag byte and after decompression, if compression is enabled.
+    #[derive(Error, Debug)]
+    #[error("Failed to turn {len} bytes into MessageType, decoding failed at byte {offset}: {cbor_error}")]
+    pub struct DecodeError {
+        pub len: usize,
+        pub offset: u64,
+        pub cbor_error: serde_cbor::Error
+    }
+
+
+    /// Custom error for a signed frame whose signature does not match the rest of it.
+    /// The frame was changed on the way, replayed, reordered, reflected, signed with another key or not signed at all.
+    /// Like DecodeError, it tells how many bytes the frame had.
+    #[derive(Error, Debug)]
+    #[error("Signature of a frame of {len} bytes does not match, the frame was changed, is out of sequence or was signed with another key.")]
+    pub struct SignatureError {
+        pub len: usize
+    }
+
+
+    impl BytesSendReceiveError {
+        /// Turn an error from reading a frame into a receive error. The end of the stream means a truncated frame.
+        fn from_receive_error(error: io::Error) -> BytesSendReceiveError {
+            match error.kind() {
+                io::ErrorKind::UnexpectedEof => BytesSendReceiveError::TruncatedFrame,
+                _ => BytesSendReceiveError::ReceiveFailed(error)
+            }
+        }
+    }
+
+
+    /// Write the length of the following bytes as a length prefix.
+    /// The length must fit into LEN_PREFIX_BYTES bytes.
+    pub async fn write_len_prefix<W: AsyncWrite + Unpin>(writer: &mut W, len: usize) -> io::Result<()> {
+        let len = u32::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Length does not fit into the length prefix."))?;
+        let len_prefix: [u8; LEN_PREFIX_BYTES] = len.to_be_bytes();
+        writer.write_all(&len_prefix).await
+    }
+
+
+    /// Read a length prefix and return the length of the bytes that follow it.
+    pub async fn read_len_prefix<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<usize> {
+        let mut len_prefix = [0u8; LEN_PREFIX_BYTES];
+        reader.read_exact(&mut len_prefix).await?;
+        Ok(u32::from_be_bytes(len_prefix) as usize)
     }
 
 
     /// Uses stream to receive data sent to a socket.
     /// It saves them in a vector of bytes and returnes them.
-    pub async fn receive_bytes(stream_reader: &mut OwnedReadHalf) -> Result<Vec<u8>, BytesSendReceiveError> {
-        let mut bytes_len_buf = [0u8; 4];
-        stream_reader.read_exact(&mut bytes_len_buf).await.map_err(BytesSendReceiveError::ReceiveFailed)?;
-        let bytes_len = u32::from_be_bytes(bytes_len_buf) as usize;
-        let mut buffer = vec![0u8; bytes_len];
-        stream_reader.read_exact(&mut buffer).await.map_err(BytesSendReceiveError::ReceiveFailed)?;
+    /// Any reader can be used, e.g. a half of a TCP stream or of a Unix domain socket stream.
+    pub async fn receive_bytes<R: AsyncRead + Unpin>(stream_reader: &mut R) -> Result<Vec<u8>, BytesSendReceiveError> {
+        let mut buffer = Vec::new();
+        receive_bytes_into(stream_reader, &mut buffer).await?;
         Ok(buffer)
     }
 
 
+    /// Receive data sent to a socket into a buffer provided by the caller.
+    /// The previous contents of the buffer are replaced and its length is set to the length of the data.
+    /// Reusing one buffer for many frames avoids allocating a new vector for each of them.
+    /// If the stream ends before the first byte of a frame, ConnectionClosed is returned. If it ends later, TruncatedFrame is returned.
+    /// A frame longer than MAX_FRAME_LENGTH is refused with FrameTooLong before any of its bytes are read.
+    pub async fn receive_bytes_into<R: AsyncRead + Unpin>(stream_reader: &mut R, buffer: &mut Vec<u8>) -> Result<(), BytesSendReceiveError> {
+        let mut len_prefix = [0u8; LEN_PREFIX_BYTES];
+        let first_read = stream_reader.read(&mut len_prefix).await.map_err(BytesSendReceiveError::ReceiveFailed)?;
+        if first_read == 0 {
+            return Err(BytesSendReceiveError::ConnectionClosed);
+        }
+        stream_reader.read_exact(&mut len_prefix[first_read..]).await.map_err(BytesSendReceiveError::from_receive_error)?;
+        let bytes_len = u32::from_be_bytes(len_prefix) as usize;
+        if bytes_len > MAX_FRAME_LENGTH {
+            return Err(BytesSendReceiveError::FrameTooLong(bytes_len));
+        }
+        buffer.clear();
+        buffer.resize(bytes_len, 0);
+        stream_reader.read_exact(buffer).await.map_err(BytesSendReceiveError::from_receive_error)?;
+        Ok(())
+    }
+
+
     /// Send an array of bytes to a socket using stream.
-    pub async fn send_bytes(stream_writer: &mut OwnedWriteHalf, bytes: &[u8]) -> Result<(), BytesSendReceiveError> {
-        let len = bytes.len() as u32;
-        stream_writer.write(&len.to_be_bytes()).await.map_err(BytesSendReceiveError::SendFailed)?;
+    /// Any writer can be used, e.g. a half of a TCP stream or of a Unix domain socket stream.
+    pub async fn send_bytes<W: AsyncWrite + Unpin>(stream_writer: &mut W, bytes: &[u8]) -> Result<(), BytesSendReceiveError> {
+        write_len_prefix(stream_writer, bytes.len()).await.map_err(BytesSendReceiveError::SendFailed)?;
         stream_writer.write_all(bytes).await.map_err(BytesSendReceiveError::SendFailed)?;
         Ok(())
     }
 
 
-    /// This function uses stream to receive data and turn them into a message.
-    pub async fn receive_message(mut stream_reader: &mut OwnedReadHalf) -> Result<MessageType> {
-        let bytes = receive_bytes(&mut stream_reader).await.context("Failed when receiving bytes.")?;
-        let message: MessageType = from_slice(&bytes).context("Failed to turn bytes into MessageType.")?;
+    /// Turn encoded bytes of a message into a frame.
+    /// With compression enabled, the bytes are compressed only if there are at least COMPRESSION_THRESHOLD of them
+    /// and the compressed bytes are shorter. Otherwise they are sent as they are behind the flag byte.
+    pub fn encode_frame(bytes: Vec<u8>, compression: Compression) -> Result<Vec<u8>> {
+        if compression == Compression::Disabled {
+            return Ok(bytes);
+        }
+        if bytes.len() >= COMPRESSION_THRESHOLD {
+            let mut encoder = DeflateEncoder::new(vec![FRAME_COMPRESSED], flate2::Compression::fast());
+            encoder.write_all(&bytes).context("Failed to compress frame.")?;
+            let compressed = encoder.finish().context("Failed to compress frame.")?;
+            if compressed.len() < bytes.len() + 1 {
+                return Ok(compressed);
+            }
+        }
+        let mut frame = Vec::with_capacity(bytes.len() + 1);
+        frame.push(FRAME_UNCOMPRESSED);
+        frame.extend_from_slice(&bytes);
+        Ok(frame)
+    }
+
+
+    /// Get encoded bytes of a message from a frame. The compression must be the same as the one used by encode_frame.
+    pub fn decode_frame(frame: &[u8], compression: Compression) -> Result<Cow<'_, [u8]>> {
+        if compression == Compression::Disabled {
+            return Ok(Cow::Borrowed(frame));
+        }
+        match frame.split_first() {
+            Some((&FRAME_UNCOMPRESSED, bytes)) => Ok(Cow::Borrowed(bytes)),
+            Some((&FRAME_COMPRESSED, compressed)) => {
+                let mut bytes = Vec::new();
+                DeflateDecoder::new(compressed).take(MAX_DECOMPRESSED_LENGTH as u64 + 1).read_to_end(&mut bytes).context("Failed to decompress frame.")?;
+                if bytes.len() > MAX_DECOMPRESSED_LENGTH {
+                    return Err(anyhow!("Decompressed frame is too long."));
+                }
+                Ok(Cow::Owned(bytes))
+            },
+            Some((flag, _)) => Err(anyhow!("Unknown frame flag {}.", flag)),
+            None => Err(anyhow!("Frame is missing the flag byte."))
+        }
+    }
+
+
+    /// Append the signature of a frame to it. The signer moves on to the next sequence number.
+    pub fn sign_frame(mut frame: Vec<u8>, signer: &mut FrameSigner) -> Vec<u8> {
+        let mac = signer.mac(&frame);
+        frame.extend_from_slice(&mac.finalize().into_bytes());
+        signer.sequence = signer.sequence.wrapping_add(1);
+        frame
+    }
+
+
+    /// Check the signature at the end of a signed frame and return the rest of the frame.
+    /// The signature is compared in constant time, so that it cannot be guessed byte by byte.
+    /// Only a matching frame moves the signer on to the next sequence number.
+    pub fn verify_frame<'a>(frame: &'a [u8], signer: &mut FrameSigner) -> Result<&'a [u8], SignatureError> {
+        let Some(unsigned_len) = frame.len().checked_sub(SIGNATURE_BYTES) else {
+            return Err(SignatureError { len: frame.len() });
+        };
+        let (unsigned, signature) = frame.split_at(unsigned_len);
+        signer.mac(unsigned).verify_slice(signature).map_err(|_| SignatureError { len: frame.len() })?;
+        signer.sequence = signer.sequence.wrapping_add(1);
+        Ok(unsigned)
+    }
+
+
+    /// Turn a message into the bytes of a frame, compressed if it is enabled and beneficial.
+    pub fn encode_message(message: &MessageType, compression: Compression) -> Result<Vec<u8>> {
+        encode_signed_message(message, compression, None)
+    }
+
+
+    /// Turn a message into the bytes of a frame like encode_message and sign the frame if there is a signer.
+    /// A frame that would be longer than MAX_FRAME_LENGTH is refused before it is signed, so the signer stays at the same sequence number.
+    pub fn encode_signed_message(message: &MessageType, compression: Compression, signer: Option<&mut FrameSigner>) -> Result<Vec<u8>> {
+        let bytes = to_vec(&message).context("Failed to turn message into a vector of bytes.")?;
+        let frame = encode_frame(bytes, compression)?;
+        let frame_len = frame.len() + if signer.is_some() { SIGNATURE_BYTES } else { 0 };
+        if frame_len > MAX_FRAME_LENGTH {
+            return Err(anyhow!("The frame has {} bytes, but a frame can have at most {} bytes.", frame_len, MAX_FRAME_LENGTH));
+        }
+        Ok(match signer {
+            Some(signer) => sign_frame(frame, signer),
+            None => frame
+        })
+    }
+
+
+    /// Turn the bytes of a frame into a message.
+    /// If the bytes are not a valid message, the error is a DecodeError.
+    pub fn decode_message(frame: &[u8], compression: Compression) -> Result<MessageType> {
+        decode_signed_message(frame, compression, None)
+    }
+
+
+    /// Turn the bytes of a frame into a message like decode_message. If there is a signer, the signature of the frame is checked first.
+    /// If the signature does not match, the error is a SignatureError and the frame is not decoded at all.
+    pub fn decode_signed_message(frame: &[u8], compression: Compression, signer: Option<&mut FrameSigner>) -> Result<MessageType> {
+        let frame = match signer {
+            Some(signer) => verify_frame(frame, signer)?,
+            None => frame
+        };
+        let bytes = decode_frame(frame, compression)?;
+        let message = from_slice(&bytes).map_err(|cbor_error: serde_cbor::Error| DecodeError { len: bytes.len(), offset: cbor_error.offset(), cbor_error })?;
         Ok(message)
     }
-    
+
+
+    /// This function uses stream to receive data and turn them into a message.
+    pub async fn receive_message<R: AsyncRead + Unpin>(stream_reader: &mut R) -> Result<MessageType> {
+        receive_message_into(stream_reader, &mut Vec::new()).await
+    }
+
+
+    /// This function receives a message like receive_message, but it reads the bytes into a buffer provided by the caller.
+    pub async fn receive_message_into<R: AsyncRead + Unpin>(stream_reader: &mut R, buffer: &mut Vec<u8>) -> Result<MessageType> {
+        receive_compressed_message_into(stream_reader, buffer, Compression::Disabled).await
+    }
+
+
+    /// This function receives a message like receive_message_into from a connection with specified compression.
+    pub async fn receive_compressed_message_into<R: AsyncRead + Unpin>(stream_reader: &mut R, buffer: &mut Vec<u8>, compression: Compression) -> Result<MessageType> {
+        receive_signed_message_into(stream_reader, buffer, compression, None).await
+    }
+
+
+    /// This function receives a message like receive_compressed_message_into and checks its signature if there is a signer.
+    pub async fn receive_signed_message_into<R: AsyncRead + Unpin>(stream_reader: &mut R, buffer: &mut Vec<u8>, compression: Compression, signer: Option<&mut FrameSigner>) -> Result<MessageType> {
+        receive_bytes_into(stream_reader, buffer).await.context("Failed when receiving bytes.")?;
+        decode_signed_message(buffer, compression, signer)
+    }
+
 
     /// This function receives a message, turns it into bytes and sends them using stream.
-    pub async fn send_message(stream_writer: &mut OwnedWriteHalf, message: &MessageType) -> Result<()> {
-        let bytes = to_vec(&message).context("Failed to turn message into a vector of bytes.")?;
+    pub async fn send_message<W: AsyncWrite + Unpin>(stream_writer: &mut W, message: &MessageType) -> Result<()> {
+        send_compressed_message(stream_writer, message, Compression::Disabled).await
+    }
+
+
+    /// This function sends a message like send_message to a connection with specified compression.
+    pub async fn send_compressed_message<W: AsyncWrite + Unpin>(stream_writer: &mut W, message: &MessageType, compression: Compression) -> Result<()> {
+        send_signed_message(stream_writer, message, compression, None).await
+    }
+
+
+    /// This function sends a message like send_compressed_message and signs it if there is a signer.
+    pub async fn send_signed_message<W: AsyncWrite + Unpin>(stream_writer: &mut W, message: &MessageType, compression: Compression, signer: Option<&mut FrameSigner>) -> Result<()> {
+        let bytes = encode_signed_message(message, compression, signer)?;
         send_bytes(stream_writer, &bytes).await.context("Failed when sending bytes.")?;
         Ok(())
     }
+
+
+    /// Parse a socket address made of an IP address and a port, e.g. "127.0.0.1:11111" or "[::1]:11111".
+    /// It is used only by the server for the addresses it binds, so host names like "localhost:11111" are refused rather than resolved.
+    /// The client accepts host names and checks its server address with its own check_host_and_port instead.
+    pub fn parse_socket_addr(input: &str) -> Result<SocketAddr> {
+        input.parse().with_context(|| format!("'{}' is not a valid socket address. Use an IP address and a port, e.g. 127.0.0.1:11111 or [::1]:11111.", input))
+    }
+
+
+    /// Enable TCP keepalive on a stream.
+    /// After the connection is idle for the interval, keepalive probes are sent in the same interval.
+    /// If the other side does not answer them, the connection is closed and reading from the stream fails.
+    pub fn enable_keepalive(stream: &TcpStream, interval: Duration) -> Result<()> {
+        let keepalive = TcpKeepalive::new()
+            .with_time(interval)
+            .with_interval(interval);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive).context("Failed to enable TCP keepalive.")?;
+        Ok(())
+    }
+
+
+    /// How the client and the server write their logs.
+    /// Text is the human-readable format of env_logger. Json is one JSON object per line, which log aggregators can ingest.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum LogFormat {
+        Text,
+        Json
+    }
+
+
+    impl LogFormat {
+        /// Names of all formats as they are typed on the command line.
+        pub const NAMES: [&'static str; 2] = ["text", "json"];
+
+        /// Get the format with the name typed on the command line.
+        pub fn from_name(name: &str) -> Result<LogFormat> {
+            match name {
+                "text" => Ok(LogFormat::Text),
+                "json" => Ok(LogFormat::Json),
+                _ => Err(anyhow!("Unknown log format '{}'.", name))
+            }
+        }
+    }
+
+
+    /// Start logging to the standard error output in the given format.
+    /// Which records are logged is set by the RUST_LOG environment variable in both formats.
+    pub fn init_logger(format: LogFormat) {
+        let mut builder = env_logger::Builder::from_default_env();
+        if format == LogFormat::Json {
+            builder.format(|buf, record| writeln!(buf, "{}", format_json_log_line(&buf.timestamp().to_string(), record)));
+        }
+        builder.init();
+    }
+
+
+    /// Turn a log record into one line of JSON with the time, the level, the target (usually the module) and the message.
+    pub fn format_json_log_line(timestamp: &str, record: &log::Record) -> String {
+        serde_json::json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string()
+        }).to_string()
+    }
+
+
+    /// Describe the version of a program and of the protocol it speaks, e.g. for the --version flag.
+    pub fn describe_version(program: &str, program_version: &str) -> String {
+        format!("{} {} (protocol version {})", program, program_version, PROTOCOL_VERSION)
+    }
 }
 
 
-pub use utils::{MessageType, BytesSendReceiveError, receive_bytes, send_bytes, receive_message, send_message};
+pub use utils::{PROTOCOL_VERSION, MAX_TEXT_LENGTH, LEN_PREFIX_BYTES, COMPRESSION_THRESHOLD, MAX_FILE_LENGTH, MAX_FRAME_LENGTH, MAX_DECOMPRESSED_LENGTH, FRAME_UNCOMPRESSED, FRAME_COMPRESSED, SIGNATURE_BYTES, MessageType, HistoryEntry, TextFormat, AuthError, RejectReason, Compression, Signing, MessageKey, Direction, FrameSigner, ReadReceipts, BytesSendReceiveError, DecodeError, SignatureError, receive_bytes, receive_bytes_into, send_bytes, encode_frame, decode_frame, sign_frame, verify_frame, encode_message, encode_signed_message, decode_message, decode_signed_message, receive_message, receive_message_into, receive_compressed_message_into, receive_signed_message_into, send_message, send_compressed_message, send_signed_message, enable_keepalive, parse_socket_addr, LogFormat, init_logger, format_json_log_line, describe_version, write_len_prefix, read_len_prefix};
@@ -1,6 +1,10 @@
 use shared::*;
 use tokio::net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpListener, TcpStream};
 use anyhow::Result;
+use socket2::SockRef;
+use std::time::Duration;
+use std::io::Write;
+use flate2::write::DeflateEncoder;
 
 
 /// Prepare one reader and one writer. A connection should exist between them.
@@ -49,3 +53,373 @@ async fn test_sending_and_receiving_messages() {
     // Check if received payload matches the sent payload.
     assert_eq!(test_message, received_message);
 }
+
+#[tokio::test]
+async fn test_enabling_keepalive() {
+    let socket_address_of_server = "127.0.0.1:55555";
+    let listener_on_server = TcpListener::bind(socket_address_of_server).await.unwrap();
+    let stream_on_client = TcpStream::connect(socket_address_of_server).await.unwrap();
+    let (stream_on_server, _) = listener_on_server.accept().await.unwrap();
+
+    enable_keepalive(&stream_on_client, Duration::from_secs(30)).unwrap();
+    enable_keepalive(&stream_on_server, Duration::from_secs(30)).unwrap();
+
+    assert!(SockRef::from(&stream_on_client).keepalive().unwrap());
+    assert!(SockRef::from(&stream_on_server).keepalive().unwrap());
+}
+
+#[tokio::test]
+async fn test_writing_and_reading_len_prefix() {
+
+    // Write a length prefix into a buffer.
+    let mut buffer: Vec<u8> = Vec::new();
+    write_len_prefix(&mut buffer, 70000).await.unwrap();
+    assert_eq!(buffer.len(), LEN_PREFIX_BYTES);
+
+    // Read the length back.
+    let len = read_len_prefix(&mut buffer.as_slice()).await.unwrap();
+    assert_eq!(len, 70000);
+
+    // A length that does not fit into the prefix cannot be written.
+    let too_long = u32::MAX as usize + 1;
+    assert!(write_len_prefix(&mut Vec::new(), too_long).await.is_err());
+}
+
+#[tokio::test]
+async fn test_receiving_bytes_from_closed_connection() {
+
+    // The peer sends one whole frame and closes the connection, so the next frame does not start at all.
+    let mut frame = Vec::new();
+    write_len_prefix(&mut frame, 5).await.unwrap();
+    frame.extend_from_slice(b"Hello");
+    let mut reader = frame.as_slice();
+    assert_eq!(receive_bytes(&mut reader).await.unwrap(), b"Hello");
+    assert!(matches!(receive_bytes(&mut reader).await, Err(BytesSendReceiveError::ConnectionClosed)));
+
+    // The error is recognizable even after it was wrapped into a message receiving error.
+    let error = receive_message(&mut [].as_slice()).await.unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(BytesSendReceiveError::ConnectionClosed)));
+}
+
+#[tokio::test]
+async fn test_receiving_truncated_frame() {
+
+    // The connection is closed after the length prefix and a part of the payload.
+    let mut frame = Vec::new();
+    write_len_prefix(&mut frame, 5).await.unwrap();
+    frame.extend_from_slice(b"Hel");
+    assert!(matches!(receive_bytes(&mut frame.as_slice()).await, Err(BytesSendReceiveError::TruncatedFrame)));
+
+    // The connection is closed in the middle of the length prefix.
+    assert!(matches!(receive_bytes(&mut &frame[..2]).await, Err(BytesSendReceiveError::TruncatedFrame)));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sending_and_receiving_messages_over_unix_socket() {
+
+    // The helpers are not tied to TCP, so a connected pair of Unix domain sockets works as well.
+    let (stream_on_client, stream_on_server) = tokio::net::UnixStream::pair().unwrap();
+    let (_, mut writer_on_client) = stream_on_client.into_split();
+    let (mut reader_on_server, _) = stream_on_server.into_split();
+
+    let test_message = MessageType::Text("This is a test string.".to_string());
+    send_message(&mut writer_on_client, &test_message).await.unwrap();
+    let received_message = receive_message(&mut reader_on_server).await.unwrap();
+
+    assert_eq!(received_message, test_message);
+}
+
+#[tokio::test]
+async fn test_receiving_bytes_into_reused_buffer() {
+
+    // An in-memory stream is enough, the frames are the same as on a socket.
+    let (mut writer, mut reader) = tokio::io::duplex(4096);
+
+    // Frames get both longer and shorter, so the buffer has to grow and shrink.
+    let payloads: Vec<Vec<u8>> = vec![vec![1u8; 100], vec![2u8; 10], vec![], vec![3u8; 1000], vec![4u8; 1]];
+    for payload in &payloads {
+        send_bytes(&mut writer, payload).await.unwrap();
+    }
+
+    // Receive all frames into the same buffer.
+    let mut buffer = Vec::new();
+    for payload in &payloads {
+        receive_bytes_into(&mut reader, &mut buffer).await.unwrap();
+        assert_eq!(&buffer, payload);
+    }
+
+    // Messages can be received into a reused buffer too.
+    let long_message = MessageType::Text("A longer message.".repeat(10));
+    let short_message = MessageType::Text("Short.".to_string());
+    send_message(&mut writer, &long_message).await.unwrap();
+    send_message(&mut writer, &short_message).await.unwrap();
+    assert_eq!(receive_message_into(&mut reader, &mut buffer).await.unwrap(), long_message);
+    assert_eq!(receive_message_into(&mut reader, &mut buffer).await.unwrap(), short_message);
+}
+
+#[tokio::test]
+async fn test_receiving_corrupt_frame() {
+    let (mut writer, mut reader) = tokio::io::duplex(4096);
+
+    // The frame is a valid text message cut short, so decoding fails at its end.
+    let mut bytes = serde_cbor::to_vec(&MessageType::Text("Hello.".to_string())).unwrap();
+    bytes.truncate(bytes.len() - 2);
+    send_bytes(&mut writer, &bytes).await.unwrap();
+
+    let error = receive_message(&mut reader).await.unwrap_err();
+    let decode_error = error.downcast_ref::<DecodeError>().unwrap();
+    assert_eq!(decode_error.len, bytes.len());
+    assert_eq!(decode_error.offset, bytes.len() as u64);
+    assert!(error.to_string().starts_with(&format!("Failed to turn {} bytes into MessageType", bytes.len())));
+
+    // Bytes that are not CBOR at all are reported too.
+    send_bytes(&mut writer, &[0xff; 16]).await.unwrap();
+    let error = receive_message(&mut reader).await.unwrap_err();
+    assert_eq!(error.downcast_ref::<DecodeError>().unwrap().len, 16);
+}
+
+#[test]
+fn test_compressing_only_when_beneficial() {
+
+    // A long repetitive message is worth compressing.
+    let repetitive_message = MessageType::Text("Compress me, please. ".repeat(200));
+    let plain_frame = encode_message(&repetitive_message, Compression::Disabled).unwrap();
+    let compressed_frame = encode_message(&repetitive_message, Compression::Enabled).unwrap();
+    assert_eq!(compressed_frame[0], FRAME_COMPRESSED);
+    assert!(compressed_frame.len() < plain_frame.len() / 10);
+    assert_eq!(decode_message(&compressed_frame, Compression::Enabled).unwrap(), repetitive_message);
+
+    // A message below the threshold is sent as it is behind the flag byte.
+    let short_message = MessageType::Text("Short, but repeated. ".repeat(10));
+    let plain_frame = encode_message(&short_message, Compression::Disabled).unwrap();
+    assert!(plain_frame.len() < COMPRESSION_THRESHOLD);
+    let flagged_frame = encode_message(&short_message, Compression::Enabled).unwrap();
+    assert_eq!(flagged_frame[0], FRAME_UNCOMPRESSED);
+    assert_eq!(&flagged_frame[1..], plain_frame.as_slice());
+    assert_eq!(decode_message(&flagged_frame, Compression::Enabled).unwrap(), short_message);
+
+    // Random bytes do not get shorter, so they are not compressed even above the threshold.
+    let mut state: u32 = 12345;
+    let random_bytes: Vec<u8> = (0..4 * COMPRESSION_THRESHOLD).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect();
+    let flagged_frame = encode_frame(random_bytes.clone(), Compression::Enabled).unwrap();
+    assert_eq!(flagged_frame[0], FRAME_UNCOMPRESSED);
+    assert_eq!(flagged_frame.len(), random_bytes.len() + 1);
+    assert_eq!(decode_frame(&flagged_frame, Compression::Enabled).unwrap().as_ref(), random_bytes.as_slice());
+
+    // Frames with an unknown flag or without any bytes are refused.
+    let mut unknown_flag_frame = flagged_frame.clone();
+    unknown_flag_frame[0] = 7;
+    assert!(decode_message(&unknown_flag_frame, Compression::Enabled).is_err());
+    assert!(decode_message(&[], Compression::Enabled).is_err());
+}
+
+#[tokio::test]
+async fn test_sending_and_receiving_compressed_messages() {
+
+    let (mut writer, mut reader) = tokio::io::duplex(4096);
+
+    // A large file is compressed and a short text is not, but both are received as they were sent.
+    let file_message = MessageType::File { name: "notes.txt".to_string(), mime: Some("text/plain".to_string()), data: b"Line of notes.\n".repeat(1000) };
+    let text_message = MessageType::Text("Hello.".to_string());
+    let sending = async {
+        send_compressed_message(&mut writer, &file_message, Compression::Enabled).await.unwrap();
+        send_compressed_message(&mut writer, &text_message, Compression::Enabled).await.unwrap();
+    };
+    let receiving = async {
+        let mut buffer = Vec::new();
+        let received_file = receive_compressed_message_into(&mut reader, &mut buffer, Compression::Enabled).await.unwrap();
+        let file_frame_len = buffer.len();
+        let received_text = receive_compressed_message_into(&mut reader, &mut buffer, Compression::Enabled).await.unwrap();
+        (received_file, file_frame_len, received_text)
+    };
+    let (_, (received_file, file_frame_len, received_text)) = tokio::join!(sending, receiving);
+
+    assert_eq!(received_file, file_message);
+    assert!(file_frame_len < 1000);
+    assert_eq!(received_text, text_message);
+}
+
+#[test]
+fn test_refusing_decompression_bombs() {
+
+    // Zeros compress very well, so a short frame would be decompressed into more bytes than the longest frame has.
+    let zeros = vec![0u8; 1024 * 1024];
+    let mut encoder = DeflateEncoder::new(vec![FRAME_COMPRESSED], flate2::Compression::fast());
+    for _ in 0..=MAX_DECOMPRESSED_LENGTH / zeros.len() {
+        encoder.write_all(&zeros).unwrap();
+    }
+    let bomb = encoder.finish().unwrap();
+    assert!(bomb.len() < MAX_DECOMPRESSED_LENGTH / 100);
+    let error = decode_frame(&bomb, Compression::Enabled).unwrap_err();
+    assert_eq!(error.to_string(), "Decompressed frame is too long.");
+}
+
+#[tokio::test]
+async fn test_refusing_too_long_frames() {
+
+    // A frame longer than the longest frame is refused before its bytes are read.
+    let mut frame = Vec::new();
+    write_len_prefix(&mut frame, MAX_FRAME_LENGTH + 1).await.unwrap();
+    let error = receive_bytes(&mut frame.as_slice()).await.unwrap_err();
+    assert!(matches!(error, BytesSendReceiveError::FrameTooLong(len) if len == MAX_FRAME_LENGTH + 1));
+
+    // A message that does not fit into a frame is not encoded, and the signer stays at the same sequence number.
+    let mut signer = FrameSigner::new(MessageKey::new(b"secret").unwrap(), Direction::ClientToServer);
+    let too_long_text = MessageType::Text("a".repeat(MAX_FRAME_LENGTH));
+    assert!(encode_signed_message(&too_long_text, Compression::Disabled, Some(&mut signer)).is_err());
+    let next_frame = encode_signed_message(&MessageType::Text("Hello.".to_string()), Compression::Disabled, Some(&mut signer)).unwrap();
+    let mut receiver = FrameSigner::new(MessageKey::new(b"secret").unwrap(), Direction::ClientToServer);
+    assert!(verify_frame(&next_frame, &mut receiver).is_ok());
+}
+
+#[test]
+fn test_signing_frames() {
+    let key = MessageKey::new(b"shared secret").unwrap();
+    let signer = || FrameSigner::new(key.clone(), Direction::ClientToServer);
+    let message = MessageType::Text("Pay Bob 10 coins.".to_string());
+
+    // A signed frame ends with the signature and is decoded with the same key, with compression too.
+    let plain_frame = encode_message(&message, Compression::Disabled).unwrap();
+    let signed_frame = encode_signed_message(&message, Compression::Disabled, Some(&mut signer())).unwrap();
+    assert_eq!(signed_frame.len(), plain_frame.len() + SIGNATURE_BYTES);
+    assert_eq!(&signed_frame[..plain_frame.len()], plain_frame.as_slice());
+    assert_eq!(decode_signed_message(&signed_frame, Compression::Disabled, Some(&mut signer())).unwrap(), message);
+    let signed_compressed_frame = encode_signed_message(&message, Compression::Enabled, Some(&mut signer())).unwrap();
+    assert_eq!(decode_signed_message(&signed_compressed_frame, Compression::Enabled, Some(&mut signer())).unwrap(), message);
+
+    // A frame changed on the way is refused, whether the change is in the message or in the signature.
+    for position in [plain_frame.len() - 1, signed_frame.len() - 1] {
+        let mut tampered_frame = signed_frame.clone();
+        tampered_frame[position] ^= 1;
+        let error = decode_signed_message(&tampered_frame, Compression::Disabled, Some(&mut signer())).unwrap_err();
+        assert_eq!(error.downcast_ref::<SignatureError>().unwrap().len, signed_frame.len());
+    }
+
+    // Frames signed with another key, unsigned frames and frames shorter than a signature are refused too.
+    let mut other_signer = FrameSigner::new(MessageKey::new(b"another secret").unwrap(), Direction::ClientToServer);
+    assert!(decode_signed_message(&signed_frame, Compression::Disabled, Some(&mut other_signer)).unwrap_err().is::<SignatureError>());
+    assert!(decode_signed_message(&plain_frame, Compression::Disabled, Some(&mut signer())).unwrap_err().is::<SignatureError>());
+    assert!(verify_frame(&[0u8; SIGNATURE_BYTES - 1], &mut signer()).is_err());
+
+    // An empty key is refused and a key is never printed.
+    assert!(MessageKey::new(b"").is_err());
+    assert!(!format!("{:?}", key).contains("secret"));
+}
+
+#[test]
+fn test_refusing_replayed_and_reflected_frames() {
+    let key = MessageKey::new(b"shared secret").unwrap();
+    let mut client_sender = FrameSigner::new(key.clone(), Direction::ClientToServer);
+    let mut server_receiver = FrameSigner::new(key.clone(), Direction::ClientToServer);
+    let first = MessageType::Text("Pay Bob 10 coins.".to_string());
+    let second = MessageType::Text("Hello.".to_string());
+    let first_frame = encode_signed_message(&first, Compression::Disabled, Some(&mut client_sender)).unwrap();
+    let second_frame = encode_signed_message(&second, Compression::Disabled, Some(&mut client_sender)).unwrap();
+
+    // A frame is accepted once. Replayed, it is out of sequence and refused.
+    assert_eq!(decode_signed_message(&first_frame, Compression::Disabled, Some(&mut server_receiver)).unwrap(), first);
+    assert!(decode_signed_message(&first_frame, Compression::Disabled, Some(&mut server_receiver)).unwrap_err().is::<SignatureError>());
+    // A refused frame does not move the receiver on, so the next frame is still accepted.
+    assert_eq!(decode_signed_message(&second_frame, Compression::Disabled, Some(&mut server_receiver)).unwrap(), second);
+
+    // A frame that comes after a dropped one is out of sequence too.
+    let mut receiver_missing_first = FrameSigner::new(key.clone(), Direction::ClientToServer);
+    assert!(decode_signed_message(&second_frame, Compression::Disabled, Some(&mut receiver_missing_first)).unwrap_err().is::<SignatureError>());
+
+    // A frame reflected back to its sender is refused, because it was signed for the other direction.
+    let mut client_receiver = FrameSigner::new(key.clone(), Direction::ServerToClient);
+    assert!(decode_signed_message(&first_frame, Compression::Disabled, Some(&mut client_receiver)).unwrap_err().is::<SignatureError>());
+    let mut server_sender = FrameSigner::new(key.clone(), Direction::ServerToClient);
+    let server_frame = encode_signed_message(&first, Compression::Disabled, Some(&mut server_sender)).unwrap();
+    let mut other_server_receiver = FrameSigner::new(key, Direction::ClientToServer);
+    assert!(decode_signed_message(&server_frame, Compression::Disabled, Some(&mut other_server_receiver)).unwrap_err().is::<SignatureError>());
+}
+
+#[tokio::test]
+async fn test_sending_and_receiving_signed_messages() {
+    let (mut writer, mut reader) = tokio::io::duplex(4096);
+    let key = MessageKey::new(b"shared secret").unwrap();
+    let mut sender = FrameSigner::new(key.clone(), Direction::ServerToClient);
+    let mut receiver = FrameSigner::new(key, Direction::ServerToClient);
+
+    let message = MessageType::Text("Hello.".to_string());
+    send_signed_message(&mut writer, &message, Compression::Enabled, Some(&mut sender)).await.unwrap();
+    let mut buffer = Vec::new();
+    assert_eq!(receive_signed_message_into(&mut reader, &mut buffer, Compression::Enabled, Some(&mut receiver)).await.unwrap(), message);
+
+    // A receiver with a key refuses a frame that was sent without a signature.
+    send_compressed_message(&mut writer, &message, Compression::Enabled).await.unwrap();
+    let error = receive_signed_message_into(&mut reader, &mut buffer, Compression::Enabled, Some(&mut receiver)).await.unwrap_err();
+    assert!(error.is::<SignatureError>());
+}
+
+#[test]
+fn test_describing_version() {
+    assert_eq!(describe_version("client", "0.1.0"), format!("client 0.1.0 (protocol version {})", PROTOCOL_VERSION));
+}
+
+#[test]
+fn test_formatted_text_and_unknown_formats() {
+    let message = MessageType::FormattedText { text: "**Hello**".to_string(), format: TextFormat::Markdown };
+    let frame = encode_message(&message, Compression::Disabled).unwrap();
+    assert_eq!(decode_message(&frame, Compression::Disabled).unwrap(), message);
+
+    // A format added by a newer version of the protocol is received as unknown.
+    let future_format = serde_cbor::to_vec(&"Html").unwrap();
+    assert_eq!(serde_cbor::from_slice::<TextFormat>(&future_format).unwrap(), TextFormat::Unknown);
+}
+
+#[test]
+fn test_message_kinds() {
+    assert_eq!(MessageType::Text("Hello.".to_string()).kind_str(), "text");
+    assert_eq!(MessageType::Image(vec![0u8; 1024]).kind_str(), "image");
+    assert_eq!(MessageType::File { name: "a.txt".to_string(), mime: None, data: Vec::new() }.kind_str(), "file");
+    let auth_request = MessageType::AuthRequest("L".to_string(), "alice".to_string(), "password".to_string(), Compression::Disabled, Signing::Disabled, ReadReceipts::Disabled);
+    assert_eq!(auth_request.kind_str(), "auth_request");
+
+    // A wrapped message is labeled by the wrapper, not by the message inside it.
+    assert_eq!(MessageType::Authored("alice".to_string(), Box::new(MessageType::Text("Hello.".to_string()))).kind_str(), "authored");
+    assert_eq!(MessageType::Tagged(1, Box::new(MessageType::Text("Hello.".to_string()))).kind_str(), "tagged");
+}
+
+#[test]
+fn test_parsing_socket_addresses() {
+    assert_eq!(parse_socket_addr("127.0.0.1:11111").unwrap(), std::net::SocketAddr::from(([127, 0, 0, 1], 11111)));
+    assert_eq!(parse_socket_addr("[::1]:11111").unwrap(), std::net::SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 11111)));
+    assert!(parse_socket_addr(":11111").is_err());
+    assert!(parse_socket_addr("localhost:11111").is_err());
+    assert!(parse_socket_addr("127.0.0.1").is_err());
+    assert!(parse_socket_addr("127.0.0.1:99999").is_err());
+    assert!(parse_socket_addr("::1:11111").is_err());
+
+    let error = parse_socket_addr("127.0.0.1;11111").unwrap_err();
+    assert_eq!(error.to_string(), "'127.0.0.1;11111' is not a valid socket address. Use an IP address and a port, e.g. 127.0.0.1:11111 or [::1]:11111.");
+}
+
+#[test]
+fn test_formatting_json_log_lines() {
+    assert_eq!(LogFormat::from_name("text").unwrap(), LogFormat::Text);
+    assert_eq!(LogFormat::from_name("json").unwrap(), LogFormat::Json);
+    assert!(LogFormat::from_name("xml").is_err());
+
+    // The message is escaped, so a line is always one valid JSON object.
+    let line = format_json_log_line("2026-10-16T12:00:00Z", &log::Record::builder()
+        .args(format_args!("Client \"alice\" sent:\n{}", 42))
+        .level(log::Level::Warn)
+        .target("server::connections")
+        .build());
+    assert!(!line.contains('\n'));
+    let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(value, serde_json::json!({
+        "timestamp": "2026-10-16T12:00:00Z",
+        "level": "WARN",
+        "target": "server::connections",
+        "message": "Client \"alice\" sent:\n42"
+    }));
+}